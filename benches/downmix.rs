@@ -0,0 +1,37 @@
+//! Compares the ways of converting a full 30 second buffer of Discord
+//! audio into Whisper's format.
+//!
+//!   cargo bench --bench downmix
+//!   cargo bench --bench downmix --features parallel
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use discrivener::audio::downmix;
+
+/// 30 seconds of 48khz stereo
+const DISCORD_SAMPLES: usize = 30 * 48000 * 2;
+
+/// each Whisper sample is made from 3 stereo Discord samples
+const WHISPER_SAMPLES: usize = DISCORD_SAMPLES / 6;
+
+fn bench_downmix(c: &mut Criterion) {
+    let discord_audio: Vec<i16> = (0..DISCORD_SAMPLES)
+        .map(|i| (i as i32 * 7919 % 65536 - 32768) as i16)
+        .collect();
+    let mut dest = vec![0.0; WHISPER_SAMPLES];
+
+    let mut group = c.benchmark_group("downmix 30s buffer");
+    group.bench_function("scalar", |b| {
+        b.iter(|| downmix::downmix_scalar(black_box(&discord_audio), black_box(&mut dest)))
+    });
+    group.bench_function("lanes", |b| {
+        b.iter(|| downmix::downmix_lanes(black_box(&discord_audio), black_box(&mut dest)))
+    });
+    #[cfg(feature = "parallel")]
+    group.bench_function("parallel", |b| {
+        b.iter(|| downmix::downmix_parallel(black_box(&discord_audio), black_box(&mut dest)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_downmix);
+criterion_main!(benches);
@@ -39,6 +39,11 @@ async fn tokio_main(cli: Cli) {
         cli.model_path,
         Arc::new(move |event| match event {
             VoiceChannelEvent::Transcription(message) => on_text(message, log_performance),
+            VoiceChannelEvent::ChannelTranscription(messages) => {
+                for message in messages {
+                    on_text(message, log_performance);
+                }
+            }
             VoiceChannelEvent::Connect(status) => {
                 println!(
                     "Connection status: connected to channel #{}",
@@ -49,6 +54,43 @@ async fn tokio_main(cli: Cli) {
                     }
                 )
             }
+            VoiceChannelEvent::TranscriptionError { message, user_id } => {
+                eprintln!("Failed to transcribe {}: {}", user_id, message);
+            }
+            VoiceChannelEvent::TranscriptionEnabledChanged { enabled, user_id } => {
+                println!(
+                    "Transcription {} for {}",
+                    if enabled { "enabled" } else { "disabled" },
+                    user_id
+                );
+            }
+            VoiceChannelEvent::TranscriptionPausedChanged { paused } => {
+                println!(
+                    "Transcription {}",
+                    if paused { "paused" } else { "resumed" }
+                );
+            }
+            VoiceChannelEvent::TranscriptionProgress { percent, user_id } => {
+                eprintln!("Transcribing {}: {}%", user_id, percent);
+            }
+            VoiceChannelEvent::TranscriptionTimedOut {
+                timeout_ms,
+                user_id,
+            } => {
+                eprintln!(
+                    "Transcription for {} timed out after {}ms",
+                    user_id, timeout_ms
+                );
+            }
+            VoiceChannelEvent::UnexpectedAudioFormat {
+                expected_samples,
+                received_samples,
+            } => {
+                eprintln!(
+                    "Unexpected audio format: {} samples per packet, expected {}",
+                    received_samples, expected_samples
+                );
+            }
             VoiceChannelEvent::UserJoin(user_id) => {
                 println!("User joined:  {}", user_id,)
             }
@@ -68,6 +110,70 @@ async fn tokio_main(cli: Cli) {
             VoiceChannelEvent::Disconnect(_) => {
                 println!("Connection status: disconnected");
             }
+            VoiceChannelEvent::Heartbeat {
+                active_users,
+                buffered_ms,
+            } => {
+                eprintln!(
+                    "Heartbeat: {} active users, {}ms of audio buffered",
+                    active_users, buffered_ms
+                );
+            }
+            VoiceChannelEvent::InputClipping {
+                clipped_percent,
+                user_id,
+            } => {
+                eprintln!(
+                    "{}% of the audio from {} is clipped, their gain may be too high",
+                    clipped_percent, user_id
+                );
+            }
+            VoiceChannelEvent::ModelReloaded { model_path } => {
+                println!("Now transcribing with {}", model_path);
+            }
+            VoiceChannelEvent::PipelineReset => {
+                println!("Transcription reset");
+            }
+            VoiceChannelEvent::SessionEnded {
+                total_audio_ms,
+                total_transcriptions,
+                ..
+            } => {
+                println!(
+                    "Session ended: {} transcriptions from {}ms of audio",
+                    total_transcriptions, total_audio_ms
+                );
+            }
+            VoiceChannelEvent::AudioEvicted {
+                discarded_ms,
+                user_id,
+                ..
+            } => {
+                println!("Evicted {}ms of audio from {}", discarded_ms, user_id);
+            }
+            VoiceChannelEvent::SpeechSegments {
+                segments,
+                speech_segments,
+                user_id,
+            } => {
+                eprintln!(
+                    "Speech in {} of {} segments from {}",
+                    speech_segments, segments, user_id
+                );
+            }
+            VoiceChannelEvent::StaleAudioDiscarded {
+                discarded_ms,
+                user_id,
+                ..
+            } => {
+                println!(
+                    "Discarded {}ms of stale audio from {}",
+                    discarded_ms, user_id
+                );
+            }
+            VoiceChannelEvent::BufferRolled { user_id } => {
+                println!("Buffer full for {}, starting a new one", user_id);
+            }
             VoiceChannelEvent::ChannelSilent(silent) => {
                 if silent {
                     println!("Channel is silent");
@@ -77,7 +183,11 @@ async fn tokio_main(cli: Cli) {
             }
         }),
     )
-    .await;
+    .await
+    .unwrap_or_else(|err| {
+        eprintln!("Failed to load model: {}", err);
+        std::process::exit(1);
+    });
 
     let connection_result = discrivener
         .connect(
@@ -96,7 +206,10 @@ async fn tokio_main(cli: Cli) {
     }
 
     signal::ctrl_c().await.unwrap();
-    discrivener.disconnect().await;
+    let shutdown_report = discrivener.disconnect().await;
+    if !shutdown_report.is_clean() {
+        eprintln!("Didn't shut down cleanly: {:?}", shutdown_report);
+    }
 }
 
 /// Connect to a discord voice channel
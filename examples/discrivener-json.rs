@@ -15,7 +15,11 @@ async fn tokio_main(cli: Cli) {
             println!("{}", json_string);
         }),
     )
-    .await;
+    .await
+    .unwrap_or_else(|err| {
+        eprintln!("Failed to load model: {}", err);
+        std::process::exit(1);
+    });
 
     let connection_result = discrivener
         .connect(
@@ -44,7 +48,10 @@ async fn tokio_main(cli: Cli) {
             }
         }
     }
-    discrivener.disconnect().await;
+    let shutdown_report = discrivener.disconnect().await;
+    if !shutdown_report.is_clean() {
+        eprintln!("Didn't shut down cleanly: {:?}", shutdown_report);
+    }
 }
 
 /// Connect to a discord voice channel
@@ -1,6 +1,8 @@
 use std::{
     cmp::{max, min},
+    collections::BTreeMap,
     num::Wrapping,
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
@@ -8,28 +10,71 @@ use bytes::Bytes;
 use whisper_rs::WhisperToken;
 
 use crate::model::{
+    clock::{Clock, SystemClock},
+    config::AudioPayloadFormat,
     constants::{
-        AUDIO_TO_RECORD, BITRATE_CONVERSION_RATIO, DISCORD_AUDIO_CHANNELS,
-        DONT_EVEN_BOTHER_RMS_THRESHOLD, RTC_CLOCK_SAMPLES_PER_MILLISECOND,
-        WHISPER_AUDIO_BUFFER_SIZE, WHISPER_SAMPLES_PER_MILLISECOND,
+        AUDIO_TO_RECORD, BITRATE_CONVERSION_RATIO, CLIPPING_LEVEL, DISCORD_AUDIO_CHANNELS,
+        DONT_EVEN_BOTHER_RMS_THRESHOLD, LEADING_SILENCE_PRE_ROLL, NANOS_PER_WHISPER_SAMPLE,
+        RTC_CLOCK_SAMPLES_PER_MILLISECOND, WHISPER_AUDIO_BUFFER_SIZE,
+        WHISPER_SAMPLES_PER_MILLISECOND,
     },
     types::{
         DiscordAudioSample, DiscordRtcTimestamp, DiscordRtcTimestampInner, WhisperAudioSample,
     },
 };
 
-use super::events::TranscriptionRequest;
+use super::{
+    downmix::downmix,
+    events::{AudioSamples, TranscriptionRequest},
+};
 
-const DISCORD_AUDIO_MAX_VALUE: WhisperAudioSample = DiscordAudioSample::MAX as WhisperAudioSample;
+/// How much of the gap between a smoothed level and the level of the
+/// audio it's given the smoothed level closes, with an exponential
+/// time constant, when the audio lasts `elapsed`.
+pub(crate) fn smoothing_step(elapsed: &Duration, time_constant: &Duration) -> f32 {
+    let time_constants = elapsed.as_secs_f32() / time_constant.as_secs_f32().max(f32::EPSILON);
+    1.0 - (-time_constants).exp()
+}
 
-pub(crate) const DISCORD_AUDIO_MAX_VALUE_TWO_SAMPLES: WhisperAudioSample =
-    DISCORD_AUDIO_MAX_VALUE * DISCORD_AUDIO_CHANNELS as WhisperAudioSample;
+/// when looking for runs of silence, look at the audio in
+/// chunks of this size.  This matches the length of a Discord
+/// audio packet.
+const SILENCE_SCAN_INTERVAL: Duration = Duration::from_millis(20);
 
-fn duration_to_rtc(duration: &Duration) -> DiscordRtcTimestamp {
-    let rtc_samples = duration.as_millis() * RTC_CLOCK_SAMPLES_PER_MILLISECOND;
+pub(crate) fn duration_to_rtc(duration: &Duration) -> DiscordRtcTimestamp {
+    let rtc_samples = duration.as_nanos() * RTC_CLOCK_SAMPLES_PER_MILLISECOND / 1_000_000;
     Wrapping(rtc_samples as DiscordRtcTimestampInner)
 }
 
+fn rtc_to_duration(rtc: &DiscordRtcTimestamp) -> Duration {
+    Duration::from_nanos((rtc.0 as u128 * 1_000_000 / RTC_CLOCK_SAMPLES_PER_MILLISECOND) as u64)
+}
+
+/// True if `rtc_timestamp` comes before `reference`, taking wrapping
+/// into account.  Anything more than a buffer's length before is
+/// assumed to instead be far in the future.
+fn rtc_is_before(rtc_timestamp: &DiscordRtcTimestamp, reference: &DiscordRtcTimestamp) -> bool {
+    let delta = reference - rtc_timestamp;
+    delta.0 != 0 && delta <= duration_to_rtc(&AUDIO_TO_RECORD)
+}
+
+/// How far the RTC clock moves over the course of the audio.
+fn discord_audio_rtc_length(discord_audio: &[DiscordAudioSample]) -> DiscordRtcTimestamp {
+    Wrapping((discord_audio.len() / DISCORD_AUDIO_CHANNELS) as DiscordRtcTimestampInner)
+}
+
+/// The RTC clock runs at Discord's sample rate, so this is exact.
+fn whisper_samples_to_rtc(num_samples: usize) -> DiscordRtcTimestamp {
+    Wrapping((num_samples * BITRATE_CONVERSION_RATIO) as DiscordRtcTimestampInner)
+}
+
+fn audio_rtc_length(audio: &AudioSamples) -> DiscordRtcTimestamp {
+    match audio {
+        AudioSamples::Discord(discord_audio) => discord_audio_rtc_length(discord_audio),
+        AudioSamples::Whisper(whisper_audio) => whisper_samples_to_rtc(whisper_audio.len()),
+    }
+}
+
 fn rtc_timestamp_to_index(ts1: &DiscordRtcTimestamp, ts2: &DiscordRtcTimestamp) -> usize {
     let delta = (ts2 - ts1).0 as usize;
     // we want the number of 16khz samples, so just multiply by 2.
@@ -41,11 +86,27 @@ fn discord_samples_to_whisper_samples(samples: usize) -> usize {
 }
 
 fn samples_to_duration(num_samples: usize) -> Duration {
-    Duration::from_millis((num_samples / WHISPER_SAMPLES_PER_MILLISECOND) as u64)
+    Duration::from_nanos(num_samples as u64 * NANOS_PER_WHISPER_SAMPLE)
 }
 
+/// Converts a duration to a number of samples, rounding down
+/// to the nearest whole sample.
 fn duration_to_index(duration: &Duration) -> usize {
-    duration.as_millis() as usize * WHISPER_SAMPLES_PER_MILLISECOND
+    (duration.as_nanos() / NANOS_PER_WHISPER_SAMPLE as u128) as usize
+}
+
+/// Converts samples to little-endian PCM16, rounding to the nearest
+/// value and clamping anything which was out of range.
+fn whisper_samples_to_pcm16(samples: &[WhisperAudioSample]) -> Bytes {
+    let mut pcm16 = Vec::with_capacity(samples.len() * std::mem::size_of::<i16>());
+    for sample in samples {
+        let value = (sample * i16::MAX as WhisperAudioSample).round().clamp(
+            i16::MIN as WhisperAudioSample,
+            i16::MAX as WhisperAudioSample,
+        );
+        pcm16.extend_from_slice(&(value as i16).to_le_bytes());
+    }
+    Bytes::from(pcm16)
 }
 
 pub fn rms_over_slice(audio_data: &[WhisperAudioSample]) -> f32 {
@@ -55,26 +116,90 @@ pub fn rms_over_slice(audio_data: &[WhisperAudioSample]) -> f32 {
     (sum_squares / audio_data.len() as f32).sqrt()
 }
 
-pub(crate) struct AudioBuffer {
+/// A smoothed RMS level of the audio in a buffer, which follows the
+/// level of each 20ms frame with an exponential time constant, so that
+/// a single loud or quiet packet doesn't swing it.
+#[derive(Debug, Default)]
+struct LevelEnvelope {
+    level: f32,
+    /// how long the level has been below the silence threshold
+    quiet: Duration,
+    /// how much of the start of the buffer has been folded into the
+    /// level.  Audio backfilled before this point isn't counted.
+    processed: usize,
+}
+
+pub(crate) struct AudioBuffer<C: Clock = SystemClock> {
     pub audio: Vec<WhisperAudioSample>,
+    /// how many of the Discord samples in the buffer were clipped, and
+    /// how many there are in all
+    clipped_samples: usize,
+    /// how many of each packet's Discord samples were clipped, and how
+    /// many it had, by where its audio ends in the stream, so that they
+    /// can be taken back out once all of its audio is discarded
+    clipping: BTreeMap<u64, (usize, usize)>,
+    clock: C,
+    counted_samples: usize,
     pub dropped_audio_frames: usize,
+    envelope: LevelEnvelope,
     pub slice_id: u64,
     pub start_time: Option<(DiscordRtcTimestamp, SystemTime)>,
+    /// how many samples of audio came before the start of the buffer,
+    /// counting everything discarded or cleared since it was created
+    stream_position: u64,
 }
 
 impl AudioBuffer {
     pub fn new(slice_id: u64) -> Self {
+        Self::with_clock(slice_id, SystemClock)
+    }
+}
+
+impl<C: Clock> AudioBuffer<C> {
+    pub fn with_clock(slice_id: u64, clock: C) -> Self {
+        Self::from_parts(
+            Vec::with_capacity(WHISPER_AUDIO_BUFFER_SIZE),
+            clock,
+            slice_id,
+        )
+    }
+
+    /// A buffer holding audio which didn't come from Discord, e.g.
+    /// from a file, as though it started at `start_system`.
+    pub fn with_audio(
+        slice_id: u64,
+        clock: C,
+        audio: Vec<WhisperAudioSample>,
+        start_system: SystemTime,
+    ) -> Self {
+        let mut buffer = Self::from_parts(audio, clock, slice_id);
+        buffer.start_time = Some((Wrapping(0), start_system));
+        buffer
+    }
+
+    fn from_parts(audio: Vec<WhisperAudioSample>, clock: C, slice_id: u64) -> Self {
         Self {
-            audio: Vec::with_capacity(WHISPER_AUDIO_BUFFER_SIZE),
+            audio,
+            clipped_samples: 0,
+            clipping: BTreeMap::new(),
+            clock,
+            counted_samples: 0,
             dropped_audio_frames: 0,
+            envelope: LevelEnvelope::default(),
             slice_id,
             start_time: None,
+            stream_position: 0,
         }
     }
 
     pub fn clear(&mut self) {
+        self.stream_position += self.audio.len() as u64;
         self.audio.clear();
+        self.clipped_samples = 0;
+        self.clipping.clear();
+        self.counted_samples = 0;
         self.dropped_audio_frames = 0;
+        self.envelope = LevelEnvelope::default();
         self.start_time = None;
     }
 
@@ -90,39 +215,53 @@ impl AudioBuffer {
             return true;
         }
         let start_rtc = self.start_time.unwrap().0;
-        let end = start_rtc + duration_to_rtc(&AUDIO_TO_RECORD);
-
-        if start_rtc < end {
-            rtc_timestamp >= start_rtc && rtc_timestamp < end
-        } else {
-            // if the slice wraps around, then we need to check
-            // if the timestamp is either before the end or after
-            // the start.
-            rtc_timestamp < end || rtc_timestamp >= start_rtc
-        }
+        // measure how far past the start the timestamp is, rather than
+        // comparing against a computed end, since the end can wrap
+        // around even when the timestamp doesn't.  Anything before the
+        // start wraps to a huge offset, so it's rejected too.
+        let offset = rtc_timestamp - start_rtc;
+        offset < duration_to_rtc(&AUDIO_TO_RECORD)
     }
 
+    /// Builds a request to transcribe the audio in the buffer, starting
+    /// `buffer_offset` into it.  Returns None if there's no audio there.
     pub fn make_transcription_request(
         &self,
+        audio_format: AudioPayloadFormat,
+        buffer_offset: &Duration,
         previous_tokens: Vec<WhisperToken>,
     ) -> Option<TranscriptionRequest> {
-        self.start_time.map(|start_time| TranscriptionRequest {
-            audio_bytes: self.get_bytes(),
-            audio_duration: self.buffer_duration(),
+        let start_index = duration_to_index(buffer_offset);
+        if start_index >= self.audio.len() {
+            return None;
+        }
+        let (_, start_system) = self.start_time?;
+        let buffer_offset = samples_to_duration(start_index);
+        let audio_bytes = match audio_format {
+            AudioPayloadFormat::F32 => self.get_bytes(start_index),
+            AudioPayloadFormat::Pcm16 => whisper_samples_to_pcm16(&self.audio[start_index..]),
+        };
+        Some(TranscriptionRequest {
+            audio_bytes,
+            audio_duration: samples_to_duration(self.audio.len() - start_index),
+            audio_format,
+            buffer_offset,
+            is_interim: false,
             previous_tokens,
-            start_timestamp: start_time.1,
+            progress: None,
+            start_timestamp: start_system + buffer_offset,
+            temperature: None,
             user_id: self.slice_id,
         })
     }
 
-    /// True if the given audio can entirely fit within this slice.
-    pub fn can_fit_audio(
+    /// True if audio which moves the RTC clock on by `rtc_length` can
+    /// entirely fit within this slice.
+    fn can_fit_audio(
         &mut self,
         rtc_timestamp: &DiscordRtcTimestamp,
-        discord_audio: &[DiscordAudioSample],
+        rtc_length: DiscordRtcTimestamp,
     ) -> bool {
-        let rtc_length = duration_to_rtc(&samples_to_duration(discord_audio.len()));
-
         if !self.fits_within_this_slice(rtc_timestamp + rtc_length) {
             // if the timestamp is not within the bounds of this slice,
             // drop the audio.
@@ -139,6 +278,17 @@ impl AudioBuffer {
         true
     }
 
+    /// True if the audio comes after the start of the buffer, but ends
+    /// too far past it to fit, so there's no room for it until the
+    /// buffer is emptied.
+    pub fn is_past_end(&self, rtc_timestamp: &DiscordRtcTimestamp, audio: &AudioSamples) -> bool {
+        let Some((start_rtc, _)) = self.start_time.as_ref() else {
+            return false;
+        };
+        !rtc_is_before(rtc_timestamp, start_rtc)
+            && !self.fits_within_this_slice(rtc_timestamp + audio_rtc_length(audio))
+    }
+
     pub fn remaining_capacity(&self) -> Duration {
         let remaining = WHISPER_AUDIO_BUFFER_SIZE - self.audio.len();
         samples_to_duration(remaining)
@@ -148,6 +298,37 @@ impl AudioBuffer {
         self.audio.is_empty() && self.start_time.is_none()
     }
 
+    /// What percentage of the audio in the buffer was at or near full
+    /// scale when it was added.
+    pub fn clipped_percent(&self) -> f32 {
+        if self.counted_samples == 0 {
+            return 0.0;
+        }
+        self.clipped_samples as f32 * 100.0 / self.counted_samples as f32
+    }
+
+    /// Clears the buffer if it has a start time but no audio.  Nothing
+    /// should leave it like that, but if something does then there's
+    /// nothing to transcribe, and the stale start time would misplace
+    /// any audio added later.  Returns true if the buffer was cleared.
+    pub fn clear_if_hollow(&mut self) -> bool {
+        if !self.audio.is_empty() || self.start_time.is_none() {
+            return false;
+        }
+        self.clear();
+        true
+    }
+
+    /// Adds the given audio to the slice, in whichever format it's in.
+    pub fn add_samples(&mut self, rtc_timestamp: &DiscordRtcTimestamp, audio: &AudioSamples) {
+        match audio {
+            AudioSamples::Discord(discord_audio) => self.add_audio(rtc_timestamp, discord_audio),
+            AudioSamples::Whisper(whisper_audio) => {
+                self.add_whisper_audio(rtc_timestamp, whisper_audio)
+            }
+        }
+    }
+
     /// Adds the given audio to the slice, resampling it from the
     /// discord format to the whisper format.
     /// If the slice is full, then the audio will be "silently" dropped.
@@ -156,6 +337,23 @@ impl AudioBuffer {
         rtc_timestamp: &DiscordRtcTimestamp,
         discord_audio: &[DiscordAudioSample],
     ) {
+        // each whisper sample comes from a whole frame of Discord
+        // samples, so anything left over can't be used
+        let remainder = discord_audio.len() % (BITRATE_CONVERSION_RATIO * DISCORD_AUDIO_CHANNELS);
+        if remainder != 0 {
+            eprintln!(
+                "{}: dropping {} samples from a packet of {} which doesn't end on a whole frame",
+                self.slice_id,
+                remainder,
+                discord_audio.len()
+            );
+        }
+        let discord_audio = &discord_audio[..discord_audio.len() - remainder];
+        if discord_audio.is_empty() {
+            // nothing to add, so the slice shouldn't change, especially
+            // not by gaining a start time
+            return;
+        }
         // if audio is entirely silent, then don't add it
         if discord_audio
             .iter()
@@ -163,8 +361,85 @@ impl AudioBuffer {
         {
             return;
         }
-        if !self.can_fit_audio(rtc_timestamp, discord_audio) {
+        let Some(start_index) = self.place_audio(
+            rtc_timestamp,
+            discord_audio_rtc_length(discord_audio),
+            discord_samples_to_whisper_samples(discord_audio.len()),
+        ) else {
+            return;
+        };
+
+        let clipped = discord_audio
+            .iter()
+            .filter(|sample| sample.unsigned_abs() >= CLIPPING_LEVEL as u16)
+            .count();
+        self.clipped_samples += clipped;
+        self.counted_samples += discord_audio.len();
+        let end_index = start_index + discord_samples_to_whisper_samples(discord_audio.len());
+        self.clipping.insert(
+            self.stream_position + end_index as u64,
+            (clipped, discord_audio.len()),
+        );
+
+        self.resample_audio_from_discord_to_whisper(start_index, discord_audio);
+    }
+
+    /// Adds audio which is already in whisper's format, as is, without
+    /// any resampling.  Otherwise this behaves the same as `add_audio`.
+    pub fn add_whisper_audio(
+        &mut self,
+        rtc_timestamp: &DiscordRtcTimestamp,
+        whisper_audio: &[WhisperAudioSample],
+    ) {
+        if whisper_audio
+            .iter()
+            .all(|&x| x == WhisperAudioSample::default())
+        {
+            // nothing to add, or nothing worth adding
+            return;
+        }
+        let Some(start_index) = self.place_audio(
+            rtc_timestamp,
+            whisper_samples_to_rtc(whisper_audio.len()),
+            whisper_audio.len(),
+        ) else {
             return;
+        };
+        let end_index = start_index + whisper_audio.len();
+        self.audio.resize(
+            max(self.audio.len(), end_index),
+            WhisperAudioSample::default(),
+        );
+        self.audio[start_index..end_index].copy_from_slice(whisper_audio);
+    }
+
+    /// Works out where in the buffer audio starting at the timestamp
+    /// should go, given how far it moves the RTC clock on and how many
+    /// samples it will take up.  If it's the first audio, the buffer
+    /// starts with it.  Returns None if it can't go anywhere, in which
+    /// case it should be dropped.
+    fn place_audio(
+        &mut self,
+        rtc_timestamp: &DiscordRtcTimestamp,
+        rtc_length: DiscordRtcTimestamp,
+        num_samples: usize,
+    ) -> Option<usize> {
+        if let Some((start_rtc, _)) = self.start_time.as_ref() {
+            // this would have an index before the start of the buffer.
+            // We could make room for it, but that would move the start
+            // of the buffer out from under any transcription that's in
+            // progress, so drop it instead.
+            if rtc_is_before(rtc_timestamp, start_rtc) {
+                eprintln!(
+                    "{}: dropping audio from {:?} before the start of the buffer",
+                    self.slice_id,
+                    rtc_to_duration(&(start_rtc - rtc_timestamp))
+                );
+                return None;
+            }
+        }
+        if !self.can_fit_audio(rtc_timestamp, rtc_length) {
+            return None;
         }
 
         let start_index;
@@ -173,11 +448,37 @@ impl AudioBuffer {
         } else {
             // this is the first audio for the slice, so we need to set
             // the start time
-            self.start_time = Some((*rtc_timestamp, SystemTime::now()));
+            self.start_time = Some((*rtc_timestamp, self.clock.now()));
             start_index = 0;
         }
+        if self.overlaps_audio(start_index, num_samples) {
+            // a packet we already have, or one which lands on top of
+            // it, most likely right at the start of the buffer after
+            // audio before it was discarded.  Only gaps can be
+            // backfilled, so that audio is never overwritten.
+            eprintln!(
+                "{}: dropping audio which would overwrite {:?} into the buffer",
+                self.slice_id,
+                samples_to_duration(start_index)
+            );
+            return None;
+        }
+        Some(start_index)
+    }
 
-        self.resample_audio_from_discord_to_whisper(start_index, discord_audio);
+    /// True if `num_samples` of audio written at `start_index` would be
+    /// written over any samples we already have which aren't silence.
+    /// The silence inserted for gaps can be filled in later, when
+    /// packets arrive out of order.
+    fn overlaps_audio(&self, start_index: usize, num_samples: usize) -> bool {
+        let end_index = start_index + num_samples;
+        self.audio
+            .get(start_index..min(end_index, self.audio.len()))
+            .is_some_and(|existing| {
+                existing
+                    .iter()
+                    .any(|&sample| sample != WhisperAudioSample::default())
+            })
     }
 
     /// Transcode the audio into the given location of the buffer,
@@ -188,8 +489,9 @@ impl AudioBuffer {
     ///  - a single allocation for the new audio at the end of the buffer
     ///  - also, inserting silence if the new audio is not contiguous with
     ///    the previous audio
-    ///  - doing it in a way that we can also backfill audio if we get
-    ///    packets out-of-order
+    ///  - backfilling audio from packets which arrive out of order, by
+    ///    writing it over the silence inserted for it, when
+    ///    `start_index` is within the buffer
     ///
     fn resample_audio_from_discord_to_whisper(
         &mut self,
@@ -203,26 +505,15 @@ impl AudioBuffer {
 
         let dest_buf = &mut self.audio[start_index..end_index];
 
-        for (i, samples) in discord_audio
-            .chunks_exact(BITRATE_CONVERSION_RATIO * DISCORD_AUDIO_CHANNELS)
-            .enumerate()
-        {
-            // sum the channel data, and divide by the max value possible to
-            // get a value between -1.0 and 1.0
-            dest_buf[i] = samples
-                .iter()
-                .take(DISCORD_AUDIO_CHANNELS)
-                .map(|x| *x as WhisperAudioSample)
-                .sum::<WhisperAudioSample>()
-                / DISCORD_AUDIO_MAX_VALUE_TWO_SAMPLES;
-        }
+        downmix(discord_audio, dest_buf);
     }
 
-    /// Returns the current audio buffer as a Bytes reference.
+    /// Returns the current audio buffer, from the given sample
+    /// onwards, as a Bytes reference.
     /// This does not copy the audio data, so it is only valid
     /// for the lifetime of the audio slice.
-    pub fn get_bytes(&self) -> Bytes {
-        let buffer = self.audio.as_slice();
+    pub fn get_bytes(&self, start_index: usize) -> Bytes {
+        let buffer = &self.audio[start_index..];
         let buffer_len_bytes = std::mem::size_of_val(buffer);
         let byte_data =
             unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, buffer_len_bytes) };
@@ -233,8 +524,12 @@ impl AudioBuffer {
     /// from the start of the buffer, shuffling the remaining
     /// audio to the start of the buffer.  Any indexes and
     /// timestamps are adjusted accordingly.
+    ///
+    /// The timestamps are moved forward by exactly the audio
+    /// which was removed, so any part of the duration which is
+    /// shorter than a single sample is not discarded.
     pub fn discard_audio(&mut self, duration: &Duration) {
-        let discard_idx = duration.as_millis() as usize * WHISPER_SAMPLES_PER_MILLISECOND;
+        let discard_idx = duration_to_index(duration);
 
         if duration.is_zero() {
             return;
@@ -248,12 +543,22 @@ impl AudioBuffer {
 
         // eliminate this many samples from the start of the buffer
         self.audio.drain(0..discard_idx);
+        self.stream_position += discard_idx as u64;
+        self.envelope.processed = self.envelope.processed.saturating_sub(discard_idx);
+
+        // packets with none of their audio left no longer count
+        // towards the buffer's clipping
+        let kept = self.clipping.split_off(&(self.stream_position + 1));
+        for (clipped, counted) in std::mem::replace(&mut self.clipping, kept).into_values() {
+            self.clipped_samples -= clipped;
+            self.counted_samples -= counted;
+        }
 
         // update the start timestamp
         if let Some((start_rtc, start_system)) = self.start_time {
             self.start_time = Some((
-                start_rtc + duration_to_rtc(duration),
-                start_system + *duration,
+                start_rtc + whisper_samples_to_rtc(discard_idx),
+                start_system + samples_to_duration(discard_idx),
             ));
         }
     }
@@ -264,6 +569,38 @@ impl AudioBuffer {
         samples_to_duration(self.audio.len())
     }
 
+    /// Where the start of the buffer is in all the audio it has ever
+    /// held, in samples.  This only ever goes up, so unlike an index
+    /// into the buffer it still means the same thing after audio is
+    /// discarded.
+    pub fn stream_position(&self) -> u64 {
+        self.stream_position
+    }
+
+    /// How much memory the audio stored in the buffer takes up.
+    pub fn buffer_bytes(&self) -> usize {
+        self.audio.len() * std::mem::size_of::<WhisperAudioSample>()
+    }
+
+    /// How much audio would have to be discarded from the start of
+    /// the buffer to bring it down to `max_bytes`.
+    pub fn duration_over(&self, max_bytes: usize) -> Duration {
+        let max_samples = max_bytes / std::mem::size_of::<WhisperAudioSample>();
+        samples_to_duration(self.audio.len().saturating_sub(max_samples))
+    }
+
+    /// How long after the end of the buffered audio the given timestamp
+    /// is.  None if the buffer is empty, or the timestamp isn't after
+    /// the end of it.
+    pub fn time_after_end(&self, rtc_timestamp: &DiscordRtcTimestamp) -> Option<Duration> {
+        let (start_rtc, _) = self.start_time.as_ref()?;
+        let end_rtc = start_rtc + whisper_samples_to_rtc(self.audio.len());
+        if rtc_is_before(rtc_timestamp, &end_rtc) {
+            return None;
+        }
+        Some(rtc_to_duration(&(rtc_timestamp - end_rtc)))
+    }
+
     pub fn rms_over_interval(&self, start: &Duration, interval_length: &Duration) -> f32 {
         let (idx_start, idx_end) = self.clamped_range(start, interval_length);
         rms_over_slice(&self.audio[idx_start..idx_end])
@@ -292,6 +629,103 @@ impl AudioBuffer {
             || (rms_over_slice(slice) < DONT_EVEN_BOTHER_RMS_THRESHOLD)
     }
 
+    /// Finds every run of silence at least `min_length` long within the
+    /// first `within` of the buffer, and returns the midpoint of each,
+    /// relative to the start of the buffer.
+    /// Silence at the very start or end of the range isn't a gap between
+    /// two utterances, so it is never returned.
+    pub fn silent_gaps(&self, within: &Duration, min_length: &Duration) -> Vec<Duration> {
+        let (_, idx_end) = self.clamped_range(&Duration::ZERO, within);
+        let frame_len = duration_to_index(&SILENCE_SCAN_INTERVAL);
+
+        let mut gaps = vec![];
+        let mut run_start: Option<usize> = None;
+        for (i, frame) in self.audio[..idx_end].chunks(frame_len).enumerate() {
+            let silent = rms_over_slice(frame) < DONT_EVEN_BOTHER_RMS_THRESHOLD;
+            match (silent, run_start) {
+                (true, None) => run_start = Some(i),
+                (false, Some(start)) => {
+                    if start > 0 && samples_to_duration((i - start) * frame_len) >= *min_length {
+                        gaps.push(samples_to_duration((start + i) * frame_len / 2));
+                    }
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        gaps
+    }
+
+    /// Returns how long the audio at the end of the buffer has been
+    /// below the silence threshold.
+    pub fn trailing_silence(&self) -> Duration {
+        let frame_len = duration_to_index(&SILENCE_SCAN_INTERVAL);
+        let silent_samples: usize = self
+            .audio
+            .rchunks(frame_len)
+            .take_while(|frame| rms_over_slice(frame) < DONT_EVEN_BOTHER_RMS_THRESHOLD)
+            .map(|frame| frame.len())
+            .sum();
+        samples_to_duration(silent_samples)
+    }
+
+    /// Returns where the first sound after `start` is, skipping over
+    /// any silence there but for a little just before it, relative to
+    /// the start of the buffer.  If there's nothing but silence after
+    /// `start`, returns `start`.
+    pub fn skip_leading_silence(&self, start: &Duration) -> Duration {
+        let idx_start = min(duration_to_index(start), self.audio.len());
+        let frame_len = duration_to_index(&SILENCE_SCAN_INTERVAL);
+        self.audio[idx_start..]
+            .chunks(frame_len)
+            .position(|frame| rms_over_slice(frame) >= DONT_EVEN_BOTHER_RMS_THRESHOLD)
+            .map_or(*start, |frame| {
+                let first_sound = samples_to_duration(idx_start + frame * frame_len);
+                max(*start, first_sound.saturating_sub(LEADING_SILENCE_PRE_ROLL))
+            })
+    }
+
+    /// Returns how long it is from the start of the first sound to the
+    /// end of the last within the part of the buffer between `start`
+    /// and `end`, leaving out any silence either side of them.
+    pub fn speech_span(&self, start: &Duration, end: &Duration) -> Duration {
+        let (idx_start, idx_end) = self.clamped_range(start, &end.saturating_sub(*start));
+        let audio = &self.audio[idx_start..idx_end];
+        let frame_len = duration_to_index(&SILENCE_SCAN_INTERVAL);
+        let is_sound =
+            |frame: &[WhisperAudioSample]| rms_over_slice(frame) >= DONT_EVEN_BOTHER_RMS_THRESHOLD;
+        let Some(first) = audio.chunks(frame_len).position(is_sound) else {
+            return Duration::ZERO;
+        };
+        // there's at least one sound, so this finds one too
+        let last = audio.chunks(frame_len).rposition(is_sound).unwrap_or(first);
+        let span_end = min((last + 1) * frame_len, audio.len());
+        samples_to_duration(span_end - first * frame_len)
+    }
+
+    /// Like `trailing_silence`, but judged on the level smoothed with
+    /// the given time constant rather than each frame's own level, so
+    /// a quiet frame in the middle of speech doesn't start the count,
+    /// and a click in the middle of silence doesn't reset it.
+    pub fn smoothed_trailing_silence(&mut self, time_constant: &Duration) -> Duration {
+        let frame_len = duration_to_index(&SILENCE_SCAN_INTERVAL);
+        let step = smoothing_step(&SILENCE_SCAN_INTERVAL, time_constant);
+        let envelope = &mut self.envelope;
+        let unprocessed = self.audio.get(envelope.processed..).unwrap_or_default();
+        // only whole frames, so the last partial one is looked at again
+        // once it's complete
+        for frame in unprocessed.chunks_exact(frame_len) {
+            envelope.level += step * (rms_over_slice(frame) - envelope.level);
+            if envelope.level < DONT_EVEN_BOTHER_RMS_THRESHOLD {
+                envelope.quiet += SILENCE_SCAN_INTERVAL;
+            } else {
+                envelope.quiet = Duration::ZERO;
+            }
+            envelope.processed += frame_len;
+        }
+        envelope.quiet
+    }
+
     fn clamped_range(&self, start: &Duration, interval_length: &Duration) -> (usize, usize) {
         let idx_start = duration_to_index(start);
         let idx_end = idx_start + duration_to_index(interval_length);
@@ -303,9 +737,65 @@ impl AudioBuffer {
     }
 }
 
+/// Holds on to the sample storage of audio buffers which are no longer
+/// in use, so that when someone new starts talking we can reuse it rather
+/// than allocating another buffer's worth of audio.  Storage is only
+/// pooled once a buffer is released, so nothing is allocated up front.
+///
+/// Clones share the same pool.
+#[derive(Clone)]
+pub(crate) struct AudioBufferPool {
+    buffers: Arc<Mutex<Vec<Vec<WhisperAudioSample>>>>,
+    max_pooled: usize,
+}
+
+impl AudioBufferPool {
+    pub fn new(max_pooled: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_pooled))),
+            max_pooled,
+        }
+    }
+
+    /// Fills the pool with freshly allocated storage, up to `count`
+    /// buffers, so that users don't have to wait on the allocation
+    /// when they start talking.
+    pub fn preallocate(&self, count: usize) {
+        let mut buffers = self.buffers.lock().unwrap();
+        let count = min(count, self.max_pooled);
+        while buffers.len() < count {
+            buffers.push(Vec::with_capacity(WHISPER_AUDIO_BUFFER_SIZE));
+        }
+    }
+
+    /// Returns an empty buffer for the given user, reusing pooled
+    /// storage if there is any.
+    pub fn acquire(&self, slice_id: u64) -> AudioBuffer {
+        match self.buffers.lock().unwrap().pop() {
+            Some(audio) => AudioBuffer::from_parts(audio, SystemClock, slice_id),
+            None => AudioBuffer::new(slice_id),
+        }
+    }
+
+    /// Takes the storage from the given buffer, leaving it empty.
+    /// If the pool is already full, the storage is freed instead.
+    pub fn release(&self, buffer: &mut AudioBuffer) {
+        let mut audio = std::mem::take(&mut buffer.audio);
+        buffer.clear();
+        audio.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_pooled {
+            buffers.push(audio);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::model::constants::{AUDIO_TO_RECORD_SECONDS, DISCORD_SAMPLES_PER_SECOND};
+    use crate::model::{
+        clock::MockClock,
+        constants::{AUDIO_TO_RECORD_SECONDS, DISCORD_SAMPLES_PER_SECOND},
+    };
 
     use super::*;
     use std::time::Duration;
@@ -328,7 +818,447 @@ mod tests {
         assert_eq!(time.0, 1500 * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
     }
 
+    #[test]
+    fn test_stream_position_across_discards() {
+        let mut slice = AudioBuffer::new(1);
+        let one_second = 1000 * WHISPER_SAMPLES_PER_MILLISECOND as u64;
+        let mut rtc = Wrapping(0);
+        for _ in 0..3 {
+            // three seconds of audio, of which the first two are discarded
+            for _ in 0..150 {
+                slice.add_audio(&rtc, &[1000; 1920]);
+                rtc += Wrapping(960);
+            }
+            slice.discard_audio(&Duration::from_secs(2));
+        }
+        // each cycle discarded two seconds and kept the rest
+        assert_eq!(slice.stream_position(), 6 * one_second);
+        assert_eq!(slice.audio.len() as u64, 3 * one_second);
+
+        // clearing moves past everything that was left
+        slice.clear();
+        assert_eq!(slice.stream_position(), 9 * one_second);
+        slice.add_audio(&Wrapping(0), &[1000; 1920]);
+        slice.discard_audio(&Duration::from_secs(1));
+        assert_eq!(slice.stream_position(), 9 * one_second + 320);
+    }
+
+    #[test]
+    fn test_clear_if_hollow() {
+        let mut slice = AudioBuffer::new(1);
+        assert!(!slice.clear_if_hollow());
+
+        slice.start_time = Some((Wrapping(48000), SystemTime::now()));
+        slice.dropped_audio_frames = 3;
+        assert!(!slice.is_empty());
+        assert!(slice.clear_if_hollow());
+        assert!(slice.is_empty());
+        assert_eq!(slice.dropped_audio_frames, 0);
+
+        // a buffer with audio is left alone
+        slice.add_audio(&Wrapping(0), &[1; 1920]);
+        assert!(!slice.clear_if_hollow());
+        assert!(!slice.audio.is_empty());
+    }
+
+    #[test]
+    fn test_clipped_percent() {
+        let mut slice = AudioBuffer::new(1);
+        assert_eq!(slice.clipped_percent(), 0.0);
+
+        // an overdriven packet, flat against the top and bottom
+        let clipped = (0..1920)
+            .map(|i| if i % 4 < 2 { i16::MAX } else { i16::MIN })
+            .collect::<Vec<_>>();
+        slice.add_audio(&Wrapping(0), &clipped);
+        assert_eq!(slice.clipped_percent(), 100.0);
+        // followed by three clean ones
+        for i in 1..4 {
+            slice.add_audio(&Wrapping(i * 960), &[1000; 1920]);
+        }
+        assert_eq!(slice.clipped_percent(), 25.0);
+
+        // once the clipped packet is discarded, it no longer counts
+        slice.discard_audio(&Duration::from_millis(10));
+        assert_eq!(slice.clipped_percent(), 25.0);
+        slice.discard_audio(&Duration::from_millis(10));
+        assert_eq!(slice.clipped_percent(), 0.0);
+
+        slice.add_audio(&Wrapping(4 * 960), &clipped);
+        assert_eq!(slice.clipped_percent(), 25.0);
+        slice.clear();
+        assert_eq!(slice.clipped_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_duration_over() {
+        let mut slice = AudioBuffer::new(1);
+        slice.audio = vec![0.0; WHISPER_SAMPLES_PER_MILLISECOND * 1000];
+        assert_eq!(
+            slice.buffer_bytes(),
+            WHISPER_SAMPLES_PER_MILLISECOND * 1000 * 4
+        );
+        assert_eq!(slice.duration_over(slice.buffer_bytes()), Duration::ZERO);
+        assert_eq!(
+            slice.duration_over(slice.buffer_bytes() / 4),
+            Duration::from_millis(750)
+        );
+        assert_eq!(slice.duration_over(0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_buffer_with_audio() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(30);
+        let slice = AudioBuffer::with_audio(
+            0,
+            SystemClock,
+            vec![0.5; 1000 * WHISPER_SAMPLES_PER_MILLISECOND],
+            start,
+        );
+        let request = slice
+            .make_transcription_request(AudioPayloadFormat::F32, &Duration::ZERO, vec![])
+            .unwrap();
+        assert_eq!(request.audio_duration, Duration::from_millis(1000));
+        assert_eq!(request.start_timestamp, start);
+    }
+
+    #[test]
+    fn test_skip_leading_silence() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(30);
+        let one_second = 1000 * WHISPER_SAMPLES_PER_MILLISECOND;
+        let slice = AudioBuffer::with_audio(
+            0,
+            SystemClock,
+            [vec![0.0; one_second * 8 / 10], vec![0.5; one_second]].concat(),
+            start,
+        );
+        // a little of the silence is kept before the sound starts
+        let buffer_offset = slice.skip_leading_silence(&Duration::ZERO);
+        assert_eq!(buffer_offset, Duration::from_millis(650));
+        let request = slice
+            .make_transcription_request(AudioPayloadFormat::F32, &buffer_offset, vec![])
+            .unwrap();
+        assert_eq!(request.buffer_offset, Duration::from_millis(650));
+        assert_eq!(request.start_timestamp, start + Duration::from_millis(650));
+        assert_eq!(request.audio_duration, Duration::from_millis(1150));
+
+        // but no more than there is after the start
+        assert_eq!(
+            slice.skip_leading_silence(&Duration::from_millis(700)),
+            Duration::from_millis(700)
+        );
+        // already past the silence
+        assert_eq!(
+            slice.skip_leading_silence(&Duration::from_secs(1)),
+            Duration::from_secs(1)
+        );
+
+        // nothing to skip to
+        let slice = AudioBuffer::with_audio(0, SystemClock, vec![0.0; one_second], start);
+        assert_eq!(
+            slice.skip_leading_silence(&Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn test_many_small_discards_stay_consistent() {
+        let mut slice = AudioBuffer::new(124);
+        let start_rtc = Wrapping(1000 * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+        let start_system = SystemTime::now();
+        slice.start_time = Some((start_rtc, start_system));
+        let original_samples = 10 * 1000 * WHISPER_SAMPLES_PER_MILLISECOND;
+        slice.audio = vec![0.0; original_samples];
+        let original_duration = slice.buffer_duration();
+
+        // deliberately not a whole number of milliseconds, or of samples
+        let small_duration = Duration::from_nanos(1_234_567);
+        let mut discarded_samples = 0;
+        for _ in 0..5000 {
+            if slice.is_empty() {
+                break;
+            }
+            slice.discard_audio(&small_duration);
+            discarded_samples += duration_to_index(&small_duration);
+
+            let Some((rtc, system)) = slice.start_time else {
+                break;
+            };
+            assert_eq!(slice.audio.len() + discarded_samples, original_samples);
+            assert_eq!(rtc - start_rtc, whisper_samples_to_rtc(discarded_samples));
+            assert_eq!(
+                system.duration_since(start_system).unwrap() + slice.buffer_duration(),
+                original_duration
+            );
+        }
+        assert!(discarded_samples > 0);
+    }
+
     const DISCORD_SAMPLES_PER_MILLISECOND: usize = DISCORD_SAMPLES_PER_SECOND / 1000;
+    #[test]
+    fn test_time_after_end() {
+        let mut slice = AudioBuffer::new(128);
+        let start_rtc = Wrapping(DiscordRtcTimestampInner::MAX - 1000);
+        assert_eq!(slice.time_after_end(&start_rtc), None);
+
+        slice.start_time = Some((start_rtc, SystemTime::now()));
+        slice.audio = vec![0.0; 1000 * WHISPER_SAMPLES_PER_MILLISECOND];
+        let end_rtc = start_rtc + duration_to_rtc(&Duration::from_secs(1));
+
+        assert_eq!(slice.time_after_end(&start_rtc), None);
+        assert_eq!(slice.time_after_end(&end_rtc), Some(Duration::ZERO));
+        // wraps around
+        assert_eq!(
+            slice.time_after_end(&(end_rtc + duration_to_rtc(&Duration::from_millis(1500)))),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn test_pcm16_payload() {
+        let mut slice = AudioBuffer::new(125);
+        slice.start_time = Some((Wrapping(0), SystemTime::now()));
+        slice.audio = vec![0.0, 0.5, -0.5, 1.0, -1.0, 1.5, -1.5, 0.00002];
+
+        let request = slice
+            .make_transcription_request(AudioPayloadFormat::Pcm16, &Duration::ZERO, vec![])
+            .unwrap();
+        let samples: Vec<i16> = request
+            .audio_bytes
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![0, 16384, -16384, 32767, -32767, 32767, -32768, 1]
+        );
+        assert_eq!(request.audio_duration, samples_to_duration(8));
+    }
+
+    #[test]
+    fn test_audio_buffer_pool() {
+        let pool = AudioBufferPool::new(1);
+
+        let mut first = pool.acquire(1);
+        first.audio.extend([0.5; 100]);
+        let storage = first.audio.as_ptr();
+        pool.release(&mut first);
+        assert!(first.audio.is_empty());
+
+        // the storage is reused, and comes back empty
+        let mut second = pool.acquire(2);
+        assert_eq!(second.audio.as_ptr(), storage);
+        assert!(second.is_empty());
+        assert_eq!(second.slice_id, 2);
+        assert!(second.audio.capacity() >= WHISPER_AUDIO_BUFFER_SIZE);
+
+        // the pool only keeps as many as it's allowed
+        let mut third = pool.acquire(3);
+        pool.release(&mut second);
+        pool.release(&mut third);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_audio_buffer_pool_preallocate() {
+        let pool = AudioBufferPool::new(3);
+        pool.preallocate(2);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 2);
+        // never more than the pool can hold
+        pool.preallocate(5);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 3);
+
+        // once the preallocated buffers run out, more are allocated
+        let mut buffers: Vec<AudioBuffer> = (0..5).map(|slice_id| pool.acquire(slice_id)).collect();
+        assert!(pool.buffers.lock().unwrap().is_empty());
+        for buffer in buffers.iter() {
+            assert!(buffer.audio.capacity() >= WHISPER_AUDIO_BUFFER_SIZE);
+        }
+        for buffer in buffers.iter_mut() {
+            pool.release(buffer);
+        }
+        assert_eq!(pool.buffers.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_start_time_uses_clock() {
+        let clock = MockClock::new();
+        let mut slice = AudioBuffer::with_clock(237, clock.clone());
+        let start_rtc = Wrapping(1000 * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+        let packet = vec![1; 20 * DISCORD_SAMPLES_PER_MILLISECOND * DISCORD_AUDIO_CHANNELS];
+
+        clock.advance(Duration::from_secs(5));
+        let expected_start = clock.now();
+        slice.add_audio(&start_rtc, &packet);
+
+        // later audio doesn't move the start time, no matter what the clock says
+        clock.advance(Duration::from_secs(5));
+        slice.add_audio(
+            &(start_rtc + duration_to_rtc(&Duration::from_millis(20))),
+            &packet,
+        );
+        assert_eq!(slice.start_time, Some((start_rtc, expected_start)));
+    }
+
+    #[test]
+    fn test_add_audio_before_start() {
+        let mut slice = AudioBuffer::new(235);
+        let start_rtc = Wrapping(1000 * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+        let packet = vec![1; 20 * DISCORD_SAMPLES_PER_MILLISECOND * DISCORD_AUDIO_CHANNELS];
+
+        slice.add_audio(&start_rtc, &packet);
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(20));
+        let start_time = slice.start_time;
+
+        // a packet from 100ms before the start
+        slice.add_audio(
+            &Wrapping(900 * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32),
+            &packet,
+        );
+        // and one which starts before, but overlaps the start
+        slice.add_audio(
+            &Wrapping(990 * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32),
+            &packet,
+        );
+
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(20));
+        assert_eq!(slice.audio.len(), 20 * WHISPER_SAMPLES_PER_MILLISECOND);
+        assert_eq!(slice.start_time, start_time);
+
+        // even when the start is right after the RTC clock wraps
+        let mut slice = AudioBuffer::new(236);
+        slice.add_audio(&Wrapping(10), &packet);
+        slice.add_audio(&Wrapping(u32::MAX - 100), &packet);
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_add_audio_at_start_after_discard() {
+        let mut slice = AudioBuffer::new(237);
+        let packet = |value: DiscordAudioSample| {
+            vec![value; 20 * DISCORD_SAMPLES_PER_MILLISECOND * DISCORD_AUDIO_CHANNELS]
+        };
+        let rtc = |ms: u32| Wrapping(ms * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+
+        // a fresh slice starts wherever its first audio does
+        slice.add_audio(&rtc(1000), &packet(1000));
+        slice.add_audio(&rtc(1020), &packet(1000));
+        slice.add_audio(&rtc(1040), &packet(1000));
+        slice.discard_audio(&Duration::from_millis(20));
+        assert_eq!(slice.start_time.unwrap().0, rtc(1020));
+        let audio = slice.audio.clone();
+
+        // the packet which is now at the start of the buffer arrives
+        // again, and is dropped rather than written over what we have
+        slice.add_audio(&rtc(1020), &packet(2000));
+        // as is one which only partly overlaps
+        slice.add_audio(&rtc(1050), &packet(2000));
+        assert_eq!(slice.audio, audio);
+    }
+
+    #[test]
+    fn test_is_past_end() {
+        let mut slice = AudioBuffer::new(239);
+        let second = AudioSamples::Discord(vec![
+            1000;
+            DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS
+        ]);
+        let rtc = |secs: u32| Wrapping(secs * DISCORD_SAMPLES_PER_SECOND as u32);
+        // an empty buffer has room for anything
+        assert!(!slice.is_past_end(&rtc(100), &second));
+
+        slice.add_samples(&rtc(100), &second);
+        assert!(!slice.is_past_end(&rtc(128), &second));
+        assert!(slice.is_past_end(&rtc(129), &second));
+        assert!(slice.is_past_end(&rtc(200), &second));
+        // audio from before the start is dropped instead
+        assert!(!slice.is_past_end(&rtc(99), &second));
+    }
+
+    #[test]
+    fn test_add_whisper_audio() {
+        let mut slice = AudioBuffer::new(240);
+        let rtc = |ms: u32| Wrapping(ms * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+        let packet = (0..100 * WHISPER_SAMPLES_PER_MILLISECOND)
+            .map(|i| i as f32 / 10000.0)
+            .collect::<Vec<_>>();
+
+        slice.add_samples(&rtc(1000), &AudioSamples::Whisper(packet.clone()));
+        slice.add_whisper_audio(&rtc(1200), &packet);
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(300));
+        // the samples go in untouched
+        assert_eq!(&slice.audio[..packet.len()], packet.as_slice());
+        assert_eq!(&slice.audio[2 * packet.len()..], packet.as_slice());
+        assert!(slice.audio[packet.len()..2 * packet.len()]
+            .iter()
+            .all(|&sample| sample == 0.0));
+
+        // audio which lands on top of what we have is dropped
+        slice.add_whisper_audio(&rtc(1050), &vec![0.5; packet.len()]);
+        assert_eq!(&slice.audio[..packet.len()], packet.as_slice());
+        // but the gap can still be filled in
+        slice.add_whisper_audio(&rtc(1100), &vec![0.5; packet.len()]);
+        assert_eq!(slice.audio[packet.len()], 0.5);
+
+        let whole_buffer = AudioSamples::Whisper(vec![0.5; WHISPER_AUDIO_BUFFER_SIZE]);
+        assert!(slice.is_past_end(&rtc(1000 + 1), &whole_buffer));
+    }
+
+    #[test]
+    fn test_add_audio_backfills_gaps() {
+        let mut slice = AudioBuffer::new(238);
+        let packet = vec![1000; 20 * DISCORD_SAMPLES_PER_MILLISECOND * DISCORD_AUDIO_CHANNELS];
+        let rtc = |ms: u32| Wrapping(ms * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+
+        slice.add_audio(&rtc(1000), &packet);
+        slice.add_audio(&rtc(1040), &packet);
+        let gap = 20 * WHISPER_SAMPLES_PER_MILLISECOND..40 * WHISPER_SAMPLES_PER_MILLISECOND;
+        assert!(slice.audio[gap.clone()].iter().all(|&sample| sample == 0.0));
+
+        // the packet in between arrives late
+        slice.add_audio(&rtc(1020), &packet);
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(60));
+        assert!(slice.audio[gap].iter().all(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn test_out_of_order_packets_match_in_order() {
+        let rtc = |ms: u32| Wrapping(ms * RTC_CLOCK_SAMPLES_PER_MILLISECOND as u32);
+        let packet = |n: i16| {
+            (0..20 * DISCORD_SAMPLES_PER_MILLISECOND * DISCORD_AUDIO_CHANNELS)
+                .map(|i| n * 1000 + (i % 100) as i16)
+                .collect::<Vec<_>>()
+        };
+        let deliver = |order: &[u32]| {
+            let mut slice = AudioBuffer::new(243);
+            for &n in order {
+                slice.add_audio(&rtc(1000 + 20 * n), &packet(n as i16));
+            }
+            slice
+        };
+
+        let in_order = deliver(&[1, 2, 3]);
+        for order in [[1, 3, 2], [1, 2, 3]] {
+            let slice = deliver(&order);
+            assert_eq!(slice.audio, in_order.audio);
+            assert_eq!(slice.buffer_duration(), Duration::from_millis(60));
+            assert_eq!(slice.clipped_percent(), in_order.clipped_percent());
+        }
+        // three packets late is still in time
+        assert_eq!(deliver(&[1, 4, 3, 2]).audio, deliver(&[1, 2, 3, 4]).audio);
+
+        // and the same goes for audio already in whisper's format
+        let deliver_whisper = |order: &[u32]| {
+            let mut slice = AudioBuffer::new(244);
+            for &n in order {
+                let whisper_packet = vec![n as f32 / 10.0; 20 * WHISPER_SAMPLES_PER_MILLISECOND];
+                slice.add_samples(&rtc(1000 + 20 * n), &AudioSamples::Whisper(whisper_packet));
+            }
+            slice.audio
+        };
+        assert_eq!(deliver_whisper(&[1, 3, 2]), deliver_whisper(&[1, 2, 3]));
+    }
+
     #[test]
     fn test_add_audio() {
         let mut slice = AudioBuffer::new(234);
@@ -394,6 +1324,78 @@ mod tests {
         assert!(slice.audio.capacity() <= original_capacity);
     }
 
+    #[test]
+    fn test_fits_within_slice_near_wrap() {
+        let record_rtc = duration_to_rtc(&AUDIO_TO_RECORD);
+        let mut slice = AudioBuffer::new(238);
+
+        // the end of the slice wraps, but the start doesn't
+        let start_rtc = Wrapping(u32::MAX - 1000);
+        slice.start_time = Some((start_rtc, SystemTime::now()));
+        assert!(slice.fits_within_this_slice(start_rtc));
+        assert!(slice.fits_within_this_slice(Wrapping(u32::MAX)));
+        assert!(slice.fits_within_this_slice(Wrapping(0)));
+        assert!(slice.fits_within_this_slice(start_rtc + record_rtc - Wrapping(1)));
+        assert!(!slice.fits_within_this_slice(start_rtc + record_rtc));
+        assert!(!slice.fits_within_this_slice(start_rtc - Wrapping(1)));
+        // well away from the slice on either side of the wrap
+        assert!(!slice.fits_within_this_slice(Wrapping(u32::MAX / 2)));
+        assert!(!slice.fits_within_this_slice(Wrapping(u32::MAX - 1_000_000)));
+
+        // the slice ends exactly at the wrap
+        let start_rtc = Wrapping(0) - record_rtc;
+        slice.start_time = Some((start_rtc, SystemTime::now()));
+        assert!(slice.fits_within_this_slice(start_rtc));
+        assert!(slice.fits_within_this_slice(Wrapping(u32::MAX)));
+        assert!(!slice.fits_within_this_slice(Wrapping(0)));
+        assert!(!slice.fits_within_this_slice(start_rtc - Wrapping(1)));
+
+        // the slice starts at the very last timestamp
+        let start_rtc = Wrapping(u32::MAX);
+        slice.start_time = Some((start_rtc, SystemTime::now()));
+        assert!(slice.fits_within_this_slice(start_rtc));
+        assert!(slice.fits_within_this_slice(Wrapping(0)));
+        assert!(slice.fits_within_this_slice(record_rtc - Wrapping(2)));
+        assert!(!slice.fits_within_this_slice(record_rtc - Wrapping(1)));
+        assert!(!slice.fits_within_this_slice(Wrapping(u32::MAX - 1)));
+    }
+
+    #[test]
+    fn test_add_audio_across_wrap() {
+        let mut slice = AudioBuffer::new(239);
+        let packet = vec![1; 20 * DISCORD_SAMPLES_PER_MILLISECOND * DISCORD_AUDIO_CHANNELS];
+        let packet_rtc = duration_to_rtc(&Duration::from_millis(20));
+
+        let start_rtc = Wrapping(u32::MAX) - packet_rtc + Wrapping(1);
+        slice.add_audio(&start_rtc, &packet);
+        slice.add_audio(&(start_rtc + packet_rtc), &packet);
+        assert_eq!(slice.start_time.unwrap().0, start_rtc);
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(40));
+
+        // too far past the wrap to fit
+        slice.add_audio(&(start_rtc + duration_to_rtc(&AUDIO_TO_RECORD)), &packet);
+        assert_eq!(slice.buffer_duration(), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_add_unusable_audio() {
+        let mut slice = AudioBuffer::new(237);
+        slice.add_audio(&Wrapping(1000), &[]);
+        assert_eq!(slice.start_time, None);
+        assert!(slice.audio.is_empty());
+
+        // not even one whole frame
+        let frame_len = BITRATE_CONVERSION_RATIO * DISCORD_AUDIO_CHANNELS;
+        slice.add_audio(&Wrapping(1000), &vec![1; frame_len - 1]);
+        assert_eq!(slice.start_time, None);
+        assert!(slice.audio.is_empty());
+
+        // the partial frame at the end is dropped
+        slice.add_audio(&Wrapping(1000), &vec![1; 2 * frame_len + 1]);
+        assert_eq!(slice.start_time.unwrap().0, Wrapping(1000));
+        assert_eq!(slice.audio.len(), 2);
+    }
+
     #[test]
     fn test_is_interval_silent() {
         let mut slice = AudioBuffer::new(345);
@@ -464,4 +1466,118 @@ mod tests {
         let duration_buffer_len_plus_one = samples_to_duration(slice.audio.len() + 1);
         assert!(slice.is_interval_silent(&duration_buffer_len_plus_one, &ONE_SECOND));
     }
+
+    #[test]
+    fn test_silent_gaps() {
+        let mut slice = AudioBuffer::new(456);
+        let one_second = 1000 * WHISPER_SAMPLES_PER_MILLISECOND;
+
+        // one second of noise, one of silence, then one more of noise,
+        // followed by some trailing silence
+        slice.audio = [
+            vec![0.5; one_second],
+            vec![0.0; one_second],
+            vec![0.5; one_second],
+            vec![0.0; one_second],
+        ]
+        .concat();
+
+        let within = Duration::from_secs(4);
+        assert_eq!(
+            slice.silent_gaps(&within, &Duration::from_millis(500)),
+            vec![Duration::from_millis(1500)]
+        );
+
+        // the gap isn't long enough
+        assert!(slice
+            .silent_gaps(&within, &Duration::from_millis(1500))
+            .is_empty());
+
+        // the gap is outside of the range we asked about
+        assert!(slice
+            .silent_gaps(&Duration::from_millis(1500), &Duration::from_millis(500))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_trailing_silence() {
+        let mut slice = AudioBuffer::new(567);
+        assert_eq!(slice.trailing_silence(), Duration::ZERO);
+
+        let one_second = 1000 * WHISPER_SAMPLES_PER_MILLISECOND;
+        slice.audio = vec![0.5; one_second];
+        assert_eq!(slice.trailing_silence(), Duration::ZERO);
+
+        // quiet, but not quite zero
+        slice.audio.extend(vec![0.001; one_second / 2]);
+        assert_eq!(slice.trailing_silence(), Duration::from_millis(500));
+
+        slice.audio.extend(vec![0.5; one_second / 10]);
+        assert_eq!(slice.trailing_silence(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_speech_span() {
+        let mut slice = AudioBuffer::new(678);
+        let one_second = 1000 * WHISPER_SAMPLES_PER_MILLISECOND;
+        assert_eq!(
+            slice.speech_span(&Duration::ZERO, &Duration::from_secs(1)),
+            Duration::ZERO
+        );
+
+        // half a second of silence either side of each second of speech
+        slice.audio = [
+            vec![0.0; one_second / 2],
+            vec![0.5; one_second],
+            vec![0.0; one_second / 2],
+            vec![0.5; one_second],
+            vec![0.0; one_second / 2],
+        ]
+        .concat();
+        assert_eq!(
+            slice.speech_span(&Duration::ZERO, &Duration::from_secs(2)),
+            Duration::from_secs(1)
+        );
+        // the silence between the two counts, as it's part of what
+        // was said
+        assert_eq!(
+            slice.speech_span(&Duration::ZERO, &Duration::from_secs(4)),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(
+            slice.speech_span(&Duration::from_millis(1500), &Duration::from_secs(2)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_smoothed_trailing_silence() {
+        let time_constant = Duration::from_millis(200);
+        let frame_len = 20 * WHISPER_SAMPLES_PER_MILLISECOND;
+        let mut slice = AudioBuffer::new(567);
+
+        // a single loud frame, then silence
+        slice.audio = vec![1.0; frame_len];
+        slice.smoothed_trailing_silence(&time_constant);
+        let peak = slice.envelope.level;
+        assert!(peak > 0.0 && peak < 1.0);
+
+        // after one time constant, the level has decayed by a factor of e
+        slice.audio.extend(vec![0.0; 10 * frame_len]);
+        slice.smoothed_trailing_silence(&time_constant);
+        let expected = peak * (-1.0f32).exp();
+        assert!((slice.envelope.level - expected).abs() < 1e-4);
+
+        // ...and the silence only counts once the level drops below the
+        // threshold, rather than straight after the loud frame
+        slice.audio.extend(vec![0.0; 100 * frame_len]);
+        let silence = slice.smoothed_trailing_silence(&time_constant);
+        assert_eq!(slice.trailing_silence(), Duration::from_millis(2200));
+        assert!(silence > Duration::ZERO && silence < Duration::from_millis(2200));
+
+        // a click in the silence doesn't reset it
+        slice.audio.extend(vec![0.02; frame_len]);
+        assert!(slice.smoothed_trailing_silence(&time_constant) > silence);
+        assert_eq!(slice.trailing_silence(), Duration::ZERO);
+    }
 }
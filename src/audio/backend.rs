@@ -0,0 +1,61 @@
+use tokio::task::JoinHandle;
+
+use crate::model::{
+    error::DiscrivenerError,
+    types::{ModelInfo, UserId},
+};
+
+use super::events::{TranscriptionRequest, TranscriptionResponse};
+
+/// Something which can turn audio into text.
+///
+/// By default this is a whisper model loaded onto the local machine,
+/// but transcription can also be done elsewhere, for instance on a
+/// separate GPU server with the `remote` feature's `RemoteBackend`.
+pub trait TranscriptionBackend: Send + Sync {
+    /// Starts transcribing the given audio.  This should return right
+    /// away, with the work done on another task or thread.
+    ///
+    /// The returned task should always produce a single response.  If
+    /// the audio couldn't be transcribed, the response's transcript
+    /// should have no segments, and its `error` should say why.
+    fn process_transcription_request(
+        &self,
+        request: TranscriptionRequest,
+    ) -> JoinHandle<TranscriptionResponse>;
+
+    /// Describes the model doing the transcribing, if the backend
+    /// knows.
+    fn model_info(&self) -> Option<ModelInfo> {
+        None
+    }
+
+    /// Has the user's audio transcribed in the given language, rather
+    /// than whichever one would otherwise be used, or goes back to
+    /// that if `language` is None.  Backends which don't choose the
+    /// language themselves can ignore this.
+    fn set_user_language(
+        &self,
+        _user_id: UserId,
+        _language: Option<String>,
+    ) -> Result<(), DiscrivenerError> {
+        Ok(())
+    }
+
+    /// Loads the model at the given path and transcribes with it from
+    /// then on, in place of the one we have now.  Requests which have
+    /// already started are finished with the old model.  This will
+    /// take a while, so don't call it on a tokio event thread.
+    ///
+    /// Backends which don't load models themselves can't do this.
+    fn reload_model(&self, _model_path: &str) -> Result<(), DiscrivenerError> {
+        Err(DiscrivenerError::InvalidConfig(
+            "this backend doesn't load models".to_string(),
+        ))
+    }
+
+    /// Forgets anything the backend has picked up about the
+    /// conversation so far, such as which language it's in.  The
+    /// model itself stays loaded.
+    fn reset(&self) {}
+}
@@ -0,0 +1,161 @@
+//! Converts audio from Discord's format (48khz stereo PCM16)
+//! to Whisper's format (16khz mono f32).
+//!
+//! This is on the hot path whenever a buffer is filled or backfilled,
+//! so besides the straightforward version there's one which works on
+//! several samples at once, and optionally one which splits large
+//! amounts of audio across threads.  They all produce identical output.
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::model::{
+    constants::{BITRATE_CONVERSION_RATIO, DISCORD_AUDIO_CHANNELS},
+    types::{DiscordAudioSample, WhisperAudioSample},
+};
+
+const DISCORD_AUDIO_MAX_VALUE: WhisperAudioSample = DiscordAudioSample::MAX as WhisperAudioSample;
+
+const DISCORD_AUDIO_MAX_VALUE_TWO_SAMPLES: WhisperAudioSample =
+    DISCORD_AUDIO_MAX_VALUE * DISCORD_AUDIO_CHANNELS as WhisperAudioSample;
+
+/// number of Discord samples which become a single Whisper sample
+const FRAME_LEN: usize = BITRATE_CONVERSION_RATIO * DISCORD_AUDIO_CHANNELS;
+
+/// number of Whisper samples to produce in each batch
+const LANES: usize = 8;
+
+/// number of Whisper samples handled by each thread.  Splitting
+/// up less than this costs more than it saves.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_LEN: usize = LANES * 1024;
+
+/// Converts the Discord audio into `dest`, which must have room for
+/// exactly `discord_audio.len() / 6` samples, using the fastest
+/// method available.
+pub fn downmix(discord_audio: &[DiscordAudioSample], dest: &mut [WhisperAudioSample]) {
+    #[cfg(feature = "parallel")]
+    downmix_parallel(discord_audio, dest);
+    #[cfg(not(feature = "parallel"))]
+    downmix_lanes(discord_audio, dest);
+}
+
+/// Splits stereo Discord audio into a stream for each channel.  Each
+/// stream is still in Discord's format, but with the one channel's
+/// samples on both sides, so that downmixing it gives back just that
+/// channel.
+pub fn split_channels(
+    discord_audio: &[DiscordAudioSample],
+) -> [Vec<DiscordAudioSample>; DISCORD_AUDIO_CHANNELS] {
+    std::array::from_fn(|channel| {
+        discord_audio
+            .chunks_exact(DISCORD_AUDIO_CHANNELS)
+            .flat_map(|frame| [frame[channel]; DISCORD_AUDIO_CHANNELS])
+            .collect()
+    })
+}
+
+/// Converts one sample at a time.
+pub fn downmix_scalar(discord_audio: &[DiscordAudioSample], dest: &mut [WhisperAudioSample]) {
+    debug_assert_eq!(dest.len(), discord_audio.len() / FRAME_LEN);
+    for (dest_sample, frame) in dest.iter_mut().zip(discord_audio.chunks_exact(FRAME_LEN)) {
+        // sum the channel data, and divide by the max value possible to
+        // get a value between -1.0 and 1.0
+        *dest_sample = (frame[0] as WhisperAudioSample + frame[1] as WhisperAudioSample)
+            / DISCORD_AUDIO_MAX_VALUE_TWO_SAMPLES;
+    }
+}
+
+/// Converts `LANES` samples at a time, gathering each batch's channels
+/// into fixed-size arrays before converting them together.  This is
+/// plain Rust, with no SIMD of its own: whether it's any faster than
+/// `downmix_scalar` is up to the compiler, which the `downmix`
+/// benchmark shows.  Anything left over is converted one sample at a
+/// time.
+pub fn downmix_lanes(discord_audio: &[DiscordAudioSample], dest: &mut [WhisperAudioSample]) {
+    debug_assert_eq!(dest.len(), discord_audio.len() / FRAME_LEN);
+    let mut src_batches = discord_audio.chunks_exact(FRAME_LEN * LANES);
+    let mut dest_batches = dest.chunks_exact_mut(LANES);
+    for (src, dest) in (&mut src_batches).zip(&mut dest_batches) {
+        let mut left = [0.0; LANES];
+        let mut right = [0.0; LANES];
+        for (lane, frame) in src.chunks_exact(FRAME_LEN).enumerate() {
+            left[lane] = frame[0] as WhisperAudioSample;
+            right[lane] = frame[1] as WhisperAudioSample;
+        }
+        for ((dest_sample, left), right) in dest.iter_mut().zip(left).zip(right) {
+            *dest_sample = (left + right) / DISCORD_AUDIO_MAX_VALUE_TWO_SAMPLES;
+        }
+    }
+    downmix_scalar(src_batches.remainder(), dest_batches.into_remainder());
+}
+
+/// Splits the audio across rayon's thread pool.  Only worthwhile for
+/// large amounts of audio, like a full buffer being backfilled, so
+/// smaller amounts are converted on this thread.
+#[cfg(feature = "parallel")]
+pub fn downmix_parallel(discord_audio: &[DiscordAudioSample], dest: &mut [WhisperAudioSample]) {
+    if dest.len() <= PARALLEL_CHUNK_LEN {
+        downmix_lanes(discord_audio, dest);
+        return;
+    }
+    dest.par_chunks_mut(PARALLEL_CHUNK_LEN)
+        .zip(discord_audio.par_chunks(PARALLEL_CHUNK_LEN * FRAME_LEN))
+        .for_each(|(dest, src)| downmix_lanes(src, dest));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// some audio which isn't a whole number of batches long
+    fn test_audio() -> Vec<DiscordAudioSample> {
+        (0..(FRAME_LEN * (LANES * 100 + 3)))
+            .map(|i| (i as i32 * 7919 % 65536 - 32768) as DiscordAudioSample)
+            .collect()
+    }
+
+    #[test]
+    fn test_downmix_lanes_matches_scalar() {
+        let discord_audio = test_audio();
+        let mut expected = vec![0.0; discord_audio.len() / FRAME_LEN];
+        downmix_scalar(&discord_audio, &mut expected);
+
+        let mut actual = vec![0.0; expected.len()];
+        downmix_lanes(&discord_audio, &mut actual);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_split_channels_diverge() {
+        // a tone on the left, and silence on the right
+        let discord_audio = (0..FRAME_LEN * 4)
+            .map(|i| if i % 2 == 0 { 1000 } else { 0 })
+            .collect::<Vec<DiscordAudioSample>>();
+        let [left, right] = split_channels(&discord_audio);
+        assert_eq!(left.len(), discord_audio.len());
+
+        let mut left_mono = vec![0.0; 4];
+        downmix_scalar(&left, &mut left_mono);
+        let mut right_mono = vec![0.0; 4];
+        downmix_scalar(&right, &mut right_mono);
+        assert_eq!(left_mono, vec![1000.0 / DISCORD_AUDIO_MAX_VALUE; 4]);
+        assert_eq!(right_mono, vec![0.0; 4]);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_downmix_parallel_matches_scalar() {
+        let discord_audio: Vec<DiscordAudioSample> = test_audio()
+            .into_iter()
+            .cycle()
+            .take(FRAME_LEN * (PARALLEL_CHUNK_LEN * 3 + 5))
+            .collect();
+        let mut expected = vec![0.0; discord_audio.len() / FRAME_LEN];
+        downmix_scalar(&discord_audio, &mut expected);
+
+        let mut actual = vec![0.0; expected.len()];
+        downmix_parallel(&discord_audio, &mut actual);
+        assert_eq!(actual, expected);
+    }
+}
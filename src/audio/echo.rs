@@ -0,0 +1,127 @@
+use tokio::task::JoinHandle;
+
+use crate::model::types::{TextSegment, TokenWithProbability, Transcription, VoiceChannelEvent};
+
+use super::{
+    backend::TranscriptionBackend,
+    events::{TranscriptionRequest, TranscriptionResponse},
+};
+
+/// A backend which doesn't listen to the audio at all, and instead
+/// "transcribes" every request as the same text.
+///
+/// Useful for exercising the rest of the pipeline without needing a
+/// model file.
+pub struct EchoBackend {
+    text: String,
+}
+
+impl EchoBackend {
+    pub fn new(text: String) -> Self {
+        Self { text }
+    }
+}
+
+impl TranscriptionBackend for EchoBackend {
+    fn process_transcription_request(
+        &self,
+        TranscriptionRequest {
+            audio_duration,
+            buffer_offset,
+            progress,
+            start_timestamp,
+            user_id,
+            ..
+        }: TranscriptionRequest,
+    ) -> JoinHandle<TranscriptionResponse> {
+        // we're done as soon as we've started
+        if let Some(progress) = progress {
+            progress
+                .send(VoiceChannelEvent::TranscriptionProgress {
+                    percent: 100,
+                    user_id,
+                })
+                .ok();
+        }
+        let segment = TextSegment {
+            start_offset_ms: 0,
+            end_offset_ms: audio_duration.as_millis() as u32,
+            start_sample: 0,
+            end_sample: 0,
+            low_confidence_p: 0,
+            cleaned_text: None,
+            phonemes: None,
+            raw_token_ids: None,
+            whisper_timing: None,
+            tokens_with_probability: vec![TokenWithProbability {
+                p: 100,
+                token_id: 0,
+                token_text: self.text.clone(),
+            }],
+        };
+        tokio::spawn(async move {
+            TranscriptionResponse {
+                buffer_offset,
+                transcript: Transcription {
+                    start_timestamp,
+                    user_id,
+                    segments: vec![segment],
+                    audio_duration,
+                    processing_time: std::time::Duration::ZERO,
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                },
+                error: None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use bytes::Bytes;
+
+    use crate::model::{config::AudioPayloadFormat, error::DiscrivenerError};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_echo_backend() {
+        let backend = EchoBackend::new(" hello there".to_string());
+        let start_timestamp = SystemTime::now();
+        let response = backend
+            .process_transcription_request(TranscriptionRequest {
+                audio_bytes: Bytes::new(),
+                audio_duration: Duration::from_millis(1500),
+                audio_format: AudioPayloadFormat::F32,
+                buffer_offset: Duration::from_millis(250),
+                is_interim: false,
+                previous_tokens: vec![],
+                progress: None,
+                start_timestamp,
+                temperature: None,
+                user_id: 42,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.buffer_offset, Duration::from_millis(250));
+        assert_eq!(response.transcript.start_timestamp, start_timestamp);
+        assert_eq!(response.transcript.user_id, 42);
+        assert_eq!(response.transcript.segments.len(), 1);
+        assert_eq!(response.transcript.segments[0].text(), " hello there");
+        assert_eq!(response.transcript.segments[0].end_offset_ms, 1500);
+    }
+
+    #[test]
+    fn test_reload_model_unsupported() {
+        let backend = EchoBackend::new(String::new());
+        assert!(matches!(
+            backend.reload_model("ggml-small.bin"),
+            Err(DiscrivenerError::InvalidConfig(_))
+        ));
+    }
+}
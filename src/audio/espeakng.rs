@@ -3,9 +3,11 @@
 use espeakng_sys::*;
 use lazy_static::lazy_static;
 use songbird::constants::MONO_FRAME_SIZE;
+#[cfg(feature = "phonemes")]
+use std::ffi::CStr;
 use std::ffi::{c_short, c_void, CString};
 use std::os::raw::c_int;
-use std::sync::Mutex;
+use std::sync::{Mutex, OnceLock};
 use tokio::sync::oneshot;
 
 use crate::model::constants::{DISCORD_SAMPLES_PER_SECOND, ESPEAK_SAMPLES_PER_SECOND};
@@ -14,6 +16,9 @@ const BUFSIZE_MS: u64 = (1000 * MONO_FRAME_SIZE / DISCORD_SAMPLES_PER_SECOND) as
 // const EE_OK: i32 = 0;
 const EE_INTERNAL_ERROR: i32 = -1;
 const VOICE_NAME: &str = "English";
+/// espeakPHONEMES_IPA, which isn't in the bindings
+#[cfg(feature = "phonemes")]
+const PHONEMES_IPA: c_int = 0x02;
 
 struct GenerationTask {
     /// The audio data, so far
@@ -25,8 +30,19 @@ struct GenerationTask {
 lazy_static! {
     static ref in_process_task_opt: Mutex<Option<GenerationTask>> = Mutex::new(None);
 }
+
+/// espeak-ng keeps its state globally, and speaking and translating
+/// to phonemes both use it, so only one call into it can run at a time
+static ESPEAK: Mutex<()> = Mutex::new(());
+
+/// espeak-ng's sample rate, once it's been initialized
+static SAMPLE_RATE: OnceLock<i32> = OnceLock::new();
+
+/// Sets up espeak-ng, if it hasn't been already, and returns its
+/// sample rate.
 pub fn init() -> i32 {
-    unsafe {
+    *SAMPLE_RATE.get_or_init(|| unsafe {
+        let _espeak = ESPEAK.lock().unwrap();
         let sample_rate = espeak_Initialize(
             // send data to our callback function, which will
             // notify us when done.  We do this so that
@@ -54,7 +70,7 @@ pub fn init() -> i32 {
 
         // return our sample rate
         sample_rate
-    }
+    })
 }
 
 unsafe extern "C" fn synth_callback(
@@ -82,8 +98,9 @@ unsafe extern "C" fn synth_callback(
         } else {
             let new_audio = std::slice::from_raw_parts(wav, sample_count as usize);
             // add new_audio to the task's wav
-            if let Some(task) = task_opt
-                .as_mut() { task.wav.extend_from_slice(new_audio) }
+            if let Some(task) = task_opt.as_mut() {
+                task.wav.extend_from_slice(new_audio)
+            }
         }
     }
 
@@ -112,6 +129,8 @@ pub async fn speak(text: &str) -> Vec<i16> {
     let cstr_text = CString::new(text).unwrap();
 
     unsafe {
+        // this returns once the callback has been given all the audio
+        let _espeak = ESPEAK.lock().unwrap();
         let str_bytes = cstr_text.as_bytes_with_nul();
         espeak_Synth(
             str_bytes.as_ptr() as *const c_void,
@@ -127,3 +146,31 @@ pub async fn speak(text: &str) -> Vec<i16> {
 
     rx.await.unwrap()
 }
+
+/// Works out how the text is pronounced, in IPA, with words separated
+/// by spaces.  None if espeak-ng has nothing to say about it.
+#[cfg(feature = "phonemes")]
+pub fn text_to_phonemes(text: &str) -> Option<String> {
+    init();
+    let cstr_text = CString::new(text).ok()?;
+    let mut phonemes = Vec::new();
+
+    let _espeak = ESPEAK.lock().unwrap();
+    let mut text_ptr = cstr_text.as_ptr() as *const c_void;
+    // each call translates a single clause, and moves text_ptr along
+    // to the next, or to null when there are no more
+    while !text_ptr.is_null() {
+        let clause = unsafe {
+            let clause =
+                espeak_TextToPhonemes(&mut text_ptr, espeakCHARS_UTF8 as c_int, PHONEMES_IPA);
+            if clause.is_null() {
+                break;
+            }
+            CStr::from_ptr(clause).to_string_lossy().trim().to_string()
+        };
+        if !clause.is_empty() {
+            phonemes.push(clause);
+        }
+    }
+    (!phonemes.is_empty()).then(|| phonemes.join(" "))
+}
@@ -5,15 +5,60 @@
 use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedSender;
 use whisper_rs::WhisperToken;
 
-use crate::model::types::{DiscordAudioSample, DiscordRtcTimestamp, Transcription, UserId};
+use crate::model::{
+    config::AudioPayloadFormat,
+    types::{
+        DiscordAudioSample, DiscordRtcTimestamp, Ssrc, Transcription, UserId, VoiceChannelEvent,
+        WhisperAudioSample,
+    },
+};
 
-#[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub(crate) enum UserAudioEventType {
     Speaking,
     Silent,
     Idle,
+    /// the user's audio should no longer be transcribed, so anything
+    /// already buffered for them should be thrown away, along with
+    /// any of their audio which is still on its way
+    TranscriptionDisabled,
+    /// the user's audio can be transcribed again
+    TranscriptionEnabled,
+    /// the user has finished what they were saying, so what we have
+    /// should be finalized without waiting for them to go quiet
+    UtteranceBoundary,
+    /// the whole channel has gone quiet, so what we have should be
+    /// finalized and handed back as our part of the flush with this
+    /// id.  `participants` is how many users the flush was sent to.
+    /// `session` is true when the session is being finalized, rather
+    /// than the channel having gone quiet, and those flushes are
+    /// numbered separately.
+    ChannelIdle {
+        flush_id: u64,
+        participants: usize,
+        session: bool,
+    },
+    /// the whole pipeline is being reset, so everything buffered for
+    /// the user should be thrown away, along with the tokens we've
+    /// been giving whisper as context
+    PipelineReset,
+    /// everyone's buffered audio is taking up too much memory, so the
+    /// user's oldest audio should be thrown away until what's left
+    /// fits in this many bytes
+    EvictAudio {
+        max_bytes: usize,
+    },
+    /// the user's audio has stayed below (true), or come back above
+    /// (false), `DiscrivenerConfig::noise_gate`.  Discord can keep
+    /// saying that someone with an open mic is speaking while they
+    /// only send noise, so while gated they're treated as silent.
+    Gated(bool),
+    /// interim transcriptions should be asked for this often from now
+    /// on, see `Discrivener::set_auto_period`
+    AutoPeriodChanged(Duration),
 }
 
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
@@ -22,23 +67,73 @@ pub(crate) struct UserAudioEvent {
     pub event_type: UserAudioEventType,
 }
 
+/// A packet's worth of audio, in whichever format it reached us.
+#[derive(Debug)]
+pub(crate) enum AudioSamples {
+    /// 48khz interleaved stereo, as Discord sends it
+    Discord(Vec<DiscordAudioSample>),
+    /// 16khz mono, already in whisper's format, from a host which
+    /// decoded the audio itself.  See `Discrivener::submit_whisper_audio`.
+    Whisper(Vec<WhisperAudioSample>),
+}
+
+/// Some of a user's audio, whether it came from Discord or was handed
+/// to us by the host.
 #[derive(Debug)]
-pub(crate) struct DiscordAudioData {
+pub(crate) struct UserAudioData {
     pub user_id: UserId,
-    pub discord_audio: Vec<DiscordAudioSample>,
+    pub audio: AudioSamples,
     pub rtc_timestamp: DiscordRtcTimestamp,
+    /// the stream the audio came from.  rtc_timestamps are only
+    /// comparable within a single stream.
+    pub ssrc: Ssrc,
+}
+
+/// A worker's part of a whole-channel flush: everything it has
+/// finalized since the last one.
+#[derive(Debug)]
+pub(crate) struct ChannelFlushReply {
+    pub flush_id: u64,
+    pub session: bool,
+    pub user_id: UserId,
+    /// in `ChannelMode::Split`, which of the user's channels the
+    /// worker was transcribing
+    pub audio_channel: Option<u8>,
+    pub transcriptions: Vec<Transcription>,
 }
 
+/// Audio for a single user, to be transcribed.
 #[derive(Debug)]
-pub(crate) struct TranscriptionRequest {
+pub struct TranscriptionRequest {
     pub audio_bytes: Bytes,
     pub audio_duration: Duration,
+    /// how the samples in audio_bytes are encoded
+    pub audio_format: AudioPayloadFormat,
+    /// where in the user's buffer the audio starts.  Zero unless
+    /// only part of the buffer is being transcribed.
+    pub buffer_offset: Duration,
+    /// true if the transcription will only be shown while the user is
+    /// still talking, rather than published as final
+    pub is_interim: bool,
     pub previous_tokens: Vec<WhisperToken>,
+    /// when set, the backend can send `TranscriptionProgress` events
+    /// here as it works through the audio.  Backends which can't tell
+    /// how far along they are ignore it.
+    pub progress: Option<UnboundedSender<VoiceChannelEvent>>,
     pub start_timestamp: SystemTime,
+    /// when set, the temperature whisper starts decoding at, in place
+    /// of the configured one, e.g. to retry audio it found nothing in
+    pub temperature: Option<f32>,
     pub user_id: UserId,
 }
 
+/// The text found in a `TranscriptionRequest`'s audio.
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
-pub(crate) struct TranscriptionResponse {
+pub struct TranscriptionResponse {
+    /// copied from the request
+    pub buffer_offset: Duration,
     pub transcript: Transcription,
+    /// why the audio couldn't be transcribed, in which case the
+    /// transcript has no segments
+    pub error: Option<String>,
 }
@@ -0,0 +1,259 @@
+//! Checks a model file's header before whisper.cpp is given it, so
+//! that a file it can't load gets a clear error, rather than whisper
+//! failing somewhere inside the binding without saying why.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+/// "ggml", as whisper.cpp writes it at the start of its model files
+const GGML_MAGIC: u32 = 0x6767_6d6c;
+
+/// the magic, then eleven i32 hyperparameters, ending with the
+/// weights' type
+const HEADER_BYTES: usize = 4 + 11 * 4;
+
+/// the weights' type is stored as `quantization version * 1000 + type`
+const QUANTIZATION_VERSION_FACTOR: i32 = 1000;
+
+/// the newest quantization format the bundled whisper.cpp understands
+const MAX_QUANTIZATION_VERSION: i32 = 2;
+
+/// whisper.cpp only handles the original models' 80 mel bands.  Newer
+/// models such as large-v3 use 128.
+const SUPPORTED_MELS: i32 = 80;
+
+const SUPPORTED_FORMATS: &str = "whisper.cpp ggml models (.bin) with f32, f16, q4_0, q4_1, \
+     q5_0, q5_1 or q8_0 weights";
+
+/// How a model's weights are stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum WeightType {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
+    /// q4_1, but with some of the weights left as f16
+    Q4_1SomeF16,
+    Q8_0,
+    Q5_0,
+    Q5_1,
+}
+
+impl WeightType {
+    /// ggml's `ggml_ftype`, for the types whisper.cpp can load
+    fn from_ggml(ftype: i32) -> Option<Self> {
+        match ftype {
+            0 => Some(WeightType::F32),
+            1 => Some(WeightType::F16),
+            2 => Some(WeightType::Q4_0),
+            3 => Some(WeightType::Q4_1),
+            4 => Some(WeightType::Q4_1SomeF16),
+            7 => Some(WeightType::Q8_0),
+            8 => Some(WeightType::Q5_0),
+            9 => Some(WeightType::Q5_1),
+            _ => None,
+        }
+    }
+
+    fn is_quantized(&self) -> bool {
+        !matches!(self, WeightType::F32 | WeightType::F16)
+    }
+}
+
+impl fmt::Display for WeightType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WeightType::F32 => "f32",
+            WeightType::F16 => "f16",
+            WeightType::Q4_0 => "q4_0",
+            WeightType::Q4_1 => "q4_1",
+            WeightType::Q4_1SomeF16 => "q4_1 (some f16)",
+            WeightType::Q8_0 => "q8_0",
+            WeightType::Q5_0 => "q5_0",
+            WeightType::Q5_1 => "q5_1",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a model file's header says about it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct ModelFormat {
+    /// which version of ggml's quantization the weights were written
+    /// with.  Only meaningful for quantized weights.
+    pub quantization_version: i32,
+    pub weight_type: WeightType,
+}
+
+impl fmt::Display for ModelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weight_type.is_quantized() {
+            write!(
+                f,
+                "ggml model with {} weights (quantization version {})",
+                self.weight_type, self.quantization_version
+            )
+        } else {
+            write!(f, "ggml model with {} weights", self.weight_type)
+        }
+    }
+}
+
+/// Reads the header of the model file at the path, and checks that
+/// whisper.cpp will be able to load it.  Returns why not if it won't.
+pub(crate) fn read_model_format(path: &Path) -> Result<ModelFormat, String> {
+    let mut header = Vec::with_capacity(HEADER_BYTES);
+    File::open(path)
+        .and_then(|file| file.take(HEADER_BYTES as u64).read_to_end(&mut header))
+        .map_err(|err: io::Error| format!("couldn't read the header: {}", err))?;
+    parse_header(&header)
+}
+
+fn parse_header(header: &[u8]) -> Result<ModelFormat, String> {
+    if let Some(format) = recognize_other_format(header) {
+        return Err(format!(
+            "this is {}, but only {} are supported",
+            format, SUPPORTED_FORMATS
+        ));
+    }
+    if header.len() < HEADER_BYTES {
+        return Err(format!(
+            "the file is only {} bytes long, which is too short to be a model.  \
+             It may have been truncated while downloading",
+            header.len()
+        ));
+    }
+    let field = |index: usize| {
+        let start = index * 4;
+        i32::from_le_bytes(header[start..start + 4].try_into().unwrap())
+    };
+    if field(0) as u32 != GGML_MAGIC {
+        return Err(format!(
+            "not a model file we recognize, only {} are supported",
+            SUPPORTED_FORMATS
+        ));
+    }
+    // n_vocab, then the audio and text hyperparameters
+    let n_mels = field(10);
+    let ftype = field(11);
+    let quantization_version = ftype / QUANTIZATION_VERSION_FACTOR;
+    let Some(weight_type) = WeightType::from_ggml(ftype % QUANTIZATION_VERSION_FACTOR) else {
+        return Err(format!(
+            "the model's weights are of type {}, which this version of whisper.cpp \
+             can't load.  Only {} are supported",
+            ftype % QUANTIZATION_VERSION_FACTOR,
+            SUPPORTED_FORMATS
+        ));
+    };
+    let format = ModelFormat {
+        quantization_version,
+        weight_type,
+    };
+    if weight_type.is_quantized() && quantization_version > MAX_QUANTIZATION_VERSION {
+        return Err(format!(
+            "the {} needs a newer whisper.cpp, which understands quantization \
+             version {}.  Versions up to {} are supported",
+            format, quantization_version, MAX_QUANTIZATION_VERSION
+        ));
+    }
+    if n_mels != SUPPORTED_MELS {
+        return Err(format!(
+            "the model uses {} mel bands, which needs a newer whisper.cpp, as this one \
+             only supports models with {}, such as up to large-v2",
+            n_mels, SUPPORTED_MELS
+        ));
+    }
+    Ok(format)
+}
+
+/// Names the kind of file, if it's one which people often mistake for
+/// a whisper.cpp model.
+fn recognize_other_format(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"GGUF") {
+        Some("a GGUF model, which this version of whisper.cpp can't load")
+    } else if header.starts_with(b"tjgg") || header.starts_with(b"fmgg") {
+        // "ggjt" and "ggmf", the way llama.cpp writes them
+        Some("a llama.cpp model")
+    } else if header.starts_with(b"PK\x03\x04") {
+        Some("a zip file, most likely an original PyTorch checkpoint")
+    } else if header.len() > 8 && header[8] == b'{' {
+        // a little-endian length, followed by a JSON header
+        Some("a safetensors file")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the header of a model with the given mels and weight type
+    fn header(n_mels: i32, ftype: i32) -> Vec<u8> {
+        let mut header = GGML_MAGIC.to_le_bytes().to_vec();
+        // tiny's hyperparameters
+        for value in [51865, 1500, 384, 6, 4, 448, 384, 6, 4, n_mels, ftype] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header
+    }
+
+    #[test]
+    fn test_model_formats() {
+        assert_eq!(
+            parse_header(&header(80, 1)),
+            Ok(ModelFormat {
+                quantization_version: 0,
+                weight_type: WeightType::F16,
+            })
+        );
+        let format = parse_header(&header(80, 2009)).unwrap();
+        assert_eq!(format.weight_type, WeightType::Q5_1);
+        assert_eq!(format.quantization_version, 2);
+        assert_eq!(
+            format.to_string(),
+            "ggml model with q5_1 weights (quantization version 2)"
+        );
+    }
+
+    #[test]
+    fn test_unsupported_models() {
+        // k-quants
+        assert!(parse_header(&header(80, 2012)).is_err());
+        assert!(parse_header(&header(80, 3008))
+            .unwrap_err()
+            .contains("quantization version 3"));
+        // large-v3
+        assert!(parse_header(&header(128, 1))
+            .unwrap_err()
+            .contains("128 mel bands"));
+        assert!(parse_header(b"GGUF\x03\x00\x00\x00")
+            .unwrap_err()
+            .contains("GGUF"));
+    }
+
+    #[test]
+    fn test_garbage_files() {
+        let truncated = header(80, 1);
+        assert!(parse_header(&truncated[..20])
+            .unwrap_err()
+            .contains("truncated"));
+        assert!(parse_header(&[]).unwrap_err().contains("truncated"));
+        let garbage = (0..200).map(|i| (i * 37) as u8).collect::<Vec<_>>();
+        assert!(parse_header(&garbage)
+            .unwrap_err()
+            .contains("not a model file"));
+
+        let path = std::env::temp_dir().join(format!(
+            "discrivener-garbage-model-{}.bin",
+            std::process::id()
+        ));
+        std::fs::write(&path, b"<html>not found</html>").unwrap();
+        assert!(read_model_format(&path).unwrap_err().contains("too short"));
+        std::fs::remove_file(path).ok();
+    }
+}
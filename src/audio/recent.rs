@@ -0,0 +1,104 @@
+//! Keeps the last few seconds of each user's audio, as it came from
+//! Discord, so that it can be played back after it's been transcribed.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::model::{
+    constants::{DISCORD_AUDIO_CHANNELS, DISCORD_SAMPLES_PER_SECOND},
+    types::{DiscordAudioSample, UserId},
+};
+
+/// A ring buffer of each user's most recent audio.  This is separate
+/// from the buffers audio is transcribed from, and nothing is ever
+/// taken out of it, only pushed out by newer audio.
+pub(crate) struct RecentAudio {
+    /// how many samples to keep for each user, across both channels
+    capacity: usize,
+    users: HashMap<UserId, VecDeque<DiscordAudioSample>>,
+}
+
+impl RecentAudio {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            capacity: samples_in(retention),
+            users: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, user_id: UserId, discord_audio: &[DiscordAudioSample]) {
+        let capacity = self.capacity;
+        let samples = self
+            .users
+            .entry(user_id)
+            .or_insert_with(|| VecDeque::with_capacity(capacity));
+        let keep = &discord_audio[discord_audio.len().saturating_sub(capacity)..];
+        let overflow = (samples.len() + keep.len()).saturating_sub(capacity);
+        samples.drain(..overflow);
+        samples.extend(keep);
+    }
+
+    /// Up to `duration` of the user's most recent audio, as 48khz
+    /// stereo with the channels interleaved.
+    pub(crate) fn get(&self, user_id: UserId, duration: Duration) -> Vec<DiscordAudioSample> {
+        let Some(samples) = self.users.get(&user_id) else {
+            return Vec::new();
+        };
+        let skip = samples.len().saturating_sub(samples_in(duration));
+        samples.iter().skip(skip).copied().collect()
+    }
+
+    pub(crate) fn forget(&mut self, user_id: UserId) {
+        self.users.remove(&user_id);
+    }
+}
+
+/// How many samples of Discord audio, across both channels, there are
+/// in the duration, rounded down to a whole frame.
+fn samples_in(duration: Duration) -> usize {
+    let frames = duration.as_micros() as usize * DISCORD_SAMPLES_PER_SECOND / 1_000_000;
+    frames * DISCORD_AUDIO_CHANNELS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 20ms of stereo audio, all at the given level
+    fn packet(level: DiscordAudioSample) -> Vec<DiscordAudioSample> {
+        vec![level; 1920]
+    }
+
+    #[test]
+    fn test_keeps_only_the_most_recent() {
+        let mut recent = RecentAudio::new(Duration::from_millis(40));
+        for level in 1..=3 {
+            recent.push(1, &packet(level));
+        }
+        recent.push(2, &packet(7));
+
+        let audio = recent.get(1, Duration::from_secs(1));
+        assert_eq!(audio.len(), 3840);
+        assert!(audio[..1920].iter().all(|sample| *sample == 2));
+        assert!(audio[1920..].iter().all(|sample| *sample == 3));
+        assert_eq!(recent.get(1, Duration::from_millis(20)), packet(3));
+        assert_eq!(recent.get(2, Duration::from_secs(1)), packet(7));
+
+        recent.forget(1);
+        assert!(recent.get(1, Duration::from_secs(1)).is_empty());
+        assert!(recent.get(3, Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_packet_longer_than_retention() {
+        let mut recent = RecentAudio::new(Duration::from_millis(10));
+        let mut audio = packet(1);
+        audio[1919] = 5;
+        recent.push(1, &audio);
+        let kept = recent.get(1, Duration::from_secs(1));
+        assert_eq!(kept.len(), 960);
+        assert_eq!(kept.last(), Some(&5));
+    }
+}
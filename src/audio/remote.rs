@@ -0,0 +1,128 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use tokio::task::JoinHandle;
+
+use crate::model::{
+    config::AudioPayloadFormat, constants::WHISPER_SAMPLES_PER_SECOND, types::Transcription,
+};
+
+use super::{
+    backend::TranscriptionBackend,
+    events::{TranscriptionRequest, TranscriptionResponse},
+};
+
+/// Sends audio to a transcription server, instead of running whisper
+/// locally.
+///
+/// Each request is POSTed to the endpoint with the audio as the body.
+/// The rest of the request is described by headers:
+///
+/// - `Content-Type`: `application/octet-stream`
+/// - `X-Audio-Format`: `f32` (native byte order) or `pcm16` (little-endian)
+/// - `X-Audio-Duration-Ms`: length of the audio
+/// - `X-Previous-Tokens`: comma-separated token ids, to use as a prompt
+/// - `X-Sample-Rate`: always 16000, mono
+/// - `X-Start-Timestamp-Ms`: milliseconds since the unix epoch
+/// - `X-Temperature`: the temperature to start decoding at, only sent
+///   when retrying audio which nothing was found in
+/// - `X-User-Id`: Discord user id of the speaker
+///
+/// The server should answer with a JSON `Transcription`.
+pub struct RemoteBackend {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl RemoteBackend {
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint,
+        }
+    }
+}
+
+impl TranscriptionBackend for RemoteBackend {
+    fn process_transcription_request(
+        &self,
+        TranscriptionRequest {
+            audio_bytes,
+            audio_duration,
+            audio_format,
+            buffer_offset,
+            previous_tokens,
+            start_timestamp,
+            temperature,
+            user_id,
+            ..
+        }: TranscriptionRequest,
+    ) -> JoinHandle<TranscriptionResponse> {
+        let processing_start = std::time::Instant::now();
+        let previous_tokens = previous_tokens
+            .iter()
+            .map(|token| token.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        let start_timestamp_ms = start_timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis();
+        let mut request = self
+            .client
+            .post(self.endpoint.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header("X-Audio-Format", audio_format_name(audio_format))
+            .header(
+                "X-Audio-Duration-Ms",
+                audio_duration.as_millis().to_string(),
+            )
+            .header("X-Previous-Tokens", previous_tokens)
+            .header("X-Sample-Rate", WHISPER_SAMPLES_PER_SECOND.to_string())
+            .header("X-Start-Timestamp-Ms", start_timestamp_ms.to_string())
+            .header("X-User-Id", user_id.to_string())
+            .body(audio_bytes);
+        if let Some(temperature) = temperature {
+            request = request.header("X-Temperature", temperature.to_string());
+        }
+        tokio::spawn(async move {
+            let (transcript, error) = match send(request).await {
+                Ok(transcript) => (transcript, None),
+                Err(err) => {
+                    eprintln!("Failed to get transcription from server: {}", err);
+                    let transcript = Transcription {
+                        start_timestamp,
+                        user_id,
+                        segments: Vec::new(),
+                        audio_duration,
+                        processing_time: processing_start.elapsed(),
+                        utterance_id: 0,
+                        language: None,
+                        audio_channel: None,
+                    };
+                    (transcript, Some(err.to_string()))
+                }
+            };
+            TranscriptionResponse {
+                buffer_offset,
+                transcript,
+                error,
+            }
+        })
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<Transcription, reqwest::Error> {
+    request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Transcription>()
+        .await
+}
+
+fn audio_format_name(audio_format: AudioPayloadFormat) -> &'static str {
+    match audio_format {
+        AudioPayloadFormat::F32 => "f32",
+        AudioPayloadFormat::Pcm16 => "pcm16",
+    }
+}
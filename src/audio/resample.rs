@@ -1,7 +1,10 @@
-/// Resample audio from espeak-ng to Discord's sample rate.
-/// This is necessary because espeak-ng generates audio at 22050hz,
-/// and Discord expects audio at 48000hz.
-use std::iter;
+//! Resample audio from espeak-ng to Discord's sample rate.
+//! This is necessary because espeak-ng generates audio at 22050hz,
+//! and Discord expects audio at 48000hz.
+//!
+//! Synthesized or recorded audio can also be converted to whisper's
+//! format, to feed it through the pipeline.
+use std::{iter, path::Path};
 
 use rubato::{
     calculate_cutoff, SincFixedOut, SincInterpolationParameters, SincInterpolationType,
@@ -9,6 +12,14 @@ use rubato::{
 };
 use songbird::constants::MONO_FRAME_SIZE;
 
+use crate::model::{
+    constants::{ESPEAK_SAMPLES_PER_SECOND, WHISPER_SAMPLES_PER_SECOND},
+    error::DiscrivenerError,
+    types::WhisperAudioSample,
+};
+
+use super::wav::WavAudio;
+
 /// number of samples needed to fully store input_frames
 /// after conversion to Discord's sample rate, rounded
 /// up to the nearest multiple of MONO_FRAME_SIZE
@@ -85,3 +96,135 @@ pub(crate) fn resample(from_sample_rate: usize, to_sample_rate: usize, data: &[i
         .map(|x| (x * i16::MAX as f64) as i16)
         .collect()
 }
+
+/// Converts mono audio from espeak-ng to whisper's format, 16khz mono
+/// f32, e.g. to use synthesized speech as a test fixture.
+pub fn espeak_to_whisper(data: &[i16]) -> Vec<WhisperAudioSample> {
+    to_whisper(ESPEAK_SAMPLES_PER_SECOND, data)
+}
+
+/// Reads a WAV file and converts it to whisper's format, 16khz mono
+/// f32, the same way `Discrivener::transcribe_wav` does, e.g. to make
+/// test fixtures or to try out other backends on the same audio.  The
+/// file can have any sample rate and number of channels, which are
+/// averaged together, but must be 16-bit PCM.
+pub fn resample_wav_to_whisper(
+    path: impl AsRef<Path>,
+) -> Result<Vec<WhisperAudioSample>, DiscrivenerError> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|source| DiscrivenerError::AudioUnreadable {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let wav = WavAudio::parse(&data).map_err(|reason| DiscrivenerError::InvalidAudio {
+        path: path.to_path_buf(),
+        reason,
+    })?;
+    Ok(wav.to_whisper())
+}
+
+/// Converts mono audio at any sample rate to whisper's format.
+pub(crate) fn to_whisper(sample_rate: usize, data: &[i16]) -> Vec<WhisperAudioSample> {
+    let mut resampled = if sample_rate == WHISPER_SAMPLES_PER_SECOND {
+        data.to_vec()
+    } else {
+        resample(sample_rate, WHISPER_SAMPLES_PER_SECOND, data)
+    };
+    // the padding out to a whole Discord frame isn't needed here
+    let whisper_samples = (data.len() * WHISPER_SAMPLES_PER_SECOND).div_ceil(sample_rate);
+    resampled.truncate(whisper_samples);
+    resampled
+        .iter()
+        .map(|x| *x as WhisperAudioSample / i16::MAX as WhisperAudioSample)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::audio::wav::make_wav;
+
+    use super::*;
+
+    /// writes a second of audio at the rate, with each channel at
+    /// its own level
+    fn write_wav(name: &str, sample_rate: u32, levels: &[i16]) -> PathBuf {
+        let samples = levels
+            .iter()
+            .copied()
+            .cycle()
+            .take(sample_rate as usize * levels.len())
+            .collect::<Vec<i16>>();
+        let path = std::env::temp_dir().join(format!(
+            "discrivener-resample-test-{}-{}.wav",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, make_wav(levels.len() as u16, sample_rate, &samples)).unwrap();
+        path
+    }
+
+    /// whether the middle of the audio, away from the resampler's
+    /// ramp up and down, is at the level
+    fn is_steady_at(audio: &[WhisperAudioSample], level: i16) -> bool {
+        let expected = level as WhisperAudioSample / i16::MAX as WhisperAudioSample;
+        audio[4000..12000]
+            .iter()
+            .all(|sample| (sample - expected).abs() < 0.003)
+    }
+
+    #[test]
+    fn test_resample_wav_48k_stereo() {
+        let path = write_wav("48k-stereo", 48000, &[2000, 0]);
+        let audio = resample_wav_to_whisper(&path).unwrap();
+        assert_eq!(audio.len(), WHISPER_SAMPLES_PER_SECOND);
+        // the channels are averaged together
+        assert!(is_steady_at(&audio, 1000));
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_resample_wav_44k_mono() {
+        let path = write_wav("44k-mono", 44100, &[1000]);
+        let audio = resample_wav_to_whisper(&path).unwrap();
+        assert_eq!(audio.len(), WHISPER_SAMPLES_PER_SECOND);
+        assert!(is_steady_at(&audio, 1000));
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_resample_wav_16k_mono() {
+        let path = write_wav("16k-mono", 16000, &[1000]);
+        let audio = resample_wav_to_whisper(&path).unwrap();
+        // already in whisper's format, so every sample is kept as is
+        assert_eq!(
+            audio,
+            vec![1000.0 / i16::MAX as WhisperAudioSample; WHISPER_SAMPLES_PER_SECOND]
+        );
+        fs::remove_file(path).ok();
+
+        assert!(matches!(
+            resample_wav_to_whisper(path),
+            Err(DiscrivenerError::AudioUnreadable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_espeak_to_whisper_duration() {
+        let one_second = vec![1000; ESPEAK_SAMPLES_PER_SECOND];
+        assert_eq!(
+            espeak_to_whisper(&one_second).len(),
+            WHISPER_SAMPLES_PER_SECOND
+        );
+
+        // partial samples are rounded up
+        let a_little_more = vec![1000; ESPEAK_SAMPLES_PER_SECOND + 1];
+        assert_eq!(
+            espeak_to_whisper(&a_little_more).len(),
+            WHISPER_SAMPLES_PER_SECOND + 1
+        );
+
+        assert!(espeak_to_whisper(&[]).is_empty());
+    }
+}
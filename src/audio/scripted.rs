@@ -0,0 +1,111 @@
+//! A backend for tests, which can be told how to answer each request.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use super::{
+    backend::TranscriptionBackend,
+    echo::EchoBackend,
+    events::{TranscriptionRequest, TranscriptionResponse},
+};
+
+/// How a `ScriptedBackend` answers a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Answer {
+    /// echoes, as sure of every token as the given percentage
+    Echo(u32),
+    /// finds nothing in the audio
+    Empty,
+    /// fails to transcribe the audio
+    Error,
+    /// never answers
+    Stuck,
+}
+
+/// What a `ScriptedBackend` was asked.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ScriptedRequest {
+    pub audio_duration: Duration,
+    pub buffer_offset: Duration,
+    pub is_interim: bool,
+    pub temperature: Option<f32>,
+}
+
+/// Echoes like an `EchoBackend`, unless scripted to answer otherwise,
+/// and reports each request it's given.
+pub(crate) struct ScriptedBackend {
+    echo: EchoBackend,
+    /// how the next few requests are answered, in order
+    script: Mutex<VecDeque<Answer>>,
+    /// how requests are answered once the script runs out
+    then: Answer,
+    tx_requests: UnboundedSender<ScriptedRequest>,
+}
+
+impl ScriptedBackend {
+    /// Answers with each of `script` in turn, and then always with
+    /// `then`, echoing " hello".
+    pub fn new(script: &[Answer], then: Answer) -> (Arc<Self>, UnboundedReceiver<ScriptedRequest>) {
+        let (tx_requests, rx_requests) = mpsc::unbounded_channel();
+        let backend = Self {
+            echo: EchoBackend::new(" hello".to_string()),
+            script: Mutex::new(script.iter().copied().collect()),
+            then,
+            tx_requests,
+        };
+        (Arc::new(backend), rx_requests)
+    }
+
+    /// Always echoes " hello", and is sure of it.
+    pub fn echo() -> (Arc<Self>, UnboundedReceiver<ScriptedRequest>) {
+        Self::new(&[], Answer::Echo(100))
+    }
+}
+
+impl TranscriptionBackend for ScriptedBackend {
+    fn process_transcription_request(
+        &self,
+        request: TranscriptionRequest,
+    ) -> JoinHandle<TranscriptionResponse> {
+        self.tx_requests
+            .send(ScriptedRequest {
+                audio_duration: request.audio_duration,
+                buffer_offset: request.buffer_offset,
+                is_interim: request.is_interim,
+                temperature: request.temperature,
+            })
+            .ok();
+        let answer = self.script.lock().unwrap().pop_front().unwrap_or(self.then);
+        if answer == Answer::Stuck {
+            return tokio::spawn(std::future::pending());
+        }
+        let echo = self.echo.process_transcription_request(request);
+        tokio::spawn(async move {
+            let mut response = echo.await.unwrap();
+            match answer {
+                Answer::Echo(p) => {
+                    for segment in response.transcript.segments.iter_mut() {
+                        for token in segment.tokens_with_probability.iter_mut() {
+                            token.p = p;
+                        }
+                    }
+                }
+                Answer::Empty => response.transcript.segments.clear(),
+                Answer::Error => {
+                    response.transcript.segments.clear();
+                    response.error = Some("the backend fell over".to_string());
+                }
+                Answer::Stuck => unreachable!(),
+            }
+            response
+        })
+    }
+}
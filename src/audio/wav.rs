@@ -0,0 +1,167 @@
+//! Reads WAV files, so that recorded audio can be fed through the
+//! same resampling and transcription as audio from Discord.
+
+use std::cmp::min;
+
+use crate::model::types::WhisperAudioSample;
+
+use super::resample::to_whisper;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// 16-bit PCM audio read from a WAV file.
+pub(crate) struct WavAudio {
+    pub channels: usize,
+    pub sample_rate: usize,
+    /// samples for each channel, interleaved
+    pub samples: Vec<i16>,
+}
+
+impl WavAudio {
+    /// Reads a WAV file's contents.  Any number of channels and any
+    /// sample rate can be read, but only 16-bit PCM encoding.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err("not a WAV file".to_string());
+        }
+        let mut format = None;
+        let mut chunks = &data[12..];
+        while chunks.len() >= 8 {
+            let chunk_id = &chunks[0..4];
+            let chunk_len = read_u32(&chunks[4..8]) as usize;
+            // some writers don't know the length of the data when they
+            // start, so take whatever is actually there
+            let chunk = &chunks[8..min(chunks.len(), 8 + chunk_len)];
+            match chunk_id {
+                b"fmt " => {
+                    if chunk.len() < 16 {
+                        return Err("format chunk is too short".to_string());
+                    }
+                    let encoding = read_u16(&chunk[0..2]);
+                    let channels = read_u16(&chunk[2..4]) as usize;
+                    let sample_rate = read_u32(&chunk[4..8]) as usize;
+                    let bits_per_sample = read_u16(&chunk[14..16]);
+                    if !matches!(encoding, WAVE_FORMAT_PCM | WAVE_FORMAT_EXTENSIBLE)
+                        || bits_per_sample != 16
+                    {
+                        return Err(format!(
+                            "only 16-bit PCM is supported, not {}-bit encoding {}",
+                            bits_per_sample, encoding
+                        ));
+                    }
+                    if channels == 0 || sample_rate == 0 {
+                        return Err("no channels, or no sample rate".to_string());
+                    }
+                    format = Some((channels, sample_rate));
+                }
+                b"data" => {
+                    let Some((channels, sample_rate)) = format else {
+                        return Err("audio data comes before its format".to_string());
+                    };
+                    let samples = chunk
+                        .chunks_exact(2)
+                        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+                        .collect();
+                    return Ok(Self {
+                        channels,
+                        sample_rate,
+                        samples,
+                    });
+                }
+                _ => {}
+            }
+            // chunks are padded to an even length
+            let padded_len = chunk_len + (chunk_len & 1);
+            chunks = chunks.get(8 + padded_len..).unwrap_or_default();
+        }
+        Err("no audio data".to_string())
+    }
+
+    /// Converts the audio to whisper's format, 16khz mono f32, by
+    /// averaging the channels together and resampling.
+    pub fn to_whisper(&self) -> Vec<WhisperAudioSample> {
+        let mono = self
+            .samples
+            .chunks_exact(self.channels)
+            .map(|frame| {
+                let sum = frame.iter().map(|sample| *sample as i32).sum::<i32>();
+                (sum / self.channels as i32) as i16
+            })
+            .collect::<Vec<i16>>();
+        to_whisper(self.sample_rate, &mono)
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// A WAV file of 16-bit PCM holding the samples, for tests.
+#[cfg(test)]
+pub(crate) fn make_wav(channels: u16, sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * channels as u32 * 2).to_le_bytes());
+    wav.extend_from_slice(&(channels * 2).to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    // an odd-length chunk we don't care about, to check padding
+    wav.extend_from_slice(b"LIST");
+    wav.extend_from_slice(&3u32.to_le_bytes());
+    wav.extend_from_slice(&[1, 2, 3, 0]);
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::constants::WHISPER_SAMPLES_PER_SECOND;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_wav() {
+        let wav = WavAudio::parse(&make_wav(2, 44100, &[1, -1, 2, -2])).unwrap();
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.sample_rate, 44100);
+        assert_eq!(wav.samples, vec![1, -1, 2, -2]);
+    }
+
+    #[test]
+    fn test_parse_rejects_other_formats() {
+        assert!(WavAudio::parse(b"not a wav file at all").is_err());
+        let mut wav = make_wav(1, 16000, &[0; 4]);
+        // claim to be 8-bit
+        wav[34] = 8;
+        assert!(WavAudio::parse(&wav).is_err());
+    }
+
+    #[test]
+    fn test_to_whisper() {
+        // a second of stereo audio at 44.1khz
+        let samples = vec![1000; 44100 * 2];
+        let wav = WavAudio::parse(&make_wav(2, 44100, &samples)).unwrap();
+        assert_eq!(wav.to_whisper().len(), WHISPER_SAMPLES_PER_SECOND);
+
+        // already in whisper's format
+        let samples = vec![1000; WHISPER_SAMPLES_PER_SECOND];
+        let wav = WavAudio::parse(&make_wav(1, 16000, &samples)).unwrap();
+        assert_eq!(wav.to_whisper().len(), WHISPER_SAMPLES_PER_SECOND);
+    }
+}
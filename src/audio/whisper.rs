@@ -1,82 +1,359 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::ErrorKind,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use bytes::Bytes;
-use tokio::task::JoinHandle;
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperToken};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+use whisper_rs::{
+    FullParams, SamplingStrategy as WhisperSamplingStrategy, WhisperContext, WhisperError,
+    WhisperState, WhisperToken,
+};
 
 use crate::{
-    audio::events::{TranscriptionRequest, TranscriptionResponse},
+    audio::{
+        backend::TranscriptionBackend,
+        events::{TranscriptionRequest, TranscriptionResponse},
+        model_file::read_model_format,
+    },
     model::{
-        constants::DONT_EVEN_BOTHER_RMS_THRESHOLD,
-        types::{TextSegment, TokenWithProbability, Transcription, WhisperAudioSample},
+        config::{AudioPayloadFormat, LanguageDetectionPolicy, SamplingStrategy, WhisperConfig},
+        constants::{DONT_EVEN_BOTHER_RMS_THRESHOLD, WHISPER_SAMPLES_PER_SECOND},
+        error::DiscrivenerError,
+        types::{
+            DetectedLanguage, ModelInfo, ModelType, TextSegment, TokenWithProbability,
+            Transcription, TranscriptionLanguage, UserId, VoiceChannelEvent, WhisperAudioSample,
+        },
     },
 };
 
 use super::audio_buffer::rms_over_slice;
 
+/// whisper's full audio context, which covers 30 seconds
+const FULL_AUDIO_CTX: usize = 1500;
+
+/// how many of our samples each frame of audio context covers
+const SAMPLES_PER_AUDIO_CTX: usize = WHISPER_SAMPLES_PER_SECOND / 50;
+
+/// when scaling the audio context to the audio, leave this much
+/// extra, so that whisper isn't cut off at the very end of it
+const AUDIO_CTX_MARGIN: usize = 50;
+
+/// Keeps track of the languages whisper has detected over a session,
+/// so that the language can be pinned once whisper has been sure of
+/// it enough times in a row.
+#[derive(Debug, Default)]
+struct LanguageTracker {
+    pinned: Option<String>,
+    /// the language whisper was last sure of, and how many times
+    /// in a row it's been sure of it
+    streak: Option<(String, u32)>,
+}
+
+impl LanguageTracker {
+    /// Picks the language to transcribe in, given what whisper
+    /// detected, if anything.  Returns None if there's nothing
+    /// to go on, in which case whisper should use its default.
+    fn choose(
+        &mut self,
+        policy: &LanguageDetectionPolicy,
+        detected: Option<DetectedLanguage>,
+    ) -> Option<TranscriptionLanguage> {
+        let confident = detected
+            .as_ref()
+            .filter(|detected| detected.p >= policy.min_confidence);
+        let language = match confident {
+            Some(confident) => {
+                let count = match &self.streak {
+                    Some((language, count)) if *language == confident.language => count + 1,
+                    _ => 1,
+                };
+                self.streak = Some((confident.language.clone(), count));
+                if matches!(policy.pin_after, Some(pin_after) if count >= pin_after) {
+                    self.pinned = Some(confident.language.clone());
+                }
+                confident.language.clone()
+            }
+            None => policy
+                .fallback_language
+                .clone()
+                .or_else(|| detected.as_ref().map(|detected| detected.language.clone()))?,
+        };
+        Some(TranscriptionLanguage { language, detected })
+    }
+}
+
 pub(crate) struct Whisper {
-    whisper_context: Arc<WhisperContext>,
+    config: Arc<WhisperConfig>,
+    /// the smaller model for interim transcriptions, if we have one,
+    /// see `WhisperConfig::fast_model_path`
+    fast_whisper_context: Option<Arc<WhisperContext>>,
+    language_tracker: Arc<Mutex<LanguageTracker>>,
+    /// languages we've been told particular users speak, which
+    /// take precedence over detection
+    user_languages: Arc<Mutex<HashMap<UserId, String>>>,
+    /// the main model.  Requests take their own reference to it, so
+    /// that swapping it out with `reload_model` doesn't disturb any
+    /// which are in flight.
+    whisper_context: Mutex<Arc<WhisperContext>>,
 }
 
 impl Whisper {
     /// Load a model from the given path
-    pub fn load(model_path: String) -> Self {
-        let path = Path::new(model_path.as_str());
-        if !path.exists() {
-            panic!("Model file does not exist: {}", path.to_str().unwrap());
+    pub fn load(model_path: String, mut config: WhisperConfig) -> Result<Self, DiscrivenerError> {
+        if let Err(err) = config.sampling_strategy.validate() {
+            return Err(DiscrivenerError::InvalidConfig(format!(
+                "invalid sampling strategy: {}",
+                err
+            )));
+        }
+        if let Some(fallback_language) = config
+            .language_detection
+            .as_ref()
+            .and_then(|policy| policy.fallback_language.as_ref())
+        {
+            if whisper_rs::get_lang_id(fallback_language).is_none() {
+                return Err(DiscrivenerError::InvalidConfig(format!(
+                    "unknown fallback language: {}",
+                    fallback_language
+                )));
+            }
+        }
+
+        if config
+            .audio_ctx
+            .is_some_and(|audio_ctx| audio_ctx == 0 || audio_ctx > FULL_AUDIO_CTX)
+        {
+            return Err(DiscrivenerError::InvalidConfig(format!(
+                "audio context must be between 1 and {}",
+                FULL_AUDIO_CTX
+            )));
         }
-        if !path.is_file() {
-            panic!("Model is not a file: {}", path.to_str().unwrap());
+
+        let whisper_context = Self::load_context(model_path.as_str())?;
+        let fast_whisper_context = config
+            .fast_model_path
+            .as_deref()
+            .map(Self::load_context)
+            .transpose()?;
+        Self::check_fast_model(
+            whisper_context.is_multilingual(),
+            fast_whisper_context
+                .as_ref()
+                .map(|fast| fast.is_multilingual()),
+        )?;
+
+        if !whisper_context.is_multilingual() {
+            // whisper is never told the language, so it assumes English,
+            // which is all these models understand anyway
+            eprintln!("Loaded an English-only model, other languages won't be transcribed");
+            if config.language_detection.take().is_some() {
+                eprintln!("English-only models can't detect languages, so detection is off");
+            }
         }
 
+        Ok(Self {
+            config: Arc::new(config),
+            fast_whisper_context,
+            language_tracker: Arc::new(Mutex::new(LanguageTracker::default())),
+            user_languages: Arc::new(Mutex::new(HashMap::new())),
+            whisper_context: Mutex::new(whisper_context),
+        })
+    }
+
+    /// Fails unless the fast model, if there is one, understands the
+    /// same languages as the main model, as a user's interim and final
+    /// transcriptions would otherwise disagree about what they said.
+    fn check_fast_model(
+        is_multilingual: bool,
+        fast_is_multilingual: Option<bool>,
+    ) -> Result<(), DiscrivenerError> {
+        let kind = |is_multilingual| {
+            if is_multilingual {
+                "multilingual"
+            } else {
+                "English-only"
+            }
+        };
+        match fast_is_multilingual {
+            Some(fast_is_multilingual) if fast_is_multilingual != is_multilingual => {
+                Err(DiscrivenerError::InvalidConfig(format!(
+                    "the fast model is {}, but the main model is {}",
+                    kind(fast_is_multilingual),
+                    kind(is_multilingual)
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The main model, as it is right now.
+    fn whisper_context(&self) -> Arc<WhisperContext> {
+        self.whisper_context.lock().unwrap().clone()
+    }
+
+    /// Loads a single model, checking first that there's a file there
+    /// for whisper to load.
+    fn load_context(model_path: &str) -> Result<Arc<WhisperContext>, DiscrivenerError> {
+        // check the file ourselves first, as whisper can only tell us
+        // that it didn't load
+        let path = Path::new(model_path);
+        let metadata = std::fs::File::open(path)
+            .and_then(|file| file.metadata())
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => DiscrivenerError::ModelNotFound(path.to_path_buf()),
+                ErrorKind::PermissionDenied => {
+                    DiscrivenerError::ModelPermissionDenied(path.to_path_buf())
+                }
+                _ => DiscrivenerError::ModelUnreadable {
+                    path: path.to_path_buf(),
+                    source: err,
+                },
+            })?;
+        if !metadata.is_file() {
+            return Err(DiscrivenerError::InvalidModel {
+                path: path.to_path_buf(),
+                reason: "not a file".to_string(),
+            });
+        }
+        // whisper.cpp can crash, rather than fail, on files it can't
+        // load, so make sure it'll understand this one
+        let model_format =
+            read_model_format(path).map_err(|reason| DiscrivenerError::InvalidModel {
+                path: path.to_path_buf(),
+                reason,
+            })?;
+        eprintln!("Loading {}, a {}", model_path, model_format);
+
         let whisper_context =
-            Arc::new(WhisperContext::new(model_path.as_str()).expect("failed to load model"));
+            WhisperContext::new(model_path).map_err(|err| DiscrivenerError::InvalidModel {
+                path: path.to_path_buf(),
+                reason: format!("{:?}", err),
+            })?;
+        Ok(Arc::new(whisper_context))
+    }
 
-        Self { whisper_context }
+    fn model_type_from_whisper(model_type: i32) -> ModelType {
+        // whisper.cpp's e_model
+        match model_type {
+            1 => ModelType::Tiny,
+            2 => ModelType::Base,
+            3 => ModelType::Small,
+            4 => ModelType::Medium,
+            5 => ModelType::Large,
+            _ => ModelType::Unknown,
+        }
     }
 
-    pub(crate) fn process_transcription_request(
-        &self,
-        TranscriptionRequest {
-            audio_bytes,
-            audio_duration,
-            previous_tokens,
-            start_timestamp,
-            user_id,
-        }: TranscriptionRequest,
-    ) -> JoinHandle<TranscriptionResponse> {
-        let processing_start = std::time::Instant::now();
-        let whisper_context_clone = self.whisper_context.clone();
-        tokio::task::spawn_blocking(move || {
-            let segments =
-                Self::audio_to_text(&whisper_context_clone, audio_bytes, previous_tokens);
-            let transcript = Transcription {
-                start_timestamp,
-                user_id,
-                segments,
-                audio_duration,
-                processing_time: processing_start.elapsed(),
-            };
-            TranscriptionResponse { transcript }
+    /// Works out which language the audio in the state is in.
+    /// Returns None if whisper couldn't tell us.
+    fn detect_language(
+        state: &mut WhisperState,
+        audio_data: &[WhisperAudioSample],
+    ) -> Option<DetectedLanguage> {
+        let threads = Self::num_threads();
+        if let Err(err) = state.pcm_to_mel(audio_data, threads) {
+            eprintln!("Failed to prepare audio for language detection: {:?}", err);
+            return None;
+        }
+        let probabilities = match state.lang_detect(0, threads) {
+            Ok(probabilities) => probabilities,
+            Err(err) => {
+                eprintln!("Failed to detect language: {:?}", err);
+                return None;
+            }
+        };
+        let (language_id, p) = probabilities
+            .iter()
+            .enumerate()
+            .max_by(|(_, p1), (_, p2)| p1.total_cmp(p2))?;
+        Some(DetectedLanguage {
+            language: whisper_rs::get_lang_str(language_id as i32)?.to_string(),
+            p: (p * 100.0) as u32,
         })
     }
 
+    /// Picks the language to transcribe the user's audio in: the one
+    /// we've been told they speak, or else whatever the language
+    /// detection policy settles on, calling `detect` if it needs to
+    /// know what whisper thinks.  None leaves whisper to assume English.
+    fn choose_language(
+        config: &WhisperConfig,
+        language_tracker: &Mutex<LanguageTracker>,
+        user_languages: &Mutex<HashMap<UserId, String>>,
+        user_id: UserId,
+        detect: impl FnOnce() -> Option<DetectedLanguage>,
+    ) -> Option<TranscriptionLanguage> {
+        if let Some(language) = user_languages.lock().unwrap().get(&user_id) {
+            return Some(TranscriptionLanguage {
+                language: language.clone(),
+                detected: None,
+            });
+        }
+        let policy = config.language_detection.as_ref()?;
+        let pinned = language_tracker.lock().unwrap().pinned.clone();
+        match pinned {
+            Some(language) => Some(TranscriptionLanguage {
+                language,
+                detected: None,
+            }),
+            None => {
+                let detected = detect();
+                language_tracker.lock().unwrap().choose(policy, detected)
+            }
+        }
+    }
+
+    /// whisper's own default for how many threads to use
+    fn num_threads() -> usize {
+        std::thread::available_parallelism()
+            .map(|threads| threads.get().min(4))
+            .unwrap_or(1)
+    }
+
     /// This will take a long time to run, don't call it
     /// on a tokio event thread.
     /// ctx came from load_model
-    /// audio data should be is f32, 16KHz, mono
+    /// audio data should be 16KHz, mono, in the given format
+    #[allow(clippy::too_many_arguments)]
     fn audio_to_text(
+        config: &WhisperConfig,
+        language_tracker: &Mutex<LanguageTracker>,
+        user_languages: &Mutex<HashMap<UserId, String>>,
         whisper_context: &WhisperContext,
+        user_id: UserId,
         audio_bytes: Bytes,
+        audio_format: AudioPayloadFormat,
         previous_tokens: Vec<WhisperToken>,
-    ) -> Vec<TextSegment> {
-        let audio_len_bytes = audio_bytes.len();
-        let audio_len_samples = audio_len_bytes / std::mem::size_of::<WhisperAudioSample>();
-        let audio_data = unsafe {
-            std::slice::from_raw_parts(
-                audio_bytes.as_ptr() as *const WhisperAudioSample,
-                audio_len_samples,
-            )
+        progress: Option<UnboundedSender<VoiceChannelEvent>>,
+    ) -> Result<(Vec<TextSegment>, Option<TranscriptionLanguage>), WhisperError> {
+        // whisper wants f32, so PCM16 needs to be converted back
+        let decoded_audio: Vec<WhisperAudioSample>;
+        let audio_data = match audio_format {
+            AudioPayloadFormat::F32 => {
+                let audio_len_samples =
+                    audio_bytes.len() / std::mem::size_of::<WhisperAudioSample>();
+                unsafe {
+                    std::slice::from_raw_parts(
+                        audio_bytes.as_ptr() as *const WhisperAudioSample,
+                        audio_len_samples,
+                    )
+                }
+            }
+            AudioPayloadFormat::Pcm16 => {
+                decoded_audio = audio_bytes
+                    .chunks_exact(std::mem::size_of::<i16>())
+                    .map(|bytes| {
+                        i16::from_le_bytes([bytes[0], bytes[1]]) as WhisperAudioSample
+                            / i16::MAX as WhisperAudioSample
+                    })
+                    .collect();
+                decoded_audio.as_slice()
+            }
         };
 
         // optimization: calculate RMS over the given range,
@@ -84,20 +361,43 @@ impl Whisper {
         // the audio to the model.
         let rms = rms_over_slice(audio_data);
         if rms < DONT_EVEN_BOTHER_RMS_THRESHOLD {
-            return Vec::new();
+            return Ok((Vec::new(), None));
         }
 
-        let mut state = whisper_context.create_state().unwrap();
+        let mut state = whisper_context.create_state()?;
+
+        let language =
+            Self::choose_language(config, language_tracker, user_languages, user_id, || {
+                Self::detect_language(&mut state, audio_data)
+            });
+
+        let mut params = Self::make_params(
+            config,
+            &previous_tokens,
+            language.as_ref().map(|language| language.language.as_str()),
+            audio_data.len(),
+        );
+        if let Some(progress) = progress {
+            params.set_progress_callback_safe(move |percent: i32| {
+                // this is called from whisper's own thread, so it mustn't
+                // wait on anything.  Sending on an unbounded channel never
+                // does.
+                progress
+                    .send(VoiceChannelEvent::TranscriptionProgress {
+                        percent: percent.clamp(0, 100) as u32,
+                        user_id,
+                    })
+                    .ok();
+            });
+        }
 
         // actually convert audio to text.  Takes a while.
-        state
-            .full(Self::make_params(&previous_tokens), audio_data)
-            .unwrap();
+        state.full(params, audio_data)?;
 
-        let num_segments = state.full_n_segments().unwrap();
+        let num_segments = state.full_n_segments()?;
         let mut segments = Vec::<TextSegment>::with_capacity(num_segments as usize);
         for i in 0..num_segments {
-            let num_tokens = state.full_n_tokens(i).unwrap();
+            let num_tokens = state.full_n_tokens(i)?;
             let mut tokens_with_probability =
                 Vec::<TokenWithProbability>::with_capacity(num_tokens as usize);
             for j in 0..num_tokens {
@@ -154,13 +454,40 @@ impl Whisper {
                         start_offset_ms + 1
                     }
                 };
+            let low_confidence_p = Self::low_confidence_p(&tokens_with_probability);
+            let raw_token_ids = config.include_raw_token_ids.then(|| {
+                (0..num_tokens)
+                    .filter_map(|j| state.full_get_token_id(i, j).ok())
+                    .collect()
+            });
             segments.push(TextSegment {
                 start_offset_ms,
                 end_offset_ms,
+                // the worker knows where the audio came from, so it
+                // fills these in
+                start_sample: 0,
+                end_sample: 0,
+                low_confidence_p,
+                cleaned_text: None,
+                phonemes: None,
+                raw_token_ids,
+                whisper_timing: None,
                 tokens_with_probability,
             });
         }
-        segments
+        Ok((segments, language))
+    }
+
+    /// How unsure whisper was of the segment, as the inverse of its
+    /// average token probability.  A segment with no tokens we kept is
+    /// as unsure as can be.
+    fn low_confidence_p(tokens_with_probability: &[TokenWithProbability]) -> u32 {
+        if tokens_with_probability.is_empty() {
+            return 100;
+        }
+        let total: u32 = tokens_with_probability.iter().map(|token| token.p).sum();
+        let average = total / tokens_with_probability.len() as u32;
+        100u32.saturating_sub(average)
     }
 
     fn ignore_token(token_text: &str) -> bool {
@@ -168,15 +495,62 @@ impl Whisper {
         "<|endoftext|>" == token_text || token_text.starts_with("[_") && token_text.ends_with(']')
     }
 
-    fn make_params(previous_tokens: &Vec<WhisperToken>) -> FullParams<'_, '_> {
-        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    fn make_params<'a, 'b>(
+        config: &'a WhisperConfig,
+        previous_tokens: &'b Vec<WhisperToken>,
+        language: Option<&'a str>,
+        num_samples: usize,
+    ) -> FullParams<'a, 'b> {
+        let sampling_strategy = match config.sampling_strategy {
+            SamplingStrategy::Greedy { best_of } => WhisperSamplingStrategy::Greedy {
+                best_of: best_of as i32,
+            },
+            SamplingStrategy::BeamSearch { beam_size } => WhisperSamplingStrategy::BeamSearch {
+                beam_size: beam_size as i32,
+                // whisper.cpp's default, which disables patience
+                patience: -1.0,
+            },
+        };
+        let mut params = FullParams::new(sampling_strategy);
+
+        // temperature fallback, only overriding whisper's defaults
+        // when asked to
+        if let Some(entropy_thold) = config.entropy_thold {
+            params.set_entropy_thold(entropy_thold);
+        }
+        if let Some(logprob_thold) = config.logprob_thold {
+            params.set_logprob_thold(logprob_thold);
+        }
+        if let Some(temperature) = config.temperature {
+            params.set_temperature(temperature);
+        }
+        if let Some(temperature_inc) = config.temperature_inc {
+            params.set_temperature_inc(temperature_inc);
+        }
+
+        // segment length limits.  whisper.cpp only applies max_len
+        // when it's working out token timestamps.
+        if let Some(max_segment_len) = config.max_segment_len {
+            params.set_token_timestamps(true);
+            params.set_max_len(max_segment_len as i32);
+            params.set_split_on_word(config.split_on_word);
+        }
+        if let Some(max_segment_tokens) = config.max_segment_tokens {
+            params.set_max_tokens(max_segment_tokens as i32);
+        }
+        params.set_single_segment(config.single_segment);
+        params.set_no_context(config.no_context);
+        if let Some(audio_ctx) = audio_ctx_for(config, num_samples) {
+            params.set_audio_ctx(audio_ctx as i32);
+        }
 
         // TODO: make configurable
         // params.set_n_threads(32);
         // enable translation
         // params.set_translate(true);
-        // and set the language to translate to
-        // params.set_language(Some("en"));
+        if language.is_some() {
+            params.set_language(language);
+        }
         // also explicitly disable anything that prints to stdout
         params.set_print_special(false);
         params.set_print_progress(false);
@@ -185,8 +559,433 @@ impl Whisper {
 
         params.set_tokens(previous_tokens.as_slice());
         params.set_suppress_blank(true);
-        params.set_suppress_non_speech_tokens(true);
+        params.set_suppress_non_speech_tokens(config.suppress_non_speech_tokens);
 
         params
     }
 }
+
+impl TranscriptionBackend for Whisper {
+    fn process_transcription_request(
+        &self,
+        TranscriptionRequest {
+            audio_bytes,
+            audio_duration,
+            audio_format,
+            buffer_offset,
+            is_interim,
+            previous_tokens,
+            progress,
+            start_timestamp,
+            temperature,
+            user_id,
+        }: TranscriptionRequest,
+    ) -> JoinHandle<TranscriptionResponse> {
+        let processing_start = std::time::Instant::now();
+        let config_clone = match temperature {
+            Some(temperature) => Arc::new(WhisperConfig {
+                temperature: Some(temperature),
+                ..(*self.config).clone()
+            }),
+            None => self.config.clone(),
+        };
+        let language_tracker_clone = self.language_tracker.clone();
+        let user_languages_clone = self.user_languages.clone();
+        let whisper_context_clone = model_for(
+            self.whisper_context(),
+            self.fast_whisper_context.clone(),
+            is_interim,
+        );
+        tokio::task::spawn_blocking(move || {
+            // every attempt is for the same request, so the response
+            // still lines up with it however many it takes
+            let result = with_retries(config_clone.max_retries, config_clone.retry_backoff, || {
+                Self::audio_to_text(
+                    &config_clone,
+                    &language_tracker_clone,
+                    &user_languages_clone,
+                    &whisper_context_clone,
+                    user_id,
+                    audio_bytes.clone(),
+                    audio_format,
+                    previous_tokens.clone(),
+                    progress.clone(),
+                )
+            });
+            let (segments, language, error) = match result {
+                Ok((segments, language)) => (segments, language, None),
+                Err(err) => {
+                    eprintln!("Failed to transcribe audio from {}: {:?}", user_id, err);
+                    (Vec::new(), None, Some(format!("{:?}", err)))
+                }
+            };
+            let transcript = Transcription {
+                start_timestamp,
+                user_id,
+                segments,
+                audio_duration,
+                processing_time: processing_start.elapsed(),
+                utterance_id: 0,
+                language,
+                audio_channel: None,
+            };
+            TranscriptionResponse {
+                buffer_offset,
+                transcript,
+                error,
+            }
+        })
+    }
+
+    fn reset(&self) {
+        *self.language_tracker.lock().unwrap() = LanguageTracker::default();
+    }
+
+    fn set_user_language(
+        &self,
+        user_id: UserId,
+        language: Option<String>,
+    ) -> Result<(), DiscrivenerError> {
+        let mut user_languages = self.user_languages.lock().unwrap();
+        let Some(language) = language else {
+            user_languages.remove(&user_id);
+            return Ok(());
+        };
+        if whisper_rs::get_lang_id(&language).is_none() {
+            return Err(DiscrivenerError::InvalidConfig(format!(
+                "unknown language: {}",
+                language
+            )));
+        }
+        if !self.whisper_context().is_multilingual() && language != "en" {
+            return Err(DiscrivenerError::InvalidConfig(format!(
+                "English-only models can't transcribe {}",
+                language
+            )));
+        }
+        user_languages.insert(user_id, language);
+        Ok(())
+    }
+
+    fn model_info(&self) -> Option<ModelInfo> {
+        let whisper_context = self.whisper_context();
+        Some(ModelInfo {
+            is_multilingual: whisper_context.is_multilingual(),
+            model_type: Self::model_type_from_whisper(whisper_context.model_type()),
+            n_audio_ctx: whisper_context.model_n_audio_ctx(),
+            n_text_ctx: whisper_context.model_n_text_ctx(),
+            n_vocab: whisper_context.model_n_vocab(),
+        })
+    }
+
+    fn reload_model(&self, model_path: &str) -> Result<(), DiscrivenerError> {
+        let whisper_context = Self::load_context(model_path)?;
+        Self::check_fast_model(
+            whisper_context.is_multilingual(),
+            self.fast_whisper_context
+                .as_ref()
+                .map(|fast| fast.is_multilingual()),
+        )?;
+        // held until the swap, so that `set_user_language` can't pick a
+        // language the new model doesn't know in the meantime
+        let user_languages = self.user_languages.lock().unwrap();
+        if !whisper_context.is_multilingual() {
+            if self.config.language_detection.is_some() {
+                return Err(DiscrivenerError::InvalidConfig(
+                    "English-only models can't detect languages".to_string(),
+                ));
+            }
+            if let Some(language) = user_languages.values().find(|language| *language != "en") {
+                return Err(DiscrivenerError::InvalidConfig(format!(
+                    "English-only models can't transcribe {}",
+                    language
+                )));
+            }
+        }
+        // the old model is freed once the last request using it is done
+        *self.whisper_context.lock().unwrap() = whisper_context;
+        Ok(())
+    }
+}
+
+/// The model to transcribe a request with: interim transcriptions
+/// can make do with the fast model, if there is one.
+fn model_for<T>(model: T, fast_model: Option<T>, is_interim: bool) -> T {
+    match fast_model {
+        Some(fast_model) if is_interim => fast_model,
+        _ => model,
+    }
+}
+
+/// How much audio context whisper should use for this many samples,
+/// or None to leave it at the full context.
+fn audio_ctx_for(config: &WhisperConfig, num_samples: usize) -> Option<usize> {
+    if !config.scale_audio_ctx {
+        return config.audio_ctx;
+    }
+    let needed = num_samples.div_ceil(SAMPLES_PER_AUDIO_CTX) + AUDIO_CTX_MARGIN;
+    Some(needed.min(config.audio_ctx.unwrap_or(FULL_AUDIO_CTX)))
+}
+
+/// Calls `attempt` until it succeeds, up to `max_retries` more times
+/// after the first, sleeping for `backoff` before the first retry and
+/// twice as long before each one after that.  Gives back the last
+/// error if none succeed.
+fn with_retries<T, E: Debug>(
+    max_retries: u32,
+    backoff: Duration,
+    mut attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut retries = 0;
+    loop {
+        match attempt() {
+            Err(err) if retries < max_retries => {
+                let wait = backoff.saturating_mul(2u32.saturating_pow(retries));
+                eprintln!("transcription failed, retrying in {:?}: {:?}", wait, err);
+                std::thread::sleep(wait);
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fails the first `failures` times it's called
+    fn flaky(failures: u32) -> impl FnMut() -> Result<u32, String> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= failures {
+                Err(format!("failure {}", calls))
+            } else {
+                Ok(calls)
+            }
+        }
+    }
+
+    #[test]
+    fn test_retries_until_success() {
+        assert_eq!(with_retries(0, Duration::ZERO, flaky(0)), Ok(1));
+        // succeeds once, rather than once per attempt
+        assert_eq!(with_retries(3, Duration::ZERO, flaky(2)), Ok(3));
+    }
+
+    #[test]
+    fn test_gives_up_after_max_retries() {
+        assert_eq!(
+            with_retries(0, Duration::ZERO, flaky(1)),
+            Err("failure 1".to_string())
+        );
+        assert_eq!(
+            with_retries(2, Duration::ZERO, flaky(5)),
+            Err("failure 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_no_context() {
+        // FullParams has no getters, but does show its fields
+        let debug_params = |config: &WhisperConfig| {
+            let params = Whisper::make_params(config, &Vec::new(), None, 0);
+            format!("{:?}", params)
+        };
+        let mut config = WhisperConfig::default();
+        assert!(debug_params(&config).contains("no_context: true"));
+        config.no_context = false;
+        assert!(debug_params(&config).contains("no_context: false"));
+    }
+
+    #[test]
+    fn test_audio_ctx_for() {
+        let two_seconds = 2 * WHISPER_SAMPLES_PER_SECOND;
+        let mut config = WhisperConfig::default();
+        assert_eq!(audio_ctx_for(&config, two_seconds), None);
+        config.audio_ctx = Some(768);
+        assert_eq!(audio_ctx_for(&config, two_seconds), Some(768));
+
+        // scaled to the audio, but never past the configured size
+        config.scale_audio_ctx = true;
+        assert_eq!(audio_ctx_for(&config, two_seconds), Some(150));
+        assert_eq!(
+            audio_ctx_for(&config, 20 * WHISPER_SAMPLES_PER_SECOND),
+            Some(768)
+        );
+        config.audio_ctx = None;
+        assert_eq!(
+            audio_ctx_for(&config, 30 * WHISPER_SAMPLES_PER_SECOND),
+            Some(FULL_AUDIO_CTX)
+        );
+    }
+
+    #[test]
+    fn test_model_type_from_whisper() {
+        assert_eq!(Whisper::model_type_from_whisper(1), ModelType::Tiny);
+        assert_eq!(Whisper::model_type_from_whisper(5), ModelType::Large);
+        assert_eq!(Whisper::model_type_from_whisper(0), ModelType::Unknown);
+        assert_eq!(Whisper::model_type_from_whisper(42), ModelType::Unknown);
+    }
+
+    fn detected(language: &str, p: u32) -> Option<DetectedLanguage> {
+        Some(DetectedLanguage {
+            language: language.to_string(),
+            p,
+        })
+    }
+
+    #[test]
+    fn test_language_fallback() {
+        let mut tracker = LanguageTracker::default();
+        let policy = LanguageDetectionPolicy::default();
+        let chosen = tracker.choose(&policy, detected("de", 30)).unwrap();
+        assert_eq!(chosen.language, "de");
+        assert_eq!(chosen.detected, detected("de", 30));
+        assert_eq!(tracker.choose(&policy, None), None);
+
+        let policy = LanguageDetectionPolicy {
+            fallback_language: Some("en".to_string()),
+            ..Default::default()
+        };
+        let chosen = tracker.choose(&policy, detected("de", 30)).unwrap();
+        assert_eq!(chosen.language, "en");
+        assert_eq!(chosen.detected, detected("de", 30));
+        assert_eq!(tracker.choose(&policy, None).unwrap().language, "en");
+        let chosen = tracker.choose(&policy, detected("de", 50)).unwrap();
+        assert_eq!(chosen.language, "de");
+        assert_eq!(tracker.pinned, None);
+    }
+
+    #[test]
+    fn test_language_pinning() {
+        let mut tracker = LanguageTracker::default();
+        let policy = LanguageDetectionPolicy {
+            pin_after: Some(2),
+            ..Default::default()
+        };
+        tracker.choose(&policy, detected("de", 90));
+        // a different language starts over
+        tracker.choose(&policy, detected("fr", 90));
+        assert_eq!(tracker.pinned, None);
+        // unsure guesses are ignored
+        tracker.choose(&policy, detected("de", 10));
+        tracker.choose(&policy, None);
+        assert_eq!(tracker.pinned, None);
+        tracker.choose(&policy, detected("fr", 60));
+        assert_eq!(tracker.pinned, Some("fr".to_string()));
+    }
+
+    #[test]
+    fn test_user_languages() {
+        let tracker = Mutex::new(LanguageTracker::default());
+        let user_languages = Mutex::new(HashMap::from([
+            (1, "fr".to_string()),
+            (2, "ja".to_string()),
+        ]));
+        let choose = |config: &WhisperConfig, user_id| {
+            Whisper::choose_language(config, &tracker, &user_languages, user_id, || {
+                detected("de", 90)
+            })
+            .map(|chosen| chosen.language)
+        };
+
+        let config = WhisperConfig::default();
+        assert_eq!(choose(&config, 1), Some("fr".to_string()));
+        assert_eq!(choose(&config, 2), Some("ja".to_string()));
+        // everyone else is left to whisper's default
+        assert_eq!(choose(&config, 3), None);
+
+        let config = WhisperConfig {
+            language_detection: Some(LanguageDetectionPolicy::default()),
+            ..Default::default()
+        };
+        assert_eq!(choose(&config, 1), Some("fr".to_string()));
+        assert_eq!(choose(&config, 3), Some("de".to_string()));
+    }
+
+    #[test]
+    fn test_interim_requests_use_fast_model() {
+        assert_eq!(model_for("main", Some("fast"), true), "fast");
+        assert_eq!(model_for("main", Some("fast"), false), "main");
+        assert_eq!(model_for("main", None, true), "main");
+        assert_eq!(model_for("main", None, false), "main");
+    }
+
+    #[test]
+    fn test_fast_model_languages_must_match() {
+        assert!(Whisper::check_fast_model(true, None).is_ok());
+        assert!(Whisper::check_fast_model(false, None).is_ok());
+        assert!(Whisper::check_fast_model(true, Some(true)).is_ok());
+        assert!(Whisper::check_fast_model(false, Some(false)).is_ok());
+        assert!(matches!(
+            Whisper::check_fast_model(true, Some(false)),
+            Err(DiscrivenerError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Whisper::check_fast_model(false, Some(true)),
+            Err(DiscrivenerError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let missing = std::env::temp_dir().join("discrivener-no-such-model.bin");
+        assert!(matches!(
+            Whisper::load(missing.to_str().unwrap().to_string(), WhisperConfig::default()),
+            Err(DiscrivenerError::ModelNotFound(path)) if path == missing
+        ));
+
+        let directory = std::env::temp_dir();
+        assert!(matches!(
+            Whisper::load(
+                directory.to_str().unwrap().to_string(),
+                WhisperConfig::default()
+            ),
+            Err(DiscrivenerError::InvalidModel { .. })
+        ));
+
+        // a download which went wrong
+        let garbage = std::env::temp_dir().join(format!(
+            "discrivener-truncated-model-{}.bin",
+            std::process::id()
+        ));
+        std::fs::write(&garbage, b"lmgg\x05\x00").unwrap();
+        assert!(matches!(
+            Whisper::load(garbage.to_str().unwrap().to_string(), WhisperConfig::default()),
+            Err(DiscrivenerError::InvalidModel { reason, .. }) if reason.contains("truncated")
+        ));
+        std::fs::remove_file(garbage).ok();
+
+        let config = WhisperConfig {
+            sampling_strategy: SamplingStrategy::Greedy { best_of: 0 },
+            ..Default::default()
+        };
+        assert!(matches!(
+            Whisper::load(missing.to_str().unwrap().to_string(), config),
+            Err(DiscrivenerError::InvalidConfig(_))
+        ));
+
+        let config = WhisperConfig {
+            language_detection: Some(LanguageDetectionPolicy {
+                fallback_language: Some("klingon".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Whisper::load(missing.to_str().unwrap().to_string(), config),
+            Err(DiscrivenerError::InvalidConfig(_))
+        ));
+
+        let config = WhisperConfig {
+            audio_ctx: Some(FULL_AUDIO_CTX + 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Whisper::load(missing.to_str().unwrap().to_string(), config),
+            Err(DiscrivenerError::InvalidConfig(_))
+        ));
+    }
+}
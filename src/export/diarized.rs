@@ -0,0 +1,208 @@
+//! Builds a session's transcript as transcriptions come in, merged
+//! across everyone who spoke into a single timeline of who said what,
+//! and when.
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::types::{Transcription, UserId, VoiceChannelEvent};
+
+/// Consecutive segments from the same speaker are merged into the same
+/// entry if there's no more than this much time between them.
+const MERGE_GAP: Duration = Duration::from_secs(2);
+
+/// A stretch of speech from one speaker.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct TranscriptEntry {
+    pub user_id: UserId,
+    pub text: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+/// Everything said over a session, by everyone, in the order it was
+/// said.  Transcriptions can be added in any order.
+#[derive(Clone, Debug, Default)]
+pub struct DiarizedTranscript {
+    /// a single entry for each segment we've been given, by start time
+    segments: Vec<TranscriptEntry>,
+}
+
+impl DiarizedTranscript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the transcription's segments to the timeline.  Segments
+    /// with no text are left out.
+    pub fn add(&mut self, transcription: &Transcription) {
+        for segment in transcription.segments.iter() {
+            let text = segment.text();
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let start = transcription.start_timestamp
+                + Duration::from_millis(segment.start_offset_ms as u64);
+            let end =
+                transcription.start_timestamp + Duration::from_millis(segment.end_offset_ms as u64);
+            // after anything which started at the same time, so that
+            // those keep the order they arrived in
+            let index = self
+                .segments
+                .partition_point(|existing| existing.start <= start);
+            self.segments.insert(
+                index,
+                TranscriptEntry {
+                    user_id: transcription.user_id,
+                    text: text.to_string(),
+                    start,
+                    end,
+                },
+            );
+        }
+    }
+
+    /// Adds any finalized transcriptions in the event.
+    pub(crate) fn record(&mut self, event: &VoiceChannelEvent) {
+        match event {
+            VoiceChannelEvent::Transcription(transcription) => self.add(transcription),
+            VoiceChannelEvent::ChannelTranscription(transcriptions) => {
+                for transcription in transcriptions {
+                    self.add(transcription);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The timeline so far, with each speaker's consecutive segments
+    /// merged into a single entry.
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        let mut entries: Vec<TranscriptEntry> = Vec::new();
+        for segment in self.segments.iter() {
+            if let Some(entry) = entries.last_mut() {
+                let gap = segment
+                    .start
+                    .duration_since(entry.end)
+                    .unwrap_or(Duration::ZERO);
+                if entry.user_id == segment.user_id && gap <= MERGE_GAP {
+                    entry.end = entry.end.max(segment.end);
+                    entry.text.push(' ');
+                    entry.text.push_str(segment.text.as_str());
+                    continue;
+                }
+            }
+            entries.push(segment.clone());
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::types::{TextSegment, TokenWithProbability};
+
+    use super::*;
+
+    fn transcription(
+        user_id: UserId,
+        start_ms: u64,
+        segments: &[(u32, u32, &str)],
+    ) -> Transcription {
+        Transcription {
+            start_timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(start_ms),
+            user_id,
+            segments: segments
+                .iter()
+                .map(|(start_offset_ms, end_offset_ms, text)| TextSegment {
+                    start_offset_ms: *start_offset_ms,
+                    end_offset_ms: *end_offset_ms,
+                    start_sample: 0,
+                    end_sample: 0,
+                    low_confidence_p: 0,
+                    cleaned_text: None,
+                    phonemes: None,
+                    raw_token_ids: None,
+                    whisper_timing: None,
+                    tokens_with_probability: vec![TokenWithProbability {
+                        p: 100,
+                        token_id: 0,
+                        token_text: text.to_string(),
+                    }],
+                })
+                .collect(),
+            audio_duration: Duration::from_secs(30),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        }
+    }
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_merges_across_speakers() {
+        let mut transcript = DiarizedTranscript::new();
+        assert!(transcript.is_empty());
+        transcript.add(&transcription(
+            1,
+            0,
+            &[(0, 1000, " hello"), (1500, 2500, " there")],
+        ));
+        // Bob's transcription finished after Alice's next one started
+        transcript.record(&VoiceChannelEvent::Transcription(transcription(
+            1,
+            8000,
+            &[(0, 1000, " how are you")],
+        )));
+        transcript.record(&VoiceChannelEvent::ChannelTranscription(vec![
+            transcription(2, 3000, &[(0, 1000, " hi"), (2000, 3000, " ")]),
+        ]));
+        assert_eq!(
+            transcript.entries(),
+            vec![
+                TranscriptEntry {
+                    user_id: 1,
+                    text: "hello there".to_string(),
+                    start: at(0),
+                    end: at(2500),
+                },
+                TranscriptEntry {
+                    user_id: 2,
+                    text: "hi".to_string(),
+                    start: at(3000),
+                    end: at(4000),
+                },
+                TranscriptEntry {
+                    user_id: 1,
+                    text: "how are you".to_string(),
+                    start: at(8000),
+                    end: at(9000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gap_splits_entries() {
+        let mut transcript = DiarizedTranscript::new();
+        transcript.add(&transcription(1, 0, &[(0, 1000, " one")]));
+        transcript.add(&transcription(1, 3000, &[(0, 1000, " two")]));
+        transcript.add(&transcription(1, 10000, &[(0, 1000, " three")]));
+        let texts = transcript
+            .entries()
+            .into_iter()
+            .map(|entry| entry.text)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["one two", "three"]);
+    }
+}
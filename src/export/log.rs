@@ -0,0 +1,309 @@
+//! Appends finalized transcriptions to a log file, one line of JSON
+//! each, moving the log aside once it gets too big.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{
+    config::LogConfig,
+    types::{SessionEvent, Transcription, UserId, VoiceChannelEvent},
+};
+
+/// A single line of the log.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LogLine {
+    /// when the audio started, in milliseconds since the Unix epoch
+    pub ts: u64,
+    pub user_id: UserId,
+    pub text: String,
+    /// the average probability of the text's tokens, as a percentage
+    pub confidence: u32,
+    /// see `DiscrivenerConfig::session_tag`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_tag: Option<Arc<str>>,
+}
+
+impl LogLine {
+    /// The line for a transcription, or None if it has no text.
+    fn from_transcription(
+        transcription: &Transcription,
+        session_tag: Option<&Arc<str>>,
+    ) -> Option<Self> {
+        let text = transcription
+            .segments
+            .iter()
+            .map(|segment| segment.text().trim().to_string())
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if text.is_empty() {
+            return None;
+        }
+        let ts = transcription
+            .start_timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        Some(Self {
+            ts,
+            user_id: transcription.user_id,
+            text,
+            confidence: transcription.average_confidence().unwrap_or(0),
+            session_tag: session_tag.cloned(),
+        })
+    }
+}
+
+pub(crate) struct TranscriptLog {
+    config: LogConfig,
+    file: BufWriter<File>,
+    /// how big the file is, including anything not yet flushed
+    size: u64,
+}
+
+impl TranscriptLog {
+    /// Opens the log for appending, creating it if need be.
+    pub fn open(config: LogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    /// Logs any finalized transcriptions in the event.
+    pub fn record(&mut self, event: &SessionEvent) -> io::Result<()> {
+        let session_tag = event.session_tag.as_ref();
+        match &event.event {
+            VoiceChannelEvent::Transcription(transcription) => {
+                self.append(transcription, session_tag)
+            }
+            VoiceChannelEvent::ChannelTranscription(transcriptions) => {
+                for transcription in transcriptions {
+                    self.append(transcription, session_tag)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn append(
+        &mut self,
+        transcription: &Transcription,
+        session_tag: Option<&Arc<str>>,
+    ) -> io::Result<()> {
+        let Some(line) = LogLine::from_transcription(transcription, session_tag) else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(&line)?;
+        line.push(b'\n');
+        // a single line bigger than the limit still gets a file
+        // of its own, rather than being dropped
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Moves each of the logs along by one, dropping the oldest, and
+    /// starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let path = &self.config.path;
+        let max_rotated_files = self.config.max_rotated_files;
+        if max_rotated_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            let oldest = rotated_path(path, max_rotated_files);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for n in (1..max_rotated_files).rev() {
+                let from = rotated_path(path, n);
+                if from.exists() {
+                    fs::rename(from, rotated_path(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Where the `n`th most recent log which was moved aside goes.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::model::types::{TextSegment, TokenWithProbability};
+
+    use super::*;
+
+    fn transcription(text: &str) -> Transcription {
+        Transcription {
+            start_timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+            user_id: 7,
+            segments: vec![TextSegment {
+                start_offset_ms: 0,
+                end_offset_ms: 1000,
+                start_sample: 0,
+                end_sample: 0,
+                low_confidence_p: 0,
+                cleaned_text: None,
+                phonemes: None,
+                raw_token_ids: None,
+                whisper_timing: None,
+                tokens_with_probability: vec![
+                    TokenWithProbability {
+                        p: 80,
+                        token_id: 0,
+                        token_text: text.to_string(),
+                    },
+                    TokenWithProbability {
+                        p: 90,
+                        token_id: 1,
+                        token_text: ".".to_string(),
+                    },
+                ],
+            }],
+            audio_duration: Duration::from_secs(1),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        }
+    }
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "discrivener-log-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn untagged(event: VoiceChannelEvent) -> SessionEvent {
+        SessionEvent {
+            session_tag: None,
+            event,
+        }
+    }
+
+    fn read_lines(path: &Path) -> Vec<LogLine> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_log_lines() {
+        let dir = log_dir("lines");
+        let path = dir.join("transcripts.jsonl");
+        let mut log = TranscriptLog::open(LogConfig::new(&path)).unwrap();
+        log.record(&untagged(VoiceChannelEvent::Transcription(transcription(
+            " hello",
+        ))))
+        .unwrap();
+        // nothing to say, so nothing logged
+        log.record(&untagged(VoiceChannelEvent::Transcription(Transcription {
+            segments: Vec::new(),
+            ..transcription(" goodbye")
+        })))
+        .unwrap();
+        log.record(&untagged(VoiceChannelEvent::UserJoin(7)))
+            .unwrap();
+        log.record(&SessionEvent {
+            session_tag: Some(Arc::from("guild-1")),
+            event: VoiceChannelEvent::Transcription(transcription(" hi")),
+        })
+        .unwrap();
+        log.flush().unwrap();
+
+        assert_eq!(
+            read_lines(&path),
+            vec![
+                LogLine {
+                    ts: 2000,
+                    user_id: 7,
+                    text: "hello.".to_string(),
+                    confidence: 85,
+                    session_tag: None,
+                },
+                LogLine {
+                    ts: 2000,
+                    user_id: 7,
+                    text: "hi.".to_string(),
+                    confidence: 85,
+                    session_tag: Some(Arc::from("guild-1")),
+                }
+            ]
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let dir = log_dir("rotation");
+        let path = dir.join("transcripts.jsonl");
+        let config = LogConfig {
+            // room for two lines at a time
+            max_bytes: 140,
+            max_rotated_files: 2,
+            path: path.clone(),
+        };
+        let mut log = TranscriptLog::open(config).unwrap();
+        for i in 0..7 {
+            log.record(&untagged(VoiceChannelEvent::Transcription(transcription(
+                format!(" line {}", i).as_str(),
+            ))))
+            .unwrap();
+        }
+        log.flush().unwrap();
+
+        let texts = |path: &Path| {
+            read_lines(path)
+                .into_iter()
+                .map(|line| line.text)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts(&path), vec!["line 6."]);
+        assert_eq!(texts(&rotated_path(&path, 1)), vec!["line 4.", "line 5."]);
+        assert_eq!(texts(&rotated_path(&path, 2)), vec!["line 2.", "line 3."]);
+        // lines 0 and 1 were rotated out entirely
+        assert!(!rotated_path(&path, 3).exists());
+        fs::remove_dir_all(dir).ok();
+    }
+}
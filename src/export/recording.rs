@@ -0,0 +1,221 @@
+//! Writes everyone's audio to WAV files as it arrives, at the 48khz
+//! stereo Discord sends it, separately from the downmixed and
+//! resampled audio which is transcribed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::model::{
+    constants::{DISCORD_AUDIO_CHANNELS, DISCORD_SAMPLES_PER_SECOND},
+    types::{DiscordAudioSample, UserId},
+};
+
+/// The size of the header we write, which is everything before the
+/// samples themselves.
+const WAV_HEADER_BYTES: u32 = 44;
+
+/// The most sample data a WAV file can hold, as the length of the
+/// whole file, less the 8 bytes saying so, has to fit in 32 bits.
+const MAX_DATA_BYTES: u32 = u32::MAX - (WAV_HEADER_BYTES - 8);
+
+/// A packet of a user's audio, on its way to be recorded.
+pub(crate) struct RecordedPacket {
+    pub user_id: UserId,
+    pub discord_audio: Vec<DiscordAudioSample>,
+}
+
+/// A WAV file of 16-bit PCM being written as audio arrives.  The
+/// header claims the file is as long as it can be until it's
+/// finished, so that most players can still read it if we never get
+/// that far.
+struct WavWriter {
+    file: BufWriter<File>,
+    /// how many bytes of samples have been written
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&u32::MAX.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&MAX_DATA_BYTES.to_le_bytes())?;
+        Ok(Self {
+            file,
+            data_bytes: 0,
+        })
+    }
+
+    /// Appends the samples, returning false if they wouldn't fit, as
+    /// a WAV file can't be bigger than 4GB.
+    fn write(&mut self, samples: &[DiscordAudioSample]) -> io::Result<bool> {
+        let len = (samples.len() * 2) as u32;
+        if self.data_bytes.saturating_add(len) > MAX_DATA_BYTES {
+            return Ok(false);
+        }
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes += len;
+        Ok(true)
+    }
+
+    /// Fills in the real lengths in the header.
+    fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(self.data_bytes + WAV_HEADER_BYTES - 8).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Records each user's audio to a WAV file of their own in the
+/// recording directory, named after their user id.  Only the audio
+/// Discord sends is recorded, so the silences between packets are
+/// left out.
+pub(crate) struct Recorder {
+    directory: PathBuf,
+    /// users whose recording is full, and so can't take any more
+    full: HashSet<UserId>,
+    writers: HashMap<UserId, WavWriter>,
+}
+
+impl Recorder {
+    /// Records into the given directory, creating it if need be.
+    pub fn new(directory: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            full: HashSet::new(),
+            writers: HashMap::new(),
+        })
+    }
+
+    pub fn record(&mut self, packet: &RecordedPacket) -> io::Result<()> {
+        if self.full.contains(&packet.user_id) {
+            return Ok(());
+        }
+        let writer = match self.writers.get_mut(&packet.user_id) {
+            Some(writer) => writer,
+            None => {
+                let path = self.path_for(packet.user_id);
+                let writer = WavWriter::create(
+                    &path,
+                    DISCORD_AUDIO_CHANNELS as u16,
+                    DISCORD_SAMPLES_PER_SECOND as u32,
+                )?;
+                self.writers.entry(packet.user_id).or_insert(writer)
+            }
+        };
+        if !writer.write(&packet.discord_audio)? {
+            eprintln!(
+                "recording of {} is full, not recording any more of their audio",
+                packet.user_id
+            );
+            self.full.insert(packet.user_id);
+        }
+        Ok(())
+    }
+
+    /// Finishes off everyone's recording.
+    pub fn finish(self) -> io::Result<()> {
+        for writer in self.writers.into_values() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    fn path_for(&self, user_id: UserId) -> PathBuf {
+        self.directory.join(format!("{}.wav", user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{audio::wav::WavAudio, Discrivener};
+
+    use super::*;
+
+    fn recording_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "discrivener-recording-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn test_recording() {
+        let dir = recording_dir("stereo");
+        let mut recorder = Recorder::new(dir.clone()).unwrap();
+        for user_id in [1, 2, 1] {
+            recorder
+                .record(&RecordedPacket {
+                    user_id,
+                    discord_audio: vec![user_id as i16, -(user_id as i16)],
+                })
+                .unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let wav = WavAudio::parse(&fs::read(dir.join("1.wav")).unwrap()).unwrap();
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.sample_rate, 48000);
+        assert_eq!(wav.samples, vec![1, -1, 1, -1]);
+        let wav = WavAudio::parse(&fs::read(dir.join("2.wav")).unwrap()).unwrap();
+        assert_eq!(wav.samples, vec![2, -2]);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_unfinished_recording_is_readable() {
+        let dir = recording_dir("unfinished");
+        let mut recorder = Recorder::new(dir.clone()).unwrap();
+        recorder
+            .record(&RecordedPacket {
+                user_id: 1,
+                discord_audio: vec![5, 6, 7, 8],
+            })
+            .unwrap();
+        for writer in recorder.writers.values_mut() {
+            writer.file.flush().unwrap();
+        }
+
+        // the header still claims the file is as long as it can be
+        let wav = WavAudio::parse(&fs::read(dir.join("1.wav")).unwrap()).unwrap();
+        assert_eq!(wav.samples, vec![5, 6, 7, 8]);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_not_recording_costs_nothing() {
+        // no thread is started, and the packet handler is given
+        // nowhere to copy the audio to
+        let (tx_recording, recording_task) =
+            Discrivener::start_recording(None, CancellationToken::new());
+        assert!(tx_recording.is_none());
+        assert!(recording_task.is_none());
+    }
+}
@@ -0,0 +1,157 @@
+//! Renders a session's transcriptions as a plain-text transcript,
+//! one line per stretch of speech:
+//!
+//! ```text
+//! [00:01:23] Alice: hello there
+//! [00:01:26] Bob: hi
+//! ```
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::model::types::{Transcription, UserId};
+
+use super::diarized::DiarizedTranscript;
+
+/// Renders the transcriptions as text, with each line's time given
+/// relative to the earliest transcription.  Speakers are named using
+/// `user_names`; anyone not in it is shown by their user id.
+pub fn render_plain_text(
+    transcriptions: &[Transcription],
+    user_names: &HashMap<UserId, String>,
+) -> String {
+    let mut diarized = DiarizedTranscript::new();
+    for transcription in transcriptions {
+        diarized.add(transcription);
+    }
+    let lines = diarized.entries();
+
+    let Some(session_start) = lines.first().map(|line| line.start) else {
+        return String::new();
+    };
+    let mut transcript = String::new();
+    for line in lines {
+        let offset = line
+            .start
+            .duration_since(session_start)
+            .unwrap_or(Duration::ZERO);
+        transcript.push_str(
+            format!(
+                "[{}] {}: {}\n",
+                format_timestamp(offset),
+                speaker_name(line.user_id, user_names),
+                line.text
+            )
+            .as_str(),
+        );
+    }
+    transcript
+}
+
+/// Formats the offset as HH:MM:SS.  Hours aren't wrapped, so a very
+/// long session may show more than 2 digits of them.
+pub fn format_timestamp(offset: Duration) -> String {
+    let seconds = offset.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+fn speaker_name(user_id: UserId, user_names: &HashMap<UserId, String>) -> String {
+    match user_names.get(&user_id) {
+        Some(name) => name.clone(),
+        None => format!("User {}", user_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::model::types::{TextSegment, TokenWithProbability};
+
+    use super::*;
+
+    fn transcription(
+        user_id: UserId,
+        start_timestamp: SystemTime,
+        segments: &[(u32, u32, &str)],
+    ) -> Transcription {
+        Transcription {
+            start_timestamp,
+            user_id,
+            segments: segments
+                .iter()
+                .map(|(start_offset_ms, end_offset_ms, text)| TextSegment {
+                    start_offset_ms: *start_offset_ms,
+                    end_offset_ms: *end_offset_ms,
+                    start_sample: 0,
+                    end_sample: 0,
+                    low_confidence_p: 0,
+                    cleaned_text: None,
+                    phonemes: None,
+                    raw_token_ids: None,
+                    whisper_timing: None,
+                    tokens_with_probability: vec![TokenWithProbability {
+                        p: 100,
+                        token_id: 0,
+                        token_text: text.to_string(),
+                    }],
+                })
+                .collect(),
+            audio_duration: Duration::from_secs(30),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        }
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(Duration::ZERO), "00:00:00");
+        assert_eq!(format_timestamp(Duration::from_millis(83_999)), "00:01:23");
+        assert_eq!(
+            format_timestamp(Duration::from_secs(3600 * 101 + 61)),
+            "101:01:01"
+        );
+    }
+
+    #[test]
+    fn test_render_plain_text() {
+        let start = SystemTime::now();
+        let user_names = HashMap::from([(1, "Alice".to_string()), (2, "Bob".to_string())]);
+        let transcriptions = vec![
+            transcription(1, start, &[(0, 1000, " hello"), (1500, 2500, " there")]),
+            // close enough to Alice's last segment to be merged
+            transcription(1, start + Duration::from_secs(4), &[(0, 1000, " friend")]),
+            transcription(2, start + Duration::from_secs(83), &[(0, 1000, " hi")]),
+            // too long after Bob's last segment to be merged
+            transcription(2, start + Duration::from_secs(90), &[(0, 1000, " bye")]),
+        ];
+
+        assert_eq!(
+            render_plain_text(&transcriptions, &user_names),
+            "[00:00:00] Alice: hello there friend\n\
+             [00:01:23] Bob: hi\n\
+             [00:01:30] Bob: bye\n"
+        );
+    }
+
+    #[test]
+    fn test_render_unknown_speaker() {
+        let start = SystemTime::now();
+        let transcriptions = vec![
+            transcription(7, start, &[(0, 1000, " who said that")]),
+            transcription(8, start, &[]),
+        ];
+
+        assert_eq!(
+            render_plain_text(&transcriptions, &HashMap::new()),
+            "[00:00:00] User 7: who said that\n"
+        );
+        assert_eq!(render_plain_text(&[], &HashMap::new()), "");
+    }
+}
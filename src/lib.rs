@@ -1,32 +1,84 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use audio::events::{DiscordAudioData, UserAudioEvent};
+use audio::audio_buffer::{duration_to_rtc, AudioBuffer};
+use audio::backend::TranscriptionBackend;
+use audio::events::{UserAudioData, UserAudioEvent};
+use audio::recent::RecentAudio;
 use audio::speaker::Speaker;
+use audio::wav::WavAudio;
 use audio::whisper::Whisper;
-use model::constants::USER_SILENCE_TIMEOUT;
-use model::types::VoiceChannelEvent;
-use scrivening::manager::UserAudioManager;
+use export::diarized::{DiarizedTranscript, TranscriptEntry};
+use export::log::TranscriptLog;
+use export::recording::{RecordedPacket, Recorder};
+use model::clock::SystemClock;
+use model::config::{AudioPayloadFormat, DiscrivenerConfig, LogConfig};
+use model::constants::{
+    AUDIO_TO_RECORD, EVENT_BROADCAST_CAPACITY, NANOS_PER_WHISPER_SAMPLE, RECORDING_QUEUE_PACKETS,
+    RECORDING_SHUTDOWN_CHECK_INTERVAL, SESSION_FINALIZE_TIMEOUT, TASK_SHUTDOWN_TIMEOUT,
+    TOKENS_TO_KEEP, USER_SILENCE_TIMEOUT, WHISPER_AUDIO_BUFFER_SIZE,
+};
+use model::error::DiscrivenerError;
+use model::metrics::{Metrics, MetricsCounters};
+use model::types::{
+    ActiveSpeaker, ConnectData, ModelInfo, SessionEvent, SessionStats, ShutdownReport,
+    TaskShutdown, Transcription, VoiceChannelEvent, WhisperAudioSample,
+};
+use scrivening::manager::{gather_session_parts, UserAudioManager};
+use scrivening::reorder::ReorderBuffer;
+use scrivening::worker::SpeakingTime;
 use songbird::id::{ChannelId, GuildId, UserId};
 use songbird::ConnectionInfo;
-use songbird_client::packet_handler::PacketHandler;
+use songbird_client::packet_handler::{PacketHandler, TranscribedUsers};
 use songbird_client::voice_activity::VoiceActivity;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
-mod audio {
+pub mod audio {
     pub(crate) mod audio_buffer;
+    pub mod backend;
+    // only public for the benchmark
+    #[doc(hidden)]
+    pub mod downmix;
+    pub mod echo;
     pub(crate) mod espeakng;
-    pub(crate) mod events;
-    pub(crate) mod resample;
+    pub mod events;
+    pub(crate) mod model_file;
+    pub(crate) mod recent;
+    #[cfg(feature = "remote")]
+    pub mod remote;
+    pub mod resample;
+    #[cfg(test)]
+    pub(crate) mod scripted;
     pub(crate) mod speaker;
+    pub(crate) mod wav;
     pub(crate) mod whisper;
 }
+pub mod export {
+    pub mod diarized;
+    pub mod log;
+    pub(crate) mod recording;
+    pub mod text;
+}
 pub mod model {
+    pub(crate) mod clock;
+    pub mod config;
     pub(crate) mod constants;
+    pub mod error;
+    pub mod metrics;
     pub mod types;
 }
 mod scrivening {
     pub(crate) mod manager;
+    pub(crate) mod reorder;
+    pub(crate) mod text;
     pub(crate) mod worker;
 }
 mod songbird_client {
@@ -43,34 +95,135 @@ pub struct Discrivener {
     // task which will fire API change events
     api_task: Option<JoinHandle<()>>,
     audio_buffer_manager_task: Option<JoinHandle<()>>,
+    config: Arc<DiscrivenerConfig>,
     // use tokio mutex as it's held during an .await
     driver: Arc<tokio::sync::Mutex<songbird::Driver>>,
+    // events go out to subscribers on this, as well as to the callback
+    event_broadcast: broadcast::Sender<VoiceChannelEvent>,
+    heartbeat_task: Option<JoinHandle<()>>,
+    metrics: Arc<MetricsCounters>,
+    model_info: Option<ModelInfo>,
+    packet_handler: Arc<PacketHandler>,
+    recording_task: Option<JoinHandle<()>>,
     shutdown_token: CancellationToken,
     speaker: Option<JoinHandle<()>>,
+    // how long each user has spent talking, kept up to date by the
+    // audio buffer manager's workers
+    speaking_time: Arc<SpeakingTime>,
+    // where each user's audio given to `submit_whisper_audio` ended,
+    // in the host's timeline for them
+    submitted_audio_ends: Mutex<HashMap<u64, Duration>>,
+    // the same events, tagged with `DiscrivenerConfig::session_tag`,
+    // go out to tagged subscribers on this
+    tagged_broadcast: broadcast::Sender<SessionEvent>,
+    // everything transcribed so far, kept up to date by the api task
+    transcript: Arc<Mutex<DiarizedTranscript>>,
+    transcript_log_task: Option<JoinHandle<()>>,
+    // asks the audio buffer manager who it has workers for
+    tx_active_speakers: tokio::sync::mpsc::UnboundedSender<oneshot::Sender<Vec<ActiveSpeaker>>>,
+    // for events we send ourselves, rather than one of our tasks
+    tx_api_events: tokio::sync::mpsc::UnboundedSender<VoiceChannelEvent>,
+    // tells the audio buffer manager how often to ask for interim
+    // transcriptions
+    tx_auto_period: tokio::sync::mpsc::UnboundedSender<Duration>,
+    // asks the audio buffer manager to finalize everything, and where
+    // to send what it finalizes
+    tx_finalize_session:
+        tokio::sync::mpsc::UnboundedSender<tokio::sync::mpsc::UnboundedSender<Vec<Transcription>>>,
+    // tells the audio buffer manager when we're paused or resumed
+    tx_paused: tokio::sync::mpsc::UnboundedSender<bool>,
+    // asks the audio buffer manager to reset the pipeline
+    tx_reset: tokio::sync::mpsc::UnboundedSender<()>,
+    transcription_backend: Arc<dyn TranscriptionBackend>,
     tx_speaker: tokio::sync::mpsc::UnboundedSender<String>,
     voice_activity_task: Option<JoinHandle<()>>,
 }
 
 impl Discrivener {
+    /// Loads the whisper model at the given path.  Fails if the model
+    /// can't be read, or isn't a whisper model.
     pub async fn load(
         model_path: String,
         event_callback: std::sync::Arc<dyn Fn(VoiceChannelEvent) + Send + Sync>,
+    ) -> Result<Self, DiscrivenerError> {
+        Self::load_with_config(model_path, DiscrivenerConfig::default(), event_callback).await
+    }
+
+    pub async fn load_with_config(
+        model_path: String,
+        config: DiscrivenerConfig,
+        event_callback: std::sync::Arc<dyn Fn(VoiceChannelEvent) + Send + Sync>,
+    ) -> Result<Self, DiscrivenerError> {
+        let whisper = Whisper::load(model_path, config.whisper.clone())?;
+        Ok(Self::load_with_backend(Box::new(whisper), config, event_callback).await)
+    }
+
+    /// Like `load_with_config`, but transcribes audio with the given
+    /// backend instead of a local whisper model.  `config.whisper` is
+    /// ignored.
+    pub async fn load_with_backend(
+        backend: Box<dyn TranscriptionBackend>,
+        config: DiscrivenerConfig,
+        event_callback: std::sync::Arc<dyn Fn(VoiceChannelEvent) + Send + Sync>,
     ) -> Self {
-        let mut config = songbird::Config::default();
-        config.decode_mode = songbird::driver::DecodeMode::Decode; // convert incoming audio from Opus to PCM
+        let config = Arc::new(config);
+        let metrics = Arc::new(MetricsCounters::new());
+        let speaking_time = Arc::new(SpeakingTime::default());
+        let transcription_backend: Arc<dyn TranscriptionBackend> = Arc::from(backend);
+        let model_info = transcription_backend.model_info();
+        let opus_sink = config.decode_policy.opus_sink();
+        let recent_audio = config.recent_audio_retention.map(RecentAudio::new);
+        let reorder_window = config.transcription_reorder_window;
+        let transcribed_users =
+            TranscribedUsers::new(config.ignore_users.clone(), config.only_users.clone());
+        // Everything downstream of the packet handler, from the
+        // resampling to the timestamps, assumes that decoded audio is
+        // 48khz stereo i16, in 20ms packets, which is what songbird
+        // gives us with `DecodeMode::Decode` and its default channel
+        // and sample rate settings.  Don't change those without
+        // changing the constants in `model::constants` to match.  See
+        // `DiscrivenerConfig::check_audio_format`.
+        let mut songbird_config = songbird::Config::default();
+        songbird_config.decode_mode = if config.decode_policy.decodes() {
+            songbird::driver::DecodeMode::Decode // convert incoming audio from Opus to PCM
+        } else {
+            // we still need the packets decrypted to pass them on
+            songbird::driver::DecodeMode::Decrypt
+        };
 
         let shutdown_token = CancellationToken::new();
         let (tx_audio_data, rx_audio_data) =
-            tokio::sync::mpsc::unbounded_channel::<DiscordAudioData>();
+            tokio::sync::mpsc::unbounded_channel::<UserAudioData>();
         let (tx_api_events, rx_api_events) =
             tokio::sync::mpsc::unbounded_channel::<VoiceChannelEvent>();
         let (tx_silent_user_events, rx_silent_user_events) =
             tokio::sync::mpsc::unbounded_channel::<UserAudioEvent>();
+        let (tx_active_speakers, rx_active_speakers) =
+            tokio::sync::mpsc::unbounded_channel::<oneshot::Sender<Vec<ActiveSpeaker>>>();
+        let (tx_auto_period, rx_auto_period) = tokio::sync::mpsc::unbounded_channel::<Duration>();
+        let (tx_finalize_session, rx_finalize_session) = tokio::sync::mpsc::unbounded_channel::<
+            tokio::sync::mpsc::UnboundedSender<Vec<Transcription>>,
+        >();
+        let (tx_paused, rx_paused) = tokio::sync::mpsc::unbounded_channel::<bool>();
+        let (tx_reset, rx_reset) = tokio::sync::mpsc::unbounded_channel::<()>();
         let (tx_speaker, rx_speaker) = tokio::sync::mpsc::unbounded_channel::<String>();
         let (tx_voice_activity, rx_voice_activity) =
             tokio::sync::mpsc::unbounded_channel::<UserAudioEvent>();
 
+        let heartbeat_task = config.heartbeat_interval.map(|interval| {
+            tokio::spawn(Self::start_heartbeat_task(
+                interval,
+                metrics.clone(),
+                shutdown_token.clone(),
+                tx_api_events.clone(),
+            ))
+        });
+
+        let (tx_recording, recording_task) =
+            Self::start_recording(config.recording_directory.clone(), shutdown_token.clone());
+
         let voice_activity_task = Some(VoiceActivity::monitor(
+            config.finalization_mode,
             rx_voice_activity,
             shutdown_token.clone(),
             tx_api_events.clone(),
@@ -78,30 +231,58 @@ impl Discrivener {
             USER_SILENCE_TIMEOUT,
         ));
 
-        let whisper = Whisper::load(model_path);
-
         // the audio buffer manager gets the voice data
         let audio_buffer_manager_task = Some(UserAudioManager::monitor(
+            config.clone(),
+            metrics.clone(),
+            rx_active_speakers,
             rx_audio_data,
+            rx_auto_period,
+            rx_finalize_session,
+            rx_paused,
+            rx_reset,
             rx_silent_user_events,
             shutdown_token.clone(),
+            speaking_time.clone(),
+            transcription_backend.clone(),
             tx_api_events.clone(),
-            whisper,
         ));
 
-        let driver = Arc::new(tokio::sync::Mutex::new(songbird::Driver::new(config)));
-        PacketHandler::register(
+        let driver = Arc::new(tokio::sync::Mutex::new(songbird::Driver::new(
+            songbird_config,
+        )));
+        let packet_handler = PacketHandler::register(
+            config.check_audio_format,
             driver.clone(),
-            tx_api_events,
+            metrics.clone(),
+            config.noise_gate,
+            opus_sink,
+            recent_audio,
+            config.rms_smoothing,
+            transcribed_users,
+            tx_api_events.clone(),
             tx_audio_data,
+            tx_recording,
             tx_voice_activity,
         )
         .await;
 
+        let (event_broadcast, _) = broadcast::channel(EVENT_BROADCAST_CAPACITY);
+        let (tagged_broadcast, _) = broadcast::channel(EVENT_BROADCAST_CAPACITY);
+        let transcript = Arc::new(Mutex::new(DiarizedTranscript::new()));
+        // subscribed before the api task starts, so it doesn't miss anything
+        let transcript_log_task = config.transcript_log.clone().map(|log_config| {
+            Self::start_transcript_log_task(log_config, tagged_broadcast.subscribe())
+        });
         let api_task = Some(tokio::spawn(Self::start_api_task(
             rx_api_events,
             shutdown_token.clone(),
             event_callback,
+            event_broadcast.clone(),
+            tagged_broadcast.clone(),
+            reorder_window,
+            config.session_tag.clone(),
+            transcript.clone(),
         )));
 
         let speaker = Some(Speaker::monitor(
@@ -113,9 +294,28 @@ impl Discrivener {
         Self {
             api_task,
             audio_buffer_manager_task,
+            config,
             driver,
+            event_broadcast,
+            heartbeat_task,
+            metrics,
+            model_info,
+            packet_handler,
+            recording_task,
             shutdown_token,
             speaker,
+            speaking_time,
+            submitted_audio_ends: Mutex::new(HashMap::new()),
+            tagged_broadcast,
+            transcript,
+            transcript_log_task,
+            transcription_backend,
+            tx_active_speakers,
+            tx_api_events,
+            tx_auto_period,
+            tx_finalize_session,
+            tx_paused,
+            tx_reset,
             tx_speaker,
             voice_activity_task,
         }
@@ -141,41 +341,878 @@ impl Discrivener {
         self.driver.lock().await.connect(connection_info).await
     }
 
-    pub async fn disconnect(&mut self) {
-        self.driver.try_lock().unwrap().stop();
-        self.driver.try_lock().unwrap().leave();
+    /// The voice channel we're connected to, or None if we aren't,
+    /// e.g. before the first connect, or after being disconnected.
+    /// This follows the connection itself, so it changes if the
+    /// driver reconnects somewhere else, and can be checked against
+    /// where we ought to be.
+    pub fn current_channel(&self) -> Option<ConnectData> {
+        self.packet_handler.current_channel()
+    }
+
+    /// Leaves the channel and shuts down.  Each of our tasks is given
+    /// a few seconds to finish, after which it's aborted, so this
+    /// won't hang even if a transcription is stuck.  The report says
+    /// which tasks didn't finish cleanly.
+    pub async fn disconnect(&mut self) -> ShutdownReport {
+        {
+            let mut driver = self.driver.lock().await;
+            driver.stop();
+            driver.leave();
+        }
+        self.packet_handler.forget_channel();
         self.shutdown_token.cancel();
 
         // join all our tasks
-        self.api_task.take().unwrap().await.unwrap();
-        self.audio_buffer_manager_task
-            .take()
-            .unwrap()
-            .await
-            .unwrap();
-        self.speaker.take().unwrap().await.unwrap();
-        self.voice_activity_task.take().unwrap().await.unwrap();
+        ShutdownReport {
+            api: Self::join_task(self.api_task.take()).await,
+            audio_buffer_manager: Self::join_task(self.audio_buffer_manager_task.take()).await,
+            heartbeat: Self::join_task(self.heartbeat_task.take()).await,
+            recording: Self::join_task(self.recording_task.take()).await,
+            speaker: Self::join_task(self.speaker.take()).await,
+            transcript_log: Self::join_task(self.transcript_log_task.take()).await,
+            voice_activity: Self::join_task(self.voice_activity_task.take()).await,
+        }
+    }
+
+    async fn join_task(task: Option<JoinHandle<()>>) -> TaskShutdown {
+        let Some(mut task) = task else {
+            return TaskShutdown::NotRunning;
+        };
+        match tokio::time::timeout(TASK_SHUTDOWN_TIMEOUT, &mut task).await {
+            Ok(Ok(())) => TaskShutdown::Clean,
+            Ok(Err(err)) => {
+                eprintln!("task failed while shutting down: {}", err);
+                TaskShutdown::Failed
+            }
+            Err(_) => {
+                task.abort();
+                TaskShutdown::TimedOut
+            }
+        }
     }
 
     async fn start_api_task(
         mut rx_api_events: tokio::sync::mpsc::UnboundedReceiver<VoiceChannelEvent>,
         shutdown_token: CancellationToken,
         event_callback: std::sync::Arc<dyn Fn(VoiceChannelEvent) + Send + Sync>,
+        event_broadcast: broadcast::Sender<VoiceChannelEvent>,
+        tagged_broadcast: broadcast::Sender<SessionEvent>,
+        reorder_window: Option<Duration>,
+        session_tag: Option<Arc<str>>,
+        transcript: Arc<Mutex<DiarizedTranscript>>,
     ) {
+        // whether a subscriber of each was last seen a full buffer behind
+        let event_broadcast_full = AtomicBool::new(false);
+        let tagged_broadcast_full = AtomicBool::new(false);
+        let deliver = |event: VoiceChannelEvent| {
+            transcript.lock().unwrap().record(&event);
+            // these only fail if everyone unsubscribed in the meantime
+            if event_broadcast.receiver_count() > 0 {
+                event_broadcast.send(event.clone()).ok();
+                warn_if_subscriber_lagging(&event_broadcast, &event_broadcast_full);
+            }
+            if tagged_broadcast.receiver_count() > 0 {
+                tagged_broadcast
+                    .send(SessionEvent {
+                        session_tag: session_tag.clone(),
+                        event: event.clone(),
+                    })
+                    .ok();
+                warn_if_subscriber_lagging(&tagged_broadcast, &tagged_broadcast_full);
+            }
+            event_callback(event);
+        };
+        // finalized transcriptions wait here to be put in order
+        let mut reorder_buffer = reorder_window.map(ReorderBuffer::new);
+        let receive = |event: VoiceChannelEvent, reorder_buffer: &mut Option<ReorderBuffer>| {
+            // everything else is passed on straight away
+            match (reorder_buffer, event) {
+                (Some(reorder_buffer), VoiceChannelEvent::Transcription(transcription)) => {
+                    reorder_buffer.push(transcription, Instant::now());
+                }
+                (Some(reorder_buffer), VoiceChannelEvent::PipelineReset) => {
+                    // these were finished before the reset
+                    for transcription in reorder_buffer.take_all() {
+                        deliver(VoiceChannelEvent::Transcription(transcription));
+                    }
+                    deliver(VoiceChannelEvent::PipelineReset);
+                }
+                (_, event) => deliver(event),
+            }
+        };
+        let mut session_stats = SessionStats::default();
         // wait for either shutdown token or rx_api_events
         loop {
+            let next_deadline = reorder_buffer
+                .as_ref()
+                .and_then(ReorderBuffer::next_deadline);
             tokio::select! {
                 _ = shutdown_token.cancelled() => {
+                    // pass on anything already queued, so that the summary
+                    // is the last event and accounts for everything
+                    while let Ok(event) = rx_api_events.try_recv() {
+                        session_stats.record(&event);
+                        receive(event, &mut reorder_buffer);
+                    }
+                    if let Some(reorder_buffer) = reorder_buffer.as_mut() {
+                        for transcription in reorder_buffer.take_all() {
+                            deliver(VoiceChannelEvent::Transcription(transcription));
+                        }
+                    }
+                    deliver(session_stats.into_event());
                     return;
                 }
                 Some(event) = rx_api_events.recv() => {
-                    event_callback(event);
+                    session_stats.record(&event);
+                    receive(event, &mut reorder_buffer);
+                }
+                _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)),
+                    if next_deadline.is_some() =>
+                {
+                    if let Some(reorder_buffer) = reorder_buffer.as_mut() {
+                        for transcription in reorder_buffer.take_ready(Instant::now()) {
+                            deliver(VoiceChannelEvent::Transcription(transcription));
+                        }
+                    }
                 }
             }
         }
     }
 
+    /// Appends each finalized transcription to the log as it's
+    /// delivered, until the session ends.  Writing to the file blocks,
+    /// so this gets a thread of its own.
+    fn start_transcript_log_task(
+        config: LogConfig,
+        mut rx_events: broadcast::Receiver<SessionEvent>,
+    ) -> JoinHandle<()> {
+        tokio::task::spawn_blocking(move || {
+            let path = config.path.clone();
+            let mut log = match TranscriptLog::open(config) {
+                Ok(log) => log,
+                Err(err) => {
+                    eprintln!("failed to open transcript log {}: {}", path.display(), err);
+                    return;
+                }
+            };
+            loop {
+                match rx_events.blocking_recv() {
+                    // the last event of the session
+                    Ok(SessionEvent {
+                        event: VoiceChannelEvent::SessionEnded { .. },
+                        ..
+                    })
+                    | Err(RecvError::Closed) => break,
+                    Ok(event) => {
+                        if let Err(err) = log.record(&event) {
+                            eprintln!("failed to write to transcript log: {}", err);
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        eprintln!("transcript log fell behind, missed {} events", missed);
+                    }
+                }
+            }
+            if let Err(err) = log.flush() {
+                eprintln!("failed to flush transcript log: {}", err);
+            }
+        })
+    }
+
+    /// Starts recording to the directory, if there is one.  If not,
+    /// nothing is set up, so that the packet handler has nothing to
+    /// copy the audio for.
+    fn start_recording(
+        directory: Option<PathBuf>,
+        shutdown_token: CancellationToken,
+    ) -> (Option<SyncSender<RecordedPacket>>, Option<JoinHandle<()>>) {
+        let Some(directory) = directory else {
+            return (None, None);
+        };
+        let (tx_recording, rx_recording) =
+            std::sync::mpsc::sync_channel::<RecordedPacket>(RECORDING_QUEUE_PACKETS);
+        let recording_task = Self::start_recording_task(directory, rx_recording, shutdown_token);
+        (Some(tx_recording), Some(recording_task))
+    }
+
+    /// Writes everyone's audio to disk as it arrives, until we're
+    /// shut down.  Like the transcript log, writing to the files
+    /// blocks, so this gets a thread of its own.
+    fn start_recording_task(
+        directory: PathBuf,
+        rx_recording: std::sync::mpsc::Receiver<RecordedPacket>,
+        shutdown_token: CancellationToken,
+    ) -> JoinHandle<()> {
+        tokio::task::spawn_blocking(move || {
+            let mut recorder = match Recorder::new(directory.clone()) {
+                Ok(recorder) => recorder,
+                Err(err) => {
+                    eprintln!(
+                        "failed to start recording in {}: {}",
+                        directory.display(),
+                        err
+                    );
+                    return;
+                }
+            };
+            // the packet handler outlives us, so we can't wait for
+            // the channel to close, and need to check for shutdown
+            // every so often instead
+            while !shutdown_token.is_cancelled() {
+                match rx_recording.recv_timeout(RECORDING_SHUTDOWN_CHECK_INTERVAL) {
+                    Ok(packet) => {
+                        if let Err(err) = recorder.record(&packet) {
+                            eprintln!("failed to record audio: {}", err);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            // whatever arrived before we stopped
+            for packet in rx_recording.try_iter() {
+                if let Err(err) = recorder.record(&packet) {
+                    eprintln!("failed to record audio: {}", err);
+                }
+            }
+            if let Err(err) = recorder.finish() {
+                eprintln!("failed to finish recording: {}", err);
+            }
+        })
+    }
+
+    async fn start_heartbeat_task(
+        interval: Duration,
+        metrics: Arc<MetricsCounters>,
+        shutdown_token: CancellationToken,
+        tx_api_events: tokio::sync::mpsc::UnboundedSender<VoiceChannelEvent>,
+    ) {
+        let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+        // after a stall, carry on at the usual interval rather than
+        // sending a burst of heartbeats
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => {
+                    return;
+                }
+                _ = ticks.tick() => {
+                    let metrics = metrics.snapshot();
+                    let heartbeat = VoiceChannelEvent::Heartbeat {
+                        active_users: metrics.active_users,
+                        buffered_ms: metrics.buffered_audio.as_millis() as u64,
+                    };
+                    if tx_api_events.send(heartbeat).is_err() {
+                        // the API task has gone, so no one would hear us
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a new receiver for all the events sent to the callback
+    /// given when loading, from now on.  There can be any number of
+    /// subscribers, and each one sees every event.  The last event of a
+    /// session is always `SessionEnded`.
+    ///
+    /// Each subscriber has a buffer of 1024 events.  If one falls
+    /// further behind than that, the oldest events it hasn't read are
+    /// dropped, and its next `recv()` returns `RecvError::Lagged` with
+    /// the number it missed.  A warning is logged when any subscriber
+    /// falls that far behind.
+    pub fn subscribe(&self) -> broadcast::Receiver<VoiceChannelEvent> {
+        self.event_broadcast.subscribe()
+    }
+
+    /// Like `subscribe`, but each event comes tagged with the config's
+    /// `session_tag`, so that one handler can tell apart the events of
+    /// several sessions, and serializes with it.
+    pub fn subscribe_tagged(&self) -> broadcast::Receiver<SessionEvent> {
+        self.tagged_broadcast.subscribe()
+    }
+
+    /// Like `subscribe`, but as a stream, for callers who would rather
+    /// `.await` events than handle them in a callback.  The stream ends
+    /// after the `SessionEnded` event.  If the stream falls behind, a
+    /// warning is logged and the missed events are skipped.
+    pub fn event_stream(&self) -> impl futures::Stream<Item = VoiceChannelEvent> {
+        futures::stream::unfold(Some(self.subscribe()), |rx_events| async move {
+            let mut rx_events = rx_events?;
+            loop {
+                match rx_events.recv().await {
+                    Ok(event) => {
+                        // nothing follows the end of the session
+                        let is_last = matches!(event, VoiceChannelEvent::SessionEnded { .. });
+                        return Some((event, (!is_last).then_some(rx_events)));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        eprintln!("event stream fell behind, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Totals of what we've done since loading, for monitoring.  With
+    /// the `metrics` feature these are also reported through the
+    /// `metrics` crate.
+    pub fn metrics_snapshot(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+
+    /// Describes the whisper model that was loaded, e.g. to check that
+    /// it's the one the config was written for.  None if transcription
+    /// is done by a backend which doesn't know what model it's using.
+    pub fn model_info(&self) -> Option<ModelInfo> {
+        self.model_info
+    }
+
+    /// Swaps the whisper model for the one at the given path, e.g. to
+    /// move up to a bigger model partway through a session, without
+    /// disconnecting or losing any audio.  Anything already being
+    /// transcribed finishes with the old model, which is freed once
+    /// it's done.  The fast model, if there is one, is kept.
+    ///
+    /// Sends a `ModelReloaded` event once the new model is in use.
+    /// Fails, keeping the old model, if the new one can't be loaded,
+    /// if it only understands English but the config or
+    /// `set_user_language` needs other languages, if it doesn't
+    /// understand the same languages as the fast model, if loading it
+    /// panics or is cut short by shutdown, or if transcription is done
+    /// by a backend which doesn't load models itself.
+    pub async fn reload_model(&mut self, model_path: String) -> Result<(), DiscrivenerError> {
+        let backend = self.transcription_backend.clone();
+        let path = model_path.clone();
+        // loading the model takes a while, and blocks
+        tokio::task::spawn_blocking(move || backend.reload_model(&path))
+            .await
+            .map_err(|err| DiscrivenerError::ModelLoadFailed {
+                path: PathBuf::from(&model_path),
+                reason: err.to_string(),
+            })??;
+        self.model_info = self.transcription_backend.model_info();
+        self.tx_api_events
+            .send(VoiceChannelEvent::ModelReloaded { model_path })
+            .ok();
+        Ok(())
+    }
+
     pub fn speak(&mut self, message: String) {
         self.tx_speaker.send(message).unwrap();
     }
+
+    /// Starts or stops transcribing the given user, e.g. because they
+    /// haven't agreed to be transcribed.  Audio from a disabled user is
+    /// dropped as soon as it arrives, and anything already buffered for
+    /// them is thrown away without being transcribed.  Who starts out
+    /// enabled is set by `ignore_users` and `only_users` in the config,
+    /// and this takes precedence over both.
+    ///
+    /// Sends a `TranscriptionEnabledChanged` event once the change has
+    /// been made.
+    pub fn set_user_transcription_enabled(&self, user_id: u64, enabled: bool) {
+        self.packet_handler
+            .set_transcription_enabled(user_id, enabled);
+    }
+
+    /// Has the given user's audio transcribed in `language`, such as
+    /// "fr" or "ja", instead of whatever `language_detection` would
+    /// pick, e.g. because you know what they speak.  Passing None goes
+    /// back to the usual language.  This applies from their next
+    /// transcription, and survives `reset`.
+    ///
+    /// Fails if whisper doesn't know the language, or the model only
+    /// understands English.
+    pub fn set_user_language(
+        &self,
+        user_id: u64,
+        language: Option<String>,
+    ) -> Result<(), DiscrivenerError> {
+        self.transcription_backend
+            .set_user_language(user_id, language)
+    }
+
+    /// Stops transcribing anyone, e.g. while recording is paused,
+    /// without disconnecting or unloading the model.  Audio which
+    /// arrives while paused is dropped, and anything already buffered
+    /// is thrown away without being transcribed, so that `resume`
+    /// starts afresh.
+    ///
+    /// Sends a `TranscriptionPausedChanged` event once paused.
+    pub fn pause(&self) {
+        self.packet_handler.set_paused(true);
+        self.tx_paused.send(true).ok();
+    }
+
+    /// Starts transcribing again after `pause`.  Users who have been
+    /// disabled with `set_user_transcription_enabled` stay disabled.
+    ///
+    /// Sends a `TranscriptionPausedChanged` event once resumed.
+    pub fn resume(&self) {
+        self.packet_handler.set_paused(false);
+        self.tx_paused.send(false).ok();
+    }
+
+    /// Up to `duration` of the user's most recent audio, e.g. to play
+    /// back the clip behind a transcription.  This is 48khz stereo,
+    /// with the channels interleaved, and only covers audio that was
+    /// passed on to be transcribed, with the gaps between what they
+    /// said left out.  Empty unless `recent_audio_retention` is set in
+    /// the config, and at most that much is kept.
+    pub fn get_recent_audio(&self, user_id: u64, duration: Duration) -> Vec<i16> {
+        self.packet_handler.recent_audio(user_id, duration)
+    }
+
+    /// Transcribes a WAV file with the same resampling and backend
+    /// used for audio from Discord, without needing a connection.
+    /// This is handy for trying out models and settings on a known
+    /// recording.  The file can have any sample rate and number of
+    /// channels, but must be 16-bit PCM.
+    ///
+    /// The file is transcribed 30 seconds at a time, each piece given
+    /// the text of the one before as context.  Timestamps count from
+    /// the Unix epoch as the start of the file, and the user id is 0.
+    /// No events are sent.  Fails if the backend can't transcribe any
+    /// piece of it, rather than leaving that piece out.
+    pub async fn transcribe_wav(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<Transcription>, DiscrivenerError> {
+        let path = path.as_ref();
+        let wav_data =
+            tokio::fs::read(path)
+                .await
+                .map_err(|source| DiscrivenerError::AudioUnreadable {
+                    path: path.to_path_buf(),
+                    source,
+                })?;
+        let wav = WavAudio::parse(&wav_data).map_err(|reason| DiscrivenerError::InvalidAudio {
+            path: path.to_path_buf(),
+            reason,
+        })?;
+        transcribe_audio(
+            self.transcription_backend.as_ref(),
+            self.config.audio_payload_format,
+            &wav.to_whisper(),
+        )
+        .await
+        .map_err(|reason| {
+            self.metrics.record_error();
+            DiscrivenerError::TranscriptionFailed {
+                path: path.to_path_buf(),
+                reason,
+            }
+        })
+    }
+
+    /// Lists everyone whose audio is in the transcription pipeline:
+    /// who has audio buffered, or has spoken recently enough that
+    /// they're still being kept track of.  This is answered from what
+    /// each user's worker last reported, without waiting on any of
+    /// them, so it's cheap enough to poll.  Empty once we've been
+    /// disconnected.
+    pub async fn active_speakers(&self) -> Vec<ActiveSpeaker> {
+        let (tx_reply, rx_reply) = oneshot::channel();
+        if self.tx_active_speakers.send(tx_reply).is_err() {
+            return Vec::new();
+        }
+        rx_reply.await.unwrap_or_default()
+    }
+
+    /// How long the user has spent talking this session, going by the
+    /// audio behind their finalized transcriptions, less any silence
+    /// before and after what they said.  Audio which didn't turn out
+    /// to have anything said in it isn't counted.  In
+    /// `ChannelMode::Split`, this is the time from whichever of the
+    /// user's channels heard the most, so speech both channels heard
+    /// is only counted once.  Kept after a `reset`.
+    pub fn speaking_time(&self, user_id: u64) -> Duration {
+        self.speaking_time.get(user_id)
+    }
+
+    /// Everyone's `speaking_time`, for everyone who has said anything
+    /// this session.
+    pub fn speaking_times(&self) -> BTreeMap<u64, Duration> {
+        self.speaking_time.snapshot()
+    }
+
+    /// Everything transcribed so far this session, from everyone, as
+    /// a single timeline of who said what and when.  Each speaker's
+    /// consecutive segments are merged into one entry.  Transcriptions
+    /// are added as they're delivered to the event callback, and stay
+    /// after a `reset`.
+    pub fn current_transcript(&self) -> Vec<TranscriptEntry> {
+        self.transcript.lock().unwrap().entries()
+    }
+
+    /// Throws away all the audio buffered for every user, the context
+    /// whisper has been given from what they said before, and any
+    /// tentative transcriptions, then carries on transcribing from
+    /// scratch.  The connection stays up and the model stays loaded,
+    /// so this is much cheaper than reconnecting if transcriptions
+    /// have gone off the rails.
+    ///
+    /// Sends a `PipelineReset` event once everything has been reset.
+    pub fn reset(&self) {
+        self.tx_reset.send(()).ok();
+    }
+
+    /// Changes how often we ask for interim transcriptions while
+    /// people are talking, once their first has been asked for after
+    /// `first_transcription_delay`.  A longer period takes some of the
+    /// load off the backend, at the cost of interim transcriptions
+    /// being further behind.  This applies to everyone, including
+    /// anyone who starts talking later, from their next request.
+    /// Defaults to 1 second.
+    ///
+    /// Fails if the period is less than a millisecond.
+    pub fn set_auto_period(&self, period: Duration) -> Result<(), DiscrivenerError> {
+        if period.as_millis() == 0 {
+            return Err(DiscrivenerError::InvalidConfig(
+                "the auto-transcription period must be at least 1ms".to_string(),
+            ));
+        }
+        self.tx_auto_period.send(period).ok();
+        Ok(())
+    }
+
+    /// Tells us that the user has just finished saying something, e.g.
+    /// because they let go of their push-to-talk key.  Their audio up
+    /// to now is transcribed and published straight away, rather than
+    /// waiting for them to go quiet, and anything they say after this
+    /// is transcribed separately.
+    pub fn mark_utterance_boundary(&self, user_id: u64) {
+        self.packet_handler.mark_utterance_boundary(user_id);
+    }
+
+    /// Finalizes everything everyone has said so far, as though the
+    /// whole channel had gone quiet, and returns it ordered by when it
+    /// was said, e.g. to have the whole of a meeting in hand before
+    /// `disconnect`.  The transcriptions are also sent as events, the
+    /// same as any others.
+    ///
+    /// Waits up to 30 seconds for the backend, after which whatever was
+    /// finalized in time is returned, and the rest is only sent as
+    /// events once it's done.  Empty once we've been disconnected.
+    pub async fn finalize_session(&self) -> Vec<Transcription> {
+        let (tx_parts, rx_parts) = tokio::sync::mpsc::unbounded_channel();
+        if self.tx_finalize_session.send(tx_parts).is_err() {
+            return Vec::new();
+        }
+        gather_session_parts(rx_parts, SESSION_FINALIZE_TIMEOUT).await
+    }
+
+    /// Transcribes audio for the user which the host has decoded
+    /// itself, rather than audio which came from Discord.  It goes
+    /// straight into the user's buffer, without any resampling, so it
+    /// must already be in whisper's format: 16khz mono f32, between
+    /// -1.0 and 1.0.  Audio at any other sample rate can't be told
+    /// apart from it, and would be transcribed as garbage, so use
+    /// `audio::resample` first if need be.
+    ///
+    /// `start_time` is when the first sample was, measured from any
+    /// point the host likes, so long as it's the same point for all of
+    /// the user's audio.  Gaps between calls are filled with silence,
+    /// much like gaps between Discord's packets.  Discord isn't there to
+    /// say when the user stops talking, so unless the host submits the
+    /// silence too, it should call `mark_utterance_boundary` instead.
+    /// This audio isn't recorded, and in `ChannelMode::Split` it's
+    /// transcribed as a single channel.  It shouldn't be mixed with
+    /// audio for the same user from Discord.
+    ///
+    /// Fails if any of the samples are out of range, or if the audio
+    /// starts before the end of the audio last submitted for the user,
+    /// as it would if the host's audio was at a higher sample rate.
+    pub fn submit_whisper_audio(
+        &self,
+        user_id: u64,
+        start_time: Duration,
+        samples: &[f32],
+    ) -> Result<(), DiscrivenerError> {
+        if let Some(sample) = samples
+            .iter()
+            .find(|sample| !sample.is_finite() || sample.abs() > 1.0)
+        {
+            return Err(DiscrivenerError::InvalidAudioSamples(format!(
+                "sample {} is out of range, whisper's samples are between -1.0 and 1.0",
+                sample
+            )));
+        }
+        let end_time =
+            start_time + Duration::from_nanos(samples.len() as u64 * NANOS_PER_WHISPER_SAMPLE);
+        {
+            let mut submitted_audio_ends = self.submitted_audio_ends.lock().unwrap();
+            let previous_end = submitted_audio_ends.entry(user_id).or_default();
+            // allow for the host rounding its times to the nearest sample
+            let tolerance = Duration::from_nanos(NANOS_PER_WHISPER_SAMPLE);
+            if start_time + tolerance < *previous_end {
+                return Err(DiscrivenerError::InvalidAudioSamples(format!(
+                    "audio starting at {:?} overlaps the audio up to {:?} already submitted \
+                     for user {}, is it really at 16khz?",
+                    start_time, previous_end, user_id
+                )));
+            }
+            *previous_end = end_time;
+        }
+        if !samples.is_empty() {
+            self.packet_handler
+                .on_whisper_audio(user_id, samples, duration_to_rtc(&start_time));
+        }
+        Ok(())
+    }
+
+    /// TESTING ONLY: behaves as if Discord told us that the user with
+    /// `user_id` is now sending audio as `ssrc`.  Audio for an ssrc is
+    /// ignored until this is called for it.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inject_user_join(&self, ssrc: u32, user_id: u64) {
+        self.packet_handler.on_user_join(ssrc, user_id);
+    }
+
+    /// TESTING ONLY: behaves as if Discord told us that the user sending
+    /// as `ssrc` started or stopped speaking.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inject_speaking(&self, ssrc: u32, speaking: bool) {
+        if speaking {
+            self.packet_handler.on_start_talking(ssrc);
+        } else {
+            self.packet_handler.on_stop_talking(ssrc);
+        }
+    }
+
+    /// TESTING ONLY: feeds decoded audio into the pipeline as if it had
+    /// arrived from Discord in a voice packet.  The audio should be
+    /// 48khz interleaved stereo, normally 20ms of it per call.
+    ///
+    /// Together with a backend like `audio::echo::EchoBackend`, this
+    /// allows the full flow of events to be tested without a Discord
+    /// connection or a model file.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inject_audio(&self, ssrc: u32, rtc_timestamp: u32, discord_audio: &[i16]) {
+        self.packet_handler
+            .on_audio(discord_audio, std::num::Wrapping(rtc_timestamp), ssrc);
+    }
+}
+
+/// Warns when the slowest subscriber of a broadcast has fallen a whole
+/// buffer behind, so that each further event drops one it hasn't read.
+/// Warns once each time it gets there, rather than for every event.
+fn warn_if_subscriber_lagging<T>(broadcast: &broadcast::Sender<T>, was_full: &AtomicBool) {
+    let full = broadcast.len() >= EVENT_BROADCAST_CAPACITY;
+    if full && !was_full.load(Ordering::Relaxed) {
+        eprintln!(
+            "an event subscriber is {} events behind, and is missing the oldest",
+            EVENT_BROADCAST_CAPACITY
+        );
+    }
+    was_full.store(full, Ordering::Relaxed);
+}
+
+/// Transcribes the audio 30 seconds at a time, each piece given the
+/// text of the one before as context, as `Discrivener::transcribe_wav`
+/// does.
+async fn transcribe_audio(
+    backend: &dyn TranscriptionBackend,
+    audio_format: AudioPayloadFormat,
+    audio: &[WhisperAudioSample],
+) -> Result<Vec<Transcription>, String> {
+    let mut transcriptions = Vec::new();
+    let mut previous_tokens = Vec::new();
+    for (index, piece) in audio.chunks(WHISPER_AUDIO_BUFFER_SIZE).enumerate() {
+        let buffer = AudioBuffer::with_audio(
+            0,
+            SystemClock,
+            piece.to_vec(),
+            SystemTime::UNIX_EPOCH + AUDIO_TO_RECORD * index as u32,
+        );
+        let request = buffer
+            .make_transcription_request(audio_format, &Duration::ZERO, previous_tokens)
+            .ok_or_else(|| format!("piece {} has no audio", index))?;
+        let response = backend
+            .process_transcription_request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+        if let Some(error) = response.error {
+            return Err(error);
+        }
+        let mut transcript = response.transcript;
+        // each piece picks up where the last one left off
+        let stream_position = (index * WHISPER_AUDIO_BUFFER_SIZE) as u64;
+        for segment in transcript.segments.iter_mut() {
+            segment.place_in_stream(stream_position);
+        }
+        let mut tokens = transcript.token_ids();
+        previous_tokens = tokens.split_off(tokens.len().saturating_sub(TOKENS_TO_KEEP));
+        transcriptions.push(transcript);
+    }
+    Ok(transcriptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::{
+        echo::EchoBackend,
+        scripted::{Answer, ScriptedBackend},
+        wav::make_wav,
+    };
+    use crate::model::constants::WHISPER_SAMPLES_PER_SECOND;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transcribe_wav() {
+        let discrivener = Discrivener::load_with_backend(
+            Box::new(EchoBackend::new(" hello".to_string())),
+            DiscrivenerConfig::default(),
+            Arc::new(|_| {}),
+        )
+        .await;
+        let path = std::env::temp_dir().join(format!(
+            "discrivener-transcribe-wav-{}.wav",
+            std::process::id()
+        ));
+        // 40 seconds, so in two pieces
+        let samples = vec![1000; 40 * WHISPER_SAMPLES_PER_SECOND];
+        std::fs::write(
+            &path,
+            make_wav(1, WHISPER_SAMPLES_PER_SECOND as u32, &samples),
+        )
+        .unwrap();
+
+        let transcriptions = discrivener.transcribe_wav(&path).await.unwrap();
+        std::fs::remove_file(&path).ok();
+        let pieces = transcriptions
+            .iter()
+            .map(|transcription| {
+                (
+                    transcription.start_timestamp,
+                    transcription.audio_duration,
+                    transcription.segments[0].text(),
+                    transcription.segments[0].start_sample,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pieces,
+            vec![
+                (
+                    SystemTime::UNIX_EPOCH,
+                    Duration::from_secs(30),
+                    " hello".to_string(),
+                    0
+                ),
+                (
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(30),
+                    Duration::from_secs(10),
+                    " hello".to_string(),
+                    WHISPER_AUDIO_BUFFER_SIZE as u64
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_audio_errors() {
+        let audio = vec![0.1; 40 * WHISPER_SAMPLES_PER_SECOND];
+        // only the second piece fails, which fails the whole lot,
+        // rather than leaving it out
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[Answer::Echo(100)], Answer::Error);
+        let result = transcribe_audio(backend.as_ref(), AudioPayloadFormat::F32, &audio).await;
+        assert_eq!(result, Err("the backend fell over".to_string()));
+        assert_eq!(
+            rx_requests.recv().await.unwrap().buffer_offset,
+            Duration::ZERO
+        );
+        assert!(rx_requests.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_tagged() {
+        let config = DiscrivenerConfig {
+            session_tag: Some(Arc::from("guild 1")),
+            ..Default::default()
+        };
+        let discrivener = Discrivener::load_with_backend(
+            Box::new(EchoBackend::new(" hello".to_string())),
+            config,
+            Arc::new(|_| {}),
+        )
+        .await;
+        let mut rx_events = discrivener.subscribe();
+        let mut rx_tagged = discrivener.subscribe_tagged();
+        discrivener.pause();
+
+        // the same events go to both, e.g. heartbeats, tagged or not
+        let paused = VoiceChannelEvent::TranscriptionPausedChanged { paused: true };
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx_events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let tagged = rx_tagged.recv().await.unwrap();
+            assert_eq!(tagged.session_tag.as_deref(), Some("guild 1"));
+            assert_eq!(tagged.event, event);
+            if event == paused {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_whisper_audio() {
+        let discrivener = Discrivener::load_with_backend(
+            Box::new(EchoBackend::new(" hello".to_string())),
+            DiscrivenerConfig::default(),
+            Arc::new(|_| {}),
+        )
+        .await;
+        let second = vec![0.1; WHISPER_SAMPLES_PER_SECOND];
+        let sample = Duration::from_nanos(NANOS_PER_WHISPER_SAMPLE);
+        let is_invalid = |result: Result<(), DiscrivenerError>| {
+            matches!(result, Err(DiscrivenerError::InvalidAudioSamples(_)))
+        };
+
+        // samples outside of whisper's range
+        for bad in [1.5, -1.5, f32::NAN, f32::INFINITY] {
+            let mut samples = second.clone();
+            samples[100] = bad;
+            assert!(is_invalid(discrivener.submit_whisper_audio(
+                1,
+                Duration::ZERO,
+                &samples
+            )));
+        }
+        // which didn't count as submitted
+        discrivener
+            .submit_whisper_audio(1, Duration::ZERO, &second)
+            .unwrap();
+
+        // audio overlapping what's already been submitted
+        assert!(is_invalid(discrivener.submit_whisper_audio(
+            1,
+            Duration::from_secs(1) - 2 * sample,
+            &second
+        )));
+        // but a sample's worth is allowed for rounding
+        discrivener
+            .submit_whisper_audio(1, Duration::from_secs(1) - sample, &second)
+            .unwrap();
+        // and gaps are fine
+        discrivener
+            .submit_whisper_audio(1, Duration::from_secs(5), &second)
+            .unwrap();
+        assert!(is_invalid(discrivener.submit_whisper_audio(
+            1,
+            Duration::from_secs(3),
+            &second
+        )));
+
+        // each user has a timeline of their own
+        discrivener
+            .submit_whisper_audio(2, Duration::ZERO, &second)
+            .unwrap();
+    }
 }
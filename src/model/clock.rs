@@ -0,0 +1,104 @@
+use std::time::SystemTime;
+
+use tokio::time::Instant;
+
+#[cfg(test)]
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Source of the current time.  Anything which timestamps audio or
+/// decides when a user has timed out should ask a Clock, rather than
+/// calling `now()` itself, so that tests can control time without
+/// needing to sleep.
+pub(crate) trait Clock {
+    /// Wall-clock time, used to timestamp audio.
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, used to schedule timeouts.
+    fn instant(&self) -> Instant;
+}
+
+/// The real clock.  It's zero-sized and its methods are inlined,
+/// so using it costs the same as calling `now()` directly.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    #[inline]
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when it's told to.  Clones share the same
+/// time, so a test can keep one and advance it while the code under
+/// test holds another.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub(crate) struct MockClock {
+    elapsed_nanos: Arc<AtomicU64>,
+    start_instant: Instant,
+    start_system: SystemTime,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+            start_instant: Instant::now(),
+            start_system: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    pub(crate) fn advance(&self, duration: Duration) {
+        self.elapsed_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.start_system + self.elapsed()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let other = clock.clone();
+        let start_system = clock.now();
+        let start_instant = clock.instant();
+
+        other.advance(Duration::from_millis(1500));
+
+        assert_eq!(
+            clock.now().duration_since(start_system).unwrap(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(clock.instant() - start_instant, Duration::from_millis(1500));
+    }
+}
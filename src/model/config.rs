@@ -0,0 +1,955 @@
+use std::{collections::HashSet, fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use super::{
+    constants::{
+        DONT_EVEN_BOTHER_RMS_THRESHOLD, EXPECTED_AUDIO_PARTICIPANTS, FIRST_TRANSCRIPT_PERIOD,
+        HEARTBEAT_INTERVAL, RETRY_BACKOFF,
+    },
+    types::OpusPacket,
+};
+
+/// Settings which control how Discrivener processes audio.
+///
+/// Every field has a default which matches Discrivener's behavior
+/// from before the setting existed, so callers only need to override
+/// the values they care about, e.g.
+/// `DiscrivenerConfig { speaker_split_silence: Some(..), ..Default::default() }`
+#[derive(Clone, Debug)]
+pub struct DiscrivenerConfig {
+    /// How many users' worth of audio buffers to keep around for reuse
+    /// after those users stop talking, so that people joining and
+    /// leaving don't each cost a fresh 30-second allocation.
+    ///
+    /// Defaults to 12.
+    pub audio_buffer_pool_size: usize,
+
+    /// The sample format of the audio handed to the transcription
+    /// backend.
+    ///
+    /// Defaults to `AudioPayloadFormat::F32`, which is what whisper uses.
+    pub audio_payload_format: AudioPayloadFormat,
+
+    /// Whether each user's stereo audio is mixed down to mono, or each
+    /// of its channels is transcribed separately.
+    ///
+    /// Defaults to `ChannelMode::Downmix`.
+    pub channel_mode: ChannelMode,
+
+    /// Checks the first decoded packet against the audio format
+    /// everything else assumes songbird delivers: 20ms of 48khz stereo.
+    /// If it doesn't match, a `VoiceChannelEvent::UnexpectedAudioFormat`
+    /// is sent and no audio is transcribed, rather than transcribing
+    /// audio we've misread.
+    ///
+    /// Defaults to true.
+    pub check_audio_format: bool,
+
+    /// When set, a `VoiceChannelEvent::InputClipping` is sent when more
+    /// than this percentage of a user's buffered audio is at or near
+    /// full scale, which usually means their mic gain is turned up too
+    /// far.  Whisper does poorly on clipped audio.  Each user is warned
+    /// at most once a minute.
+    ///
+    /// Defaults to None, which never warns.
+    pub clipping_threshold: Option<u32>,
+
+    /// How long to wait after a user goes silent before asking for
+    /// the final transcription of what they said.  If they start
+    /// talking again in the meantime, it carries on as one utterance.
+    /// A short delay gives the last interim transcription more of a
+    /// chance to be confirmed as-is, so live captions change less when
+    /// they're finalized, at the cost of finalizing that much later.
+    ///
+    /// Defaults to zero, which asks straight away.
+    pub commit_delay: Duration,
+
+    /// Whether incoming audio is decoded for transcription, passed
+    /// through as Opus, or both.
+    ///
+    /// Defaults to `DecodePolicy::DecodeForTranscription`.
+    pub decode_policy: DecodePolicy,
+
+    /// What to do when whisper finds nothing at all in audio which
+    /// had sound in it.  This happens now and then with real speech,
+    /// which would otherwise be thrown away without ever being
+    /// published.
+    ///
+    /// Defaults to `EmptyResponsePolicy::Discard`.
+    pub empty_response_policy: EmptyResponsePolicy,
+
+    /// How much audio to keep from the end of each finalized
+    /// transcription, so that whisper hears it again as the lead-in to
+    /// the next one.  Without this, words which start right at the
+    /// boundary are sometimes clipped.  Any text in the kept audio was
+    /// already published, so it is left out of the next transcription.
+    /// The tail is dropped if the user pauses for longer than the user
+    /// silence timeout after it.
+    ///
+    /// Defaults to zero, which keeps nothing.
+    pub finalize_context_tail: Duration,
+
+    /// Whether users' audio is finalized as each of them stops
+    /// talking, or all together once the whole channel goes quiet.
+    ///
+    /// Defaults to `FinalizationMode::PerUser`.
+    pub finalization_mode: FinalizationMode,
+
+    /// How much audio a user's buffer needs before we ask for an
+    /// interim transcription of it.  Transcriptions of very short
+    /// stretches of audio tend to change a lot as more comes in, which
+    /// makes captions jumpy when someone starts talking.  After the
+    /// first, interim transcriptions are requested every second as
+    /// usual.  This doesn't hold back the final transcription when the
+    /// user stops talking, however short it is.
+    ///
+    /// Defaults to 5 seconds.
+    pub first_transcription_delay: Duration,
+
+    /// How often to send a `Heartbeat` event.  Heartbeats are sent
+    /// whether or not anyone is talking, so if they stop arriving,
+    /// something has gone wrong.
+    ///
+    /// Defaults to every 10 seconds.  None sends no heartbeats.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// Users whose audio is never transcribed, e.g. other bots or music
+    /// players.  `Discrivener::set_user_transcription_enabled` overrides
+    /// this for any user it's called for.
+    ///
+    /// Defaults to empty.
+    pub ignore_users: HashSet<u64>,
+
+    /// When set, and at least this many transcription requests are
+    /// already waiting on the backend across all users, we stop asking
+    /// for transcriptions of what people are still saying.  Requests
+    /// for audio which is about to be finalized are still made, so the
+    /// backend spends its time on the transcriptions which get
+    /// published.  The number waiting is in
+    /// `Metrics::pending_transcription_requests`.
+    ///
+    /// Defaults to None, which never skips requests.
+    pub interim_request_high_water_mark: Option<usize>,
+
+    /// When set, segments whose `low_confidence_p` is above this
+    /// percentage are dropped before their transcription is published.
+    /// Whisper tends to hallucinate text like "Thank you." when fed
+    /// silence or noise, and those segments are the ones it is least
+    /// sure of.  Unlike `min_finalized_confidence`, this judges each
+    /// segment on its own, rather than the transcription as a whole.
+    ///
+    /// Defaults to None, which keeps every segment.
+    pub low_confidence_threshold: Option<u32>,
+
+    /// When set, the longest gap between a user's packets which is
+    /// filled in with silence to keep their audio in time.  After a
+    /// longer gap, e.g. from a network stall, what they said before it
+    /// is finalized, and what comes after starts a new utterance,
+    /// rather than having whisper transcribe seconds of nothing.
+    ///
+    /// Defaults to None, which fills in any gap that fits in the buffer.
+    pub max_inserted_silence: Option<Duration>,
+
+    /// When set, the most memory every user's buffered audio can take
+    /// up between them, however many people are talking.  Once it's
+    /// exceeded, the oldest audio is thrown away from whoever has the
+    /// most buffered, until everything fits.  Each second of audio
+    /// takes up 64KB.  A `VoiceChannelEvent::AudioEvicted` is sent for
+    /// each user who loses audio.
+    ///
+    /// Defaults to None, which leaves it to each buffer's own limit.
+    pub max_total_audio_bytes: Option<usize>,
+
+    /// When set, finalized transcriptions whose tokens' average
+    /// probability is below this percentage are dropped rather than
+    /// published.  Their audio is still discarded, so nothing after
+    /// them is held up.
+    ///
+    /// Defaults to None, which publishes transcriptions however unsure
+    /// whisper was of them.
+    pub min_finalized_confidence: Option<u32>,
+
+    /// When set, a user whose audio stays below the gate is treated as
+    /// silent, even while Discord says they're speaking, as it does for
+    /// someone with an open mic who's only sending noise.  Their audio
+    /// is still passed on to be transcribed.
+    ///
+    /// Defaults to `NoiseGate::default()`.
+    pub noise_gate: Option<NoiseGate>,
+
+    /// When set, only these users are transcribed, as long as they
+    /// aren't also in `ignore_users`.
+    /// `Discrivener::set_user_transcription_enabled` overrides this for
+    /// any user it's called for.
+    ///
+    /// Defaults to None, which transcribes everyone.
+    pub only_users: Option<HashSet<u64>>,
+
+    /// How many users' worth of audio buffers to allocate when
+    /// Discrivener starts, rather than as each user first talks.  Each
+    /// holds 30 seconds of 16khz f32 audio, which is about 1.9MB.
+    /// More users than this can still talk at once; buffers for the
+    /// rest are allocated as they're needed.  The pool keeps at least
+    /// this many buffers for reuse, even if `audio_buffer_pool_size`
+    /// is smaller.
+    ///
+    /// Defaults to zero.
+    pub preallocated_audio_buffers: usize,
+
+    /// When set, keeps about this much of each user's most recent
+    /// audio, even after it's been transcribed, so that it can be
+    /// played back with `Discrivener::get_recent_audio`.  This is
+    /// 48khz stereo as it came from Discord, which takes about 190KB
+    /// a second for each user who has spoken, so a minute is about
+    /// 11.5MB each.
+    ///
+    /// Defaults to None, which keeps nothing.
+    pub recent_audio_retention: Option<Duration>,
+
+    /// When set, everyone's audio is recorded to WAV files in this
+    /// directory as it arrives, one for each user named after their
+    /// user id, at the full 48khz stereo quality Discord sends it.
+    /// This is separate from transcription's resampling and noise
+    /// gate, so audio which is too quiet to be transcribed is still
+    /// recorded.  Users whose audio isn't being transcribed aren't
+    /// recorded either, and nobody is while transcription is paused.
+    /// Only the audio Discord sends is recorded, so the silences
+    /// between packets are left out.  The directory is created if it
+    /// doesn't exist, and any recordings already in it are replaced.
+    ///
+    /// Defaults to None, which doesn't record anything.
+    pub recording_directory: Option<PathBuf>,
+
+    /// When set, whether the end of a user's audio is quiet enough for
+    /// `trailing_silence_finalize`, and whether their audio is below
+    /// the `noise_gate`, are judged on its level smoothed over roughly
+    /// this long, rather than the level of each 20ms packet on its
+    /// own.  Around 200ms keeps a breath or a click from starting or
+    /// interrupting a run of silence, or from opening the gate.
+    ///
+    /// Defaults to None, which judges each packet on its own.
+    pub rms_smoothing: Option<Duration>,
+
+    /// Where a transcription is split in two, e.g. to finalize the
+    /// start of it while holding back the rest, a segment which ends
+    /// no more than this long after the split is kept whole on the
+    /// earlier side, rather than being left for the later one.  This
+    /// keeps a sentence which was all but finished from being held
+    /// back, or split across two transcriptions, for the sake of its
+    /// last few hundred milliseconds.
+    ///
+    /// Defaults to zero, which splits exactly where asked.
+    pub segment_join_threshold: Duration,
+
+    /// When set, every event this session sends to subscribers from
+    /// `Discrivener::subscribe_tagged` carries this as its
+    /// `SessionEvent::session_tag`, as does each line of the transcript
+    /// log.  Tagging each session, e.g. with its guild ID, lets a
+    /// single handler take events from all of them.  The callback,
+    /// `subscribe` and `event_stream` carry on getting untagged events.
+    ///
+    /// Defaults to None, which leaves events untagged.
+    pub session_tag: Option<Arc<str>>,
+
+    /// When set, a finalized transcription is split into several
+    /// transcriptions wherever the audio contains a run of silence
+    /// at least this long.  This keeps sentences from different
+    /// people sharing a single mic from being merged together when
+    /// the pause between them was too short to trip the user
+    /// silence timeout.
+    ///
+    /// Defaults to None, which never splits.
+    pub speaker_split_silence: Option<Duration>,
+
+    /// If true, a `VoiceChannelEvent::SpeechSegments` is sent for every
+    /// transcription response, counting how many of its segments had
+    /// anything said in them.  A user whose responses keep coming back
+    /// without speech is probably setting off transcription with
+    /// background noise.
+    ///
+    /// Defaults to false.
+    pub speech_segments: bool,
+
+    /// When to hold on to the unfinished end of a transcription, so
+    /// that it can be published as-is if the user stops talking.
+    pub tentative_transcripts: TentativeTranscriptPolicy,
+
+    /// Tidies up the text of transcriptions before they're delivered.
+    ///
+    /// Defaults to leaving the text as whisper wrote it.
+    pub text_normalizer: TextNormalizer,
+
+    /// When set, a user's audio is finalized once the end of their
+    /// buffer has been below the silence threshold for this long, even
+    /// if Discord still considers them to be speaking.  This catches
+    /// users who hold their push-to-talk key down after they've
+    /// finished talking.  This is in addition to the usual
+    /// user silence timeout.
+    ///
+    /// Defaults to None, which only uses the user silence timeout.
+    pub trailing_silence_finalize: Option<Duration>,
+
+    /// When set, every finalized transcription is appended to a log
+    /// file as a line of JSON, for analysis after the fact.  The log
+    /// is flushed when disconnecting.
+    ///
+    /// Defaults to None, which doesn't keep a log.
+    pub transcript_log: Option<LogConfig>,
+
+    /// How much of a user's buffered audio is sent to whisper each
+    /// time we ask for a transcription.
+    ///
+    /// Defaults to `TranscriptionMode::WholeBuffer`.
+    pub transcription_mode: TranscriptionMode,
+
+    /// Sends `VoiceChannelEvent::TranscriptionProgress` events while
+    /// whisper works through each user's audio, so that a UI can show
+    /// that something is happening during long transcriptions.  This
+    /// costs a little overhead for every transcription, and interim
+    /// transcriptions report progress too.
+    ///
+    /// Defaults to false.
+    pub transcription_progress: bool,
+
+    /// When set, finalized transcriptions are held for this long
+    /// before they're delivered, and delivered in the order their
+    /// audio started.  Without this, when several people talk at once
+    /// their transcriptions are delivered in whatever order whisper
+    /// finishes them, which can scramble a transcript.  Anything still
+    /// held is delivered when disconnecting.
+    ///
+    /// Defaults to None, which delivers transcriptions immediately.
+    pub transcription_reorder_window: Option<Duration>,
+
+    /// When set, a transcription request which takes longer than this
+    /// is abandoned, and a `VoiceChannelEvent::TranscriptionTimedOut`
+    /// is sent, so that audio which whisper struggles with doesn't
+    /// hold up that user's transcriptions indefinitely.  The audio is
+    /// kept, and asked about again later.  Whisper itself can't be
+    /// interrupted, so it carries on with the request in the
+    /// background, and its result is thrown away.
+    ///
+    /// Defaults to None, which waits as long as it takes.
+    pub transcription_timeout: Option<Duration>,
+
+    /// If true, any silence at the start of the audio is left out of
+    /// transcription requests, so whisper has less to get through and
+    /// nothing to hallucinate from.  The 150ms just before the first
+    /// sound is kept, so that a quiet start to it isn't cut off.  The
+    /// segments' offsets still count from the start of the buffer.
+    ///
+    /// Defaults to false.
+    pub trim_leading_silence: bool,
+
+    /// Settings passed through to whisper when transcribing.
+    pub whisper: WhisperConfig,
+}
+
+impl Default for DiscrivenerConfig {
+    fn default() -> Self {
+        DiscrivenerConfig {
+            audio_buffer_pool_size: EXPECTED_AUDIO_PARTICIPANTS,
+            audio_payload_format: AudioPayloadFormat::default(),
+            channel_mode: ChannelMode::default(),
+            check_audio_format: true,
+            clipping_threshold: None,
+            commit_delay: Duration::ZERO,
+            decode_policy: DecodePolicy::default(),
+            empty_response_policy: EmptyResponsePolicy::default(),
+            finalize_context_tail: Duration::ZERO,
+            finalization_mode: FinalizationMode::default(),
+            first_transcription_delay: FIRST_TRANSCRIPT_PERIOD,
+            heartbeat_interval: Some(HEARTBEAT_INTERVAL),
+            ignore_users: HashSet::new(),
+            interim_request_high_water_mark: None,
+            low_confidence_threshold: None,
+            max_inserted_silence: None,
+            max_total_audio_bytes: None,
+            min_finalized_confidence: None,
+            noise_gate: Some(NoiseGate::default()),
+            only_users: None,
+            preallocated_audio_buffers: 0,
+            recent_audio_retention: None,
+            recording_directory: None,
+            rms_smoothing: None,
+            segment_join_threshold: Duration::ZERO,
+            session_tag: None,
+            speaker_split_silence: None,
+            speech_segments: false,
+            tentative_transcripts: TentativeTranscriptPolicy::default(),
+            text_normalizer: TextNormalizer::default(),
+            trailing_silence_finalize: None,
+            transcript_log: None,
+            transcription_mode: TranscriptionMode::default(),
+            transcription_progress: false,
+            transcription_reorder_window: None,
+            transcription_timeout: None,
+            trim_leading_silence: false,
+            whisper: WhisperConfig::default(),
+        }
+    }
+}
+
+/// Sample format of the audio sent for transcription.  Either way the
+/// audio is 16khz mono.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AudioPayloadFormat {
+    /// 32-bit floats between -1.0 and 1.0, in native byte order.
+    #[default]
+    F32,
+    /// Signed 16-bit integers, little-endian.
+    Pcm16,
+}
+
+/// What to do with the two channels of a user's stereo audio.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChannelMode {
+    /// Mix the channels together, and transcribe the result.
+    #[default]
+    Downmix,
+    /// Transcribe the left and right channels separately, as though
+    /// they were two speakers.  This is for sources which put a
+    /// different person on each channel, like some interview setups
+    /// bridged into Discord.  Each transcription's `audio_channel`
+    /// says which channel it came from.
+    Split,
+}
+
+/// What to do with the Opus audio we receive from Discord.
+#[derive(Clone, Debug, Default)]
+pub enum DecodePolicy {
+    /// Decode the audio and transcribe it.
+    #[default]
+    DecodeForTranscription,
+    /// Hand each Opus packet to the sink without decoding it.  Nothing
+    /// is transcribed, though speaking and join/leave events are still
+    /// sent.
+    PassthroughOnly(OpusSink),
+    /// Hand each Opus packet to the sink, and also decode and
+    /// transcribe it.
+    Both(OpusSink),
+}
+
+impl DecodePolicy {
+    pub(crate) fn decodes(&self) -> bool {
+        !matches!(self, DecodePolicy::PassthroughOnly(_))
+    }
+
+    pub(crate) fn opus_sink(&self) -> Option<OpusSink> {
+        match self {
+            DecodePolicy::DecodeForTranscription => None,
+            DecodePolicy::PassthroughOnly(opus_sink) | DecodePolicy::Both(opus_sink) => {
+                Some(opus_sink.clone())
+            }
+        }
+    }
+}
+
+/// What to do with audio that whisper found nothing in, despite it
+/// having sound in it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EmptyResponsePolicy {
+    /// Throw the audio away, as though it had been transcribed.
+    #[default]
+    Discard,
+    /// Ask about the same audio once more, with whisper's starting
+    /// temperature raised to this, which makes it less likely to
+    /// settle on saying nothing.  If the retry finds nothing either,
+    /// the audio is thrown away.
+    Retry { temperature: f32 },
+    /// Keep the audio, so that it's transcribed along with whatever
+    /// the user says next.  Audio which is being finalized because
+    /// the user's stream changed can't be kept, and is thrown away.
+    KeepAudio,
+}
+
+/// When the audio we've buffered is finalized and published.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FinalizationMode {
+    /// Each user's audio is finalized once they've been quiet for a
+    /// moment, and published as a `Transcription` event.
+    #[default]
+    PerUser,
+
+    /// Nothing is published until everyone in the channel has been
+    /// quiet for a moment.  Then everyone's audio is finalized, and
+    /// everything they've said since the channel last went quiet is
+    /// published together as a single `ChannelTranscription` event.
+    /// This suits meeting minutes, where interleaved partial
+    /// transcriptions from each person are harder to follow.
+    WholeChannel,
+}
+
+/// Receives raw Opus packets as they arrive, e.g. to archive them.
+///
+/// This is called from songbird's event handler, so it should return
+/// quickly, handing anything slow off to another task.
+#[derive(Clone)]
+pub struct OpusSink(pub Arc<dyn Fn(OpusPacket) + Send + Sync>);
+
+impl fmt::Debug for OpusSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OpusSink")
+    }
+}
+
+/// While a user is talking, the end of each transcription we get is
+/// held back, as they may not have finished the words in it.  If they
+/// then stop talking without saying anything more, that tentative
+/// transcript can be published, instead of having to wait on another
+/// transcription of the same audio.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TentativeTranscriptPolicy {
+    /// A tentative transcript is only kept if it covers the whole
+    /// buffer, give or take this much, as rounding can leave the two
+    /// very slightly different.
+    ///
+    /// Defaults to 5ms.
+    pub duration_tolerance: Duration,
+
+    /// When set, a tentative transcript is only kept if the average
+    /// probability of its tokens, as a percentage, is above this.
+    ///
+    /// Defaults to None, which keeps tentative transcripts regardless.
+    pub min_confidence: Option<u32>,
+}
+
+impl Default for TentativeTranscriptPolicy {
+    fn default() -> Self {
+        TentativeTranscriptPolicy {
+            duration_tolerance: Duration::from_millis(5),
+            min_confidence: None,
+        }
+    }
+}
+
+/// When a user's audio is quiet enough to be treated as silence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseGate {
+    /// The RMS level, from 0 to 1, of each 20ms packet, below which
+    /// the audio counts as noise.  With `rms_smoothing`, this is the
+    /// smoothed level instead.
+    ///
+    /// Defaults to 0.01.
+    pub threshold: f32,
+
+    /// How long the audio has to stay below `threshold` before the gate
+    /// closes, so that the gaps between words don't close it.  It opens
+    /// again as soon as the level is back above `threshold`.
+    ///
+    /// Defaults to 300ms.
+    pub hold: Duration,
+}
+
+impl Default for NoiseGate {
+    fn default() -> Self {
+        NoiseGate {
+            threshold: DONT_EVEN_BOTHER_RMS_THRESHOLD,
+            hold: Duration::from_millis(300),
+        }
+    }
+}
+
+/// How the text of each segment is encoded for delivery, for
+/// consumers which can't cope with anything but ASCII.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TextEncoding {
+    /// Leave the text as whisper wrote it.
+    #[default]
+    Utf8,
+
+    /// Replace accented letters and typographic punctuation with their
+    /// closest ASCII equivalents, e.g. "café" becomes "cafe", and drop
+    /// anything else which isn't ASCII, such as CJK text and emoji.
+    AsciiTranslit,
+
+    /// Replace everything which isn't ASCII with its Unicode escape,
+    /// as JSON and JavaScript write them, so that nothing is lost.
+    /// Each escape is `\u` followed by four hex digits of UTF-16, e.g.
+    /// "café" becomes "caf\u00e9", and characters outside the Basic
+    /// Multilingual Plane take a surrogate pair, e.g. "😀" becomes
+    /// "\ud83d\ude00".
+    AsciiEscape,
+}
+
+/// Changes made to the text of each transcription's segments before
+/// it's delivered, so that it reads consistently.  The results go in
+/// the segments' cleaned text, leaving their raw text alone.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TextNormalizer {
+    /// Capitalize the first letter of each sentence.
+    pub capitalize_sentences: bool,
+
+    /// How the text is encoded, once everything else has been done to
+    /// it.  Each segment's raw text is still available as whisper
+    /// wrote it, in UTF-8.
+    ///
+    /// Defaults to `TextEncoding::Utf8`.
+    pub encoding: TextEncoding,
+
+    /// Remove a period from the end of each segment, as people rarely
+    /// end chat messages with one.  Ellipses are left alone.
+    pub strip_trailing_period: bool,
+
+    /// Remove the space whisper puts at the start of each segment.
+    /// Segments will then need to be joined with a space of their own.
+    pub trim_leading_space: bool,
+}
+
+/// Which audio to send to whisper for each transcription.
+///
+/// Until a user pauses for long enough for their words to be finalized,
+/// their audio accumulates in a buffer of up to 30 seconds, and we
+/// periodically ask whisper for a transcription of it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TranscriptionMode {
+    /// Send the whole buffer each time.  This gives whisper the most
+    /// context, but the work done grows with the length of the buffer,
+    /// so people who talk for a long time without pausing get expensive.
+    #[default]
+    WholeBuffer,
+
+    /// Keep the segments from earlier transcriptions of the buffer, and
+    /// only send the audio after them, plus `context_tail` of the audio
+    /// before that.  Their tokens are also used as whisper's prompt.
+    /// This is much cheaper for long buffers, at the risk of slightly
+    /// less accurate text where the pieces meet.
+    Incremental { context_tail: Duration },
+
+    /// Like `Incremental`, but the pieces meet where the user paused,
+    /// found by looking for at least `min_silence` of silence, rather
+    /// than wherever the last segment happened to end.  Once there's a
+    /// pause, everything before it is kept, and the next request starts
+    /// in the middle of it, so whisper never hears a word cut in half.
+    /// Until there is one, each request overlaps the last by `overlap`,
+    /// moved back to a pause if there's one within it.
+    Streaming {
+        min_silence: Duration,
+        overlap: Duration,
+    },
+}
+
+/// Settings which control how whisper decodes audio.
+#[derive(Clone, Debug)]
+pub struct WhisperConfig {
+    /// How much of whisper's audio context to use, in whisper's
+    /// encoder frames of 20ms each.  The full context of 1500 frames
+    /// covers 30 seconds of audio; a smaller one makes the encoder
+    /// much faster, but anything said past the end of it is lost,
+    /// and accuracy suffers even within it, as the model was only
+    /// trained on the full context.  Worth trying for chat which is
+    /// mostly short utterances, see also `scale_audio_ctx`.
+    ///
+    /// Defaults to None, which uses the full context.
+    pub audio_ctx: Option<usize>,
+
+    /// If the average entropy of a decoded segment's tokens is above
+    /// this, whisper considers the decode to have failed (it's usually
+    /// stuck repeating itself) and retries at a higher temperature.
+    ///
+    /// Defaults to None, which uses whisper's default of 2.4.
+    pub entropy_thold: Option<f32>,
+
+    /// When set, a second, smaller model is loaded from this path, and
+    /// interim transcriptions, which are only shown while someone is
+    /// still talking, are done with it, leaving the model given when
+    /// loading for final transcriptions.  That keeps live captions
+    /// quick when lots of people are talking at once, without giving
+    /// up accuracy on what's published.  Both models are kept in
+    /// memory, so this costs as much again as the smaller model takes,
+    /// on the GPU too if whisper is using one.  Both have to be
+    /// multilingual, or both English-only, including any main model
+    /// swapped in later with `Discrivener::reload_model`.
+    ///
+    /// Defaults to None, which uses the one model for everything.
+    pub fast_model_path: Option<String>,
+
+    /// Keeps every token id whisper produces for each segment, in the
+    /// segment's `raw_token_ids`, for feeding transcripts to other
+    /// models.  Their text is still in `tokens_with_probability`.
+    ///
+    /// Defaults to false, which leaves `raw_token_ids` as None.
+    pub include_raw_token_ids: bool,
+
+    /// When set, whisper works out which language each utterance is
+    /// in before transcribing it, and the result is given in the
+    /// transcription's `language`.  This needs a multilingual model,
+    /// and costs roughly an extra second of whisper's time per
+    /// transcription until the language is pinned.
+    ///
+    /// Defaults to None, which leaves whisper to assume English.
+    pub language_detection: Option<LanguageDetectionPolicy>,
+
+    /// If the average log probability of a decoded segment's tokens is
+    /// below this, whisper considers the decode to have failed and
+    /// retries at a higher temperature.  Lowering it gives up on
+    /// marginal audio less readily, at the cost of keeping more
+    /// low-confidence text.
+    ///
+    /// Defaults to None, which uses whisper's default of -1.0.
+    pub logprob_thold: Option<f32>,
+
+    /// How many more times to try transcribing a piece of audio when
+    /// whisper fails outright, for instance when the GPU is briefly out
+    /// of memory.  Retries wait `retry_backoff`, doubling each time.
+    /// If every attempt fails, a `VoiceChannelEvent::TranscriptionError`
+    /// is sent, and the audio is asked about again later.
+    ///
+    /// Defaults to zero, which doesn't retry.
+    pub max_retries: u32,
+
+    /// When set, whisper starts a new segment rather than letting one
+    /// grow longer than this many characters.  Around 42 suits
+    /// subtitles.  Splitting happens on token boundaries, which can
+    /// fall in the middle of a word unless `split_on_word` is set.
+    ///
+    /// Defaults to None, which doesn't limit segment length.
+    pub max_segment_len: Option<u32>,
+
+    /// When set, whisper starts a new segment rather than letting one
+    /// have more than this many tokens.
+    ///
+    /// Defaults to None, which doesn't limit segment length.
+    pub max_segment_tokens: Option<u32>,
+
+    /// Whether whisper starts each request with a fresh decoder,
+    /// rather than carrying over the text it decoded in the previous
+    /// window of the same request.  This only affects whisper's
+    /// internal state: the tokens we keep from earlier transcriptions
+    /// (up to `TOKENS_TO_KEEP`) are still passed as the prompt either
+    /// way.  Turning it off can help with long requests, at the risk
+    /// of whisper repeating itself.
+    ///
+    /// Defaults to true, which is whisper's own default.
+    pub no_context: bool,
+
+    /// How long to wait before the first retry, see `max_retries`.
+    ///
+    /// Defaults to 100ms.
+    pub retry_backoff: Duration,
+
+    /// How whisper picks tokens while decoding.
+    pub sampling_strategy: SamplingStrategy,
+
+    /// Shrinks whisper's audio context to fit each piece of audio
+    /// it's given, plus a little to spare, so that short buffers are
+    /// transcribed much faster.  `audio_ctx`, if set, is the most
+    /// that's used.  The same accuracy caveats apply as for
+    /// `audio_ctx`.
+    ///
+    /// Defaults to false, which uses the same context for everything.
+    pub scale_audio_ctx: bool,
+
+    /// Makes whisper return everything it hears in a request as one
+    /// segment, which saves it working out where to break the text,
+    /// so short requests come back a little sooner.  It's less
+    /// accurate on anything longer than a sentence or so, as whisper
+    /// loses track of the timing.
+    ///
+    /// This only changes what whisper gives back, not which audio is
+    /// sent, and what's done with it afterwards works a segment at a
+    /// time.  `TranscriptionMode::Incremental` never keeps the last
+    /// segment, so with only one it keeps nothing, and sends the whole
+    /// buffer each time, as `WholeBuffer` does.  `Streaming` keeps any
+    /// segment which starts before a pause, so it keeps everything,
+    /// including words after the pause which may have been cut off.
+    /// `speaker_split_silence` and `segment_join_threshold` can only
+    /// split between segments, so they leave each transcription whole.
+    ///
+    /// Defaults to false, which lets whisper split the text as it likes.
+    pub single_segment: bool,
+
+    /// Stops whisper from producing tokens for non-speech sounds,
+    /// and strips the bracketed sound descriptions and music notes
+    /// it produces anyway, like `[BLANK_AUDIO]` or `(music)`, from
+    /// segment text before it's delivered.  The unmodified text is
+    /// still available from `TextSegment::raw_text`.
+    ///
+    /// Defaults to true.
+    pub suppress_non_speech_tokens: bool,
+
+    /// When segments are split because of `max_segment_len`, only split
+    /// them between words.  Segments may then run a little over the
+    /// limit.
+    ///
+    /// Defaults to false.
+    pub split_on_word: bool,
+
+    /// Temperature used for the first decoding attempt.
+    ///
+    /// Defaults to None, which uses whisper's default of 0.0.
+    pub temperature: Option<f32>,
+
+    /// How much the temperature is raised on each retry after a failed
+    /// decode.  Smaller steps mean more retries, and so slower
+    /// transcriptions on noisy audio; 0.0 disables retries entirely.
+    ///
+    /// Defaults to None, which uses whisper's default of 0.2.
+    pub temperature_inc: Option<f32>,
+}
+
+impl Default for WhisperConfig {
+    fn default() -> Self {
+        WhisperConfig {
+            audio_ctx: None,
+            entropy_thold: None,
+            fast_model_path: None,
+            include_raw_token_ids: false,
+            language_detection: None,
+            logprob_thold: None,
+            max_retries: 0,
+            max_segment_len: None,
+            max_segment_tokens: None,
+            no_context: true,
+            retry_backoff: RETRY_BACKOFF,
+            sampling_strategy: SamplingStrategy::default(),
+            scale_audio_ctx: false,
+            single_segment: false,
+            suppress_non_speech_tokens: true,
+            split_on_word: false,
+            temperature: None,
+            temperature_inc: None,
+        }
+    }
+}
+
+/// Where to keep a log of finalized transcriptions, and how big to let
+/// it get.  Each line of the log is a JSON object like
+/// `{"ts":1700000000000,"user_id":1234,"text":"hello","confidence":87}`,
+/// where `ts` is when the audio started, in milliseconds since the Unix
+/// epoch, and `confidence` is the average probability of the text's
+/// tokens, as a percentage.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogConfig {
+    /// Once the log is this big, it's moved aside and a new one is
+    /// started.
+    ///
+    /// Defaults to 10MB.
+    pub max_bytes: u64,
+
+    /// How many logs which have been moved aside to keep, as `path`
+    /// with `.1`, `.2` and so on added, newest first.  Anything older
+    /// is deleted.
+    ///
+    /// Defaults to 5.
+    pub max_rotated_files: usize,
+
+    /// The file to append to.  It's created if it doesn't exist.
+    pub path: PathBuf,
+}
+
+impl LogConfig {
+    /// Logs to the given file, with the default size limits.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LogConfig {
+            max_bytes: 10 * 1024 * 1024,
+            max_rotated_files: 5,
+            path: path.into(),
+        }
+    }
+}
+
+/// How to use whisper's guess at the language of each utterance.
+/// Short or noisy audio, like someone clearing their throat at the
+/// start of a session, can easily be mistaken for another language,
+/// so guesses whisper isn't sure of aren't relied on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LanguageDetectionPolicy {
+    /// The language to transcribe in when whisper isn't sure what
+    /// language the audio is in, as a whisper language code like "en".
+    ///
+    /// Defaults to None, which uses whisper's best guess anyway.
+    pub fallback_language: Option<String>,
+
+    /// How sure whisper has to be, as a percentage, before its guess
+    /// is trusted.
+    ///
+    /// Defaults to 50.
+    pub min_confidence: u32,
+
+    /// When set, once whisper has confidently detected the same
+    /// language this many times in a row, every later transcription
+    /// in the session is made in that language without checking.
+    /// Unsure guesses don't count towards this, but don't reset it
+    /// either.
+    ///
+    /// Defaults to None, which checks every transcription.
+    pub pin_after: Option<u32>,
+}
+
+impl Default for LanguageDetectionPolicy {
+    fn default() -> Self {
+        LanguageDetectionPolicy {
+            fallback_language: None,
+            min_confidence: 50,
+            pin_after: None,
+        }
+    }
+}
+
+/// Decoding strategy used by whisper.
+///
+/// Greedy decoding is the fastest.  Beam search keeps `beam_size`
+/// candidate transcriptions alive at every step, which is generally more
+/// accurate but costs roughly `beam_size` times as much decoding work, so
+/// transcriptions will take noticeably longer to arrive.  On slower
+/// machines this may mean whisper can't keep up with a busy channel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplingStrategy {
+    /// Take the most likely token at each step, choosing the best of
+    /// `best_of` samples when whisper falls back to a higher temperature.
+    Greedy { best_of: u32 },
+    /// Search across `beam_size` candidate token sequences.
+    BeamSearch { beam_size: u32 },
+}
+
+impl Default for SamplingStrategy {
+    fn default() -> Self {
+        SamplingStrategy::Greedy { best_of: 1 }
+    }
+}
+
+impl SamplingStrategy {
+    /// Both `best_of` and `beam_size` must be at least 1.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            SamplingStrategy::Greedy { best_of: 0 } => {
+                Err("greedy sampling needs best_of >= 1".to_string())
+            }
+            SamplingStrategy::BeamSearch { beam_size: 0 } => {
+                Err("beam search needs beam_size >= 1".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_strategy_validation() {
+        assert!(SamplingStrategy::default().validate().is_ok());
+        assert!(SamplingStrategy::BeamSearch { beam_size: 5 }
+            .validate()
+            .is_ok());
+        assert!(SamplingStrategy::Greedy { best_of: 0 }.validate().is_err());
+        assert!(SamplingStrategy::BeamSearch { beam_size: 0 }
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_policy() {
+        let opus_sink = OpusSink(Arc::new(|_| {}));
+
+        assert!(DecodePolicy::default().decodes());
+        assert!(DecodePolicy::default().opus_sink().is_none());
+        assert!(!DecodePolicy::PassthroughOnly(opus_sink.clone()).decodes());
+        assert!(DecodePolicy::PassthroughOnly(opus_sink.clone())
+            .opus_sink()
+            .is_some());
+        assert!(DecodePolicy::Both(opus_sink.clone()).decodes());
+        assert!(DecodePolicy::Both(opus_sink).opus_sink().is_some());
+    }
+}
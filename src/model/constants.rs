@@ -14,13 +14,32 @@ pub(crate) const USER_SILENCE_TIMEOUT_MS: usize = 1000;
 pub(crate) const USER_SILENCE_TIMEOUT: Duration =
     Duration::from_millis(USER_SILENCE_TIMEOUT_MS as u64);
 
+/// how much of a user's audio we wait for before asking for their
+/// first interim transcription
+pub(crate) const FIRST_TRANSCRIPT_PERIOD: Duration = Duration::from_secs(5);
+
+/// how long whisper waits before first retrying a transcription
+/// which failed
+pub(crate) const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
 pub(crate) const DISCARD_USER_AUDIO_AFTER: Duration = Duration::from_secs(10 * 60);
 
+/// how many people we expect to be talking in a channel at once.
+/// We'll keep around this many users' worth of audio buffers for reuse.
+pub(crate) const EXPECTED_AUDIO_PARTICIPANTS: usize = 12;
+
 pub(crate) const WHISPER_SAMPLES_PER_SECOND: usize = 16000;
 pub(crate) const WHISPER_SAMPLES_PER_MILLISECOND: usize = 16;
+// at 16khz each sample is exactly 62.5us, so durations can be
+// converted to and from samples without any rounding
+pub(crate) const NANOS_PER_WHISPER_SAMPLE: u64 = 1_000_000_000 / WHISPER_SAMPLES_PER_SECOND as u64;
 
 pub(crate) const DISCORD_AUDIO_CHANNELS: usize = 2;
 pub(crate) const DISCORD_SAMPLES_PER_SECOND: usize = 48000;
+// Discord sends a packet every 20ms, which songbird decodes into this
+// many samples, with the channels interleaved
+pub(crate) const DISCORD_PACKET_SAMPLES: usize =
+    DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS / 50;
 
 // The RTC timestamp uses an 48khz clock.
 pub(crate) const RTC_CLOCK_SAMPLES_PER_MILLISECOND: u128 = 48;
@@ -39,4 +58,40 @@ pub(crate) const FOREVER: Duration = Duration::from_secs(1000 * 1000 * 1000);
 
 pub(crate) const DONT_EVEN_BOTHER_RMS_THRESHOLD: f32 = 0.01;
 
+// when leaving out the silence before someone speaks, keep this much of
+// it, so that quiet onsets and the first consonant aren't cut off
+pub(crate) const LEADING_SILENCE_PRE_ROLL: Duration = Duration::from_millis(150);
+
+// Discord samples at least this loud are counted as clipped
+pub(crate) const CLIPPING_LEVEL: i16 = 32000;
+
 pub(crate) const ESPEAK_SAMPLES_PER_SECOND: usize = 22050;
+
+// how long disconnecting waits for each of our tasks to finish
+// before giving up on it
+pub(crate) const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long `Discrivener::finalize_session` waits for everyone's
+// audio to be transcribed before returning what it has
+pub(crate) const SESSION_FINALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how often we send a heartbeat event, unless configured otherwise
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// how many events each subscriber can fall behind by before it
+// starts missing them
+pub(crate) const EVENT_BROADCAST_CAPACITY: usize = 1024;
+
+// how many packets of audio can be waiting to be recorded before we
+// start dropping them, which is ten seconds' worth of everyone
+// talking at once
+pub(crate) const RECORDING_QUEUE_PACKETS: usize = 50 * 10 * EXPECTED_AUDIO_PARTICIPANTS;
+
+// how often the recording checks whether we're shutting down, while
+// there's no audio to record
+pub(crate) const RECORDING_SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// the stream that audio handed to us by `submit_whisper_audio` is
+// treated as coming from, as it didn't come over a Discord stream of
+// its own.  Real ssrcs are random, so this won't clash in practice.
+pub(crate) const SUBMITTED_AUDIO_SSRC: u32 = 0;
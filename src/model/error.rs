@@ -0,0 +1,95 @@
+use std::{fmt, path::PathBuf};
+
+/// Why a `Discrivener` couldn't be loaded, couldn't transcribe an
+/// audio file, or couldn't change a setting.
+#[derive(Debug)]
+pub enum DiscrivenerError {
+    /// an audio file couldn't be opened or read
+    AudioUnreadable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// the audio file was read, but isn't audio we can use
+    InvalidAudio {
+        path: PathBuf,
+        reason: String,
+    },
+    /// audio handed to `Discrivener::submit_whisper_audio` isn't in
+    /// whisper's format
+    InvalidAudioSamples(String),
+    /// the config can't be used as given
+    InvalidConfig(String),
+    /// the model file was opened, but isn't a model whisper can use
+    InvalidModel {
+        path: PathBuf,
+        reason: String,
+    },
+    /// loading the model was cut short, by a panic while loading it or
+    /// by the runtime shutting down
+    ModelLoadFailed {
+        path: PathBuf,
+        reason: String,
+    },
+    ModelNotFound(PathBuf),
+    ModelPermissionDenied(PathBuf),
+    /// the model file couldn't be opened for some other reason
+    ModelUnreadable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// the audio file was read, but the backend couldn't transcribe it
+    TranscriptionFailed {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for DiscrivenerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscrivenerError::AudioUnreadable { path, source } => {
+                write!(f, "can't read audio file {}: {}", path.display(), source)
+            }
+            DiscrivenerError::InvalidAudio { path, reason } => {
+                write!(f, "invalid audio file {}: {}", path.display(), reason)
+            }
+            DiscrivenerError::InvalidAudioSamples(reason) => {
+                write!(f, "invalid audio samples: {}", reason)
+            }
+            DiscrivenerError::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            DiscrivenerError::InvalidModel { path, reason } => {
+                write!(f, "invalid model file {}: {}", path.display(), reason)
+            }
+            DiscrivenerError::ModelLoadFailed { path, reason } => {
+                write!(
+                    f,
+                    "failed to load model file {}: {}",
+                    path.display(),
+                    reason
+                )
+            }
+            DiscrivenerError::ModelNotFound(path) => {
+                write!(f, "model file does not exist: {}", path.display())
+            }
+            DiscrivenerError::ModelPermissionDenied(path) => {
+                write!(f, "not allowed to read model file: {}", path.display())
+            }
+            DiscrivenerError::ModelUnreadable { path, source } => {
+                write!(f, "can't read model file {}: {}", path.display(), source)
+            }
+            DiscrivenerError::TranscriptionFailed { path, reason } => {
+                write!(f, "failed to transcribe {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiscrivenerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiscrivenerError::AudioUnreadable { source, .. } => Some(source),
+            DiscrivenerError::ModelUnreadable { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::constants::{DISCORD_AUDIO_CHANNELS, DISCORD_SAMPLES_PER_SECOND};
+
+/// Totals since the `Discrivener` was loaded, for monitoring.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Metrics {
+    /// users with audio buffered right now, or who have had recently
+    pub active_users: u64,
+    /// total length of the audio received to be transcribed, not
+    /// counting audio from users who aren't being transcribed
+    pub audio_received: Duration,
+    /// audio waiting in users' buffers right now
+    pub buffered_audio: Duration,
+    /// audio we had to throw away without transcribing it, e.g.
+    /// because it sat in a buffer for too long, counted as 16-bit 48khz
+    /// stereo, the way Discord sends it.  Audio from users who aren't
+    /// being transcribed isn't counted.
+    pub dropped_audio_bytes: u64,
+    /// things which went wrong without stopping us, like failing to
+    /// hand audio or transcriptions on
+    pub errors: u64,
+    /// total time the transcription backend spent transcribing
+    pub inference_time: Duration,
+    /// transcription requests waiting on the backend right now, across
+    /// all users.  If this keeps growing, the backend can't keep up.
+    pub pending_transcription_requests: u64,
+    /// interim transcription requests we skipped because too many were
+    /// already pending, see `DiscrivenerConfig::interim_request_high_water_mark`
+    pub shed_transcription_requests: u64,
+    pub transcriptions: u64,
+}
+
+/// With the `metrics` feature, the counters are also reported through
+/// the `metrics` crate under these names.
+#[cfg(feature = "metrics")]
+mod names {
+    pub(super) const ACTIVE_USERS: &str = "discrivener_active_users";
+    pub(super) const AUDIO_RECEIVED: &str = "discrivener_audio_received_seconds";
+    pub(super) const BUFFERED_AUDIO: &str = "discrivener_buffered_audio_seconds";
+    pub(super) const DROPPED_AUDIO_BYTES: &str = "discrivener_dropped_audio_bytes";
+    pub(super) const ERRORS: &str = "discrivener_errors";
+    pub(super) const INFERENCE_TIME: &str = "discrivener_inference_seconds";
+    pub(super) const PENDING_REQUESTS: &str = "discrivener_pending_transcription_requests";
+    pub(super) const SHED_REQUESTS: &str = "discrivener_shed_transcription_requests";
+    pub(super) const TRANSCRIPTIONS: &str = "discrivener_transcriptions";
+}
+
+/// The counters behind `Metrics`, shared by the tasks which update them.
+/// Updating them never takes a lock.
+#[derive(Debug, Default)]
+pub(crate) struct MetricsCounters {
+    active_users: AtomicU64,
+    audio_received_us: AtomicU64,
+    buffered_us: AtomicU64,
+    dropped_audio_bytes: AtomicU64,
+    errors: AtomicU64,
+    inference_us: AtomicU64,
+    pending_requests: AtomicU64,
+    shed_requests: AtomicU64,
+    transcriptions: AtomicU64,
+}
+
+impl MetricsCounters {
+    pub fn new() -> Self {
+        #[cfg(feature = "metrics")]
+        describe_counters();
+        Self::default()
+    }
+
+    pub fn set_active_users(&self, users: usize) {
+        self.active_users.store(users as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(names::ACTIVE_USERS, users as f64);
+    }
+
+    /// Records that audio has been added to a user's buffer.
+    pub fn record_audio_buffered(&self, audio: Duration) {
+        let _total = add(&self.buffered_us, audio.as_micros() as u64);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(names::BUFFERED_AUDIO, _total as f64 / 1_000_000.0);
+    }
+
+    /// Records that audio has left a user's buffer, whether or not
+    /// it was transcribed.
+    pub fn record_audio_unbuffered(&self, audio: Duration) {
+        let micros = audio.as_micros() as u64;
+        let _total = self.buffered_us.fetch_sub(micros, Ordering::Relaxed) - micros;
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(names::BUFFERED_AUDIO, _total as f64 / 1_000_000.0);
+    }
+
+    pub fn record_audio_received(&self, audio: Duration) {
+        let _total = add(&self.audio_received_us, audio.as_micros() as u64);
+        #[cfg(feature = "metrics")]
+        metrics::absolute_counter!(names::AUDIO_RECEIVED, _total / 1_000_000);
+    }
+
+    pub fn record_dropped_audio(&self, audio: Duration) {
+        let bytes = audio.as_micros() as u64
+            * (DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS * std::mem::size_of::<i16>())
+                as u64
+            / 1_000_000;
+        let _total = add(&self.dropped_audio_bytes, bytes);
+        #[cfg(feature = "metrics")]
+        metrics::absolute_counter!(names::DROPPED_AUDIO_BYTES, _total);
+    }
+
+    pub fn record_error(&self) {
+        let _total = add(&self.errors, 1);
+        #[cfg(feature = "metrics")]
+        metrics::absolute_counter!(names::ERRORS, _total);
+    }
+
+    pub fn record_inference(&self, processing_time: Duration) {
+        let _total = add(&self.inference_us, processing_time.as_micros() as u64);
+        #[cfg(feature = "metrics")]
+        metrics::absolute_counter!(names::INFERENCE_TIME, _total / 1_000_000);
+    }
+
+    /// How many transcription requests are waiting on the backend.
+    pub fn pending_requests(&self) -> u64 {
+        self.pending_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn record_requests_sent(&self, count: u64) {
+        let _depth = add(&self.pending_requests, count);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(names::PENDING_REQUESTS, _depth as f64);
+    }
+
+    /// Records that requests are no longer pending, either because
+    /// they were answered or because they were abandoned.
+    pub fn record_requests_finished(&self, count: u64) {
+        let _depth = self.pending_requests.fetch_sub(count, Ordering::Relaxed) - count;
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(names::PENDING_REQUESTS, _depth as f64);
+    }
+
+    pub fn record_request_shed(&self) {
+        let _total = add(&self.shed_requests, 1);
+        #[cfg(feature = "metrics")]
+        metrics::absolute_counter!(names::SHED_REQUESTS, _total);
+    }
+
+    pub fn record_transcription(&self) {
+        let _total = add(&self.transcriptions, 1);
+        #[cfg(feature = "metrics")]
+        metrics::absolute_counter!(names::TRANSCRIPTIONS, _total);
+    }
+
+    pub fn snapshot(&self) -> Metrics {
+        Metrics {
+            active_users: self.active_users.load(Ordering::Relaxed),
+            audio_received: Duration::from_micros(self.audio_received_us.load(Ordering::Relaxed)),
+            buffered_audio: Duration::from_micros(self.buffered_us.load(Ordering::Relaxed)),
+            dropped_audio_bytes: self.dropped_audio_bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            inference_time: Duration::from_micros(self.inference_us.load(Ordering::Relaxed)),
+            pending_transcription_requests: self.pending_requests.load(Ordering::Relaxed),
+            shed_transcription_requests: self.shed_requests.load(Ordering::Relaxed),
+            transcriptions: self.transcriptions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn describe_counters() {
+    use metrics::{describe_counter, describe_gauge, Unit};
+    describe_gauge!(
+        names::ACTIVE_USERS,
+        Unit::Count,
+        "users with audio buffered"
+    );
+    describe_counter!(
+        names::AUDIO_RECEIVED,
+        Unit::Seconds,
+        "audio received to be transcribed"
+    );
+    describe_gauge!(
+        names::BUFFERED_AUDIO,
+        Unit::Seconds,
+        "audio waiting in users' buffers"
+    );
+    describe_counter!(
+        names::DROPPED_AUDIO_BYTES,
+        Unit::Bytes,
+        "audio thrown away without being transcribed"
+    );
+    describe_counter!(names::ERRORS, Unit::Count, "errors which didn't stop us");
+    describe_counter!(
+        names::INFERENCE_TIME,
+        Unit::Seconds,
+        "time spent transcribing"
+    );
+    describe_gauge!(
+        names::PENDING_REQUESTS,
+        Unit::Count,
+        "transcription requests waiting on the backend"
+    );
+    describe_counter!(
+        names::SHED_REQUESTS,
+        Unit::Count,
+        "interim transcription requests skipped to shed load"
+    );
+    describe_counter!(
+        names::TRANSCRIPTIONS,
+        Unit::Count,
+        "transcriptions published"
+    );
+}
+
+/// Adds to the counter, returning its new total.
+fn add(counter: &AtomicU64, amount: u64) -> u64 {
+    counter.fetch_add(amount, Ordering::Relaxed) + amount
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_counters() {
+        let counters = MetricsCounters::new();
+        assert_eq!(counters.snapshot(), Metrics::default());
+
+        counters.set_active_users(2);
+        counters.record_audio_received(Duration::from_millis(20));
+        counters.record_audio_received(Duration::from_millis(20));
+        counters.record_audio_buffered(Duration::from_millis(40));
+        counters.record_audio_unbuffered(Duration::from_millis(30));
+        // 10ms of Discord audio is 480 stereo 16-bit samples
+        counters.record_dropped_audio(Duration::from_millis(10));
+        counters.record_error();
+        counters.record_inference(Duration::from_millis(1500));
+        counters.record_requests_sent(3);
+        counters.record_requests_finished(1);
+        counters.record_request_shed();
+        counters.record_transcription();
+        assert_eq!(counters.pending_requests(), 2);
+
+        assert_eq!(
+            counters.snapshot(),
+            Metrics {
+                active_users: 2,
+                audio_received: Duration::from_millis(40),
+                buffered_audio: Duration::from_millis(10),
+                dropped_audio_bytes: 1920,
+                errors: 1,
+                inference_time: Duration::from_millis(1500),
+                pending_transcription_requests: 2,
+                shed_transcription_requests: 1,
+                transcriptions: 1,
+            }
+        );
+    }
+}
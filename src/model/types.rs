@@ -1,6 +1,8 @@
 use std::{
     cmp::{max, min},
+    collections::BTreeMap,
     num::Wrapping,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
@@ -10,6 +12,8 @@ use serde_with::serde_as;
 use songbird::events::context_data;
 use whisper_rs::WhisperToken;
 
+use super::constants::WHISPER_SAMPLES_PER_MILLISECOND;
+
 pub(crate) type DiscordAudioSample = i16;
 pub(crate) type DiscordRtcTimestampInner = u32;
 pub(crate) type DiscordRtcTimestamp = Wrapping<DiscordRtcTimestampInner>;
@@ -43,6 +47,91 @@ pub struct Transcription {
     /// total time spent converting this audio
     /// to text
     pub processing_time: Duration,
+
+    /// Identifies the stretch of speech this came from.  A user's
+    /// transcriptions share an id for as long as they keep talking,
+    /// and get a new one after their audio has been completely
+    /// finalized or thrown away, so this can be used to tie the
+    /// pieces of a long utterance back together.
+    ///
+    /// Each user's ids count up from 1 over the session, and carry on
+    /// counting if they leave and come back, so a user's utterances
+    /// can be put in order, and `(user_id, utterance_id)` is never
+    /// reused.  Different users can have the same id.
+    #[serde(default)]
+    pub utterance_id: u64,
+
+    /// The language the audio was transcribed as.  Only set when
+    /// language detection is turned on in the whisper config.
+    #[serde(default)]
+    pub language: Option<TranscriptionLanguage>,
+
+    /// In `ChannelMode::Split`, which of the user's stereo channels
+    /// this came from, 0 for left and 1 for right.  Otherwise None.
+    #[serde(default)]
+    pub audio_channel: Option<u8>,
+}
+
+/// The language a transcription was made in, and how it was chosen.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct TranscriptionLanguage {
+    /// whisper's code for the language, e.g. "en" or "de"
+    pub language: String,
+
+    /// What whisper made of the audio.  This can differ from
+    /// `language` when whisper wasn't sure enough and the fallback
+    /// was used instead.  None if the session's language had already
+    /// been pinned, so the audio wasn't checked.
+    pub detected: Option<DetectedLanguage>,
+}
+
+/// The language whisper thinks some audio is in.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct DetectedLanguage {
+    /// whisper's code for the language, e.g. "en" or "de"
+    pub language: String,
+
+    /// how likely whisper thinks it is, as a percentage, that the
+    /// audio is in this language
+    pub p: u32,
+}
+
+/// An audio packet exactly as Discord sent it, before decoding.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct OpusPacket {
+    /// the Opus frame, with the RTP header and any padding removed
+    pub opus_frame: Vec<u8>,
+    /// RTP timestamp of the packet, in 48khz samples
+    pub rtc_timestamp: u32,
+    /// RTP sequence number, which can be used to spot lost packets
+    pub sequence: u16,
+    pub ssrc: u32,
+    /// Discord user id of the speaker, if we know it yet
+    pub user_id: Option<UserId>,
+}
+
+/// Details of the whisper model that was loaded.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct ModelInfo {
+    /// false for the English-only `.en` models
+    pub is_multilingual: bool,
+    pub model_type: ModelType,
+    /// how many audio frames the model looks at at once
+    pub n_audio_ctx: i32,
+    /// how many tokens of text the model can hold at once
+    pub n_text_ctx: i32,
+    pub n_vocab: i32,
+}
+
+/// The size of a whisper model, as whisper.cpp reports it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ModelType {
+    Tiny,
+    Base,
+    Small,
+    Medium,
+    Large,
+    Unknown,
 }
 
 #[serde_as]
@@ -64,9 +153,81 @@ pub struct TextSegment {
     /// Time is relative to when the Message was received.
     pub end_offset_ms: u32,
 
+    /// Where the audio for this segment starts and ends, as a range
+    /// of 16khz samples counted from the first audio we received from
+    /// the user.  Unlike the millisecond offsets, this doesn't change
+    /// as earlier audio is thrown away, so segments from different
+    /// transcriptions can be lined up with each other and with a
+    /// recording of the user.
+    #[serde(default)]
+    pub start_sample: u64,
+    #[serde(default)]
+    pub end_sample: u64,
+
+    /// How unsure whisper was of the segment's tokens, in percent: 100
+    /// less their average probability, or 100 if there are none.  High
+    /// values on short or quiet audio usually mean the text was
+    /// hallucinated.  This isn't whisper.cpp's no-speech probability,
+    /// which the whisper bindings don't expose.
+    #[serde(default)]
+    pub low_confidence_p: u32,
+
+    /// The segment's text with non-speech artifacts removed, if any
+    /// were found.  `text()` prefers this over the raw token text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cleaned_text: Option<String>,
+
+    /// Roughly how the segment's text is pronounced, in IPA, e.g. for
+    /// pronunciation practice.  Only filled in for finalized
+    /// transcriptions when built with the `phonemes` feature, which
+    /// asks espeak-ng, so it's only as good as espeak-ng's guess, and
+    /// always pronounced as English.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phonemes: Option<String>,
+
+    /// Every token whisper produced for this segment, in order, including
+    /// the special tokens which are left out of `tokens_with_probability`.
+    /// Only filled in when `WhisperConfig::include_raw_token_ids` is set.
+    ///
+    /// Token ids are specific to the model which produced them:
+    /// multilingual and English-only models have different vocabularies,
+    /// so the same id can mean different text depending on the model.
+    /// `Discrivener::model_info` says which model is in use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_token_ids: Option<Vec<WhisperToken>>,
+
+    /// When this segment was according to whisper, before its offsets
+    /// were moved to be relative to `Transcription::start_timestamp`,
+    /// for debugging timing problems.  Only set on segments which came
+    /// from a transcription of a user's audio.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub whisper_timing: Option<WhisperTiming>,
+
     pub tokens_with_probability: Vec<TokenWithProbability>,
 }
 
+/// The timing of a segment as whisper reported it, along with the
+/// request it came from.  If the segment's audio was placed correctly,
+/// `audio_start + t0_ms` is the same time as the transcription's
+/// `start_timestamp + start_offset_ms`, and any difference between
+/// the two is drift between the audio and the transcript.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct WhisperTiming {
+    /// when the audio sent to whisper started, by the same clock as
+    /// `Transcription::start_timestamp`
+    pub audio_start: SystemTime,
+    /// local time when the transcription was asked for.  Comparing
+    /// this with the end of the audio shows how far behind the
+    /// request was.
+    pub requested_at: SystemTime,
+    /// where whisper said the segment started, in milliseconds after
+    /// `audio_start`.  Whisper works in 10ms steps.
+    pub t0_ms: u32,
+    /// where whisper said the segment ended, in milliseconds after
+    /// `audio_start`
+    pub t1_ms: u32,
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialOrd, PartialEq, Serialize)]
 pub struct ConnectData {
@@ -165,15 +326,267 @@ pub struct DisconnectData {
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub enum VoiceChannelEvent {
+    /// Some of a user's oldest buffered audio was thrown away without
+    /// being transcribed, to keep everyone's audio within
+    /// `DiscrivenerConfig::max_total_audio_bytes`.
+    AudioEvicted {
+        /// how much audio was thrown away
+        discarded_ms: u64,
+        /// the buffer the audio was in, as it appears in the logs
+        slice_id: u64,
+        user_id: UserId,
+        /// the utterance the audio would have been transcribed as part of,
+        /// see `Transcription::utterance_id`
+        utterance_id: u64,
+    },
+    /// A user talked for so long without a pause that their buffer
+    /// filled up, so everything in it was finalized as it was, and
+    /// their audio carries on in a fresh buffer.  Nothing is lost, but
+    /// the transcription will be split where the buffer filled.
+    BufferRolled {
+        user_id: UserId,
+    },
     ChannelSilent(bool),
+    /// Everything said since the channel last went quiet, finalized
+    /// together once everyone has been quiet for a moment, in order of
+    /// when each transcription starts.  Only sent in
+    /// `FinalizationMode::WholeChannel`, which sends these instead of
+    /// `Transcription` events.
+    ChannelTranscription(Vec<Transcription>),
     Connect(ConnectData),
     Disconnect(DisconnectData),
+    /// Sent regularly while we're running, see
+    /// `DiscrivenerConfig::heartbeat_interval`.
+    Heartbeat {
+        /// users with audio buffered, or who have had recently
+        active_users: u64,
+        /// audio waiting in users' buffers, across all of them
+        buffered_ms: u64,
+    },
+    /// Too much of a user's audio is clipped, see
+    /// `DiscrivenerConfig::clipping_threshold`.
+    InputClipping {
+        /// how much of their buffered audio is at or near full scale
+        clipped_percent: u32,
+        user_id: UserId,
+    },
+    /// Transcription has switched over to a new model, as requested
+    /// with `Discrivener::reload_model`.
+    ModelReloaded {
+        model_path: String,
+    },
+    /// Everything buffered for every user has been thrown away, and
+    /// transcription has started afresh, as requested with
+    /// `Discrivener::reset`.  Nothing transcribed before the reset is
+    /// sent after this.
+    PipelineReset,
+    /// The voice connection dropped, and has been resumed.  Whatever
+    /// everyone had said up to then is finalized, and what they say
+    /// after is transcribed separately, as its timing may not line up
+    /// with what came before.
     Reconnect(ConnectData),
+    /// Sent once as the session shuts down, after every other event.
+    SessionEnded {
+        /// totals for each user we transcribed
+        per_user: BTreeMap<UserId, UserSessionStats>,
+        total_audio_ms: u64,
+        total_transcriptions: u64,
+    },
+    /// How many of the segments in a transcription response had
+    /// anything said in them, sent when
+    /// `DiscrivenerConfig::speech_segments` is set.  Segments which
+    /// were blank, only described sounds, or which whisper was less
+    /// sure of than `DiscrivenerConfig::low_confidence_threshold` allows
+    /// don't count as speech.
+    SpeechSegments {
+        /// every segment in the response, speech or not
+        segments: u32,
+        speech_segments: u32,
+        user_id: UserId,
+    },
+    /// A user's buffered audio was thrown away without being transcribed,
+    /// because we hadn't heard anything from them for a long time.
+    StaleAudioDiscarded {
+        /// how much audio was thrown away
+        discarded_ms: u64,
+        /// the buffer the audio was in, as it appears in the logs
+        slice_id: u64,
+        user_id: UserId,
+        /// the utterance the audio would have been transcribed as part of,
+        /// see `Transcription::utterance_id`
+        utterance_id: u64,
+    },
     Transcription(Transcription),
+    /// Some of a user's audio couldn't be transcribed, even after
+    /// retrying as many times as `WhisperConfig::max_retries` allows.
+    /// The audio is kept, and asked about again later.
+    TranscriptionError {
+        /// what went wrong, as the backend described it
+        message: String,
+        user_id: UserId,
+    },
+    /// Confirms that a user's audio will, or will no longer, be
+    /// transcribed, as requested with
+    /// `Discrivener::set_user_transcription_enabled`.
+    TranscriptionEnabledChanged {
+        enabled: bool,
+        user_id: UserId,
+    },
+    /// Confirms that transcription has been paused, or resumed, as
+    /// requested with `Discrivener::pause` or `Discrivener::resume`.
+    TranscriptionPausedChanged {
+        paused: bool,
+    },
+    /// How far the backend has got through transcribing some of a
+    /// user's audio, sent along the way when
+    /// `DiscrivenerConfig::transcription_progress` is set.
+    TranscriptionProgress {
+        /// how much of the audio has been transcribed so far
+        percent: u32,
+        user_id: UserId,
+    },
+    /// A transcription request took longer than
+    /// `DiscrivenerConfig::transcription_timeout`, and was abandoned.
+    /// The audio is kept, and asked about again later.
+    TranscriptionTimedOut {
+        /// how long we waited
+        timeout_ms: u64,
+        user_id: UserId,
+    },
+    /// The first packet of decoded audio wasn't the size we expected,
+    /// which means songbird isn't giving us 48khz stereo.  No audio is
+    /// transcribed after this.  See
+    /// `DiscrivenerConfig::check_audio_format`.
+    UnexpectedAudioFormat {
+        /// how many samples a 20ms packet should have
+        expected_samples: usize,
+        received_samples: usize,
+    },
     UserJoin(UserId),
     UserLeave(UserId),
 }
 
+/// An event from a session, along with which session it came from,
+/// so that a single handler can take events from several of them.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct SessionEvent {
+    /// whatever the session was tagged with, e.g. its guild ID.  See
+    /// `DiscrivenerConfig::session_tag`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_tag: Option<Arc<str>>,
+    pub event: VoiceChannelEvent,
+}
+
+/// How one of our background tasks finished when disconnecting.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum TaskShutdown {
+    /// it finished on its own
+    Clean,
+    /// it panicked, or was cancelled
+    Failed,
+    /// it wasn't running, e.g. because we'd already disconnected
+    NotRunning,
+    /// it didn't finish in time, so it was aborted
+    TimedOut,
+}
+
+/// How each of our background tasks finished when disconnecting.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct ShutdownReport {
+    /// delivers events to the callback and subscribers
+    pub api: TaskShutdown,
+    /// buffers and transcribes each user's audio
+    pub audio_buffer_manager: TaskShutdown,
+    /// sends heartbeat events, if they're turned on
+    pub heartbeat: TaskShutdown,
+    /// writes everyone's audio to disk, if we're recording
+    pub recording: TaskShutdown,
+    /// speaks messages in the channel
+    pub speaker: TaskShutdown,
+    /// writes the transcript log, if there is one
+    pub transcript_log: TaskShutdown,
+    /// keeps track of who is talking
+    pub voice_activity: TaskShutdown,
+}
+
+impl ShutdownReport {
+    /// True if every task finished on its own.  The heartbeat,
+    /// recording and transcript log tasks only run when they're turned
+    /// on, so they may not have been running at all.
+    pub fn is_clean(&self) -> bool {
+        [
+            self.api,
+            self.audio_buffer_manager,
+            self.speaker,
+            self.voice_activity,
+        ]
+        .iter()
+        .all(|task| *task == TaskShutdown::Clean)
+            && [self.heartbeat, self.recording, self.transcript_log]
+                .iter()
+                .all(|task| matches!(task, TaskShutdown::Clean | TaskShutdown::NotRunning))
+    }
+}
+
+/// Someone whose audio is in the transcription pipeline, as given by
+/// `Discrivener::active_speakers`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ActiveSpeaker {
+    pub user_id: UserId,
+    /// in `ChannelMode::Split`, which of the user's channels this is
+    pub audio_channel: Option<u8>,
+    /// the stream their buffered audio came from, if they have any
+    pub ssrc: Option<u32>,
+    /// how much of their audio is waiting to be finalized
+    pub buffered: Duration,
+    /// whether their audio is being transcribed right now
+    pub transcribing: bool,
+}
+
+/// What was transcribed from a single user over a session.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct UserSessionStats {
+    /// total length of the audio behind the user's transcriptions
+    pub audio_ms: u64,
+    pub transcriptions: u64,
+}
+
+/// Keeps count of the transcriptions sent out, so that they can be
+/// summarized when the session ends.
+#[derive(Debug, Default)]
+pub(crate) struct SessionStats {
+    per_user: BTreeMap<UserId, UserSessionStats>,
+}
+
+impl SessionStats {
+    pub fn record(&mut self, event: &VoiceChannelEvent) {
+        let transcriptions = match event {
+            VoiceChannelEvent::Transcription(transcription) => std::slice::from_ref(transcription),
+            VoiceChannelEvent::ChannelTranscription(transcriptions) => transcriptions.as_slice(),
+            _ => return,
+        };
+        for transcription in transcriptions {
+            let user_stats = self.per_user.entry(transcription.user_id).or_default();
+            user_stats.audio_ms += transcription.audio_duration.as_millis() as u64;
+            user_stats.transcriptions += 1;
+        }
+    }
+
+    pub fn into_event(self) -> VoiceChannelEvent {
+        VoiceChannelEvent::SessionEnded {
+            total_audio_ms: self.per_user.values().map(|stats| stats.audio_ms).sum(),
+            total_transcriptions: self
+                .per_user
+                .values()
+                .map(|stats| stats.transcriptions)
+                .sum(),
+            per_user: self.per_user,
+        }
+    }
+}
+
 impl From<context_data::DisconnectKind> for DisconnectKind {
     fn from(value: context_data::DisconnectKind) -> DisconnectKind {
         match value {
@@ -227,7 +640,24 @@ impl From<&context_data::DisconnectData<'_>> for DisconnectData {
 }
 
 impl TextSegment {
+    /// Sets the segment's sample range from its offsets, given where
+    /// the audio its offsets are relative to starts.
+    pub(crate) fn place_in_stream(&mut self, stream_position: u64) {
+        let samples_per_ms = WHISPER_SAMPLES_PER_MILLISECOND as u64;
+        self.start_sample = stream_position + self.start_offset_ms as u64 * samples_per_ms;
+        self.end_sample = stream_position + self.end_offset_ms as u64 * samples_per_ms;
+    }
+
+    /// The text of this segment, with any non-speech artifacts removed.
     pub fn text(&self) -> String {
+        match &self.cleaned_text {
+            Some(cleaned_text) => cleaned_text.clone(),
+            None => self.raw_text(),
+        }
+    }
+
+    /// The text of this segment exactly as whisper produced it.
+    pub fn raw_text(&self) -> String {
         // take all token_text values and concatenate them
         // returning the string
         let mut text = String::new();
@@ -243,10 +673,47 @@ impl Transcription {
         self.segments.is_empty()
     }
 
+    /// The average probability of the transcription's tokens, as a
+    /// percentage, or None if it has no tokens.
+    pub fn average_confidence(&self) -> Option<u32> {
+        let probabilities = self
+            .segments
+            .iter()
+            .flat_map(|segment| segment.tokens_with_probability.iter())
+            .map(|token| token.p)
+            .collect::<Vec<u32>>();
+        if probabilities.is_empty() {
+            return None;
+        }
+        Some(probabilities.iter().sum::<u32>() / probabilities.len() as u32)
+    }
+
+    /// The wall-clock times at which each segment's audio started and
+    /// ended, in the same order as `segments`.
+    ///
+    /// `start_timestamp` is fixed when the audio is sent to be
+    /// transcribed, so these stay correct even if audio was trimmed
+    /// from the user's buffer while the transcription was running.
+    pub fn segment_absolute_times(&self) -> Vec<(SystemTime, SystemTime)> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                (
+                    self.start_timestamp + Duration::from_millis(segment.start_offset_ms as u64),
+                    self.start_timestamp + Duration::from_millis(segment.end_offset_ms as u64),
+                )
+            })
+            .collect()
+    }
+
     /// Splits the Transcription into two separate messages.
     /// The first message will contain all segments that end before the given end_time.
     /// The second message will contain all segments that end at or after the given end_time.
     ///
+    /// A segment which ends no more than `join_threshold` after the
+    /// end_time is kept whole in the first message, rather than going
+    /// in the second, and the first message's audio runs to its end.
+    ///
     /// If there are no segments that end before the given end_time, the first message
     /// will return true for .is_empty().  Same for the second message.
     ///
@@ -255,11 +722,12 @@ impl Transcription {
     pub(crate) fn split_at_end_time(
         message: &Transcription,
         end_time_desired: SystemTime,
+        join_threshold: Duration,
     ) -> (Self, Self) {
         let end_time = max(end_time_desired, message.start_timestamp);
         let fn_first_half = |segment: &TextSegment| {
             message.start_timestamp + Duration::from_millis(segment.end_offset_ms as u64)
-                <= end_time
+                <= end_time + join_threshold
         };
         let mut first_segments = vec![];
         let mut second_segments = vec![];
@@ -287,17 +755,16 @@ impl Transcription {
                 // make sure that we don't cut out any of their audio
                 // by setting the first_duration to take us to the
                 // soonest ending segment in the second half which
-                // is no greater than the end_time
+                // is no greater than the end_time.  If none of them
+                // start before the end_time, we can cut right there.
+                let end_duration = end_time.duration_since(message.start_timestamp).unwrap();
                 let earliest_second_segment = second_segments
                     .iter()
                     .map(|segment| Duration::from_millis(segment.start_offset_ms as u64))
                     .filter(|duration| message.start_timestamp + *duration <= end_time)
                     .min()
-                    .unwrap();
-                first_duration = min(
-                    earliest_second_segment,
-                    end_time.duration_since(message.start_timestamp).unwrap(),
-                );
+                    .unwrap_or(end_duration);
+                first_duration = min(earliest_second_segment, end_duration);
             }
         }
         first_duration = min(first_duration, message.audio_duration);
@@ -308,15 +775,23 @@ impl Transcription {
             user_id: message.user_id,
             audio_duration: first_duration,
             processing_time: message.processing_time,
+            utterance_id: message.utterance_id,
+            language: message.language.clone(),
+            audio_channel: message.audio_channel,
         };
 
         let second_duration = message.audio_duration - first_duration;
 
         // for all the second segments, remove the amount of time chopped
-        // off the beginning of the message
+        // off the beginning of the message.  Whisper will sometimes give
+        // us segments which overlap, so don't let the offsets underflow.
         for segment in &mut second_segments {
-            segment.start_offset_ms -= first_duration.as_millis() as u32;
-            segment.end_offset_ms -= first_duration.as_millis() as u32;
+            segment.start_offset_ms = segment
+                .start_offset_ms
+                .saturating_sub(first_duration.as_millis() as u32);
+            segment.end_offset_ms = segment
+                .end_offset_ms
+                .saturating_sub(first_duration.as_millis() as u32);
         }
 
         let second_transcript = Self {
@@ -325,6 +800,9 @@ impl Transcription {
             user_id: message.user_id,
             audio_duration: second_duration,
             processing_time: Duration::from_millis(1),
+            utterance_id: message.utterance_id,
+            language: message.language.clone(),
+            audio_channel: message.audio_channel,
         };
 
         (first_transcript, second_transcript)
@@ -356,6 +834,161 @@ mod tests {
     use super::*;
     use std::time::Duration;
 
+    #[test]
+    fn test_session_event_json() {
+        let tagged = SessionEvent {
+            session_tag: Some(Arc::from("guild-1")),
+            event: VoiceChannelEvent::UserJoin(7),
+        };
+        assert_eq!(
+            serde_json::to_string(&tagged).unwrap(),
+            r#"{"session_tag":"guild-1","event":{"UserJoin":7}}"#
+        );
+        let untagged = SessionEvent {
+            session_tag: None,
+            event: VoiceChannelEvent::UserJoin(7),
+        };
+        let json = serde_json::to_string(&untagged).unwrap();
+        assert_eq!(json, r#"{"event":{"UserJoin":7}}"#);
+        assert_eq!(
+            serde_json::from_str::<SessionEvent>(&json).unwrap(),
+            untagged
+        );
+    }
+
+    #[test]
+    fn test_segment_absolute_times() {
+        let start_timestamp = SystemTime::now();
+        let segment = |start_offset_ms, end_offset_ms| TextSegment {
+            start_offset_ms,
+            end_offset_ms,
+            ..Default::default()
+        };
+        let message = Transcription {
+            start_timestamp,
+            user_id: 1,
+            segments: vec![segment(0, 1200), segment(1500, 4000)],
+            audio_duration: Duration::from_secs(5),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+
+        assert_eq!(
+            message.segment_absolute_times(),
+            vec![
+                (
+                    start_timestamp,
+                    start_timestamp + Duration::from_millis(1200)
+                ),
+                (
+                    start_timestamp + Duration::from_millis(1500),
+                    start_timestamp + Duration::from_millis(4000)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_stats() {
+        let transcription = |user_id, audio_ms| Transcription {
+            start_timestamp: SystemTime::now(),
+            user_id,
+            segments: vec![],
+            audio_duration: Duration::from_millis(audio_ms),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+        let mut session_stats = SessionStats::default();
+        session_stats.record(&VoiceChannelEvent::Transcription(transcription(1, 1500)));
+        session_stats.record(&VoiceChannelEvent::UserJoin(2));
+        session_stats.record(&VoiceChannelEvent::Transcription(transcription(2, 250)));
+        session_stats.record(&VoiceChannelEvent::Transcription(transcription(1, 500)));
+        session_stats.record(&VoiceChannelEvent::ChannelSilent(true));
+        session_stats.record(&VoiceChannelEvent::ChannelTranscription(vec![
+            transcription(1, 250),
+            transcription(3, 100),
+        ]));
+
+        match session_stats.into_event() {
+            VoiceChannelEvent::SessionEnded {
+                per_user,
+                total_audio_ms,
+                total_transcriptions,
+            } => {
+                assert_eq!(total_audio_ms, 2600);
+                assert_eq!(total_transcriptions, 5);
+                assert_eq!(per_user.len(), 3);
+                assert_eq!(
+                    per_user[&1],
+                    UserSessionStats {
+                        audio_ms: 2250,
+                        transcriptions: 3
+                    }
+                );
+                assert_eq!(
+                    per_user[&2],
+                    UserSessionStats {
+                        audio_ms: 250,
+                        transcriptions: 1
+                    }
+                );
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_shutdown_report_without_heartbeat() {
+        let report = ShutdownReport {
+            api: TaskShutdown::Clean,
+            audio_buffer_manager: TaskShutdown::Clean,
+            heartbeat: TaskShutdown::NotRunning,
+            recording: TaskShutdown::NotRunning,
+            speaker: TaskShutdown::Clean,
+            transcript_log: TaskShutdown::NotRunning,
+            voice_activity: TaskShutdown::Clean,
+        };
+        assert!(report.is_clean());
+        assert!(!ShutdownReport {
+            transcript_log: TaskShutdown::TimedOut,
+            ..report
+        }
+        .is_clean());
+        assert!(!ShutdownReport {
+            heartbeat: TaskShutdown::TimedOut,
+            ..report
+        }
+        .is_clean());
+        assert!(!ShutdownReport {
+            speaker: TaskShutdown::NotRunning,
+            ..report
+        }
+        .is_clean());
+    }
+
+    #[test]
+    fn test_raw_token_ids_serialization() {
+        let segment = TextSegment {
+            start_offset_ms: 0,
+            end_offset_ms: 1000,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&segment).unwrap();
+        assert!(!json.contains("raw_token_ids"));
+
+        let segment = TextSegment {
+            raw_token_ids: Some(vec![50364, 2425, 50414]),
+            whisper_timing: None,
+            ..segment
+        };
+        let json = serde_json::to_string(&segment).unwrap();
+        assert_eq!(serde_json::from_str::<TextSegment>(&json).unwrap(), segment);
+    }
+
     #[test]
     fn test_split_at_end_time() {
         let message = Transcription {
@@ -368,6 +1001,13 @@ mod tests {
                     }],
                     start_offset_ms: 0,
                     end_offset_ms: 1000,
+                    start_sample: 0,
+                    end_sample: 0,
+                    low_confidence_p: 0,
+                    cleaned_text: None,
+                    phonemes: None,
+                    raw_token_ids: None,
+                    whisper_timing: None,
                 },
                 TextSegment {
                     tokens_with_probability: vec![TokenWithProbability {
@@ -377,16 +1017,27 @@ mod tests {
                     }],
                     start_offset_ms: 1000,
                     end_offset_ms: 2000,
+                    start_sample: 0,
+                    end_sample: 0,
+                    low_confidence_p: 0,
+                    cleaned_text: None,
+                    phonemes: None,
+                    raw_token_ids: None,
+                    whisper_timing: None,
                 },
             ],
             start_timestamp: SystemTime::UNIX_EPOCH,
             user_id: 0,
             audio_duration: Duration::from_secs(2),
             processing_time: Duration::from_millis(1),
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
         };
         let (first, second) = Transcription::split_at_end_time(
             &message,
             SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            Duration::ZERO,
         );
         let first_segments = first.segments;
         let second_segments = second.segments;
@@ -406,4 +1057,234 @@ mod tests {
         );
         assert_eq!(second_segments[0].start_offset_ms, 0)
     }
+
+    #[test]
+    fn test_split_at_end_time_short_segments() {
+        // the sort of thing whisper gives back with a max segment length
+        let words = ["a", "long", "run", "of", "short", "segments"];
+        let message = Transcription {
+            segments: words
+                .iter()
+                .enumerate()
+                .map(|(i, word)| TextSegment {
+                    tokens_with_probability: vec![TokenWithProbability {
+                        token_id: i as i32,
+                        token_text: word.to_string(),
+                        p: 90,
+                    }],
+                    start_offset_ms: i as u32 * 300,
+                    end_offset_ms: (i as u32 + 1) * 300,
+                    start_sample: 0,
+                    end_sample: 0,
+                    low_confidence_p: 0,
+                    cleaned_text: None,
+                    phonemes: None,
+                    raw_token_ids: None,
+                    whisper_timing: None,
+                })
+                .collect(),
+            start_timestamp: SystemTime::UNIX_EPOCH,
+            user_id: 0,
+            audio_duration: Duration::from_millis(1800),
+            processing_time: Duration::from_millis(1),
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+        let (first, second) = Transcription::split_at_end_time(
+            &message,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1000),
+            Duration::ZERO,
+        );
+        assert_eq!(first.segments.len(), 3);
+        assert_eq!(first.audio_duration, Duration::from_millis(900));
+        assert_eq!(second.segments.len(), 3);
+        assert_eq!(
+            second.start_timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(900)
+        );
+        assert_eq!(second.segments[0].start_offset_ms, 0);
+        assert_eq!(
+            second.segments[0].tokens_with_probability[0].token_text,
+            "of"
+        );
+        assert_eq!(
+            first.audio_duration + second.audio_duration,
+            message.audio_duration
+        );
+    }
+
+    #[test]
+    fn test_split_at_end_time_before_any_segment() {
+        let message = Transcription {
+            segments: vec![TextSegment {
+                tokens_with_probability: vec![TokenWithProbability {
+                    token_id: 0,
+                    token_text: "hello".to_string(),
+                    p: 50,
+                }],
+                start_offset_ms: 1500,
+                end_offset_ms: 2000,
+                start_sample: 0,
+                end_sample: 0,
+                low_confidence_p: 0,
+                cleaned_text: None,
+                phonemes: None,
+                raw_token_ids: None,
+                whisper_timing: None,
+            }],
+            start_timestamp: SystemTime::UNIX_EPOCH,
+            user_id: 0,
+            audio_duration: Duration::from_secs(2),
+            processing_time: Duration::from_millis(1),
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+        let (first, second) = Transcription::split_at_end_time(
+            &message,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            Duration::ZERO,
+        );
+        assert!(first.is_empty());
+        assert_eq!(first.audio_duration, Duration::from_secs(1));
+        assert_eq!(second.segments.len(), 1);
+        assert_eq!(second.audio_duration, Duration::from_secs(1));
+        assert_eq!(second.segments[0].start_offset_ms, 500);
+        assert_eq!(second.segments[0].end_offset_ms, 1000);
+    }
+
+    #[test]
+    fn test_split_at_end_time_single_segment() {
+        // what whisper gives back in single segment mode, one segment
+        // covering all the audio
+        let message = Transcription {
+            segments: vec![TextSegment {
+                tokens_with_probability: vec![TokenWithProbability {
+                    token_id: 0,
+                    token_text: "hello world".to_string(),
+                    p: 50,
+                }],
+                start_offset_ms: 0,
+                end_offset_ms: 2000,
+                start_sample: 0,
+                end_sample: 0,
+                low_confidence_p: 0,
+                cleaned_text: None,
+                phonemes: None,
+                raw_token_ids: None,
+                whisper_timing: None,
+            }],
+            start_timestamp: SystemTime::UNIX_EPOCH,
+            user_id: 0,
+            audio_duration: Duration::from_secs(2),
+            processing_time: Duration::from_millis(1),
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+        // the segment runs past the end time, so none of its audio
+        // can be cut off into the first half
+        let (first, second) = Transcription::split_at_end_time(
+            &message,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            Duration::ZERO,
+        );
+        assert!(first.is_empty());
+        assert_eq!(first.audio_duration, Duration::ZERO);
+        assert_eq!(second.segments.len(), 1);
+        assert_eq!(second.audio_duration, Duration::from_secs(2));
+        assert_eq!(second.start_timestamp, SystemTime::UNIX_EPOCH);
+
+        // and once it's over, it's all in the first half
+        let (first, second) = Transcription::split_at_end_time(
+            &message,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+            Duration::ZERO,
+        );
+        assert_eq!(first.segments.len(), 1);
+        assert_eq!(first.audio_duration, Duration::from_secs(2));
+        assert!(second.is_empty());
+        assert_eq!(second.audio_duration, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_split_at_end_time_join_threshold() {
+        let words = ["hello", "there", "world"];
+        let message = Transcription {
+            segments: words
+                .iter()
+                .enumerate()
+                .map(|(i, word)| TextSegment {
+                    tokens_with_probability: vec![TokenWithProbability {
+                        token_id: i as i32,
+                        token_text: word.to_string(),
+                        p: 90,
+                    }],
+                    start_offset_ms: i as u32 * 1000,
+                    end_offset_ms: (i as u32 + 1) * 1000,
+                    start_sample: 0,
+                    end_sample: 0,
+                    low_confidence_p: 0,
+                    cleaned_text: None,
+                    phonemes: None,
+                    raw_token_ids: None,
+                    whisper_timing: None,
+                })
+                .collect(),
+            start_timestamp: SystemTime::UNIX_EPOCH,
+            user_id: 0,
+            audio_duration: Duration::from_millis(3500),
+            processing_time: Duration::from_millis(1),
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+        let split = |end_ms: u64, join_threshold_ms: u64| {
+            Transcription::split_at_end_time(
+                &message,
+                SystemTime::UNIX_EPOCH + Duration::from_millis(end_ms),
+                Duration::from_millis(join_threshold_ms),
+            )
+        };
+
+        // "there" ends right at the edge of the threshold, so it's kept whole
+        let (first, second) = split(1700, 300);
+        assert_eq!(first.segments.len(), 2);
+        assert_eq!(first.audio_duration, Duration::from_millis(2000));
+        assert_eq!(second.segments.len(), 1);
+        assert_eq!(
+            second.start_timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(2000)
+        );
+        assert_eq!(second.segments[0].start_offset_ms, 0);
+        assert_eq!(second.segments[0].end_offset_ms, 1000);
+        assert_eq!(
+            first.audio_duration + second.audio_duration,
+            message.audio_duration
+        );
+
+        // and a millisecond further out, it isn't
+        let (first, second) = split(1699, 300);
+        assert_eq!(first.segments.len(), 1);
+        assert_eq!(first.audio_duration, Duration::from_millis(1000));
+        assert_eq!(second.segments.len(), 2);
+        assert_eq!(
+            first.audio_duration + second.audio_duration,
+            message.audio_duration
+        );
+
+        // which holds even when nothing ends before the split
+        let (first, second) = split(800, 300);
+        assert_eq!(first.segments.len(), 1);
+        assert_eq!(first.audio_duration, Duration::from_millis(1000));
+        assert_eq!(second.segments.len(), 2);
+
+        // the audio after the last segment stays with the second half
+        let (first, second) = split(3300, 1000);
+        assert_eq!(first.segments.len(), 3);
+        assert_eq!(first.audio_duration, Duration::from_millis(3000));
+        assert!(second.is_empty());
+        assert_eq!(second.audio_duration, Duration::from_millis(500));
+    }
 }
@@ -1,96 +1,262 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    sync::Arc,
-    time::Instant,
+    cmp::max,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
 
 use tokio::{
     sync::{
         self,
-        mpsc::{error::SendError, UnboundedSender},
+        mpsc::{error::SendError, UnboundedReceiver, UnboundedSender},
+        oneshot,
     },
     task,
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    audio::events::{DiscordAudioData, UserAudioEvent, UserAudioEventType},
+    audio::{
+        audio_buffer::AudioBufferPool,
+        backend::TranscriptionBackend,
+        downmix::split_channels,
+        events::{
+            AudioSamples, ChannelFlushReply, UserAudioData, UserAudioEvent, UserAudioEventType,
+        },
+    },
     model::{
-        constants::DISCARD_USER_AUDIO_AFTER,
-        types::{UserId, VoiceChannelEvent},
+        config::{ChannelMode, DiscrivenerConfig, FinalizationMode},
+        constants::{DISCARD_USER_AUDIO_AFTER, DISCORD_AUDIO_CHANNELS, NANOS_PER_WHISPER_SAMPLE},
+        metrics::MetricsCounters,
+        types::{ActiveSpeaker, Transcription, UserId, VoiceChannelEvent, WhisperAudioSample},
     },
-    strategies::five_second_strategy::FiveSecondStrategy,
+    strategies::five_second_strategy::{FiveSecondStrategy, SUBSEQUENT_TRANSCRIPT_PERIOD},
 };
 
-use super::{super::Whisper, worker::UserAudioWorker};
+use super::worker::{SpeakingTime, UserAudioWorker, UtteranceIds, WorkerStatus};
+
+/// Identifies a worker.  Each user has one, or in `ChannelMode::Split`,
+/// one for each of their channels.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct WorkerKey {
+    user_id: UserId,
+    audio_channel: Option<u8>,
+}
+
+/// A whole-channel flush which is still being put together.
+struct ChannelFlush {
+    /// how many of the flush's users we've yet to hear about
+    unannounced: usize,
+    /// workers which have yet to hand back their part
+    waiting: HashSet<WorkerKey>,
+    transcriptions: Vec<Transcription>,
+    /// when finalizing the session, where each worker's part is sent
+    /// as soon as it's handed back.  Dropped once the flush is done.
+    tx_parts: Option<UnboundedSender<Vec<Transcription>>>,
+}
+
+impl ChannelFlush {
+    fn is_finished(&self) -> bool {
+        self.unannounced == 0 && self.waiting.is_empty()
+    }
+}
 
 /// Creates an audio buffer for each user who is talking in the conversation.
 /// Takes in events related to those users, and forwards them to the
 /// appropriate buffer.
 pub(crate) struct UserAudioManager {
+    // audio storage from workers which have exited, for reuse by new ones
+    audio_buffer_pool: AudioBufferPool,
+
+    // how often workers ask for interim transcriptions, which new
+    // workers start out with
+    auto_period: Duration,
+
+    // whole-channel flushes which are waiting on some of their users,
+    // by id and whether they're finalizing the session
+    channel_flushes: HashMap<(u64, bool), ChannelFlush>,
+
+    config: Arc<DiscrivenerConfig>,
+
+    // users whose transcription is disabled.  Their audio is dropped
+    // here, as some may have been sent before they were disabled.
+    disabled_users: HashSet<UserId>,
+
+    metrics: Arc<MetricsCounters>,
+
+    // the flush id for the next time the session is finalized.  These
+    // are numbered apart from the flushes voice activity asks for.
+    next_session_flush_id: u64,
+
+    // while set, nobody's audio is transcribed, and everyone's workers
+    // have been told to throw away what they have
+    paused: bool,
+
     // these are the buffers which we've assigned to a user
     // in the conversation.  We'll keep them around until some
     // period of time after the user has stopped talking.
     // The tuple stored is:
-    //   (time of last activity, buffer)
+    //   (events, audio, time of last activity, what the worker is up to)
     user_audio_map: HashMap<
-        UserId,
+        WorkerKey,
         (
             UnboundedSender<UserAudioEventType>,
-            UnboundedSender<DiscordAudioData>,
+            UnboundedSender<UserAudioData>,
             Instant,
+            Arc<WorkerStatus>,
         ),
     >,
 
     tx_api: sync::mpsc::UnboundedSender<VoiceChannelEvent>,
 
+    // workers hand back their part of a whole-channel flush on this
+    tx_flush: UnboundedSender<ChannelFlushReply>,
+
+    // each user's utterance ids, kept for the whole session so that
+    // their workers carry on where the last ones left off
+    utterance_ids: HashMap<UserId, Arc<UtteranceIds>>,
+
     // this is used to signal the audio buffer manager to shut down.
     shutdown_token: CancellationToken,
 
-    whisper: Arc<Whisper>,
+    // how long each user has spent talking, added to by the workers
+    speaking_time: Arc<SpeakingTime>,
+
+    transcription_backend: Arc<dyn TranscriptionBackend>,
 }
 
 impl UserAudioManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn monitor(
-        rx_audio_data: sync::mpsc::UnboundedReceiver<DiscordAudioData>,
+        config: Arc<DiscrivenerConfig>,
+        metrics: Arc<MetricsCounters>,
+        rx_active_speakers: sync::mpsc::UnboundedReceiver<oneshot::Sender<Vec<ActiveSpeaker>>>,
+        rx_audio_data: sync::mpsc::UnboundedReceiver<UserAudioData>,
+        rx_auto_period: sync::mpsc::UnboundedReceiver<Duration>,
+        rx_finalize_session: UnboundedReceiver<UnboundedSender<Vec<Transcription>>>,
+        rx_paused: sync::mpsc::UnboundedReceiver<bool>,
+        rx_reset: sync::mpsc::UnboundedReceiver<()>,
         rx_silent_user_events: sync::mpsc::UnboundedReceiver<UserAudioEvent>,
         shutdown_token: CancellationToken,
+        speaking_time: Arc<SpeakingTime>,
+        transcription_backend: Arc<dyn TranscriptionBackend>,
         tx_api: sync::mpsc::UnboundedSender<VoiceChannelEvent>,
-        whisper: Whisper,
     ) -> task::JoinHandle<()> {
-        let mut audio_buffer_manager = UserAudioManager {
+        let (tx_flush, rx_flush) = sync::mpsc::unbounded_channel::<ChannelFlushReply>();
+        let mut audio_buffer_manager = UserAudioManager::new(
+            config,
+            metrics,
             shutdown_token,
+            speaking_time,
+            transcription_backend,
             tx_api,
-            user_audio_map: HashMap::new(),
-            whisper: Arc::new(whisper),
-        };
+            tx_flush,
+        );
         task::spawn(async move {
             audio_buffer_manager
-                .loop_forever(rx_audio_data, rx_silent_user_events)
+                .loop_forever(
+                    rx_active_speakers,
+                    rx_audio_data,
+                    rx_auto_period,
+                    rx_finalize_session,
+                    rx_flush,
+                    rx_paused,
+                    rx_reset,
+                    rx_silent_user_events,
+                )
                 .await;
         })
     }
 
+    fn new(
+        config: Arc<DiscrivenerConfig>,
+        metrics: Arc<MetricsCounters>,
+        shutdown_token: CancellationToken,
+        speaking_time: Arc<SpeakingTime>,
+        transcription_backend: Arc<dyn TranscriptionBackend>,
+        tx_api: sync::mpsc::UnboundedSender<VoiceChannelEvent>,
+        tx_flush: UnboundedSender<ChannelFlushReply>,
+    ) -> Self {
+        let audio_buffer_pool = AudioBufferPool::new(max(
+            config.audio_buffer_pool_size,
+            config.preallocated_audio_buffers,
+        ));
+        audio_buffer_pool.preallocate(config.preallocated_audio_buffers);
+        UserAudioManager {
+            audio_buffer_pool,
+            auto_period: SUBSEQUENT_TRANSCRIPT_PERIOD,
+            channel_flushes: HashMap::new(),
+            // sized for the users we've preallocated for, and grows
+            // like any other map if more than that turn up
+            user_audio_map: HashMap::with_capacity(config.preallocated_audio_buffers),
+            config,
+            disabled_users: HashSet::new(),
+            metrics,
+            next_session_flush_id: 1,
+            paused: false,
+            shutdown_token,
+            speaking_time,
+            transcription_backend,
+            tx_api,
+            tx_flush,
+            utterance_ids: HashMap::new(),
+        }
+    }
+
+    /// The workers the user has, or would have, given our channel mode.
+    fn worker_keys(&self, user_id: UserId) -> Vec<WorkerKey> {
+        match self.config.channel_mode {
+            ChannelMode::Downmix => vec![WorkerKey {
+                user_id,
+                audio_channel: None,
+            }],
+            ChannelMode::Split => (0..DISCORD_AUDIO_CHANNELS as u8)
+                .map(|audio_channel| WorkerKey {
+                    user_id,
+                    audio_channel: Some(audio_channel),
+                })
+                .collect(),
+        }
+    }
+
     fn get_worker(
         &mut self,
-        user_id: UserId,
+        key: WorkerKey,
     ) -> &mut (
         UnboundedSender<UserAudioEventType>,
-        UnboundedSender<DiscordAudioData>,
+        UnboundedSender<UserAudioData>,
         Instant,
+        Arc<WorkerStatus>,
     ) {
-        // insert a new buffer if we don't have one for this user
-        match self.user_audio_map.entry(user_id) {
+        // insert a new buffer if we don't have one for this worker
+        match self.user_audio_map.entry(key) {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
+                let status = Arc::new(WorkerStatus::new());
                 let (tx_worker, tx_audio) = UserAudioWorker::monitor(
-                    self.shutdown_token.clone(),
-                    FiveSecondStrategy::new(),
+                    self.audio_buffer_pool.acquire(key.user_id),
+                    self.audio_buffer_pool.clone(),
+                    key.audio_channel,
+                    self.config.clone(),
+                    self.metrics.clone(),
+                    // the worker cancels its token when it exits, so
+                    // make sure that only stops the worker
+                    self.shutdown_token.child_token(),
+                    self.speaking_time.clone(),
+                    status.clone(),
+                    FiveSecondStrategy::new(
+                        self.config.commit_delay,
+                        self.config.first_transcription_delay,
+                        self.config.tentative_transcripts,
+                        self.config.segment_join_threshold,
+                        self.auto_period,
+                    ),
+                    self.transcription_backend.clone(),
                     self.tx_api.clone(),
-                    user_id,
-                    self.whisper.clone(),
+                    self.tx_flush.clone(),
+                    self.utterance_ids.entry(key.user_id).or_default().clone(),
                 );
-                entry.insert((tx_worker, tx_audio, Instant::now()))
+                entry.insert((tx_worker, tx_audio, Instant::now(), status))
             }
         }
     }
@@ -99,37 +265,365 @@ impl UserAudioManager {
     /// the given user, and then call the given function with a
     /// mutable reference to that buffer.
     fn send_to_worker(&mut self, event: UserAudioEvent) {
-        let (tx_worker, _, _) = self.get_worker(event.user_id);
-        let result = tx_worker.send(event.event_type);
-        self.handle_send_response(event.user_id, result);
+        match event.event_type {
+            UserAudioEventType::TranscriptionDisabled => {
+                self.disabled_users.insert(event.user_id);
+            }
+            UserAudioEventType::TranscriptionEnabled => {
+                self.disabled_users.remove(&event.user_id);
+                if self.paused {
+                    // they'll be let through when we resume
+                    return;
+                }
+            }
+            _ => {}
+        }
+        if let UserAudioEventType::ChannelIdle {
+            flush_id,
+            participants,
+            session,
+        } = event.event_type
+        {
+            self.join_channel_flush(event.user_id, (flush_id, session), participants);
+        }
+        let needs_audio = matches!(
+            event.event_type,
+            UserAudioEventType::TranscriptionDisabled
+                | UserAudioEventType::TranscriptionEnabled
+                | UserAudioEventType::UtteranceBoundary
+                | UserAudioEventType::ChannelIdle { .. }
+                | UserAudioEventType::PipelineReset
+        );
+        for key in self.worker_keys(event.user_id) {
+            if needs_audio && !self.user_audio_map.contains_key(&key) {
+                // nothing buffered, so there's nothing to throw away,
+                // finalize, or start letting through again
+                continue;
+            }
+            let (tx_worker, _, _, _) = self.get_worker(key);
+            let result = tx_worker.send(event.event_type.clone());
+            self.handle_send_response(key, result);
+        }
     }
 
-    fn send_audio_to_worker(&mut self, audio: DiscordAudioData) {
-        let user_id = audio.user_id;
-        let (_, tx_audio, _) = self.get_worker(user_id);
-        let result = tx_audio.send(audio);
-        self.handle_send_response(user_id, result);
+    fn send_audio_to_worker(&mut self, audio: UserAudioData) {
+        if self.paused || self.disabled_users.contains(&audio.user_id) {
+            // sent before they were disabled, or we were paused, but it
+            // mustn't be transcribed all the same
+            return;
+        }
+        let split = self.config.channel_mode == ChannelMode::Split;
+        match audio.audio {
+            AudioSamples::Discord(ref discord_audio) if split => {
+                let channels = split_channels(discord_audio);
+                for (audio_channel, discord_audio) in channels.into_iter().enumerate() {
+                    let key = WorkerKey {
+                        user_id: audio.user_id,
+                        audio_channel: Some(audio_channel as u8),
+                    };
+                    let (_, tx_audio, _, _) = self.get_worker(key);
+                    let result = tx_audio.send(UserAudioData {
+                        user_id: audio.user_id,
+                        audio: AudioSamples::Discord(discord_audio),
+                        rtc_timestamp: audio.rtc_timestamp,
+                        ssrc: audio.ssrc,
+                    });
+                    self.handle_send_response(key, result);
+                }
+            }
+            // audio which is already mono has no channels to split, so
+            // it's transcribed as a whole, as in `ChannelMode::Downmix`
+            _ => {
+                let key = WorkerKey {
+                    user_id: audio.user_id,
+                    audio_channel: None,
+                };
+                let (_, tx_audio, _, _) = self.get_worker(key);
+                let result = tx_audio.send(audio);
+                self.handle_send_response(key, result);
+            }
+        }
     }
 
-    fn handle_send_response<T>(&mut self, user_id: UserId, response: Result<(), SendError<T>>) {
+    fn handle_send_response<T>(&mut self, key: WorkerKey, response: Result<(), SendError<T>>) {
         match response {
             Ok(_) => {
-                let (_, _, last_activity) = self.get_worker(user_id);
+                let (_, _, last_activity, _) = self.get_worker(key);
                 *last_activity = Instant::now();
             }
             Err(err) => {
                 eprintln!("Failed to send audio to worker: {}", err);
+                self.metrics.record_error();
                 // the worker has shut down, so we can remove it from the map
-                self.user_audio_map.remove(&user_id);
+                self.forget_worker(key);
+            }
+        }
+    }
+
+    /// Removes the worker, and stops waiting on it for any
+    /// whole-channel flushes.
+    fn forget_worker(&mut self, key: WorkerKey) {
+        self.user_audio_map.remove(&key);
+        for channel_flush in self.channel_flushes.values_mut() {
+            channel_flush.waiting.remove(&key);
+        }
+        self.finish_channel_flushes();
+    }
+
+    /// Counts the user towards the whole-channel flush, and if they
+    /// have workers, waits for them to hand back their parts.
+    fn join_channel_flush(&mut self, user_id: UserId, flush_key: (u64, bool), participants: usize) {
+        let keys = self.worker_keys(user_id);
+        let channel_flush = self
+            .channel_flushes
+            .entry(flush_key)
+            .or_insert_with(|| ChannelFlush {
+                unannounced: participants,
+                waiting: HashSet::new(),
+                transcriptions: Vec::new(),
+                tx_parts: None,
+            });
+        channel_flush.unannounced = channel_flush.unannounced.saturating_sub(1);
+        for key in keys {
+            if self.user_audio_map.contains_key(&key) {
+                channel_flush.waiting.insert(key);
+            }
+        }
+        self.finish_channel_flushes();
+    }
+
+    fn handle_flush_reply(&mut self, reply: ChannelFlushReply) {
+        if let Some(channel_flush) = self
+            .channel_flushes
+            .get_mut(&(reply.flush_id, reply.session))
+        {
+            channel_flush.waiting.remove(&WorkerKey {
+                user_id: reply.user_id,
+                audio_channel: reply.audio_channel,
+            });
+            if let Some(tx_parts) = channel_flush.tx_parts.as_ref() {
+                // they may have stopped waiting
+                tx_parts.send(reply.transcriptions.clone()).ok();
+            }
+            channel_flush.transcriptions.extend(reply.transcriptions);
+        }
+        self.finish_channel_flushes();
+    }
+
+    /// Has every worker finalize what they have, as though the whole
+    /// channel had gone quiet, sending each of their parts to
+    /// `tx_parts` as they come in.  It's dropped once everyone's part
+    /// has been sent.
+    fn finalize_session(&mut self, tx_parts: UnboundedSender<Vec<Transcription>>) {
+        let flush_id = self.next_session_flush_id;
+        self.next_session_flush_id += 1;
+        let user_ids = self
+            .user_audio_map
+            .keys()
+            .map(|key| key.user_id)
+            .collect::<HashSet<UserId>>();
+        let participants = user_ids.len();
+        self.channel_flushes.insert(
+            (flush_id, true),
+            ChannelFlush {
+                unannounced: participants,
+                waiting: HashSet::new(),
+                transcriptions: Vec::new(),
+                tx_parts: Some(tx_parts),
+            },
+        );
+        for user_id in user_ids {
+            self.send_to_worker(UserAudioEvent {
+                user_id,
+                event_type: UserAudioEventType::ChannelIdle {
+                    flush_id,
+                    participants,
+                    session: true,
+                },
+            });
+        }
+        // in case there was nobody to wait for
+        self.finish_channel_flushes();
+    }
+
+    /// Publishes every whole-channel flush which isn't waiting on
+    /// anyone, oldest first.
+    fn finish_channel_flushes(&mut self) {
+        let mut finished = self
+            .channel_flushes
+            .iter()
+            .filter(|(_, channel_flush)| channel_flush.is_finished())
+            .map(|(flush_key, _)| *flush_key)
+            .collect::<Vec<(u64, bool)>>();
+        finished.sort_unstable();
+        for flush_key in finished {
+            let mut transcriptions = self
+                .channel_flushes
+                .remove(&flush_key)
+                .unwrap()
+                .transcriptions;
+            // when each user's transcriptions are published as they're
+            // finalized, the only flushes are to finalize the session,
+            // and what they collect has already been sent
+            if transcriptions.is_empty()
+                || self.config.finalization_mode != FinalizationMode::WholeChannel
+            {
+                continue;
+            }
+            transcriptions.sort_by_key(|transcription| {
+                (transcription.start_timestamp, transcription.user_id)
+            });
+            if let Err(err) = self
+                .tx_api
+                .send(VoiceChannelEvent::ChannelTranscription(transcriptions))
+            {
+                eprintln!("error sending channel transcription to API: {}", err);
+                self.metrics.record_error();
+            }
+        }
+    }
+
+    /// Throws away everything every worker has, and anything the
+    /// backend has picked up, while leaving the workers and the
+    /// backend's model in place.
+    fn reset_pipeline(&mut self) {
+        let user_ids = self
+            .user_audio_map
+            .keys()
+            .map(|key| key.user_id)
+            .collect::<HashSet<UserId>>();
+        for user_id in user_ids {
+            self.send_to_worker(UserAudioEvent {
+                user_id,
+                event_type: UserAudioEventType::PipelineReset,
+            });
+        }
+        // the workers' parts would only be what we just threw away
+        self.channel_flushes.clear();
+        self.transcription_backend.reset();
+        if let Err(err) = self.tx_api.send(VoiceChannelEvent::PipelineReset) {
+            eprintln!("error sending pipeline reset to API: {}", err);
+            self.metrics.record_error();
+        }
+    }
+
+    /// Has every worker ask for interim transcriptions this often from
+    /// now on, as will any we start later.
+    fn set_auto_period(&mut self, auto_period: Duration) {
+        eprintln!(
+            "asking for interim transcriptions every {} ms",
+            auto_period.as_millis()
+        );
+        self.auto_period = auto_period;
+        let keys = self.user_audio_map.keys().copied().collect::<Vec<_>>();
+        for key in keys {
+            // this isn't activity, so the worker's last activity stays as it is
+            let (tx_worker, _, _, _) = &self.user_audio_map[&key];
+            if tx_worker
+                .send(UserAudioEventType::AutoPeriodChanged(auto_period))
+                .is_err()
+            {
+                // the worker has shut down
+                self.forget_worker(key);
+            }
+        }
+    }
+
+    /// Stops or restarts transcribing everyone.  When pausing, every
+    /// worker throws away what it has, and what it's waiting on from
+    /// the backend, whether or not the user is still in the channel.
+    /// When resuming, users who were disabled in the meantime stay
+    /// disabled.
+    fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        self.paused = paused;
+        let keys = self.user_audio_map.keys().copied().collect::<Vec<_>>();
+        for key in keys {
+            let event_type = if paused {
+                UserAudioEventType::TranscriptionDisabled
+            } else if self.disabled_users.contains(&key.user_id) {
+                // they were disabled before, or while, we were paused
+                continue;
+            } else {
+                UserAudioEventType::TranscriptionEnabled
+            };
+            // this isn't activity, so the worker's last activity stays as it is
+            let (tx_worker, _, _, _) = &self.user_audio_map[&key];
+            if tx_worker.send(event_type).is_err() {
+                // the worker has shut down
+                self.forget_worker(key);
             }
         }
     }
 
+    /// Has the workers with the most audio buffered throw away their
+    /// oldest, until everyone's fits within `max_total_audio_bytes`.
+    fn enforce_memory_limit(&mut self) {
+        let Some(max_total_bytes) = self.config.max_total_audio_bytes else {
+            return;
+        };
+        let sizes = self
+            .user_audio_map
+            .iter()
+            .map(|(key, (_, _, _, status))| (*key, status.buffered_bytes.load(Ordering::Relaxed)))
+            .collect::<Vec<(WorkerKey, usize)>>();
+        let bytes = sizes
+            .iter()
+            .map(|(_, bytes)| *bytes)
+            .collect::<Vec<usize>>();
+        let Some(max_bytes) = eviction_level(&bytes, max_total_bytes) else {
+            return;
+        };
+        for (key, bytes) in sizes {
+            if bytes <= max_bytes {
+                continue;
+            }
+            // this isn't activity from the user, so leave their last
+            // activity alone
+            let (tx_worker, _, _, _) = &self.user_audio_map[&key];
+            if let Err(err) = tx_worker.send(UserAudioEventType::EvictAudio { max_bytes }) {
+                eprintln!("Failed to send eviction to worker: {}", err);
+                self.metrics.record_error();
+                self.forget_worker(key);
+            }
+        }
+    }
+
+    /// Everyone with a worker, and what it's up to, ordered by user.
+    fn active_speakers(&self) -> Vec<ActiveSpeaker> {
+        let mut speakers = self
+            .user_audio_map
+            .iter()
+            .map(|(key, (_, _, _, status))| {
+                let samples = status.buffered_bytes.load(Ordering::Relaxed)
+                    / std::mem::size_of::<WhisperAudioSample>();
+                ActiveSpeaker {
+                    user_id: key.user_id,
+                    audio_channel: key.audio_channel,
+                    ssrc: status.ssrc(),
+                    buffered: Duration::from_nanos(samples as u64 * NANOS_PER_WHISPER_SAMPLE),
+                    transcribing: status.transcribing.load(Ordering::Relaxed),
+                }
+            })
+            .collect::<Vec<ActiveSpeaker>>();
+        speakers.sort_unstable();
+        speakers
+    }
+
     /// Worker function, which will loop forever, processing audio
     /// data and transcription requests.
+    #[allow(clippy::too_many_arguments)]
     async fn loop_forever(
         &mut self,
-        mut rx_audio_data: sync::mpsc::UnboundedReceiver<DiscordAudioData>,
+        mut rx_active_speakers: UnboundedReceiver<oneshot::Sender<Vec<ActiveSpeaker>>>,
+        mut rx_audio_data: sync::mpsc::UnboundedReceiver<UserAudioData>,
+        mut rx_auto_period: UnboundedReceiver<Duration>,
+        mut rx_finalize_session: UnboundedReceiver<UnboundedSender<Vec<Transcription>>>,
+        mut rx_flush: UnboundedReceiver<ChannelFlushReply>,
+        mut rx_paused: UnboundedReceiver<bool>,
+        mut rx_reset: UnboundedReceiver<()>,
         mut rx_silent_user_events: sync::mpsc::UnboundedReceiver<UserAudioEvent>,
     ) {
         loop {
@@ -145,14 +639,1000 @@ impl UserAudioManager {
                     // there's new audio for this user
                     self.send_to_worker(user_audio_event);
                 }
+                Some(reply) = rx_flush.recv() => {
+                    self.handle_flush_reply(reply);
+                }
+                Some(()) = rx_reset.recv() => {
+                    self.reset_pipeline();
+                }
+                Some(auto_period) = rx_auto_period.recv() => {
+                    self.set_auto_period(auto_period);
+                }
+                Some(paused) = rx_paused.recv() => {
+                    self.set_paused(paused);
+                }
+                Some(tx_reply) = rx_active_speakers.recv() => {
+                    // they may have stopped waiting
+                    tx_reply.send(self.active_speakers()).ok();
+                }
+                Some(tx_parts) = rx_finalize_session.recv() => {
+                    self.finalize_session(tx_parts);
+                }
             }
 
             // look through every buffer, and discard any which haven't been
-            // updated in the past DISCARD_USER_AUDIO_AFTER period
+            // updated in the past DISCARD_USER_AUDIO_AFTER period.  Dropping
+            // our end of its channels makes the worker report any audio it
+            // still has as StaleAudioDiscarded and exit.
             let now = Instant::now();
-            self.user_audio_map.retain(|_, (_, _, last_activity)| {
-                now.duration_since(*last_activity) < DISCARD_USER_AUDIO_AFTER
+            let stale_workers = self
+                .user_audio_map
+                .iter()
+                .filter(|(_, (_, _, last_activity, _))| {
+                    now.duration_since(*last_activity) >= DISCARD_USER_AUDIO_AFTER
+                })
+                .map(|(key, _)| *key)
+                .collect::<Vec<WorkerKey>>();
+            for key in stale_workers {
+                self.forget_worker(key);
+            }
+            self.enforce_memory_limit();
+            let active_users = self
+                .user_audio_map
+                .keys()
+                .map(|key| key.user_id)
+                .collect::<HashSet<UserId>>();
+            self.metrics.set_active_users(active_users.len());
+        }
+    }
+}
+
+/// Collects the parts of a session flush as `finalize_session` sends
+/// them, until everyone's is in or `timeout` is up, ordered by when
+/// they were said.
+pub(crate) async fn gather_session_parts(
+    mut rx_parts: UnboundedReceiver<Vec<Transcription>>,
+    timeout: Duration,
+) -> Vec<Transcription> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut transcriptions = Vec::new();
+    loop {
+        match tokio::time::timeout_at(deadline, rx_parts.recv()).await {
+            Ok(Some(part)) => transcriptions.extend(part),
+            // everyone has handed back their part
+            Ok(None) => break,
+            Err(_) => {
+                eprintln!("timed out finalizing the session, returning what was finished");
+                break;
+            }
+        }
+    }
+    transcriptions
+        .sort_by_key(|transcription| (transcription.start_timestamp, transcription.user_id));
+    transcriptions
+}
+
+/// The most memory each worker can keep for all of them together to
+/// fit within `max_total_bytes`, trimming whoever has the most first.
+/// None if they already fit.
+fn eviction_level(sizes: &[usize], max_total_bytes: usize) -> Option<usize> {
+    let mut remaining = sizes.iter().sum::<usize>();
+    if remaining <= max_total_bytes {
+        return None;
+    }
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    for (trimmed, size) in sizes.iter().enumerate() {
+        // try trimming everyone up to and including this worker down
+        // to the same level, and leaving the rest alone
+        remaining -= size;
+        let level = max_total_bytes.saturating_sub(remaining) / (trimmed + 1);
+        if level >= sizes.get(trimmed + 1).copied().unwrap_or(0) {
+            return Some(level);
+        }
+    }
+    // not reached, as trimming everyone to the same level always fits
+    Some(max_total_bytes / sizes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        num::Wrapping,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        audio::{
+            echo::EchoBackend,
+            scripted::{Answer, ScriptedBackend},
+        },
+        model::config::EmptyResponsePolicy,
+    };
+
+    use super::*;
+
+    fn make_manager(
+        config: DiscrivenerConfig,
+        shutdown_token: CancellationToken,
+    ) -> (UserAudioManager, UnboundedReceiver<VoiceChannelEvent>) {
+        make_manager_with_backend(
+            config,
+            shutdown_token,
+            Arc::new(EchoBackend::new(" hello".to_string())),
+        )
+    }
+
+    fn make_manager_with_backend(
+        config: DiscrivenerConfig,
+        shutdown_token: CancellationToken,
+        backend: Arc<dyn TranscriptionBackend>,
+    ) -> (UserAudioManager, UnboundedReceiver<VoiceChannelEvent>) {
+        let (tx_api, rx_api) = sync::mpsc::unbounded_channel();
+        let (tx_flush, _) = sync::mpsc::unbounded_channel();
+        let manager = UserAudioManager::new(
+            Arc::new(config),
+            Arc::new(MetricsCounters::new()),
+            shutdown_token,
+            Arc::new(SpeakingTime::default()),
+            backend,
+            tx_api,
+            tx_flush,
+        );
+        (manager, rx_api)
+    }
+
+    fn send_audio(manager: &mut UserAudioManager, user_id: UserId) {
+        manager.send_audio_to_worker(UserAudioData {
+            user_id,
+            audio: AudioSamples::Discord(vec![1; 1920]),
+            rtc_timestamp: Wrapping(0),
+            ssrc: 100 + user_id as u32,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_more_users_than_preallocated() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            preallocated_audio_buffers: 2,
+            ..Default::default()
+        };
+        let (mut manager, _rx_api) = make_manager(config, shutdown_token.clone());
+
+        for user_id in 0..20 {
+            send_audio(&mut manager, user_id);
+        }
+        assert_eq!(manager.user_audio_map.len(), 20);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_channel_flush_waits_for_everyone() {
+        let shutdown_token = CancellationToken::new();
+        let (mut manager, mut rx_api) =
+            make_manager(DiscrivenerConfig::default(), shutdown_token.clone());
+        send_audio(&mut manager, 1);
+        send_audio(&mut manager, 2);
+
+        let start_timestamp = SystemTime::now();
+        let transcription = |user_id, start_ms| Transcription {
+            start_timestamp: start_timestamp + Duration::from_millis(start_ms),
+            user_id,
+            segments: vec![],
+            audio_duration: Duration::from_millis(500),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+
+        // user 3 has nothing buffered, so there's nothing to wait for
+        manager.join_channel_flush(1, (7, false), 3);
+        manager.join_channel_flush(3, (7, false), 3);
+        manager.join_channel_flush(2, (7, false), 3);
+        manager.handle_flush_reply(ChannelFlushReply {
+            flush_id: 7,
+            session: false,
+            user_id: 2,
+            audio_channel: None,
+            transcriptions: vec![transcription(2, 0)],
+        });
+        assert!(rx_api.try_recv().is_err());
+
+        manager.handle_flush_reply(ChannelFlushReply {
+            flush_id: 7,
+            session: false,
+            user_id: 1,
+            audio_channel: None,
+            transcriptions: vec![transcription(1, 1000), transcription(1, 0)],
+        });
+        match rx_api.try_recv().unwrap() {
+            VoiceChannelEvent::ChannelTranscription(transcriptions) => {
+                assert_eq!(
+                    transcriptions,
+                    vec![
+                        transcription(1, 0),
+                        transcription(2, 0),
+                        transcription(1, 1000)
+                    ]
+                );
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(manager.channel_flushes.is_empty());
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_finalize_session() {
+        let shutdown_token = CancellationToken::new();
+        let (tx_api, mut rx_api) = sync::mpsc::unbounded_channel();
+        let (tx_flush, mut rx_flush) = sync::mpsc::unbounded_channel();
+        let mut manager = UserAudioManager::new(
+            Arc::new(DiscrivenerConfig::default()),
+            Arc::new(MetricsCounters::new()),
+            shutdown_token.clone(),
+            Arc::new(SpeakingTime::default()),
+            Arc::new(EchoBackend::new(" hello".to_string())),
+            tx_api,
+            tx_flush,
+        );
+        // neither of them has stopped talking
+        for user_id in [1, 2] {
+            manager.send_audio_to_worker(UserAudioData {
+                user_id,
+                audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+                rtc_timestamp: Wrapping(0),
+                ssrc: 100 + user_id as u32,
+            });
+        }
+
+        let (tx_parts, mut rx_parts) = sync::mpsc::unbounded_channel();
+        manager.finalize_session(tx_parts);
+        while !manager.channel_flushes.is_empty() {
+            let reply = tokio::time::timeout(Duration::from_secs(5), rx_flush.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            manager.handle_flush_reply(reply);
+        }
+
+        let mut user_ids = Vec::new();
+        while let Some(part) = rx_parts.recv().await {
+            user_ids.extend(part.iter().map(|transcription| transcription.user_id));
+        }
+        let returned = user_ids.len();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        assert_eq!(user_ids, vec![1, 2]);
+        // in per-user mode, everyone's transcriptions were published as
+        // usual, and not as a whole channel
+        let mut published = 0;
+        while let Ok(event) = rx_api.try_recv() {
+            match event {
+                VoiceChannelEvent::Transcription(_) => published += 1,
+                VoiceChannelEvent::ChannelTranscription(_) => {
+                    panic!("unexpected channel transcription")
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(published, returned);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_session_parts_gathered() {
+        let start_timestamp = SystemTime::now();
+        let transcription = |user_id, start_ms| Transcription {
+            start_timestamp: start_timestamp + Duration::from_millis(start_ms),
+            user_id,
+            segments: vec![],
+            audio_duration: Duration::from_millis(500),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+
+        // everyone's parts, in whatever order they come, are put in
+        // the order they were said
+        let (tx_parts, rx_parts) = sync::mpsc::unbounded_channel();
+        tx_parts
+            .send(vec![transcription(2, 500), transcription(2, 0)])
+            .unwrap();
+        tx_parts.send(vec![transcription(1, 500)]).unwrap();
+        drop(tx_parts);
+        assert_eq!(
+            gather_session_parts(rx_parts, Duration::from_secs(5)).await,
+            vec![
+                transcription(2, 0),
+                transcription(1, 500),
+                transcription(2, 500)
+            ]
+        );
+
+        // if someone's part never comes, what did is returned
+        let (tx_parts, rx_parts) = sync::mpsc::unbounded_channel();
+        tx_parts.send(vec![transcription(1, 0)]).unwrap();
+        assert_eq!(
+            gather_session_parts(rx_parts, Duration::from_millis(50)).await,
+            vec![transcription(1, 0)]
+        );
+        drop(tx_parts);
+    }
+
+    #[tokio::test]
+    async fn test_split_mode_has_a_worker_per_channel() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            channel_mode: ChannelMode::Split,
+            ..Default::default()
+        };
+        let (mut manager, _rx_api) = make_manager(config, shutdown_token.clone());
+        send_audio(&mut manager, 1);
+        assert_eq!(manager.user_audio_map.len(), 2);
+
+        // the flush waits on both channels
+        manager.join_channel_flush(1, (7, false), 1);
+        assert_eq!(manager.channel_flushes[&(7, false)].waiting.len(), 2);
+        manager.handle_flush_reply(ChannelFlushReply {
+            flush_id: 7,
+            session: false,
+            user_id: 1,
+            audio_channel: Some(0),
+            transcriptions: vec![],
+        });
+        assert_eq!(manager.channel_flushes[&(7, false)].waiting.len(), 1);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_long_gap_starts_new_utterance() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            max_inserted_silence: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let (mut manager, mut rx_api) = make_manager(config, shutdown_token.clone());
+        let second = vec![1000; 2 * 48000];
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(second.clone()),
+            rtc_timestamp: Wrapping(0),
+            ssrc: 100,
+        });
+        // the network stalls for 5 seconds
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(second),
+            rtc_timestamp: Wrapping(6 * 48000),
+            ssrc: 100,
+        });
+
+        // what came before the gap is finalized on its own, rather
+        // than with 5 seconds of silence after it
+        let transcription = loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let VoiceChannelEvent::Transcription(transcription) = event {
+                break transcription;
+            }
+        };
+        assert_eq!(transcription.audio_duration, Duration::from_secs(1));
+        shutdown_token.cancel();
+    }
+
+    /// Has the user say something, then pause for long enough that
+    /// it's finalized, and returns the transcription's utterance id.
+    async fn utterance_id(
+        manager: &mut UserAudioManager,
+        rx_api: &mut UnboundedReceiver<VoiceChannelEvent>,
+        user_id: UserId,
+    ) -> u64 {
+        let second = vec![1000; 2 * 48000];
+        manager.send_audio_to_worker(UserAudioData {
+            user_id,
+            audio: AudioSamples::Discord(second.clone()),
+            rtc_timestamp: Wrapping(0),
+            ssrc: 100 + user_id as u32,
+        });
+        manager.send_audio_to_worker(UserAudioData {
+            user_id,
+            audio: AudioSamples::Discord(second),
+            rtc_timestamp: Wrapping(6 * 48000),
+            ssrc: 100 + user_id as u32,
+        });
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let VoiceChannelEvent::Transcription(transcription) = event {
+                assert_eq!(transcription.user_id, user_id);
+                return transcription.utterance_id;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_utterance_ids_survive_rejoin() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            max_inserted_silence: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let (mut manager, mut rx_api) = make_manager(config, shutdown_token.clone());
+
+        let first = utterance_id(&mut manager, &mut rx_api, 1).await;
+        assert_eq!(first, 1);
+        // user 1 leaves, and their worker is forgotten, before they
+        // come back
+        for key in manager.worker_keys(1) {
+            manager.forget_worker(key);
+        }
+        let after_rejoin = utterance_id(&mut manager, &mut rx_api, 1).await;
+        assert!(after_rejoin > first);
+        // other users count separately
+        assert_eq!(utterance_id(&mut manager, &mut rx_api, 2).await, 1);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_speaking_time() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            max_inserted_silence: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let (mut manager, mut rx_api) = make_manager(config, shutdown_token.clone());
+
+        // each utterance is a second of talking
+        for _ in 0..3 {
+            utterance_id(&mut manager, &mut rx_api, 1).await;
+            for key in manager.worker_keys(1) {
+                manager.forget_worker(key);
+            }
+        }
+        utterance_id(&mut manager, &mut rx_api, 2).await;
+
+        assert_eq!(manager.speaking_time.get(1), Duration::from_secs(3));
+        assert_eq!(manager.speaking_time.get(3), Duration::ZERO);
+        assert_eq!(
+            manager.speaking_time.snapshot(),
+            BTreeMap::from([(1, Duration::from_secs(3)), (2, Duration::from_secs(1))])
+        );
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_speaking_time_split() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            channel_mode: ChannelMode::Split,
+            max_inserted_silence: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let (mut manager, mut rx_api) = make_manager(config, shutdown_token.clone());
+
+        // both channels hear the same second of talking
+        utterance_id(&mut manager, &mut rx_api, 1).await;
+        next_transcription(&mut rx_api).await;
+
+        assert_eq!(manager.speaking_time.get(1), Duration::from_secs(1));
+        assert_eq!(
+            manager.speaking_time.snapshot(),
+            BTreeMap::from([(1, Duration::from_secs(1))])
+        );
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_full_buffer_rolls_over() {
+        let shutdown_token = CancellationToken::new();
+        let (mut manager, mut rx_api) =
+            make_manager(DiscrivenerConfig::default(), shutdown_token.clone());
+        // 35 seconds without a pause, which is more than a buffer holds
+        for second in 0..35 {
+            manager.send_audio_to_worker(UserAudioData {
+                user_id: 1,
+                audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+                rtc_timestamp: Wrapping(second * 48000),
+                ssrc: 100,
+            });
+        }
+
+        let mut transcribed = Duration::ZERO;
+        let mut rolled = false;
+        while transcribed < Duration::from_secs(35) {
+            let event = tokio::time::timeout(Duration::from_secs(5), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                VoiceChannelEvent::Transcription(transcription) => {
+                    transcribed += transcription.audio_duration;
+                }
+                VoiceChannelEvent::BufferRolled { user_id } => {
+                    assert_eq!(user_id, 1);
+                    // everything before the roll was finalized first
+                    assert_eq!(transcribed, Duration::from_secs(29));
+                    rolled = true;
+                    // and the rest is finalized when they stop
+                    manager.send_to_worker(UserAudioEvent {
+                        user_id: 1,
+                        event_type: UserAudioEventType::Silent,
+                    });
+                }
+                _ => {}
+            }
+        }
+        assert!(rolled);
+        // none of the audio was lost, or transcribed twice
+        assert_eq!(transcribed, Duration::from_secs(35));
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_transcription_progress() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            max_inserted_silence: Some(Duration::from_secs(1)),
+            transcription_progress: true,
+            ..Default::default()
+        };
+        let (mut manager, mut rx_api) = make_manager(config, shutdown_token.clone());
+        let second = vec![1000; 2 * 48000];
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(second.clone()),
+            rtc_timestamp: Wrapping(0),
+            ssrc: 100,
+        });
+        // a long gap, so that what came before it is transcribed
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(second),
+            rtc_timestamp: Wrapping(6 * 48000),
+            ssrc: 100,
+        });
+
+        let mut progress = Vec::new();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                VoiceChannelEvent::TranscriptionProgress { percent, user_id } => {
+                    assert_eq!(user_id, 1);
+                    progress.push(percent);
+                }
+                VoiceChannelEvent::Transcription(_) => break,
+                _ => {}
+            }
+        }
+        assert_eq!(progress, vec![100]);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_transcription_timeout() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            max_inserted_silence: Some(Duration::from_secs(1)),
+            transcription_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (backend, _rx_requests) = ScriptedBackend::new(&[], Answer::Stuck);
+        let (mut manager, mut rx_api) =
+            make_manager_with_backend(config, shutdown_token.clone(), backend);
+        let second = vec![1000; 2 * 48000];
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(second.clone()),
+            rtc_timestamp: Wrapping(0),
+            ssrc: 100,
+        });
+        // a long gap, so that what came before it is transcribed
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(second),
+            rtc_timestamp: Wrapping(6 * 48000),
+            ssrc: 100,
+        });
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let VoiceChannelEvent::TranscriptionTimedOut {
+                timeout_ms,
+                user_id,
+            } = event
+            {
+                assert_eq!(timeout_ms, 100);
+                assert_eq!(user_id, 1);
+                break;
+            }
+        }
+        assert_eq!(manager.metrics.snapshot().errors, 1);
+        shutdown_token.cancel();
+    }
+
+    #[test]
+    fn test_eviction_level() {
+        assert_eq!(eviction_level(&[10, 20], 30), None);
+        // only the largest needs trimming
+        assert_eq!(eviction_level(&[100, 20, 10], 80), Some(50));
+        // the two largest end up level
+        assert_eq!(eviction_level(&[100, 90, 10], 110), Some(50));
+        assert_eq!(eviction_level(&[40, 40], 0), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit_evicts_largest() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            max_total_audio_bytes: Some(40000),
+            ..Default::default()
+        };
+        let (mut manager, mut rx_api) = make_manager(config, shutdown_token.clone());
+        let before = SystemTime::now();
+        // a second of audio, and a fifth of one
+        for (user_id, samples) in [(1, 2 * 48000), (2, 2 * 9600)] {
+            manager.send_audio_to_worker(UserAudioData {
+                user_id,
+                audio: AudioSamples::Discord(vec![1000; samples]),
+                rtc_timestamp: Wrapping(0),
+                ssrc: 100 + user_id as u32,
+            });
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while manager.active_speakers().len() < 2
+                || manager.active_speakers()[0].buffered != Duration::from_secs(1)
+                || manager.active_speakers()[1].buffered != Duration::from_millis(200)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // only the larger buffer is over its share, and loses its
+        // oldest audio
+        manager.enforce_memory_limit();
+        let evicted = loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let VoiceChannelEvent::AudioEvicted {
+                discarded_ms,
+                user_id,
+                ..
+            } = event
+            {
+                break (user_id, discarded_ms);
+            }
+        };
+        assert_eq!(evicted, (1, 575));
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::Silent,
+        });
+        let transcription = next_transcription(&mut rx_api).await;
+        assert_eq!(transcription.user_id, 1);
+        assert_eq!(transcription.audio_duration, Duration::from_millis(425));
+        assert!(
+            transcription
+                .start_timestamp
+                .duration_since(before)
+                .unwrap()
+                >= Duration::from_millis(575)
+        );
+        assert_eq!(
+            manager.active_speakers()[1].buffered,
+            Duration::from_millis(200)
+        );
+
+        // under the limit, nobody is asked to evict anything
+        manager.enforce_memory_limit();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        while let Ok(event) = rx_api.try_recv() {
+            assert!(!matches!(event, VoiceChannelEvent::AudioEvicted { .. }));
+        }
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_active_speakers() {
+        let shutdown_token = CancellationToken::new();
+        let (mut manager, _rx_api) =
+            make_manager(DiscrivenerConfig::default(), shutdown_token.clone());
+        assert!(manager.active_speakers().is_empty());
+
+        // a second of audio, and half a second
+        for (user_id, samples) in [(2, 2 * 48000), (1, 48000)] {
+            manager.send_audio_to_worker(UserAudioData {
+                user_id,
+                audio: AudioSamples::Discord(vec![1000; samples]),
+                rtc_timestamp: Wrapping(0),
+                ssrc: 100 + user_id as u32,
+            });
+        }
+        let expected = vec![
+            ActiveSpeaker {
+                user_id: 1,
+                audio_channel: None,
+                ssrc: Some(101),
+                buffered: Duration::from_millis(500),
+                transcribing: false,
+            },
+            ActiveSpeaker {
+                user_id: 2,
+                audio_channel: None,
+                ssrc: Some(102),
+                buffered: Duration::from_secs(1),
+                transcribing: false,
+            },
+        ];
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while manager.active_speakers() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_disabled_users_audio_dropped() {
+        let shutdown_token = CancellationToken::new();
+        let (mut manager, _rx_api) =
+            make_manager(DiscrivenerConfig::default(), shutdown_token.clone());
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::TranscriptionDisabled,
+        });
+        // sent before they were disabled, but it arrives after
+        send_audio(&mut manager, 1);
+        assert!(manager.user_audio_map.is_empty());
+
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::TranscriptionEnabled,
+        });
+        send_audio(&mut manager, 1);
+        assert_eq!(manager.user_audio_map.len(), 1);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_pause_clears_every_worker() {
+        let shutdown_token = CancellationToken::new();
+        let (backend, mut rx_requests) = ScriptedBackend::echo();
+        let (mut manager, mut rx_api) = make_manager_with_backend(
+            DiscrivenerConfig::default(),
+            shutdown_token.clone(),
+            backend,
+        );
+        for user_id in [1, 2] {
+            manager.send_audio_to_worker(UserAudioData {
+                user_id,
+                audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+                rtc_timestamp: Wrapping(0),
+                ssrc: 100 + user_id as u32,
+            });
+        }
+        let buffered = |manager: &UserAudioManager| {
+            manager
+                .active_speakers()
+                .iter()
+                .map(|speaker| speaker.buffered)
+                .collect::<Vec<Duration>>()
+        };
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while buffered(&manager) != vec![Duration::from_secs(1); 2] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // everyone's audio is thrown away, and nothing new is let in
+        manager.set_paused(true);
+        send_audio(&mut manager, 3);
+        assert_eq!(manager.user_audio_map.len(), 2);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while buffered(&manager) != vec![Duration::ZERO; 2] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // and whatever was asked about it before we paused
+        while rx_requests.try_recv().is_ok() {}
+        for user_id in [1, 2] {
+            manager.send_to_worker(UserAudioEvent {
+                user_id,
+                event_type: UserAudioEventType::Silent,
             });
         }
+        // users enabled while we're paused are still paused
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 2,
+            event_type: UserAudioEventType::TranscriptionDisabled,
+        });
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 2,
+            event_type: UserAudioEventType::TranscriptionEnabled,
+        });
+        send_audio(&mut manager, 2);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(rx_requests.try_recv().is_err());
+
+        manager.set_paused(false);
+        say_something(&mut manager, 4);
+        assert_eq!(next_transcription(&mut rx_api).await.user_id, 1);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_reset_keeps_workers() {
+        let shutdown_token = CancellationToken::new();
+        let (mut manager, mut rx_api) =
+            make_manager(DiscrivenerConfig::default(), shutdown_token.clone());
+        send_audio(&mut manager, 1);
+        send_audio(&mut manager, 2);
+        manager.join_channel_flush(1, (7, false), 2);
+
+        manager.reset_pipeline();
+        assert!(matches!(
+            rx_api.try_recv().unwrap(),
+            VoiceChannelEvent::PipelineReset
+        ));
+        assert!(manager.channel_flushes.is_empty());
+        assert_eq!(manager.user_audio_map.len(), 2);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_unsure_transcriptions_are_dropped() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            min_finalized_confidence: Some(70),
+            ..Default::default()
+        };
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[], Answer::Echo(60));
+        let (mut manager, mut rx_api) =
+            make_manager_with_backend(config, shutdown_token.clone(), backend);
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+            rtc_timestamp: Wrapping(0),
+            ssrc: 101,
+        });
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::Silent,
+        });
+
+        // the audio is thrown away, once it's been transcribed
+        tokio::time::timeout(Duration::from_secs(1), async {
+            rx_requests.recv().await;
+            while manager.active_speakers()[0].buffered > Duration::ZERO {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // but nothing is published
+        while let Ok(event) = rx_api.try_recv() {
+            assert!(!matches!(event, VoiceChannelEvent::Transcription(_)));
+        }
+        shutdown_token.cancel();
+    }
+
+    /// sends the user's audio, starting at the given second, and then
+    /// says they've stopped talking
+    fn say_something(manager: &mut UserAudioManager, second: u32) {
+        manager.send_audio_to_worker(UserAudioData {
+            user_id: 1,
+            audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+            rtc_timestamp: Wrapping(second * 48000),
+            ssrc: 101,
+        });
+        manager.send_to_worker(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::Silent,
+        });
+    }
+
+    async fn next_transcription(
+        rx_api: &mut UnboundedReceiver<VoiceChannelEvent>,
+    ) -> Transcription {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), rx_api.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let VoiceChannelEvent::Transcription(transcription) = event {
+                return transcription;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_discarded() {
+        let shutdown_token = CancellationToken::new();
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[], Answer::Empty);
+        let (mut manager, mut rx_api) = make_manager_with_backend(
+            DiscrivenerConfig::default(),
+            shutdown_token.clone(),
+            backend,
+        );
+        say_something(&mut manager, 0);
+
+        assert_eq!(rx_requests.recv().await.unwrap().temperature, None);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while manager.active_speakers()[0].buffered > Duration::ZERO {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // the audio is gone, without being asked about again
+        assert!(rx_requests.try_recv().is_err());
+        while let Ok(event) = rx_api.try_recv() {
+            assert!(!matches!(event, VoiceChannelEvent::Transcription(_)));
+        }
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_retried() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            empty_response_policy: EmptyResponsePolicy::Retry { temperature: 0.4 },
+            ..Default::default()
+        };
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[Answer::Empty], Answer::Echo(100));
+        let (mut manager, mut rx_api) =
+            make_manager_with_backend(config, shutdown_token.clone(), backend);
+        say_something(&mut manager, 0);
+
+        let transcription = next_transcription(&mut rx_api).await;
+        assert_eq!(transcription.text(), " hello");
+        assert_eq!(transcription.audio_duration, Duration::from_secs(2));
+        assert_eq!(rx_requests.try_recv().unwrap().temperature, None);
+        assert_eq!(rx_requests.try_recv().unwrap().temperature, Some(0.4));
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_keeps_audio() {
+        let shutdown_token = CancellationToken::new();
+        let config = DiscrivenerConfig {
+            empty_response_policy: EmptyResponsePolicy::KeepAudio,
+            ..Default::default()
+        };
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[Answer::Empty], Answer::Echo(100));
+        let (mut manager, mut rx_api) =
+            make_manager_with_backend(config, shutdown_token.clone(), backend);
+        say_something(&mut manager, 0);
+        assert_eq!(rx_requests.recv().await.unwrap().temperature, None);
+        say_something(&mut manager, 2);
+
+        // the first two seconds are transcribed along with the next two
+        let transcription = next_transcription(&mut rx_api).await;
+        assert_eq!(transcription.audio_duration, Duration::from_secs(4));
+        shutdown_token.cancel();
     }
 }
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::model::types::Transcription;
+
+/// Holds on to finalized transcriptions for a little while before
+/// they're delivered, so that when several people are talking at once
+/// their transcriptions come out in the order they were said, rather
+/// than the order whisper finished them in.
+pub(crate) struct ReorderBuffer {
+    /// held transcriptions, each with when it has to be delivered by
+    held: Vec<(Instant, Transcription)>,
+
+    window: Duration,
+}
+
+impl ReorderBuffer {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            held: Vec::new(),
+            window,
+        }
+    }
+
+    pub(crate) fn push(&mut self, transcription: Transcription, now: Instant) {
+        self.held.push((now + self.window, transcription));
+    }
+
+    /// When the next transcription has to be delivered, if we're
+    /// holding any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.held.iter().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Removes the transcriptions which have been held for the whole
+    /// window, along with any which started before them, sorted by
+    /// when they started.
+    pub(crate) fn take_ready(&mut self, now: Instant) -> Vec<Transcription> {
+        let Some(latest_start) = self
+            .held
+            .iter()
+            .filter(|(deadline, _)| *deadline <= now)
+            .map(|(_, transcription)| transcription.start_timestamp)
+            .max()
+        else {
+            return Vec::new();
+        };
+        let (ready, held) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|(_, transcription)| transcription.start_timestamp <= latest_start);
+        self.held = held;
+        Self::sorted(ready)
+    }
+
+    /// Removes everything we're holding, sorted by when it started.
+    pub(crate) fn take_all(&mut self) -> Vec<Transcription> {
+        Self::sorted(std::mem::take(&mut self.held))
+    }
+
+    fn sorted(held: Vec<(Instant, Transcription)>) -> Vec<Transcription> {
+        let mut transcriptions = held
+            .into_iter()
+            .map(|(_, transcription)| transcription)
+            .collect::<Vec<_>>();
+        // stable, so transcriptions which started together stay in
+        // the order they arrived
+        transcriptions.sort_by_key(|transcription| transcription.start_timestamp);
+        transcriptions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn transcription_at(user_id: u64, start_ms: u64) -> Transcription {
+        Transcription {
+            start_timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(start_ms),
+            user_id,
+            segments: Vec::new(),
+            audio_duration: Duration::ZERO,
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        }
+    }
+
+    fn user_ids(transcriptions: &[Transcription]) -> Vec<u64> {
+        transcriptions
+            .iter()
+            .map(|transcription| transcription.user_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_held_until_window_passes() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(2));
+        buffer.push(transcription_at(1, 0), start);
+
+        assert!(buffer.take_ready(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(buffer.next_deadline(), Some(start + Duration::from_secs(2)));
+        assert_eq!(
+            user_ids(&buffer.take_ready(start + Duration::from_secs(2))),
+            vec![1]
+        );
+        assert_eq!(buffer.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_released_in_start_order() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(2));
+        // user 2 started talking later, but finished transcribing first
+        buffer.push(transcription_at(2, 5000), start);
+        buffer.push(transcription_at(1, 3000), start + Duration::from_secs(1));
+
+        assert_eq!(
+            user_ids(&buffer.take_ready(start + Duration::from_secs(2))),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_later_starts_stay_held() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(2));
+        buffer.push(transcription_at(1, 3000), start);
+        buffer.push(transcription_at(2, 5000), start + Duration::from_secs(1));
+
+        assert_eq!(
+            user_ids(&buffer.take_ready(start + Duration::from_secs(2))),
+            vec![1]
+        );
+        assert_eq!(user_ids(&buffer.take_all()), vec![2]);
+    }
+}
@@ -0,0 +1,627 @@
+use std::{
+    cmp::{max, min},
+    fmt::Write,
+};
+
+use crate::model::{
+    config::{TextEncoding, TextNormalizer},
+    types::TextSegment,
+};
+
+/// How many words back we look for repeats across a boundary.
+const MAX_OVERLAP_WORDS: usize = 10;
+
+/// Records how each segment's text is pronounced, as best espeak-ng
+/// can tell.  espeak-ng blocks while it works, so it's given a thread
+/// of its own.  If that thread fails, the segments are left without
+/// phonemes.
+#[cfg(feature = "phonemes")]
+pub(crate) async fn add_phonemes(mut segments: Vec<TextSegment>) -> Vec<TextSegment> {
+    let texts: Vec<String> = segments
+        .iter()
+        .map(|segment| segment.text().trim().to_string())
+        .collect();
+    let phonemes = tokio::task::spawn_blocking(move || {
+        texts
+            .iter()
+            .map(|text| crate::audio::espeakng::text_to_phonemes(text))
+            .collect::<Vec<_>>()
+    })
+    .await;
+    match phonemes {
+        Ok(phonemes) => {
+            for (segment, phonemes) in segments.iter_mut().zip(phonemes) {
+                segment.phonemes = phonemes;
+            }
+        }
+        Err(err) => eprintln!("failed to find phonemes, leaving them out: {}", err),
+    }
+    segments
+}
+
+/// Strips non-speech artifacts from the segment's text, recording the
+/// result as its cleaned text.  The raw token text is left untouched.
+pub(crate) fn clean_segment(segment: &mut TextSegment) {
+    let raw_text = segment.raw_text();
+    let cleaned_text = strip_non_speech_artifacts(raw_text.as_str());
+    if cleaned_text != raw_text {
+        segment.cleaned_text = Some(cleaned_text);
+    }
+}
+
+/// How many of the segments have something said in them, rather than
+/// being blank or only describing sounds.  Segments whisper was less
+/// sure of than `low_confidence_threshold` allows don't count.
+pub(crate) fn count_speech_segments(
+    segments: &[TextSegment],
+    low_confidence_threshold: Option<u32>,
+) -> usize {
+    let low_confidence_threshold = low_confidence_threshold.unwrap_or(100);
+    segments
+        .iter()
+        .filter(|segment| segment.low_confidence_p <= low_confidence_threshold)
+        .filter(|segment| {
+            let text = strip_non_speech_artifacts(segment.text().as_str());
+            !text.trim().is_empty()
+        })
+        .count()
+}
+
+/// Removes the bracketed or parenthesized sound descriptions, like
+/// `[BLANK_AUDIO]` or `(upbeat music)`, and the music notes which
+/// whisper produces when it hears something other than speech.
+pub(crate) fn strip_non_speech_artifacts(text: &str) -> String {
+    let mut kept = String::with_capacity(text.len());
+    // closing brackets we're waiting on, innermost last
+    let mut expected_closers = Vec::<char>::new();
+    // everything since the outermost open bracket, in case it's never closed
+    let mut bracketed = String::new();
+    let mut removed_any = false;
+    for c in text.chars() {
+        if let Some(closer) = closer_for(c) {
+            expected_closers.push(closer);
+            bracketed.push(c);
+            continue;
+        }
+        if !expected_closers.is_empty() {
+            bracketed.push(c);
+            if expected_closers.last() == Some(&c) {
+                expected_closers.pop();
+                if expected_closers.is_empty() {
+                    bracketed.clear();
+                    removed_any = true;
+                }
+            }
+            continue;
+        }
+        if is_music_note(c) {
+            removed_any = true;
+        } else {
+            kept.push(c);
+        }
+    }
+    if !removed_any {
+        return text.to_string();
+    }
+    // an unmatched bracket is more likely to be real text than an artifact
+    kept.push_str(bracketed.as_str());
+
+    // removing artifacts can leave runs of whitespace behind
+    collapse_whitespace(kept.as_str(), text)
+}
+
+/// Collapses the runs of whitespace left behind when parts of
+/// `original` were removed to make `text`.  Whisper starts segments
+/// with a space, so if `original` starts with one, so does the result,
+/// unless there's nothing else left.
+fn collapse_whitespace(text: &str, original: &str) -> String {
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !collapsed.is_empty() && original.starts_with(char::is_whitespace) {
+        format!(" {}", collapsed)
+    } else {
+        collapsed
+    }
+}
+
+/// Applies the normalizer to the segments of a single transcription,
+/// recording the results as their cleaned text, and dropping any
+/// segments it leaves with nothing in them.  Sentences can run from
+/// one segment into the next, so the segments need to be normalized
+/// together, in order.
+pub(crate) fn normalize_segments(segments: &mut Vec<TextSegment>, normalizer: &TextNormalizer) {
+    if *normalizer == TextNormalizer::default() {
+        return;
+    }
+    // the transcription starts a sentence
+    let mut sentence_start = true;
+    segments.retain_mut(|segment| {
+        let mut text = segment.text();
+        let had_text = !text.trim().is_empty();
+        if normalizer.capitalize_sentences {
+            text = capitalize_sentences(text.as_str(), &mut sentence_start);
+        }
+        if normalizer.strip_trailing_period && text.ends_with('.') && !text.ends_with("..") {
+            text.pop();
+        }
+        if normalizer.trim_leading_space {
+            text = text.trim_start().to_string();
+        }
+        text = encode_text(text, normalizer.encoding);
+        let emptied = had_text && text.trim().is_empty();
+        if text != segment.raw_text() {
+            segment.cleaned_text = Some(text);
+        }
+        !emptied
+    });
+}
+
+/// Encodes the text for delivery.  When transliterating, dropping
+/// words can leave runs of whitespace behind, which are collapsed.
+fn encode_text(text: String, encoding: TextEncoding) -> String {
+    if encoding == TextEncoding::Utf8 || text.is_ascii() {
+        return text;
+    }
+    let mut encoded = String::with_capacity(text.len());
+    let mut dropped = false;
+    for c in text.chars() {
+        if c.is_ascii() {
+            encoded.push(c);
+        } else if encoding == TextEncoding::AsciiEscape {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                write!(encoded, "\\u{:04x}", unit).unwrap();
+            }
+        } else if let Some(ascii) = transliterate(c) {
+            encoded.push_str(ascii);
+        } else {
+            dropped = true;
+        }
+    }
+    if !dropped {
+        return encoded;
+    }
+    collapse_whitespace(encoded.as_str(), text.as_str())
+}
+
+/// The closest ASCII to the character, for the accented Latin letters
+/// and punctuation whisper is likely to produce.  None for anything
+/// else, such as CJK text and emoji, which has no ASCII equivalent.
+fn transliterate(c: char) -> Option<&'static str> {
+    let ascii = match c {
+        'À'..='Å' => "A",
+        'Æ' => "AE",
+        'Ç' => "C",
+        'È'..='Ë' => "E",
+        'Ì'..='Ï' => "I",
+        'Ð' => "D",
+        'Ñ' => "N",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'Ù'..='Ü' => "U",
+        'Ý' => "Y",
+        'Þ' => "TH",
+        'ß' => "ss",
+        'à'..='å' => "a",
+        'æ' => "ae",
+        'ç' => "c",
+        'è'..='ë' => "e",
+        'ì'..='ï' => "i",
+        'ð' => "d",
+        'ñ' => "n",
+        'ò'..='ö' | 'ø' => "o",
+        'ù'..='ü' => "u",
+        'ý' | 'ÿ' => "y",
+        'þ' => "th",
+        'Œ' => "OE",
+        'œ' => "oe",
+        '\u{a0}' | '\u{3000}' => " ",
+        '\u{2018}' | '\u{2019}' | '\u{201a}' => "'",
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '「' | '」' => "\"",
+        '\u{2013}' | '\u{2014}' => "-",
+        '\u{2026}' => "...",
+        // CJK punctuation, which whisper uses even in mixed text
+        '。' | '．' => ".",
+        '、' | '，' => ",",
+        '？' => "?",
+        '！' => "!",
+        '：' => ":",
+        '；' => ";",
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+/// Capitalizes the first letter after each sentence start.  Whether
+/// we're at the start of a sentence carries over between calls.
+fn capitalize_sentences(text: &str, sentence_start: &mut bool) -> String {
+    let mut capitalized = String::with_capacity(text.len());
+    for c in text.chars() {
+        if *sentence_start && c.is_alphabetic() {
+            capitalized.extend(c.to_uppercase());
+            *sentence_start = false;
+            continue;
+        }
+        if matches!(c, '.' | '!' | '?') {
+            *sentence_start = true;
+        } else if !c.is_whitespace() && !matches!(c, '"' | '\'' | '(') {
+            // e.g. "3.5" isn't the end of a sentence
+            *sentence_start = false;
+        }
+        capitalized.push(c);
+    }
+    capitalized
+}
+
+/// `segments` were transcribed from audio which starts before
+/// `boundary_ms`, where `previous` ends, so whisper may have repeated
+/// some of the words from the end of `previous`.  Removes the longest
+/// run of words at the start of `segments` which matches the end of
+/// `previous`, along with any segments that leaves empty.
+pub(crate) fn trim_overlap(
+    previous: &[TextSegment],
+    boundary_ms: u32,
+    segments: &mut Vec<TextSegment>,
+) {
+    if !segments
+        .first()
+        .is_some_and(|segment| segment.start_offset_ms < boundary_ms)
+    {
+        // nothing overlaps
+        return;
+    }
+
+    let previous_words = previous
+        .iter()
+        .flat_map(|segment| segment.tokens_with_probability.iter())
+        .map(|token| normalize_word(token.token_text.as_str()))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<String>>();
+    let previous_words = &previous_words[previous_words.len().saturating_sub(MAX_OVERLAP_WORDS)..];
+    // where each of the new words is, as (segment, token) indices
+    let new_words = segments
+        .iter()
+        .enumerate()
+        .flat_map(|(i, segment)| {
+            segment
+                .tokens_with_probability
+                .iter()
+                .enumerate()
+                .map(move |(j, token)| ((i, j), normalize_word(token.token_text.as_str())))
+        })
+        .filter(|(_, word)| !word.is_empty())
+        .take(MAX_OVERLAP_WORDS)
+        .collect::<Vec<((usize, usize), String)>>();
+
+    let Some(overlap) = (1..=min(previous_words.len(), new_words.len()))
+        .rev()
+        .find(|len| {
+            previous_words[previous_words.len() - len..]
+                .iter()
+                .eq(new_words[..*len].iter().map(|(_, word)| word))
+        })
+    else {
+        return;
+    };
+
+    let (last_segment, last_token) = new_words[overlap - 1].0;
+    segments.drain(..last_segment);
+    let first = &mut segments[0];
+    first.tokens_with_probability.drain(..=last_token);
+    if first.tokens_with_probability.is_empty() {
+        segments.remove(0);
+    } else {
+        // what's left of it comes after the repeated words
+        first.start_offset_ms = min(max(first.start_offset_ms, boundary_ms), first.end_offset_ms);
+    }
+}
+
+/// Reduces a token to the letters and digits in it, so that the same
+/// word is recognized whatever the case or punctuation around it.
+fn normalize_word(token_text: &str) -> String {
+    token_text
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn closer_for(c: char) -> Option<char> {
+    match c {
+        '[' => Some(']'),
+        '(' => Some(')'),
+        _ => None,
+    }
+}
+
+fn is_music_note(c: char) -> bool {
+    matches!(c, '♩' | '♪' | '♫' | '♬')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::types::TokenWithProbability;
+
+    #[test]
+    fn test_strip_common_artifacts() {
+        assert_eq!(strip_non_speech_artifacts(" [BLANK_AUDIO]"), "");
+        assert_eq!(strip_non_speech_artifacts(" (music)"), "");
+        assert_eq!(strip_non_speech_artifacts(" ♪ ♪"), "");
+        assert_eq!(
+            strip_non_speech_artifacts(" [Music] Hello there (upbeat music) ♪"),
+            " Hello there"
+        );
+        assert_eq!(
+            strip_non_speech_artifacts(" Nested [sound (laughs)] text"),
+            " Nested text"
+        );
+    }
+
+    #[test]
+    fn test_strip_leaves_speech_alone() {
+        assert_eq!(
+            strip_non_speech_artifacts(" Hello, how are you?"),
+            " Hello, how are you?"
+        );
+        // an unclosed bracket is kept
+        assert_eq!(
+            strip_non_speech_artifacts(" I think (maybe"),
+            " I think (maybe"
+        );
+    }
+
+    #[test]
+    fn test_clean_segment_keeps_raw_text() {
+        let mut segment = TextSegment {
+            tokens_with_probability: vec![
+                TokenWithProbability {
+                    p: 90,
+                    token_id: 0,
+                    token_text: " Hi".to_string(),
+                },
+                TokenWithProbability {
+                    p: 90,
+                    token_id: 1,
+                    token_text: " (laughs)".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        clean_segment(&mut segment);
+        assert_eq!(segment.text(), " Hi");
+        assert_eq!(segment.raw_text(), " Hi (laughs)");
+
+        let mut speech = TextSegment {
+            tokens_with_probability: vec![TokenWithProbability {
+                p: 90,
+                token_id: 0,
+                token_text: " Hi".to_string(),
+            }],
+            ..Default::default()
+        };
+        clean_segment(&mut speech);
+        assert_eq!(speech.cleaned_text, None);
+    }
+
+    #[test]
+    fn test_clean_segment_leaves_spacing_alone() {
+        let mut segment = TextSegment {
+            tokens_with_probability: vec![
+                TokenWithProbability {
+                    p: 90,
+                    token_id: 0,
+                    token_text: " Hello ".to_string(),
+                },
+                TokenWithProbability {
+                    p: 90,
+                    token_id: 1,
+                    token_text: " there ".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        clean_segment(&mut segment);
+        assert_eq!(segment.cleaned_text, None);
+        assert_eq!(segment.text(), " Hello  there ");
+    }
+
+    fn segment_with_words(start_offset_ms: u32, end_offset_ms: u32, words: &[&str]) -> TextSegment {
+        TextSegment {
+            start_offset_ms,
+            end_offset_ms,
+            tokens_with_probability: words
+                .iter()
+                .map(|word| TokenWithProbability {
+                    p: 90,
+                    token_id: 0,
+                    token_text: word.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_trim_overlap() {
+        let previous = vec![segment_with_words(0, 1000, &[" Hello", " there", " the"])];
+
+        // "the" was heard on both sides of the boundary
+        let mut segments = vec![
+            segment_with_words(800, 1500, &[" The", " world"]),
+            segment_with_words(1500, 2000, &[" again."]),
+        ];
+        trim_overlap(&previous, 1000, &mut segments);
+        assert_eq!(
+            segments,
+            vec![
+                segment_with_words(1000, 1500, &[" world"]),
+                segment_with_words(1500, 2000, &[" again."]),
+            ]
+        );
+
+        // a repeat can take up a whole segment
+        let mut segments = vec![
+            segment_with_words(500, 1000, &[" there", " the"]),
+            segment_with_words(1000, 1500, &[" world"]),
+        ];
+        trim_overlap(&previous, 1000, &mut segments);
+        assert_eq!(segments, vec![segment_with_words(1000, 1500, &[" world"])]);
+    }
+
+    #[test]
+    fn test_trim_overlap_leaves_new_words() {
+        let previous = vec![segment_with_words(0, 1000, &[" Hello", " there"])];
+
+        // starting after the boundary, even a repeated word is new
+        let mut segments = vec![segment_with_words(1000, 1500, &[" there"])];
+        trim_overlap(&previous, 1000, &mut segments);
+        assert_eq!(segments, vec![segment_with_words(1000, 1500, &[" there"])]);
+
+        // overlapping, but with nothing in common
+        let mut segments = vec![segment_with_words(900, 1500, &[" world"])];
+        trim_overlap(&previous, 1000, &mut segments);
+        assert_eq!(segments, vec![segment_with_words(900, 1500, &[" world"])]);
+    }
+
+    fn normalized(texts: &[&str], normalizer: TextNormalizer) -> Vec<String> {
+        let mut segments = texts
+            .iter()
+            .map(|text| segment_with_words(0, 1000, &[text]))
+            .collect::<Vec<TextSegment>>();
+        normalize_segments(&mut segments, &normalizer);
+        segments.iter().map(|segment| segment.text()).collect()
+    }
+
+    #[test]
+    fn test_normalize_segments() {
+        let everything = TextNormalizer {
+            capitalize_sentences: true,
+            strip_trailing_period: true,
+            trim_leading_space: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalized(
+                &[" so i said. no way,", " and then she", " left. bye."],
+                everything
+            ),
+            vec!["So i said. No way,", "and then she", "left. Bye"]
+        );
+        assert_eq!(
+            normalized(
+                &[" It costs 3.50 dollars...", " \"really?\" yes."],
+                everything
+            ),
+            vec!["It costs 3.50 dollars...", "\"Really?\" Yes"]
+        );
+
+        let capitalize = TextNormalizer {
+            capitalize_sentences: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalized(&[" hello there."], capitalize),
+            vec![" Hello there."]
+        );
+    }
+
+    #[test]
+    fn test_normalize_segments_keeps_raw_text() {
+        let mut segments = vec![segment_with_words(0, 1000, &[" hello", " there."])];
+        normalize_segments(
+            &mut segments,
+            &TextNormalizer {
+                strip_trailing_period: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(segments[0].text(), " hello there");
+        assert_eq!(segments[0].raw_text(), " hello there.");
+
+        // nothing to change
+        let mut segments = vec![segment_with_words(0, 1000, &[" Hello"])];
+        normalize_segments(&mut segments, &TextNormalizer::default());
+        assert_eq!(segments[0].cleaned_text, None);
+    }
+
+    #[test]
+    fn test_text_encoding() {
+        let encoded = |texts: &[&str], encoding| {
+            normalized(
+                texts,
+                TextNormalizer {
+                    encoding,
+                    ..Default::default()
+                },
+            )
+        };
+        let texts = [" 你好, world 👋", " café “déjà vu”… 😀", " 東京。"];
+        assert_eq!(encoded(&texts, TextEncoding::Utf8), texts);
+        assert_eq!(
+            encoded(&texts, TextEncoding::AsciiTranslit),
+            vec![" , world", " cafe \"deja vu\"...", " ."]
+        );
+        assert_eq!(
+            encoded(&texts, TextEncoding::AsciiEscape),
+            vec![
+                " \\u4f60\\u597d, world \\ud83d\\udc4b",
+                " caf\\u00e9 \\u201cd\\u00e9j\\u00e0 vu\\u201d\\u2026 \\ud83d\\ude00",
+                " \\u6771\\u4eac\\u3002",
+            ]
+        );
+
+        // segments left with nothing are dropped, and the raw text of
+        // the rest is still there in UTF-8
+        let mut segments = vec![
+            segment_with_words(0, 1000, &[" 你好", " 😀"]),
+            segment_with_words(1000, 2000, &[" café"]),
+        ];
+        normalize_segments(
+            &mut segments,
+            &TextNormalizer {
+                encoding: TextEncoding::AsciiTranslit,
+                ..Default::default()
+            },
+        );
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].text(), " cafe");
+        assert_eq!(segments[0].raw_text(), " café");
+    }
+
+    #[test]
+    fn test_count_speech_segments() {
+        let segments = vec![
+            segment_with_words(0, 1000, &[" Hello"]),
+            segment_with_words(1000, 2000, &[" [BLANK_AUDIO]"]),
+            segment_with_words(2000, 3000, &[" "]),
+            TextSegment {
+                low_confidence_p: 80,
+                ..segment_with_words(3000, 4000, &[" Thank", " you."])
+            },
+        ];
+        assert_eq!(count_speech_segments(&segments, None), 2);
+        assert_eq!(count_speech_segments(&segments, Some(60)), 1);
+        assert_eq!(count_speech_segments(&segments[1..3], None), 0);
+    }
+
+    #[cfg(feature = "phonemes")]
+    #[tokio::test]
+    async fn test_add_phonemes() {
+        let segments = add_phonemes(vec![
+            segment_with_words(0, 1000, &[" hello", " world"]),
+            segment_with_words(1000, 2000, &[" cat"]),
+            segment_with_words(2000, 3000, &[]),
+        ])
+        .await;
+        let phonemes = segments
+            .into_iter()
+            .map(|segment| segment.phonemes)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            phonemes,
+            vec![
+                Some("həlˈəʊ wˈɜːld".to_string()),
+                Some("kˈat".to_string()),
+                None
+            ]
+        );
+    }
+}
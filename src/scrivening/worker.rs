@@ -1,4 +1,12 @@
-use std::{collections::VecDeque, sync::Arc, time::Duration};
+use std::{
+    cmp::{max, min},
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 
 use futures::{stream::FuturesUnordered, TryStreamExt};
 use tokio::{
@@ -14,32 +22,161 @@ use whisper_rs::WhisperToken;
 
 use crate::{
     audio::{
-        audio_buffer::AudioBuffer,
-        events::{DiscordAudioData, TranscriptionResponse, UserAudioEventType},
+        audio_buffer::{AudioBuffer, AudioBufferPool},
+        backend::TranscriptionBackend,
+        events::{ChannelFlushReply, TranscriptionResponse, UserAudioData, UserAudioEventType},
     },
     model::{
+        config::{DiscrivenerConfig, EmptyResponsePolicy, FinalizationMode, TranscriptionMode},
         constants::{TOKENS_TO_KEEP, USER_SILENCE_TIMEOUT},
-        types::{TextSegment, TokenWithProbability, Transcription, UserId, VoiceChannelEvent},
+        metrics::MetricsCounters,
+        types::{
+            Ssrc, TextSegment, TokenWithProbability, Transcription, UserId, VoiceChannelEvent,
+            WhisperTiming,
+        },
     },
     strategies::strategy_trait::{TranscriptStrategy, WorkerActions, WorkerContext},
 };
 
-use super::super::Whisper;
+#[cfg(feature = "phonemes")]
+use super::text::add_phonemes;
+use super::text::{clean_segment, count_speech_segments, normalize_segments, trim_overlap};
 
 pub(crate) struct UserAudioWorker {
     audio_buffer: AudioBuffer,
 
+    /// our audio buffer's storage goes back here when we exit
+    audio_buffer_pool: AudioBufferPool,
+
+    /// in `ChannelMode::Split`, which of the user's channels we're
+    /// transcribing
+    audio_channel: Option<u8>,
+
+    /// whole-channel flushes we'll hand our held transcriptions to
+    /// once we've finalized our audio, by id and whether they're
+    /// finalizing the session
+    channel_flushes: Vec<(u64, bool)>,
+
+    config: Arc<DiscrivenerConfig>,
+
+    /// in whole-channel mode, what we've finalized since the last
+    /// whole-channel flush
+    held_transcriptions: Vec<Transcription>,
+
+    /// when we last warned that the user's audio is clipped
+    last_clipping_warning: Option<Instant>,
+
+    last_request: Option<LastRequestInfo>,
+
     last_tokens: BoundedTokenBuffer,
 
+    metrics: Arc<MetricsCounters>,
+
+    /// audio from a new stream, waiting for us to finish with the
+    /// audio from the old one
+    next_stream: Option<NextStream>,
+
+    /// how much of the start of our buffer is audio which has already
+    /// been published, and was only kept for context
+    published_tail: Duration,
+
+    /// how many of our transcription requests are counted in the
+    /// metrics as pending
+    requests_in_flight: usize,
+
+    /// how much of our audio is counted in the metrics as buffered
+    reported_buffered_audio: Duration,
+
+    /// when set, our next request is a retry of audio whisper found
+    /// nothing in, at this temperature
+    retry_temperature: Option<f32>,
+
     shutdown_token: CancellationToken,
 
-    whisper: Arc<Whisper>,
+    /// where we add up how long the user has spent talking
+    speaking_time: Arc<SpeakingTime>,
+
+    /// what we're up to, for the manager to read
+    status: Arc<WorkerStatus>,
+
+    /// the stream which the audio in our buffer came from
+    ssrc: Option<Ssrc>,
+
+    /// in per-user mode, while the session is being finalized, copies
+    /// of what we've published since, to hand back to it
+    session_transcriptions: Vec<Transcription>,
+
+    /// true once we've told the strategy about the current run of
+    /// trailing silence, so that we only tell it once.
+    trailing_silence_reported: bool,
+
+    /// true while the user is talking, so anything we transcribe
+    /// is only an interim result
+    speaking: bool,
+
+    /// in incremental mode, what we've already transcribed from the
+    /// start of the buffer
+    transcribed_prefix: TranscribedPrefix,
+
+    transcription_backend: Arc<dyn TranscriptionBackend>,
+
+    /// true while the user's transcription is disabled, so that any
+    /// of their audio which was already on its way is dropped
+    transcription_disabled: bool,
+
+    tx_flush: UnboundedSender<ChannelFlushReply>,
+
+    /// given to everything we publish from our current audio
+    utterance_id: u64,
+
+    /// where our utterance ids come from
+    utterance_ids: Arc<UtteranceIds>,
+}
+
+/// What a worker is up to, kept where the manager can read it without
+/// interrupting the worker.
+pub(crate) struct WorkerStatus {
+    /// how much memory the worker's buffered audio takes up
+    pub buffered_bytes: AtomicUsize,
+    /// the stream the worker's audio came from, or NO_SSRC
+    ssrc: AtomicU64,
+    /// whether the worker is waiting on a transcription
+    pub transcribing: AtomicBool,
+}
+
+/// stands in for the worker not having a stream, as no real ssrc is
+/// this big
+const NO_SSRC: u64 = u64::MAX;
+
+impl WorkerStatus {
+    pub fn new() -> Self {
+        Self {
+            buffered_bytes: AtomicUsize::new(0),
+            ssrc: AtomicU64::new(NO_SSRC),
+            transcribing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn ssrc(&self) -> Option<Ssrc> {
+        match self.ssrc.load(Ordering::Relaxed) {
+            NO_SSRC => None,
+            ssrc => Some(ssrc as Ssrc),
+        }
+    }
+
+    pub fn update(&self, buffered_bytes: usize, ssrc: Option<Ssrc>, transcribing: bool) {
+        self.buffered_bytes.store(buffered_bytes, Ordering::Relaxed);
+        self.ssrc
+            .store(ssrc.map_or(NO_SSRC, u64::from), Ordering::Relaxed);
+        self.transcribing.store(transcribing, Ordering::Relaxed);
+    }
 }
 
 impl Drop for UserAudioWorker {
     fn drop(&mut self) {
         // make our worker task exit
         self.shutdown_token.cancel();
+        self.audio_buffer_pool.release(&mut self.audio_buffer);
     }
 }
 
@@ -47,34 +184,135 @@ impl Drop for UserAudioWorker {
 /// This is expressed as a percentage of the audio buffer duration.
 const FAILSAFE_TRANSCRIPTION_PERCENTAGE: f32 = 0.8;
 
+/// if we skip an interim transcription request because the backend
+/// is backed up, we'll try again after this long
+const SHED_REQUEST_RETRY: Duration = Duration::from_secs(1);
+
+/// if a transcription request times out or fails, we'll ask about its
+/// audio again after this long
+const FAILED_REQUEST_RETRY: Duration = Duration::from_secs(1);
+
+/// a user is warned that their audio is clipped at most this often
+const CLIPPING_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how much audio we need before judging whether it's clipped, so that
+/// a single loud click isn't enough
+const CLIPPING_MIN_AUDIO: Duration = Duration::from_secs(1);
+
 /// if we have this many tokens in a single segment, we'll assume the
 /// AI is hallucinating and ignore it
 const OUTRAGEOUSLY_MANY_TOKENS: usize = 100;
 
+/// Hands out a user's utterance ids, counting up from 1.  This is
+/// shared by all of the user's workers, and the manager keeps it for
+/// the whole session, so that a user's ids are never reused, even if
+/// they leave and come back.
+#[derive(Debug, Default)]
+pub(crate) struct UtteranceIds(AtomicU64);
+
+impl UtteranceIds {
+    fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// How long each user has spent talking over the session, counting
+/// only the audio behind their finalized transcriptions, less any
+/// silence before or after what they said.  Shared by every worker.
+///
+/// In `ChannelMode::Split`, each of a user's channels is counted on its
+/// own, as both can hear the same speech, and the user's time is that
+/// of whichever channel has the most.
+#[derive(Debug, Default)]
+pub(crate) struct SpeakingTime(Mutex<BTreeMap<(UserId, Option<u8>), Duration>>);
+
+impl SpeakingTime {
+    fn add(&self, user_id: UserId, audio_channel: Option<u8>, speech: Duration) {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry((user_id, audio_channel))
+            .or_default() += speech;
+    }
+
+    pub fn get(&self, user_id: UserId) -> Duration {
+        self.0
+            .lock()
+            .unwrap()
+            .range((user_id, None)..=(user_id, Some(u8::MAX)))
+            .map(|(_, speech)| *speech)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<UserId, Duration> {
+        let mut snapshot = BTreeMap::<UserId, Duration>::new();
+        for ((user_id, _), speech) in self.0.lock().unwrap().iter() {
+            let most = snapshot.entry(*user_id).or_default();
+            *most = max(*most, *speech);
+        }
+        snapshot
+    }
+}
+
 impl UserAudioWorker {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn monitor<T>(
+        audio_buffer: AudioBuffer,
+        audio_buffer_pool: AudioBufferPool,
+        audio_channel: Option<u8>,
+        config: Arc<DiscrivenerConfig>,
+        metrics: Arc<MetricsCounters>,
         shutdown_token: CancellationToken,
+        speaking_time: Arc<SpeakingTime>,
+        status: Arc<WorkerStatus>,
         transcript_strategy: T,
+        transcription_backend: Arc<dyn TranscriptionBackend>,
         tx_api: UnboundedSender<VoiceChannelEvent>,
-        user_id: UserId,
-        whisper: Arc<Whisper>,
+        tx_flush: UnboundedSender<ChannelFlushReply>,
+        utterance_ids: Arc<UtteranceIds>,
     ) -> (
         UnboundedSender<UserAudioEventType>,
-        UnboundedSender<DiscordAudioData>,
+        UnboundedSender<UserAudioData>,
     )
     where
         T: TranscriptStrategy + Send + Sync + 'static,
     {
         let (tx_event, rx_event) = sync::mpsc::unbounded_channel::<UserAudioEventType>();
-        let (tx_audio, rx_audio) = sync::mpsc::unbounded_channel::<DiscordAudioData>();
+        let (tx_audio, rx_audio) = sync::mpsc::unbounded_channel::<UserAudioData>();
 
         // start our worker thread
         tokio::spawn(
             Self {
-                audio_buffer: AudioBuffer::new(user_id),
+                audio_buffer,
+                audio_buffer_pool,
+                audio_channel,
+                channel_flushes: Vec::new(),
+                config,
+                held_transcriptions: Vec::new(),
+                last_clipping_warning: None,
+                last_request: None,
                 last_tokens: BoundedTokenBuffer::new(),
+                metrics,
+                next_stream: None,
+                published_tail: Duration::ZERO,
+                reported_buffered_audio: Duration::ZERO,
+                requests_in_flight: 0,
+                retry_temperature: None,
+                session_transcriptions: Vec::new(),
                 shutdown_token,
-                whisper,
+                speaking: false,
+                speaking_time,
+                ssrc: None,
+                status,
+                trailing_silence_reported: false,
+                transcribed_prefix: TranscribedPrefix::default(),
+                transcription_backend,
+                transcription_disabled: false,
+                tx_flush,
+                utterance_id: utterance_ids.next(),
+                utterance_ids,
             }
             .loop_forever(rx_event, rx_audio, transcript_strategy, tx_api),
         );
@@ -84,7 +322,7 @@ impl UserAudioWorker {
     async fn loop_forever<T>(
         mut self,
         mut rx_event: UnboundedReceiver<UserAudioEventType>,
-        mut rx_audio: UnboundedReceiver<DiscordAudioData>,
+        mut rx_audio: UnboundedReceiver<UserAudioData>,
         mut transcript_strategy: T,
         tx_api: UnboundedSender<VoiceChannelEvent>,
     ) where
@@ -99,6 +337,11 @@ impl UserAudioWorker {
         let next_transcription_time = time::sleep_until(never);
         tokio::pin!(next_transcription_time);
 
+        // when we give up on the request we're waiting on, if there's
+        // a timeout
+        let request_deadline = time::sleep_until(never);
+        tokio::pin!(request_deadline);
+
         loop {
             if let Some(actions) = tokio::select! {
                 _ = self.shutdown_token.cancelled() => {
@@ -106,46 +349,242 @@ impl UserAudioWorker {
                     break;
                 }
                 _ = &mut next_transcription_time => {
-                    // request transcription
-                    if let Some(transcription_request) = self.audio_buffer.make_transcription_request(
-                        self.last_tokens.get(),
+                    // request transcription, unless we'd just be asking
+                    // about the exact same audio as last time.  If we're
+                    // finishing up a stream, we need the request regardless.
+                    let buffer_duration = self.audio_buffer.buffer_duration();
+                    // a retry is for the same audio on purpose
+                    let is_duplicate = self.next_stream.is_none()
+                        && self.retry_temperature.is_none()
+                        && self
+                            .last_request
+                            .as_ref()
+                            .is_some_and(|last| last.is_duplicate(&buffer_duration));
+                    let mut buffer_offset = self
+                        .transcribed_prefix
+                        .request_offset(&self.config.transcription_mode, &self.pauses());
+                    if self.config.trim_leading_silence {
+                        buffer_offset = self.audio_buffer.skip_leading_silence(&buffer_offset);
+                    }
+                    let mut previous_tokens = self.last_tokens.get();
+                    previous_tokens.extend(self.transcribed_prefix.token_ids());
+                    let is_interim = self.speaking && self.next_stream.is_none();
+                    if should_shed_request(
+                        is_interim,
+                        self.metrics.pending_requests(),
+                        self.config.interim_request_high_water_mark,
                     ) {
-                        if pending_transcription_requests.is_empty() {
+                        // the backend is behind, so leave it to catch up
+                        // on the requests which will be published
+                        self.metrics.record_request_shed();
+                        next_transcription_time
+                            .as_mut()
+                            .reset(time::Instant::now() + SHED_REQUEST_RETRY);
+                        None
+                    } else if let Some(mut transcription_request) =
+                        self.audio_buffer.make_transcription_request(
+                            self.config.audio_payload_format,
+                            &buffer_offset,
+                            previous_tokens,
+                        )
+                    {
+                        if pending_transcription_requests.is_empty() && !is_duplicate {
+                            // only once it's actually sent is the retry done
+                            let retry_temperature = self.retry_temperature.take();
+                            let mut last_request = LastRequestInfo::new(
+                                buffer_duration,
+                                self.audio_buffer.stream_position(),
+                            );
+                            last_request.is_retry = retry_temperature.is_some();
+                            self.last_request = Some(last_request);
+                            transcription_request.is_interim = is_interim;
+                            transcription_request.temperature = retry_temperature;
+                            if self.config.transcription_progress {
+                                transcription_request.progress = Some(tx_api.clone());
+                            }
                             pending_transcription_requests.push(
-                                self.whisper
+                                self.transcription_backend
                                 .process_transcription_request(transcription_request)
                             );
+                            if let Some(timeout) = self.config.transcription_timeout {
+                                request_deadline.as_mut().reset(time::Instant::now() + timeout);
+                            }
+                            if let Some(next_stream) = self.next_stream.as_mut() {
+                                next_stream.final_request_sent = true;
+                            }
                         }
+                        next_transcription_time.as_mut().reset(never);
+                        None
+                    } else {
+                        next_transcription_time.as_mut().reset(never);
+                        // nothing left of the old stream to transcribe
+                        self.start_next_stream(None, &mut transcript_strategy, &tx_api)
+                            .await
                     }
-                    next_transcription_time.as_mut().reset(never);
-                    None
-                }
-                Some(DiscordAudioData{discord_audio,rtc_timestamp,..}) = rx_audio.recv() => {
-                    self.audio_buffer.add_audio(&rtc_timestamp, discord_audio.as_slice());
-                    None
                 }
-                Some(event) = rx_event.recv() => {
-                    transcript_strategy.handle_event(&event, &self.audio_buffer.buffer_duration())
+                Some(audio) = rx_audio.recv() => {
+                    if self.transcription_disabled {
+                        // sent before they were disabled
+                        continue;
+                    }
+                    let actions = self.handle_audio(audio, &mut transcript_strategy);
+                    self.check_clipping(&tx_api);
+                    actions
                 }
-                Ok(Some(TranscriptionResponse{ transcript })) = pending_transcription_requests.try_next() => {
-                    // we got a transcription response, determine if it's a final transcription
-                    // and if so send it to the API
-                    if !transcript.is_empty() {
-                        eprintln!(
-                            "received transcription ({:?} ms): {}",
-                            transcript.audio_duration,
-                            transcript.text()
-                        );
-                        self.print_rms(&transcript);
+                event = rx_event.recv() => {
+                    let Some(event) = event else {
+                        // the manager has forgotten about us, so no
+                        // more events or audio are coming
+                        self.report_discarded_audio(&tx_api);
+                        break;
+                    };
+                    if let UserAudioEventType::EvictAudio { max_bytes } = event {
+                        if self.audio_buffer.duration_over(max_bytes).is_zero() {
+                            // we've already thrown away what we were asked to
+                            continue;
+                        }
+                        // whatever we're waiting on covers audio we're
+                        // about to lose, so ask again about what's left
+                        let was_waiting = !pending_transcription_requests.is_empty();
+                        pending_transcription_requests.clear();
+                        self.evict_audio(max_bytes, &tx_api);
+                        if was_waiting || self.next_stream.is_some() {
+                            next_transcription_time.as_mut().reset(time::Instant::now());
+                        }
                     }
+                    if matches!(
+                        event,
+                        UserAudioEventType::TranscriptionDisabled
+                            | UserAudioEventType::PipelineReset
+                    ) {
+                        // forget what we have, and what we're waiting on
+                        pending_transcription_requests.clear();
+                        next_transcription_time.as_mut().reset(never);
+                        self.next_stream = None;
+                        self.reset_buffer();
+                        self.held_transcriptions.clear();
+                        self.session_transcriptions.clear();
+                        self.answer_channel_flushes();
+                    }
+                    if event == UserAudioEventType::PipelineReset {
+                        self.last_tokens = BoundedTokenBuffer::new();
+                    }
+                    match event {
+                        UserAudioEventType::TranscriptionDisabled => {
+                            self.transcription_disabled = true
+                        }
+                        UserAudioEventType::TranscriptionEnabled => {
+                            self.transcription_disabled = false
+                        }
+                        UserAudioEventType::Speaking => self.speaking = true,
+                        UserAudioEventType::Silent | UserAudioEventType::Idle => {
+                            self.speaking = false;
+                            self.reset_hollow_buffer();
+                        }
+                        _ => {}
+                    }
+                    let actions = transcript_strategy
+                        .handle_event(&event, &self.audio_buffer.buffer_duration());
+                    match event {
+                        UserAudioEventType::UtteranceBoundary => self.end_utterance(),
+                        UserAudioEventType::ChannelIdle {
+                            flush_id, session, ..
+                        } => {
+                            let actions = self.end_utterance();
+                            self.channel_flushes.push((flush_id, session));
+                            if self.next_stream.is_none() {
+                                // there was nothing left to finalize
+                                self.answer_channel_flushes();
+                            }
+                            actions
+                        }
+                        _ => actions,
+                    }
+                }
+                _ = &mut request_deadline, if !pending_transcription_requests.is_empty() => {
+                    // stop waiting, and ask about the same audio again
+                    pending_transcription_requests.clear();
+                    self.report_transcription_timeout(&tx_api);
+                    self.retry_failed_request()
+                }
+                Ok(Some(mut response)) = pending_transcription_requests.try_next() => {
+                    let is_final_request = self
+                        .next_stream
+                        .as_ref()
+                        .is_some_and(|next_stream| next_stream.final_request_sent);
+                    if let Some(error) = response.error {
+                        // nothing was transcribed, so ask again
+                        self.report_transcription_error(error, &tx_api);
+                        self.retry_failed_request()
+                    } else if let Some(actions) =
+                        self.handle_empty_response(&response, is_final_request)
+                    {
+                        Some(actions)
+                    } else {
+                        self.metrics.record_inference(response.transcript.processing_time);
+                        if self.config.speech_segments {
+                            self.report_speech_segments(&response.transcript, &tx_api);
+                        }
+                        if let Some(last_request) = self.last_request.as_ref() {
+                            last_request.record_whisper_timing(&mut response);
+                        }
+                        // we got a transcription response, determine if it's a final transcription
+                        // and if so send it to the API
+                        let mut transcript = match self.config.transcription_mode {
+                            // the request may have skipped some leading silence
+                            TranscriptionMode::WholeBuffer => from_buffer_start(response),
+                            TranscriptionMode::Incremental { .. }
+                            | TranscriptionMode::Streaming { .. } => {
+                                let pauses = self.pauses();
+                                self.transcribed_prefix.merge(response, &pauses)
+                            }
+                        };
+                        if let Some(last_request) = self.last_request.as_ref() {
+                            last_request.place_segments(&mut transcript);
+                        }
+                        if !transcript.is_empty() {
+                            eprintln!(
+                                "received transcription ({:?} ms): {}",
+                                transcript.audio_duration,
+                                transcript.text()
+                            );
+                            self.print_rms(&transcript);
+                        }
 
-                    transcript_strategy.handle_transcription(&transcript, WorkerContext {
-                        audio_duration: self.audio_buffer.buffer_duration(),
-                        silent_after: self.audio_buffer.is_interval_silent(
-                            &transcript.audio_duration,
-                            &USER_SILENCE_TIMEOUT,
-                        )
-                    })
+                        if is_final_request {
+                            // this covers everything the old stream had
+                            self.start_next_stream(
+                                Some(transcript),
+                                &mut transcript_strategy,
+                                &tx_api,
+                            )
+                            .await
+                        } else {
+                            let audio_duration = self.audio_buffer.buffer_duration();
+                            let context = WorkerContext {
+                                audio_duration,
+                                requested_duration: self
+                                    .last_request
+                                    .as_ref()
+                                    .map_or(audio_duration, LastRequestInfo::effective_duration),
+                                silent_after: self.audio_buffer.is_interval_silent(
+                                    &transcript.audio_duration,
+                                    &USER_SILENCE_TIMEOUT,
+                                )
+                            };
+                            let actions =
+                                transcript_strategy.handle_transcription(&transcript, context);
+                            if self.next_stream.is_some() {
+                                // this was requested before the stream changed,
+                                // so we still need to ask about the rest of it
+                                let mut actions = actions.unwrap_or_default();
+                                actions.push(WorkerActions::NewTranscript(Some(Duration::ZERO)));
+                                Some(actions)
+                            } else {
+                                actions
+                            }
+                        }
+                    }
                 }
             } {
                 for action in actions {
@@ -157,13 +596,22 @@ impl UserAudioWorker {
                             );
                         }
                         WorkerActions::Publish(transcription) => {
-                            self.publish(transcription, &tx_api);
+                            self.publish(transcription, &tx_api).await;
                         }
                     }
                 }
             }
+            self.sync_pending_requests(pending_transcription_requests.len());
+            self.sync_buffered_audio(self.audio_buffer.buffer_duration());
+            self.status.update(
+                self.audio_buffer.buffer_bytes(),
+                self.ssrc,
+                !pending_transcription_requests.is_empty(),
+            );
             // sanity check on the pending transcription requests
-            if !self.audio_buffer.is_empty() && pending_transcription_requests.is_empty() {
+            if self.audio_buffer.buffer_duration() > self.published_tail
+                && pending_transcription_requests.is_empty()
+            {
                 let next_transcription_delay = next_transcription_time
                     .deadline()
                     .duration_since(Instant::now());
@@ -179,6 +627,518 @@ impl UserAudioWorker {
             }
         }
         // exit!
+        self.sync_pending_requests(0);
+        self.sync_buffered_audio(Duration::ZERO);
+        self.release_held_transcriptions(&tx_api);
+    }
+
+    /// Brings the shared count of pending transcription requests in
+    /// line with how many we're actually waiting on.
+    fn sync_pending_requests(&mut self, in_flight: usize) {
+        if in_flight > self.requests_in_flight {
+            self.metrics
+                .record_requests_sent((in_flight - self.requests_in_flight) as u64);
+        } else if in_flight < self.requests_in_flight {
+            self.metrics
+                .record_requests_finished((self.requests_in_flight - in_flight) as u64);
+        }
+        self.requests_in_flight = in_flight;
+    }
+
+    /// Brings the shared count of buffered audio in line with how
+    /// much we actually have.
+    fn sync_buffered_audio(&mut self, buffered: Duration) {
+        // the metrics count whole microseconds, so only ever hand them
+        // whole microseconds, or the total would drift
+        let buffered = Duration::from_micros(buffered.as_micros() as u64);
+        if buffered > self.reported_buffered_audio {
+            self.metrics
+                .record_audio_buffered(buffered - self.reported_buffered_audio);
+        } else if buffered < self.reported_buffered_audio {
+            self.metrics
+                .record_audio_unbuffered(self.reported_buffered_audio - buffered);
+        }
+        self.reported_buffered_audio = buffered;
+    }
+
+    /// Adds the audio to our buffer, unless it's from a different
+    /// stream than the audio we already have, or comes too long after
+    /// it.
+    fn handle_audio<T>(
+        &mut self,
+        audio: UserAudioData,
+        transcript_strategy: &mut T,
+    ) -> Option<Vec<WorkerActions>>
+    where
+        T: TranscriptStrategy,
+    {
+        if let Some(next_stream) = self.next_stream.as_mut() {
+            next_stream.audio.push(audio);
+            return None;
+        }
+        if self.ssrc.is_some_and(|ssrc| ssrc != audio.ssrc) && !self.audio_buffer.is_empty() {
+            // Discord has given this user a new stream, e.g. because they
+            // reconnected.  Its timestamps have nothing to do with the old
+            // stream's, so finish off the audio we have before starting
+            // over with the new stream.
+            eprintln!(
+                "{}: audio stream changed from ssrc {} to {}",
+                self.audio_buffer.slice_id,
+                self.ssrc.unwrap(),
+                audio.ssrc
+            );
+            self.next_stream = Some(NextStream {
+                audio: vec![audio],
+                buffer_rolled: false,
+                final_request_sent: false,
+            });
+            return Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))]);
+        }
+        if !self.published_tail.is_zero()
+            && self.audio_buffer.buffer_duration() <= self.published_tail
+            && self
+                .audio_buffer
+                .time_after_end(&audio.rtc_timestamp)
+                .is_some_and(|gap| gap >= USER_SILENCE_TIMEOUT)
+        {
+            // the user paused after the tail we kept, so it won't
+            // help with what they say next
+            self.audio_buffer.clear();
+            self.published_tail = Duration::ZERO;
+        }
+        if let (Some(gap), Some(max_inserted_silence)) = (
+            self.audio_buffer.time_after_end(&audio.rtc_timestamp),
+            self.config.max_inserted_silence,
+        ) {
+            if gap > max_inserted_silence {
+                if self.audio_buffer.buffer_duration() <= self.published_tail {
+                    // all we have is the tail we kept, which is too far
+                    // back to help with what comes next
+                    self.audio_buffer.clear();
+                    self.published_tail = Duration::ZERO;
+                } else {
+                    // rather than fill the gap with silence, finish off
+                    // what we have and start afresh with this audio
+                    eprintln!(
+                        "{}: {} ms gap in the audio, starting a new utterance",
+                        self.audio_buffer.slice_id,
+                        gap.as_millis()
+                    );
+                    self.next_stream = Some(NextStream {
+                        audio: vec![audio],
+                        buffer_rolled: false,
+                        final_request_sent: false,
+                    });
+                    return Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))]);
+                }
+            }
+        }
+        if self
+            .audio_buffer
+            .is_past_end(&audio.rtc_timestamp, &audio.audio)
+        {
+            if self.audio_buffer.buffer_duration() <= self.published_tail {
+                // only the tail we kept is in the way
+                self.audio_buffer.clear();
+                self.published_tail = Duration::ZERO;
+            } else {
+                // the buffer is full.  Rather than drop the audio,
+                // finish off what we have and carry on in a fresh buffer
+                eprintln!(
+                    "{}: buffer full after {} ms, starting a new utterance",
+                    self.audio_buffer.slice_id,
+                    self.audio_buffer.buffer_duration().as_millis()
+                );
+                self.next_stream = Some(NextStream {
+                    audio: vec![audio],
+                    buffer_rolled: true,
+                    final_request_sent: false,
+                });
+                return Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))]);
+            }
+        }
+        self.ssrc = Some(audio.ssrc);
+        self.audio_buffer
+            .add_samples(&audio.rtc_timestamp, &audio.audio);
+        self.trailing_silence_event().and_then(|event| {
+            transcript_strategy.handle_event(&event, &self.audio_buffer.buffer_duration())
+        })
+    }
+
+    /// Finishes off the audio we have in the same way as when the stream
+    /// changes, so that anything after this point is transcribed
+    /// separately.
+    fn end_utterance(&mut self) -> Option<Vec<WorkerActions>> {
+        if self.next_stream.is_some() || self.audio_buffer.buffer_duration() <= self.published_tail
+        {
+            // we're already finishing off, or have nothing to finish
+            return None;
+        }
+        eprintln!("{}: utterance ended", self.audio_buffer.slice_id);
+        self.next_stream = Some(NextStream {
+            audio: Vec::new(),
+            buffer_rolled: false,
+            final_request_sent: false,
+        });
+        Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))])
+    }
+
+    /// Deals with a response which found nothing in audio that had
+    /// sound in it, as `empty_response_policy` says to.  Returns None
+    /// if the response should be handled like any other, which throws
+    /// its audio away.
+    fn handle_empty_response(
+        &mut self,
+        response: &TranscriptionResponse,
+        is_final_request: bool,
+    ) -> Option<Vec<WorkerActions>> {
+        if !response.transcript.segments.is_empty() || !self.had_sound(response) {
+            return None;
+        }
+        let is_retry = self
+            .last_request
+            .as_ref()
+            .is_some_and(|last_request| last_request.is_retry);
+        match empty_response_policy(
+            self.config.empty_response_policy,
+            is_retry,
+            is_final_request,
+        ) {
+            EmptyResponsePolicy::Discard => None,
+            EmptyResponsePolicy::Retry { temperature } => {
+                eprintln!(
+                    "{}: nothing found in {:?} of audio, retrying at temperature {}",
+                    self.audio_buffer.slice_id, response.transcript.audio_duration, temperature
+                );
+                self.metrics
+                    .record_inference(response.transcript.processing_time);
+                self.retry_temperature = Some(temperature);
+                Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))])
+            }
+            EmptyResponsePolicy::KeepAudio => {
+                eprintln!(
+                    "{}: nothing found in {:?} of audio, keeping it for next time",
+                    self.audio_buffer.slice_id, response.transcript.audio_duration
+                );
+                self.metrics
+                    .record_inference(response.transcript.processing_time);
+                // it's asked about again once there's more audio
+                Some(Vec::new())
+            }
+        }
+    }
+
+    /// True if the audio the response covers had any sound in it, going
+    /// by as much of it as is still in the buffer.
+    fn had_sound(&self, response: &TranscriptionResponse) -> bool {
+        let trimmed = self
+            .last_request
+            .as_ref()
+            .map_or(Duration::ZERO, |last_request| {
+                last_request.audio_trimmed_since_request
+            });
+        let start = response.buffer_offset.saturating_sub(trimmed);
+        let end =
+            (response.buffer_offset + response.transcript.audio_duration).saturating_sub(trimmed);
+        !self.audio_buffer.speech_span(&start, &end).is_zero()
+    }
+
+    /// Publishes the final transcription of the old stream, if there is
+    /// one, and replaces its audio with the audio from the new stream.
+    async fn start_next_stream<T>(
+        &mut self,
+        final_transcript: Option<Transcription>,
+        transcript_strategy: &mut T,
+        tx_api: &UnboundedSender<VoiceChannelEvent>,
+    ) -> Option<Vec<WorkerActions>>
+    where
+        T: TranscriptStrategy,
+    {
+        let next_stream = self.next_stream.take()?;
+        if let Some(final_transcript) = final_transcript {
+            self.publish(final_transcript, tx_api).await;
+        }
+        if next_stream.buffer_rolled {
+            tx_api
+                .send(VoiceChannelEvent::BufferRolled {
+                    user_id: self.user_id(),
+                })
+                .ok();
+        }
+        self.answer_channel_flushes();
+        // anything that's left can't be lined up with the new stream
+        self.reset_buffer();
+
+        let mut actions = Vec::new();
+        for audio in next_stream.audio {
+            actions.extend(
+                self.handle_audio(audio, transcript_strategy)
+                    .unwrap_or_default(),
+            );
+        }
+        // the user was speaking on the new stream, so get things going
+        if self.next_stream.is_some() {
+            // ...unless it's already been replaced by another one
+            return Some(actions);
+        }
+        actions.extend(
+            transcript_strategy
+                .handle_event(
+                    &UserAudioEventType::Speaking,
+                    &self.audio_buffer.buffer_duration(),
+                )
+                .unwrap_or_default(),
+        );
+        Some(actions)
+    }
+
+    /// Forgets the request which failed, or which we gave up on, so
+    /// that its audio is asked about again after a while, as the final
+    /// request if it was one.
+    fn retry_failed_request(&mut self) -> Option<Vec<WorkerActions>> {
+        self.last_request = None;
+        if let Some(next_stream) = self.next_stream.as_mut() {
+            next_stream.final_request_sent = false;
+        }
+        Some(vec![WorkerActions::NewTranscript(Some(
+            FAILED_REQUEST_RETRY,
+        ))])
+    }
+
+    /// Throws away all our audio, along with everything we know about it.
+    fn reset_buffer(&mut self) {
+        self.audio_buffer.clear();
+        self.last_request = None;
+        self.published_tail = Duration::ZERO;
+        self.retry_temperature = None;
+        self.ssrc = None;
+        self.transcribed_prefix = TranscribedPrefix::default();
+        self.utterance_id = self.utterance_ids.next();
+    }
+
+    /// The user whose audio we're transcribing.  Each user gets their
+    /// own slice, named after them.
+    fn user_id(&self) -> UserId {
+        self.audio_buffer.slice_id
+    }
+
+    /// Starts afresh if our buffer has a start time but no audio, as
+    /// there's nothing there to finalize, and what we know about it
+    /// would only get in the way of the next utterance.
+    fn reset_hollow_buffer(&mut self) {
+        if self.audio_buffer.clear_if_hollow() {
+            eprintln!(
+                "{}: buffer had a start time but no audio, resetting it",
+                self.audio_buffer.slice_id
+            );
+            self.reset_buffer();
+        }
+    }
+
+    /// Hands everything we've finalized to the whole-channel flushes
+    /// we've been asked to take part in.
+    fn answer_channel_flushes(&mut self) {
+        if self.channel_flushes.is_empty() {
+            return;
+        }
+        let mut transcriptions = std::mem::take(&mut self.held_transcriptions);
+        transcriptions.append(&mut self.session_transcriptions);
+        let mut handed_to_channel = false;
+        for (flush_id, session) in std::mem::take(&mut self.channel_flushes) {
+            // finalizing the session needs everything, whichever
+            // flushes it overlaps, but the channel's flushes are each
+            // published, so only the first of them gets it
+            let part = if session || !handed_to_channel {
+                transcriptions.clone()
+            } else {
+                Vec::new()
+            };
+            handed_to_channel |= !session;
+            self.tx_flush
+                .send(ChannelFlushReply {
+                    flush_id,
+                    session,
+                    user_id: self.user_id(),
+                    audio_channel: self.audio_channel,
+                    transcriptions: part,
+                })
+                .ok();
+        }
+    }
+
+    /// Publishes anything we were holding for a whole-channel flush
+    /// on its own, as there won't be a flush to hand it to.
+    fn release_held_transcriptions(&mut self, tx_api: &UnboundedSender<VoiceChannelEvent>) {
+        for transcription in self.held_transcriptions.drain(..) {
+            tx_api
+                .send(VoiceChannelEvent::Transcription(transcription))
+                .ok();
+        }
+    }
+
+    /// Warns if too much of our audio is clipped, unless we've warned
+    /// recently.
+    fn check_clipping(&mut self, tx_api: &UnboundedSender<VoiceChannelEvent>) {
+        if self.audio_buffer.buffer_duration() < CLIPPING_MIN_AUDIO {
+            return;
+        }
+        let clipped_percent = self.audio_buffer.clipped_percent();
+        let now = Instant::now();
+        if !should_warn_of_clipping(
+            clipped_percent,
+            self.config.clipping_threshold,
+            self.last_clipping_warning.map(|last| now - last),
+        ) {
+            return;
+        }
+        self.last_clipping_warning = Some(now);
+        let slice_id = self.audio_buffer.slice_id;
+        eprintln!("{}: {:.1}% of audio is clipped", slice_id, clipped_percent);
+        tx_api
+            .send(VoiceChannelEvent::InputClipping {
+                clipped_percent: clipped_percent.round() as u32,
+                user_id: self.user_id(),
+            })
+            .ok();
+    }
+
+    /// Lets the API know that whatever audio we still have is about
+    /// to be thrown away without being transcribed.
+    fn report_discarded_audio(&self, tx_api: &UnboundedSender<VoiceChannelEvent>) {
+        let discarded = self
+            .audio_buffer
+            .buffer_duration()
+            .saturating_sub(self.published_tail);
+        if discarded.is_zero() {
+            return;
+        }
+        let slice_id = self.audio_buffer.slice_id;
+        eprintln!(
+            "{}: discarding {} ms of stale audio",
+            slice_id,
+            discarded.as_millis()
+        );
+        self.metrics.record_dropped_audio(discarded);
+        tx_api
+            .send(VoiceChannelEvent::StaleAudioDiscarded {
+                discarded_ms: discarded.as_millis() as u64,
+                slice_id,
+                user_id: self.user_id(),
+                utterance_id: self.utterance_id,
+            })
+            .ok();
+    }
+
+    /// Throws away our oldest audio until what's left fits in
+    /// `max_bytes`, and lets the API know.
+    fn evict_audio(&mut self, max_bytes: usize, tx_api: &UnboundedSender<VoiceChannelEvent>) {
+        let discarded = self.audio_buffer.duration_over(max_bytes);
+        self.audio_buffer.discard_audio(&discarded);
+        self.published_tail = self.published_tail.saturating_sub(discarded);
+        // which leaves nothing for our prefix to be relative to
+        self.transcribed_prefix = TranscribedPrefix::default();
+        if let Some(last_request) = self.last_request.as_mut() {
+            last_request.record_trim(&discarded);
+        }
+
+        let slice_id = self.audio_buffer.slice_id;
+        eprintln!(
+            "{}: evicting {} ms of audio to stay within the memory limit",
+            slice_id,
+            discarded.as_millis()
+        );
+        self.metrics.record_dropped_audio(discarded);
+        tx_api
+            .send(VoiceChannelEvent::AudioEvicted {
+                discarded_ms: discarded.as_millis() as u64,
+                slice_id,
+                user_id: self.user_id(),
+                utterance_id: self.utterance_id,
+            })
+            .ok();
+    }
+
+    /// Lets the API know that the backend couldn't transcribe our audio.
+    fn report_transcription_error(
+        &self,
+        message: String,
+        tx_api: &UnboundedSender<VoiceChannelEvent>,
+    ) {
+        let slice_id = self.audio_buffer.slice_id;
+        eprintln!("{}: transcription failed: {}", slice_id, message);
+        self.metrics.record_error();
+        tx_api
+            .send(VoiceChannelEvent::TranscriptionError {
+                message,
+                user_id: self.user_id(),
+            })
+            .ok();
+    }
+
+    /// Lets the API know that we gave up waiting on a transcription.
+    fn report_transcription_timeout(&self, tx_api: &UnboundedSender<VoiceChannelEvent>) {
+        let slice_id = self.audio_buffer.slice_id;
+        let timeout = self.config.transcription_timeout.unwrap_or_default();
+        eprintln!(
+            "{}: transcription timed out after {} ms",
+            slice_id,
+            timeout.as_millis()
+        );
+        self.metrics.record_error();
+        tx_api
+            .send(VoiceChannelEvent::TranscriptionTimedOut {
+                timeout_ms: timeout.as_millis() as u64,
+                user_id: self.user_id(),
+            })
+            .ok();
+    }
+
+    fn report_speech_segments(
+        &self,
+        transcript: &Transcription,
+        tx_api: &UnboundedSender<VoiceChannelEvent>,
+    ) {
+        let speech_segments =
+            count_speech_segments(&transcript.segments, self.config.low_confidence_threshold);
+        tx_api
+            .send(VoiceChannelEvent::SpeechSegments {
+                segments: transcript.segments.len() as u32,
+                speech_segments: speech_segments as u32,
+                user_id: self.user_id(),
+            })
+            .ok();
+    }
+
+    /// Where the user paused in the buffer, for streaming mode to split
+    /// requests at.  Other modes don't need to know.
+    fn pauses(&self) -> Vec<Duration> {
+        match self.config.transcription_mode {
+            TranscriptionMode::Streaming { min_silence, .. } => self
+                .audio_buffer
+                .silent_gaps(&self.audio_buffer.buffer_duration(), &min_silence),
+            _ => Vec::new(),
+        }
+    }
+
+    /// If the end of our buffer has been quiet for long enough, then
+    /// treat it as though the user has stopped talking, even if
+    /// Discord is still sending us their (quiet) audio.
+    fn trailing_silence_event(&mut self) -> Option<UserAudioEventType> {
+        let window = self.config.trailing_silence_finalize?;
+        let trailing_silence = match self.config.rms_smoothing {
+            Some(time_constant) => self.audio_buffer.smoothed_trailing_silence(&time_constant),
+            None => self.audio_buffer.trailing_silence(),
+        };
+        if trailing_silence < window {
+            self.trailing_silence_reported = false;
+            return None;
+        }
+        if self.trailing_silence_reported {
+            return None;
+        }
+        self.trailing_silence_reported = true;
+        self.speaking = false;
+        Some(UserAudioEventType::Silent)
     }
 
     /// Publish a transcription to the API
@@ -186,34 +1146,155 @@ impl UserAudioWorker {
     /// In addition, publishing has these side-effects:
     /// - the audio associated with the transcription is removed from the buffer
     /// - the tokens associated with the transcription are added to last_tokens
-    fn publish(
+    async fn publish(
         &mut self,
-        mut transcription: Transcription,
+        transcription: Transcription,
         tx_api: &UnboundedSender<VoiceChannelEvent>,
     ) {
-        // remove the audio associated with this transcription
-        self.audio_buffer
-            .discard_audio(&transcription.audio_duration);
+        let audio_duration = transcription.audio_duration;
+        // anything before this was in the tail of the last
+        // transcription we published
+        let published_until = (!self.published_tail.is_zero())
+            .then(|| transcription.start_timestamp + self.published_tail);
 
-        // filter out any "spurious" segments from the transcription
-        transcription.segments.retain(is_valid_segment);
+        // look for gaps before we throw away the audio
+        let pieces = self.split_on_silence(transcription);
+        // the tail was counted when it was published, and any silence
+        // around what the user said isn't them talking
+        let mut speech = self
+            .audio_buffer
+            .speech_span(&self.published_tail, &audio_duration);
 
-        // if the transcription is empty, don't send it.
-        // we still needed to remove the audio, though.
-        if transcription.segments.is_empty() {
-            return;
+        // remove the audio associated with this transcription, except
+        // for the tail we've been asked to keep
+        let tail = min(self.config.finalize_context_tail, audio_duration);
+        let discarded = audio_duration - tail;
+        self.audio_buffer.discard_audio(&discarded);
+        self.published_tail = min(tail, self.audio_buffer.buffer_duration());
+        // which leaves nothing for our prefix to be relative to
+        self.transcribed_prefix = TranscribedPrefix::default();
+        if let Some(last_request) = self.last_request.as_mut() {
+            last_request.record_trim(&discarded);
+        }
+
+        let low_confidence_threshold = self.config.low_confidence_threshold;
+        for mut piece in pieces {
+            // filter out any "spurious" segments from the transcription,
+            // as well as any we've already published
+            let piece_start = piece.start_timestamp;
+            piece.segments.retain(|segment| {
+                let is_new = match published_until {
+                    Some(published_until) => {
+                        piece_start + Duration::from_millis(segment.end_offset_ms as u64)
+                            > published_until
+                    }
+                    None => true,
+                };
+                is_new
+                    && is_valid_segment(segment)
+                    && is_confident_segment(segment, low_confidence_threshold)
+            });
+
+            // strip sound descriptions like "[BLANK_AUDIO]", dropping
+            // any segments which were nothing but
+            if self.config.whisper.suppress_non_speech_tokens {
+                piece.segments.iter_mut().for_each(clean_segment);
+                piece
+                    .segments
+                    .retain(|segment| !segment.text().trim().is_empty());
+            }
+
+            normalize_segments(&mut piece.segments, &self.config.text_normalizer);
+            #[cfg(feature = "phonemes")]
+            {
+                piece.segments = add_phonemes(std::mem::take(&mut piece.segments)).await;
+            }
+
+            // if the transcription is empty, don't send it.
+            // we still needed to remove the audio, though.
+            if piece.segments.is_empty() {
+                continue;
+            }
+            if !is_confident_enough(&piece, self.config.min_finalized_confidence) {
+                continue;
+            }
+
+            // only counted once some of it is published, so that noise
+            // whisper found nothing in isn't counted as talking.  Each
+            // of a user's channels gets its own slice.
+            self.speaking_time.add(
+                self.user_id(),
+                self.audio_channel,
+                std::mem::take(&mut speech),
+            );
+
+            // add the tokens from this transcription to our last_tokens
+            self.last_tokens.add_all(&piece.token_ids());
+            piece.utterance_id = self.utterance_id;
+            piece.audio_channel = self.audio_channel;
+
+            if self.config.finalization_mode == FinalizationMode::WholeChannel {
+                // this waits for the whole channel to go quiet
+                self.held_transcriptions.push(piece);
+                self.metrics.record_transcription();
+                continue;
+            }
+            if !self.channel_flushes.is_empty() {
+                // the session is being finalized, and wants this back
+                self.session_transcriptions.push(piece.clone());
+            }
+
+            // send the transcription to the API
+            match tx_api.send(VoiceChannelEvent::Transcription(piece)) {
+                Ok(_) => self.metrics.record_transcription(),
+                Err(err) => {
+                    eprintln!("error sending transcription to API: {}", err);
+                    self.metrics.record_error();
+                }
+            }
         }
 
-        // add the tokens from this transcription to our last_tokens
-        self.last_tokens.add_all(&transcription.token_ids());
+        if self.audio_buffer.buffer_duration() <= self.published_tail {
+            // that was the end of what the user had to say
+            self.utterance_id = self.utterance_ids.next();
+        }
+    }
 
-        // send the transcription to the API
-        match tx_api.send(VoiceChannelEvent::Transcription(transcription)) {
-            Ok(_) => {} // everything is fine
-            Err(err) => {
-                eprintln!("error sending transcription to API: {}", err);
+    /// If configured, splits the transcription wherever there's a
+    /// long enough run of silence in the audio behind it.  This needs
+    /// to be called before the transcription's audio is discarded.
+    fn split_on_silence(&self, transcription: Transcription) -> Vec<Transcription> {
+        let Some(min_gap) = self.config.speaker_split_silence else {
+            return vec![transcription];
+        };
+        // we can only find gaps if the transcription lines up with our audio
+        let start_time = match self.audio_buffer.start_time {
+            Some((_, start_time)) if start_time == transcription.start_timestamp => start_time,
+            _ => return vec![transcription],
+        };
+
+        let mut pieces = vec![];
+        let mut remaining = transcription;
+        for gap in self
+            .audio_buffer
+            .silent_gaps(&remaining.audio_duration, &min_gap)
+        {
+            let split_time = start_time + gap;
+            if split_time <= remaining.start_timestamp {
+                continue;
+            }
+            let (first, second) = Transcription::split_at_end_time(
+                &remaining,
+                split_time,
+                self.config.segment_join_threshold,
+            );
+            if !first.is_empty() {
+                pieces.push(first);
             }
+            remaining = second;
         }
+        pieces.push(remaining);
+        pieces
     }
 
     fn print_rms(&self, transcription: &Transcription) {
@@ -261,6 +1342,213 @@ impl UserAudioWorker {
     }
 }
 
+/// Audio which arrived while we were still finishing off the audio
+/// before it, either on a user's new stream, after the end of an
+/// utterance, or once the buffer was full.
+struct NextStream {
+    audio: Vec<UserAudioData>,
+    /// true if the old stream is being finished off because there
+    /// was no more room in the buffer
+    buffer_rolled: bool,
+    /// true once we've asked for the old stream's final transcription
+    final_request_sent: bool,
+}
+
+/// What we know about the most recent transcription request, so we
+/// can tell whether another request would transcribe the same audio.
+struct LastRequestInfo {
+    /// how much audio has been discarded from the start of the buffer
+    /// since the request was made
+    audio_trimmed_since_request: Duration,
+
+    /// true if this was a retry of audio whisper found nothing in
+    is_retry: bool,
+
+    /// how much audio was in the buffer when the request was made
+    original_duration: Duration,
+
+    /// local time when the request was made
+    requested_at: SystemTime,
+
+    /// where the start of the buffer was when the request was made,
+    /// which is what the response's offsets are relative to
+    stream_position: u64,
+}
+
+impl LastRequestInfo {
+    fn new(original_duration: Duration, stream_position: u64) -> Self {
+        Self {
+            audio_trimmed_since_request: Duration::ZERO,
+            is_retry: false,
+            original_duration,
+            requested_at: SystemTime::now(),
+            stream_position,
+        }
+    }
+
+    /// Keeps the timing of each of the response's segments as whisper
+    /// gave it, before their offsets are moved to be relative to the
+    /// start of the buffer.
+    fn record_whisper_timing(&self, response: &mut TranscriptionResponse) {
+        let audio_start = response.transcript.start_timestamp;
+        for segment in response.transcript.segments.iter_mut() {
+            segment.whisper_timing = Some(WhisperTiming {
+                audio_start,
+                requested_at: self.requested_at,
+                t0_ms: segment.start_offset_ms,
+                t1_ms: segment.end_offset_ms,
+            });
+        }
+    }
+
+    /// Gives each of the response's segments its place in the user's
+    /// audio.  Audio may have been discarded since the request was
+    /// made, so this can't be worked out from the buffer as it is now.
+    fn place_segments(&self, transcript: &mut Transcription) {
+        for segment in transcript.segments.iter_mut() {
+            segment.place_in_stream(self.stream_position);
+        }
+    }
+
+    /// How much of the request's audio is still in the buffer.
+    fn effective_duration(&self) -> Duration {
+        self.original_duration
+            .saturating_sub(self.audio_trimmed_since_request)
+    }
+
+    /// Records that audio was discarded from the start of the buffer.
+    fn record_trim(&mut self, trimmed: &Duration) {
+        // anything beyond the request's audio wasn't part of it
+        self.audio_trimmed_since_request += min(*trimmed, self.effective_duration());
+    }
+
+    /// True if a request for a buffer of the given length would be for
+    /// exactly the audio we already asked about.  This has to account
+    /// for any audio that was trimmed since, as otherwise a buffer which
+    /// was trimmed and then refilled to the same length would look like
+    /// a duplicate.
+    fn is_duplicate(&self, buffer_duration: &Duration) -> bool {
+        self.effective_duration() == *buffer_duration
+    }
+}
+
+/// The segments we've already transcribed from the start of the buffer,
+/// so that in incremental and streaming modes we only need to send
+/// whisper the audio after them.
+#[derive(Default)]
+struct TranscribedPrefix {
+    /// where the last of the segments ends, relative to the start
+    /// of the buffer
+    end: Duration,
+
+    segments: Vec<TextSegment>,
+}
+
+impl TranscribedPrefix {
+    /// Where in the buffer the next transcription request should start.
+    /// `pauses` are the middles of the pauses in the buffer, in order.
+    fn request_offset(
+        &self,
+        transcription_mode: &TranscriptionMode,
+        pauses: &[Duration],
+    ) -> Duration {
+        match transcription_mode {
+            TranscriptionMode::WholeBuffer => Duration::ZERO,
+            TranscriptionMode::Incremental { context_tail } => {
+                self.end.saturating_sub(*context_tail)
+            }
+            TranscriptionMode::Streaming { overlap, .. } => {
+                let earliest = self.end.saturating_sub(*overlap);
+                pauses
+                    .iter()
+                    .rev()
+                    .find(|pause| (earliest..=self.end).contains(*pause))
+                    .copied()
+                    .unwrap_or(earliest)
+            }
+        }
+    }
+
+    fn token_ids(&self) -> impl Iterator<Item = WhisperToken> + '_ {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.tokens_with_probability.iter())
+            .map(|token| token.token_id)
+    }
+
+    /// Takes a response for part of the buffer, and returns a
+    /// transcription of the whole buffer by putting our segments in
+    /// front of it.  Then remembers the result for next time, up to
+    /// the last of the `pauses` if there's a new one.
+    fn merge(&mut self, response: TranscriptionResponse, pauses: &[Duration]) -> Transcription {
+        let mut transcript = from_buffer_start(response);
+
+        // whisper will have transcribed the context tail again, but
+        // we already have text for that.  Keep any segment which runs
+        // past the end of our prefix, less any words it repeats, so
+        // that words on the boundary are neither lost nor doubled.
+        let prefix_end_ms = self.end.as_millis() as u32;
+        transcript
+            .segments
+            .retain(|segment| segment.end_offset_ms > prefix_end_ms);
+        trim_overlap(&self.segments, prefix_end_ms, &mut transcript.segments);
+        let new_segments = std::mem::take(&mut transcript.segments);
+        transcript.segments = self.segments.iter().cloned().chain(new_segments).collect();
+
+        let pause = pauses
+            .iter()
+            .map(|pause| pause.as_millis() as u32)
+            .filter(|pause| *pause > prefix_end_ms)
+            .filter(|pause| *pause as u128 <= transcript.audio_duration.as_millis())
+            .last();
+        if let Some(pause_ms) = pause {
+            // nothing said after the pause can change what was said
+            // before it, so all of that can be kept.  Whisper's
+            // timestamps are loose, so a segment which starts before
+            // the pause may end a little after it.
+            let stable_segments = transcript
+                .segments
+                .iter()
+                .take_while(|segment| segment.start_offset_ms < pause_ms)
+                .count();
+            self.segments = transcript.segments[..stable_segments].to_vec();
+            let kept_end_ms = self
+                .segments
+                .last()
+                .map_or(pause_ms, |segment| segment.end_offset_ms.max(pause_ms));
+            self.end = Duration::from_millis(kept_end_ms as u64);
+        } else {
+            // the last segment may have been cut off mid-word, so it
+            // needs to be transcribed again next time
+            let stable_segments = transcript.segments.len().saturating_sub(1);
+            self.segments = transcript.segments[..stable_segments].to_vec();
+            self.end = self.segments.last().map_or(Duration::ZERO, |segment| {
+                Duration::from_millis(segment.end_offset_ms as u64)
+            });
+        }
+
+        transcript
+    }
+}
+
+/// The response's transcript, with everything made relative to the
+/// start of the buffer rather than to where the request started.
+fn from_buffer_start(response: TranscriptionResponse) -> Transcription {
+    let TranscriptionResponse {
+        buffer_offset,
+        mut transcript,
+        ..
+    } = response;
+    let offset_ms = buffer_offset.as_millis() as u32;
+    transcript.start_timestamp -= buffer_offset;
+    transcript.audio_duration += buffer_offset;
+    for segment in transcript.segments.iter_mut() {
+        segment.start_offset_ms += offset_ms;
+        segment.end_offset_ms += offset_ms;
+    }
+    transcript
+}
+
 struct BoundedTokenBuffer(VecDeque<WhisperToken>);
 
 impl BoundedTokenBuffer {
@@ -354,3 +1642,972 @@ fn is_valid_segment(segment: &TextSegment) -> bool {
     }
     true
 }
+
+/// Whether to skip a transcription request so the backend can catch
+/// up.  Only interim requests are skipped, since final ones are what
+/// gets published.
+fn should_shed_request(
+    is_interim: bool,
+    pending_requests: u64,
+    high_water_mark: Option<usize>,
+) -> bool {
+    is_interim && high_water_mark.is_some_and(|mark| pending_requests >= mark as u64)
+}
+
+/// Whether to warn that a user's audio is clipped, given when we last
+/// warned them, if ever.
+fn should_warn_of_clipping(
+    clipped_percent: f32,
+    threshold: Option<u32>,
+    since_last_warning: Option<Duration>,
+) -> bool {
+    threshold.is_some_and(|threshold| clipped_percent > threshold as f32)
+        && !since_last_warning.is_some_and(|since| since < CLIPPING_WARNING_INTERVAL)
+}
+
+/// What to do about a response which found nothing in audio that had
+/// sound in it.  Audio is only retried once, and can't be kept if it's
+/// being finalized because the user's stream changed, as the buffer is
+/// about to be cleared for the new stream.
+fn empty_response_policy(
+    policy: EmptyResponsePolicy,
+    is_retry: bool,
+    is_final_request: bool,
+) -> EmptyResponsePolicy {
+    match policy {
+        EmptyResponsePolicy::Retry { .. } if is_retry => EmptyResponsePolicy::Discard,
+        EmptyResponsePolicy::KeepAudio if is_final_request => EmptyResponsePolicy::Discard,
+        policy => policy,
+    }
+}
+
+/// Checks how unsure whisper was of the segment against the configured
+/// threshold, if there is one.
+fn is_confident_segment(segment: &TextSegment, low_confidence_threshold: Option<u32>) -> bool {
+    let Some(threshold) = low_confidence_threshold else {
+        return true;
+    };
+    if segment.low_confidence_p > threshold {
+        eprintln!(
+            "discarding transcript segment whisper was {}% unsure of",
+            segment.low_confidence_p
+        );
+        return false;
+    }
+    true
+}
+
+/// Checks the transcription's average token probability against the
+/// configured floor, if there is one.
+fn is_confident_enough(transcription: &Transcription, min_confidence: Option<u32>) -> bool {
+    let Some(min_confidence) = min_confidence else {
+        return true;
+    };
+    let confidence = transcription.average_confidence().unwrap_or(0);
+    if confidence < min_confidence {
+        eprintln!(
+            "discarding transcription with average token probability {}%",
+            confidence
+        );
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        audio::{
+            echo::EchoBackend,
+            events::AudioSamples,
+            scripted::{Answer, ScriptedBackend},
+        },
+        model::{
+            clock::MockClock,
+            config::{AudioPayloadFormat, TentativeTranscriptPolicy},
+            constants::{
+                DISCORD_AUDIO_CHANNELS, DISCORD_SAMPLES_PER_SECOND, FIRST_TRANSCRIPT_PERIOD,
+            },
+        },
+        strategies::five_second_strategy::{FiveSecondStrategy, SUBSEQUENT_TRANSCRIPT_PERIOD},
+    };
+
+    use super::*;
+
+    fn segment_with_low_confidence_p(low_confidence_p: u32) -> TextSegment {
+        TextSegment {
+            start_offset_ms: 0,
+            end_offset_ms: 1000,
+            start_sample: 0,
+            end_sample: 0,
+            low_confidence_p,
+            cleaned_text: None,
+            phonemes: None,
+            raw_token_ids: None,
+            whisper_timing: None,
+            tokens_with_probability: vec![TokenWithProbability {
+                p: 90,
+                token_id: 0,
+                token_text: "Thank you.".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_low_confidence_segment_is_filtered() {
+        let mut segments = vec![
+            segment_with_low_confidence_p(90),
+            segment_with_low_confidence_p(10),
+        ];
+        segments.retain(|segment| is_confident_segment(segment, Some(60)));
+        assert_eq!(segments, vec![segment_with_low_confidence_p(10)]);
+    }
+
+    #[test]
+    fn test_no_threshold_keeps_everything() {
+        assert!(is_confident_segment(
+            &segment_with_low_confidence_p(100),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_speaking_time_takes_the_busiest_channel() {
+        let speaking_time = SpeakingTime::default();
+        speaking_time.add(1, Some(0), Duration::from_secs(2));
+        speaking_time.add(1, Some(1), Duration::from_secs(3));
+        speaking_time.add(1, Some(0), Duration::from_secs(2));
+        speaking_time.add(2, None, Duration::from_secs(1));
+
+        assert_eq!(speaking_time.get(1), Duration::from_secs(4));
+        assert_eq!(speaking_time.get(2), Duration::from_secs(1));
+        assert_eq!(speaking_time.get(3), Duration::ZERO);
+        assert_eq!(
+            speaking_time.snapshot(),
+            BTreeMap::from([(1, Duration::from_secs(4)), (2, Duration::from_secs(1))])
+        );
+    }
+
+    #[test]
+    fn test_interim_requests_shed_under_backlog() {
+        assert!(should_shed_request(true, 4, Some(4)));
+        assert!(!should_shed_request(true, 3, Some(4)));
+        // finals are always sent
+        assert!(!should_shed_request(false, 10, Some(4)));
+        assert!(!should_shed_request(true, 10, None));
+    }
+
+    #[test]
+    fn test_empty_response_policy() {
+        let retry = EmptyResponsePolicy::Retry { temperature: 0.4 };
+        assert_eq!(empty_response_policy(retry, false, false), retry);
+        assert_eq!(empty_response_policy(retry, false, true), retry);
+        // only ever retried once
+        assert_eq!(
+            empty_response_policy(retry, true, false),
+            EmptyResponsePolicy::Discard
+        );
+        let keep = EmptyResponsePolicy::KeepAudio;
+        assert_eq!(empty_response_policy(keep, false, false), keep);
+        // there's nowhere to keep it
+        assert_eq!(
+            empty_response_policy(keep, false, true),
+            EmptyResponsePolicy::Discard
+        );
+    }
+
+    #[test]
+    fn test_clipping_warnings_throttled() {
+        assert!(should_warn_of_clipping(5.0, Some(1), None));
+        assert!(!should_warn_of_clipping(0.5, Some(1), None));
+        assert!(!should_warn_of_clipping(100.0, None, None));
+        // once warned, not again for a while
+        assert!(!should_warn_of_clipping(
+            5.0,
+            Some(1),
+            Some(Duration::from_secs(10))
+        ));
+        assert!(should_warn_of_clipping(
+            5.0,
+            Some(1),
+            Some(CLIPPING_WARNING_INTERVAL)
+        ));
+    }
+
+    #[test]
+    fn test_pending_requests_follow_worker() {
+        let metrics = MetricsCounters::default();
+        metrics.record_requests_sent(2);
+        let pending = metrics.pending_requests();
+        assert!(should_shed_request(true, pending, Some(2)));
+        metrics.record_requests_finished(1);
+        let pending = metrics.pending_requests();
+        assert!(!should_shed_request(true, pending, Some(2)));
+    }
+
+    #[tokio::test]
+    async fn test_tentative_survives_audio_arriving_before_response() {
+        let mut audio_buffer = AudioBuffer::with_clock(1, MockClock::new());
+        let three_seconds = vec![1; 3 * DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS];
+        audio_buffer.add_audio(&Wrapping(0), &three_seconds);
+        let request = audio_buffer
+            .make_transcription_request(AudioPayloadFormat::F32, &Duration::ZERO, vec![])
+            .unwrap();
+        let requested_duration = request.audio_duration;
+        let last_request = LastRequestInfo::new(
+            audio_buffer.buffer_duration(),
+            audio_buffer.stream_position(),
+        );
+
+        // more audio arrives while whisper is busy
+        let half_second = vec![1; DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS / 2];
+        audio_buffer.add_audio(&Wrapping(3 * 48000), &half_second);
+
+        let response = EchoBackend::new(" hello".to_string())
+            .process_transcription_request(request)
+            .await
+            .unwrap();
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::ZERO,
+            FIRST_TRANSCRIPT_PERIOD,
+            TentativeTranscriptPolicy::default(),
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        strategy.handle_transcription(
+            &from_buffer_start(response),
+            WorkerContext {
+                audio_duration: audio_buffer.buffer_duration(),
+                requested_duration: last_request.effective_duration(),
+                silent_after: false,
+            },
+        );
+
+        // the tentative transcript still covers the audio it was for
+        let actions = strategy
+            .handle_event(&UserAudioEventType::Idle, &requested_duration)
+            .unwrap();
+        assert!(matches!(actions.as_slice(), [WorkerActions::Publish(_)]));
+    }
+
+    fn segment_at(start_offset_ms: u32, end_offset_ms: u32) -> TextSegment {
+        TextSegment {
+            start_offset_ms,
+            end_offset_ms,
+            ..segment_with_low_confidence_p(0)
+        }
+    }
+
+    fn offsets(transcript: &Transcription) -> Vec<(u32, u32)> {
+        transcript
+            .segments
+            .iter()
+            .map(|segment| (segment.start_offset_ms, segment.end_offset_ms))
+            .collect()
+    }
+
+    #[test]
+    fn test_incremental_merge() {
+        let mode = TranscriptionMode::Incremental {
+            context_tail: Duration::from_millis(500),
+        };
+        let start_timestamp = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut prefix = TranscribedPrefix::default();
+        assert_eq!(prefix.request_offset(&mode, &[]), Duration::ZERO);
+
+        // the first request covers the whole buffer
+        let first = prefix.merge(
+            TranscriptionResponse {
+                buffer_offset: Duration::ZERO,
+                transcript: Transcription {
+                    audio_duration: Duration::from_millis(2500),
+                    processing_time: Duration::from_millis(1),
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                    segments: vec![
+                        segment_at(0, 1000),
+                        segment_at(1000, 2000),
+                        segment_at(2000, 2500),
+                    ],
+                    start_timestamp,
+                    user_id: 1,
+                },
+                error: None,
+            },
+            &[],
+        );
+        assert_eq!(offsets(&first), vec![(0, 1000), (1000, 2000), (2000, 2500)]);
+
+        // the last segment will be transcribed again, along with some context
+        assert_eq!(
+            prefix.request_offset(&mode, &[]),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(prefix.token_ids().count(), 2);
+
+        let second = prefix.merge(
+            TranscriptionResponse {
+                buffer_offset: Duration::from_millis(1500),
+                transcript: Transcription {
+                    audio_duration: Duration::from_millis(2000),
+                    processing_time: Duration::from_millis(1),
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                    // the first segment is a repeat of the context tail
+                    segments: vec![
+                        segment_at(0, 500),
+                        segment_at(500, 1500),
+                        segment_at(1500, 2000),
+                    ],
+                    start_timestamp: start_timestamp + Duration::from_millis(1500),
+                    user_id: 1,
+                },
+                error: None,
+            },
+            &[],
+        );
+        assert_eq!(second.start_timestamp, start_timestamp);
+        assert_eq!(second.audio_duration, Duration::from_millis(3500));
+        assert_eq!(
+            offsets(&second),
+            vec![(0, 1000), (1000, 2000), (2000, 3000), (3000, 3500)]
+        );
+        assert_eq!(
+            TranscribedPrefix::default().request_offset(&TranscriptionMode::WholeBuffer, &[]),
+            Duration::ZERO
+        );
+    }
+
+    fn segment_saying(start_offset_ms: u32, end_offset_ms: u32, text: &str) -> TextSegment {
+        TextSegment {
+            start_offset_ms,
+            end_offset_ms,
+            tokens_with_probability: text
+                .split_inclusive(' ')
+                .map(|word| TokenWithProbability {
+                    p: 90,
+                    token_id: 0,
+                    token_text: format!(" {}", word.trim()),
+                })
+                .collect(),
+            ..segment_with_low_confidence_p(0)
+        }
+    }
+
+    /// Merges a response for the buffer from 1500ms on into a prefix
+    /// which ends at 2000ms with "hello there the".
+    fn merge_after_boundary(segments: Vec<TextSegment>) -> String {
+        let start_timestamp = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut prefix = TranscribedPrefix::default();
+        prefix.merge(
+            TranscriptionResponse {
+                buffer_offset: Duration::ZERO,
+                transcript: Transcription {
+                    audio_duration: Duration::from_millis(2500),
+                    processing_time: Duration::from_millis(1),
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                    segments: vec![
+                        segment_saying(0, 1000, "hello"),
+                        segment_saying(1000, 2000, "there the"),
+                        segment_saying(2000, 2500, "wor"),
+                    ],
+                    start_timestamp,
+                    user_id: 1,
+                },
+                error: None,
+            },
+            &[],
+        );
+        let merged = prefix.merge(
+            TranscriptionResponse {
+                buffer_offset: Duration::from_millis(1500),
+                transcript: Transcription {
+                    audio_duration: Duration::from_millis(1500),
+                    processing_time: Duration::from_millis(1),
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                    segments,
+                    start_timestamp: start_timestamp + Duration::from_millis(1500),
+                    user_id: 1,
+                },
+                error: None,
+            },
+            &[],
+        );
+        merged
+            .segments
+            .iter()
+            .map(|segment| segment.text())
+            .collect()
+    }
+
+    #[test]
+    fn test_incremental_merge_boundary_words() {
+        // a segment which is mostly before the boundary, but still has
+        // a new word in it, isn't dropped
+        assert_eq!(
+            merge_after_boundary(vec![
+                segment_saying(0, 900, "the world"),
+                segment_saying(900, 1500, "again"),
+            ]),
+            " hello there the world again"
+        );
+        // a segment which is mostly after the boundary doesn't repeat
+        // the words before it
+        assert_eq!(
+            merge_after_boundary(vec![
+                segment_saying(400, 1000, "the world"),
+                segment_saying(1000, 1500, "again"),
+            ]),
+            " hello there the world again"
+        );
+    }
+
+    #[test]
+    fn test_streaming_keeps_segment_across_pause() {
+        let start_timestamp = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut prefix = TranscribedPrefix::default();
+        prefix.merge(
+            TranscriptionResponse {
+                buffer_offset: Duration::ZERO,
+                transcript: Transcription {
+                    audio_duration: Duration::from_millis(3000),
+                    processing_time: Duration::from_millis(1),
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                    // whisper has the second segment end a little
+                    // after the pause
+                    segments: vec![
+                        segment_saying(0, 1000, "hello"),
+                        segment_saying(1000, 2100, "there"),
+                        segment_saying(2300, 3000, "wor"),
+                    ],
+                    start_timestamp,
+                    user_id: 1,
+                },
+                error: None,
+            },
+            &[Duration::from_millis(2000)],
+        );
+        assert_eq!(prefix.segments.len(), 2);
+        assert_eq!(prefix.end, Duration::from_millis(2100));
+
+        // the pause is still where the next request starts, and what
+        // comes after it isn't lost
+        let mode = TranscriptionMode::Streaming {
+            min_silence: Duration::from_millis(200),
+            overlap: Duration::from_millis(500),
+        };
+        let pauses = [Duration::from_millis(2000)];
+        let buffer_offset = prefix.request_offset(&mode, &pauses);
+        assert_eq!(buffer_offset, Duration::from_millis(2000));
+        let merged = prefix.merge(
+            TranscriptionResponse {
+                buffer_offset,
+                transcript: Transcription {
+                    audio_duration: Duration::from_millis(1500),
+                    processing_time: Duration::from_millis(1),
+                    utterance_id: 0,
+                    language: None,
+                    audio_channel: None,
+                    segments: vec![segment_saying(300, 1500, "world")],
+                    start_timestamp: start_timestamp + buffer_offset,
+                    user_id: 1,
+                },
+                error: None,
+            },
+            &pauses,
+        );
+        let text: String = merged
+            .segments
+            .iter()
+            .map(|segment| segment.text())
+            .collect();
+        assert_eq!(text, " hello there world");
+    }
+
+    #[test]
+    fn test_streaming_matches_whole_buffer() {
+        let mode = TranscriptionMode::Streaming {
+            min_silence: Duration::from_millis(200),
+            overlap: Duration::from_millis(500),
+        };
+        let start_timestamp = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let response = |buffer_offset_ms: u64, audio_ms: u64, segments| TranscriptionResponse {
+            buffer_offset: Duration::from_millis(buffer_offset_ms),
+            transcript: Transcription {
+                audio_duration: Duration::from_millis(audio_ms),
+                processing_time: Duration::from_millis(1),
+                utterance_id: 0,
+                language: None,
+                audio_channel: None,
+                segments,
+                start_timestamp: start_timestamp + Duration::from_millis(buffer_offset_ms),
+                user_id: 1,
+            },
+            error: None,
+        };
+        // 4 seconds of audio, with a pause around 2 seconds in
+        let pauses = [Duration::from_millis(2000)];
+        let whole_buffer = response(
+            0,
+            4000,
+            vec![
+                segment_saying(0, 1800, "hello there"),
+                segment_saying(2200, 3200, "how are"),
+                segment_saying(3200, 4000, "you"),
+            ],
+        )
+        .transcript;
+
+        let mut prefix = TranscribedPrefix::default();
+        assert_eq!(prefix.request_offset(&mode, &[]), Duration::ZERO);
+        // the first window ends partway through the second sentence,
+        // but everything before the pause is kept
+        let first = prefix.merge(
+            response(
+                0,
+                2500,
+                vec![
+                    segment_saying(0, 1800, "hello there"),
+                    segment_saying(2200, 2500, "how"),
+                ],
+            ),
+            &pauses,
+        );
+        assert_eq!(first.text(), " hello there how");
+        // so the next window starts in the pause
+        assert_eq!(
+            prefix.request_offset(&mode, &pauses),
+            Duration::from_millis(2000)
+        );
+
+        let second = prefix.merge(
+            response(
+                2000,
+                2000,
+                vec![
+                    segment_saying(200, 1200, "how are"),
+                    segment_saying(1200, 2000, "you"),
+                ],
+            ),
+            &pauses,
+        );
+        assert_eq!(second.text(), whole_buffer.text());
+        assert_eq!(offsets(&second), offsets(&whole_buffer));
+        assert_eq!(second.audio_duration, whole_buffer.audio_duration);
+
+        // without a pause, the windows overlap like incremental mode
+        assert_eq!(
+            TranscribedPrefix {
+                end: Duration::from_millis(3000),
+                segments: vec![],
+            }
+            .request_offset(&mode, &pauses),
+            Duration::from_millis(2500)
+        );
+    }
+
+    #[test]
+    fn test_segments_placed_across_discards() {
+        let mut audio_buffer = AudioBuffer::with_clock(1, MockClock::new());
+        let one_second = vec![1000; DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS];
+        let transcript = |segments| Transcription {
+            start_timestamp: std::time::SystemTime::UNIX_EPOCH,
+            user_id: 1,
+            segments,
+            audio_duration: Duration::from_secs(2),
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        };
+        let mut placed = Vec::new();
+        for i in 0..3 {
+            audio_buffer.add_audio(&Wrapping(i * 2 * 48000), &one_second);
+            audio_buffer.add_audio(&Wrapping((i * 2 + 1) * 48000), &one_second);
+            let last_request = LastRequestInfo::new(
+                audio_buffer.buffer_duration(),
+                audio_buffer.stream_position(),
+            );
+            // the first second is published and discarded before the
+            // response for the rest of it comes back
+            audio_buffer.discard_audio(&Duration::from_secs(1));
+            let mut response = transcript(vec![segment_at(0, 1000), segment_at(1000, 1500)]);
+            last_request.place_segments(&mut response);
+            placed.extend(
+                response
+                    .segments
+                    .iter()
+                    .map(|segment| (segment.start_sample, segment.end_sample)),
+            );
+            // and then the rest is too
+            audio_buffer.discard_audio(&Duration::from_secs(1));
+        }
+        assert_eq!(
+            placed,
+            vec![
+                (0, 16000),
+                (16000, 24000),
+                (32000, 48000),
+                (48000, 56000),
+                (64000, 80000),
+                (80000, 88000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_whisper_timing() {
+        let buffer_start = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let request_start = buffer_start + Duration::from_millis(500);
+        let last_request = LastRequestInfo::new(Duration::from_secs(2), 0);
+        let mut response = TranscriptionResponse {
+            buffer_offset: Duration::from_millis(500),
+            transcript: Transcription {
+                audio_duration: Duration::from_millis(1500),
+                processing_time: Duration::from_millis(1),
+                utterance_id: 0,
+                language: None,
+                audio_channel: None,
+                segments: vec![segment_at(0, 1000), segment_at(1000, 1500)],
+                start_timestamp: request_start,
+                user_id: 1,
+            },
+            error: None,
+        };
+        last_request.record_whisper_timing(&mut response);
+        let transcript = from_buffer_start(response);
+
+        assert_eq!(transcript.start_timestamp, buffer_start);
+        let segment = &transcript.segments[1];
+        assert_eq!(segment.start_offset_ms, 1500);
+        let timing = segment.whisper_timing.unwrap();
+        assert_eq!(timing.audio_start, request_start);
+        assert_eq!(timing.requested_at, last_request.requested_at);
+        assert_eq!((timing.t0_ms, timing.t1_ms), (1000, 1500));
+        // no drift between where whisper put it and where it ended up
+        assert_eq!(
+            timing.audio_start + Duration::from_millis(timing.t0_ms as u64),
+            transcript.start_timestamp + Duration::from_millis(segment.start_offset_ms as u64)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_request_after_trim() {
+        let three_seconds = Duration::from_secs(3);
+        let mut last_request = LastRequestInfo::new(three_seconds, 0);
+        assert!(last_request.is_duplicate(&three_seconds));
+
+        // finalize the first second, then get another second of audio.
+        // This buffer is the same length, but it's new audio.
+        last_request.record_trim(&Duration::from_secs(1));
+        assert_eq!(last_request.effective_duration(), Duration::from_secs(2));
+        assert!(!last_request.is_duplicate(&three_seconds));
+
+        // without any new audio it really is a duplicate
+        assert!(last_request.is_duplicate(&Duration::from_secs(2)));
+
+        // trimming past the end of the request's audio
+        last_request.record_trim(&three_seconds);
+        assert_eq!(last_request.effective_duration(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_stacked_trims_past_request() {
+        let original_duration = Duration::from_millis(1000);
+        let mut last_request = LastRequestInfo::new(original_duration, 0);
+        // discards which don't land on whole samples, adding up to
+        // more than the request covered
+        let trim = Duration::from_nanos(123_456_789);
+        for _ in 0..20 {
+            last_request.record_trim(&trim);
+            assert!(last_request.effective_duration() <= original_duration);
+        }
+        assert_eq!(last_request.effective_duration(), Duration::ZERO);
+        assert!(!last_request.is_duplicate(&original_duration));
+
+        // even if the trimmed total somehow overtakes the original
+        last_request.audio_trimmed_since_request = original_duration + trim;
+        assert_eq!(last_request.effective_duration(), Duration::ZERO);
+    }
+
+    /// A worker for user 1, started the way the manager starts one.
+    struct TestWorker {
+        metrics: Arc<MetricsCounters>,
+        rx_api: UnboundedReceiver<VoiceChannelEvent>,
+        rx_flush: UnboundedReceiver<ChannelFlushReply>,
+        shutdown_token: CancellationToken,
+        status: Arc<WorkerStatus>,
+        tx_audio: UnboundedSender<UserAudioData>,
+        tx_event: UnboundedSender<UserAudioEventType>,
+    }
+
+    impl TestWorker {
+        fn spawn(config: DiscrivenerConfig, backend: Arc<dyn TranscriptionBackend>) -> Self {
+            let config = Arc::new(config);
+            let metrics = Arc::new(MetricsCounters::new());
+            let shutdown_token = CancellationToken::new();
+            let status = Arc::new(WorkerStatus::new());
+            let (tx_api, rx_api) = sync::mpsc::unbounded_channel();
+            let (tx_flush, rx_flush) = sync::mpsc::unbounded_channel();
+            let strategy = FiveSecondStrategy::new(
+                config.commit_delay,
+                config.first_transcription_delay,
+                config.tentative_transcripts,
+                config.segment_join_threshold,
+                SUBSEQUENT_TRANSCRIPT_PERIOD,
+            );
+            let (tx_event, tx_audio) = UserAudioWorker::monitor(
+                AudioBuffer::new(1),
+                AudioBufferPool::new(0),
+                None,
+                config,
+                metrics.clone(),
+                shutdown_token.clone(),
+                Arc::new(SpeakingTime::default()),
+                status.clone(),
+                strategy,
+                backend,
+                tx_api,
+                tx_flush,
+                Arc::new(UtteranceIds::default()),
+            );
+            Self {
+                metrics,
+                rx_api,
+                rx_flush,
+                shutdown_token,
+                status,
+                tx_audio,
+                tx_event,
+            }
+        }
+
+        /// Sends two seconds of the user's audio, starting at the given
+        /// second, and waits for it to be buffered.
+        async fn say_something(&self, second: u32) {
+            let buffered_bytes = || self.status.buffered_bytes.load(Ordering::Relaxed);
+            let before = buffered_bytes();
+            self.tx_audio
+                .send(UserAudioData {
+                    user_id: 1,
+                    audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+                    rtc_timestamp: Wrapping(second * 48000),
+                    ssrc: 101,
+                })
+                .unwrap();
+            time::timeout(Duration::from_secs(1), async {
+                while buffered_bytes() == before {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        fn send(&self, event: UserAudioEventType) {
+            self.tx_event.send(event).unwrap();
+        }
+
+        /// Waits for the worker to publish an event `pick` picks out.
+        async fn next_event<R>(&mut self, pick: impl Fn(VoiceChannelEvent) -> Option<R>) -> R {
+            loop {
+                let event = time::timeout(Duration::from_secs(5), self.rx_api.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                if let Some(picked) = pick(event) {
+                    return picked;
+                }
+            }
+        }
+
+        async fn next_transcription(&mut self) -> Transcription {
+            self.next_event(|event| match event {
+                VoiceChannelEvent::Transcription(transcription) => Some(transcription),
+                _ => None,
+            })
+            .await
+        }
+    }
+
+    impl Drop for TestWorker {
+        fn drop(&mut self) {
+            self.shutdown_token.cancel();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_request_retried() {
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[Answer::Stuck], Answer::Echo(100));
+        let config = DiscrivenerConfig {
+            transcription_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut worker = TestWorker::spawn(config, backend);
+        worker.say_something(0).await;
+        worker.send(UserAudioEventType::UtteranceBoundary);
+        worker
+            .next_event(|event| {
+                matches!(event, VoiceChannelEvent::TranscriptionTimedOut { .. }).then_some(())
+            })
+            .await;
+
+        // the final request is sent again, and what it finds published
+        let transcription = worker.next_transcription().await;
+        assert_eq!(transcription.text(), " hello");
+        assert_eq!(transcription.audio_duration, Duration::from_secs(2));
+        for _ in 0..2 {
+            let request = rx_requests.try_recv().unwrap();
+            assert_eq!(request.audio_duration, Duration::from_secs(2));
+        }
+        assert!(rx_requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_request_retried() {
+        let (backend, mut rx_requests) = ScriptedBackend::new(&[Answer::Error], Answer::Echo(100));
+        let mut worker = TestWorker::spawn(DiscrivenerConfig::default(), backend);
+        worker.say_something(0).await;
+        worker.send(UserAudioEventType::UtteranceBoundary);
+        worker
+            .next_event(|event| {
+                matches!(event, VoiceChannelEvent::TranscriptionError { .. }).then_some(())
+            })
+            .await;
+
+        // the final request is sent again, and what it finds published
+        let transcription = worker.next_transcription().await;
+        assert_eq!(transcription.text(), " hello");
+        assert_eq!(transcription.audio_duration, Duration::from_secs(2));
+        for _ in 0..2 {
+            let request = rx_requests.try_recv().unwrap();
+            assert_eq!(request.audio_duration, Duration::from_secs(2));
+        }
+        assert!(rx_requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interim_requests_shed_by_worker() {
+        let (backend, mut rx_requests) = ScriptedBackend::echo();
+        let config = DiscrivenerConfig {
+            first_transcription_delay: Duration::from_millis(50),
+            interim_request_high_water_mark: Some(1),
+            ..Default::default()
+        };
+        let mut worker = TestWorker::spawn(config, backend);
+        // another worker is already waiting on the backend
+        worker.metrics.record_requests_sent(1);
+        worker.say_something(0).await;
+        worker.send(UserAudioEventType::Speaking);
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(rx_requests.try_recv().is_err());
+        assert!(worker.metrics.snapshot().shed_transcription_requests >= 1);
+
+        // but once they stop talking, the final request goes through
+        worker.send(UserAudioEventType::Silent);
+        let transcription = worker.next_transcription().await;
+        assert_eq!(transcription.text(), " hello");
+        assert!(!rx_requests.try_recv().unwrap().is_interim);
+    }
+
+    /// How long after the user goes silent the final request is sent.
+    async fn final_request_delay(commit_delay: Duration) -> Duration {
+        let (backend, mut rx_requests) = ScriptedBackend::echo();
+        let config = DiscrivenerConfig {
+            commit_delay,
+            ..Default::default()
+        };
+        let worker = TestWorker::spawn(config, backend);
+        worker.say_something(0).await;
+        let silent_at = Instant::now();
+        worker.send(UserAudioEventType::Silent);
+        let request = time::timeout(Duration::from_secs(5), rx_requests.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!request.is_interim);
+        silent_at.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_commit_delay_holds_back_final_request() {
+        assert!(final_request_delay(Duration::ZERO).await < Duration::from_millis(250));
+        assert!(
+            final_request_delay(Duration::from_millis(500)).await >= Duration::from_millis(500)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_flush_overlapping_channel_flush() {
+        let (backend, _rx_requests) = ScriptedBackend::echo();
+        let config = DiscrivenerConfig {
+            finalization_mode: FinalizationMode::WholeChannel,
+            ..Default::default()
+        };
+        let mut worker = TestWorker::spawn(config, backend);
+        worker.say_something(0).await;
+        // the channel goes quiet, and the session is finalized, before
+        // what was said is transcribed
+        for (flush_id, session) in [(1, false), (2, false), (1, true)] {
+            worker.send(UserAudioEventType::ChannelIdle {
+                flush_id,
+                participants: 1,
+                session,
+            });
+        }
+
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            let reply = time::timeout(Duration::from_secs(5), worker.rx_flush.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            replies.push((reply.flush_id, reply.session, reply.transcriptions.len()));
+        }
+        // the channel's transcription is only published once, but the
+        // session still gets all of it
+        assert_eq!(replies, vec![(1, false, 1), (2, false, 0), (1, true, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_audio_on_its_way_dropped_once_disabled() {
+        let (backend, mut rx_requests) = ScriptedBackend::echo();
+        let mut worker = TestWorker::spawn(DiscrivenerConfig::default(), backend);
+        worker.say_something(0).await;
+        worker.send(UserAudioEventType::TranscriptionDisabled);
+        // this was sent before they were disabled
+        worker
+            .tx_audio
+            .send(UserAudioData {
+                user_id: 1,
+                audio: AudioSamples::Discord(vec![1000; 2 * 48000]),
+                rtc_timestamp: Wrapping(2 * 48000),
+                ssrc: 101,
+            })
+            .unwrap();
+        worker.send(UserAudioEventType::Silent);
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(worker.status.buffered_bytes.load(Ordering::Relaxed), 0);
+        assert!(rx_requests.try_recv().is_err());
+        while let Ok(event) = worker.rx_api.try_recv() {
+            assert!(!matches!(event, VoiceChannelEvent::Transcription(_)));
+        }
+
+        // once enabled again, what they say is transcribed
+        worker.send(UserAudioEventType::TranscriptionEnabled);
+        worker.say_something(4).await;
+        worker.send(UserAudioEventType::Silent);
+        let transcription = worker.next_transcription().await;
+        assert_eq!(transcription.audio_duration, Duration::from_secs(2));
+    }
+}
@@ -9,55 +9,137 @@ use songbird::events::context_data::VoiceData;
 use songbird::model::payload::Speaking;
 use songbird::EventContext;
 
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock, RwLock};
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::audio::events::DiscordAudioData;
+use crate::audio::audio_buffer::{rms_over_slice, smoothing_step};
+use crate::audio::events::AudioSamples;
+use crate::audio::events::UserAudioData;
 use crate::audio::events::UserAudioEvent;
 use crate::audio::events::UserAudioEventType;
+use crate::audio::recent::RecentAudio;
+use crate::export::recording::RecordedPacket;
+use crate::model::config::{NoiseGate, OpusSink};
+use crate::model::constants::{
+    DISCORD_AUDIO_CHANNELS, DISCORD_PACKET_SAMPLES, DISCORD_SAMPLES_PER_SECOND,
+    SUBMITTED_AUDIO_SSRC, WHISPER_SAMPLES_PER_SECOND,
+};
+use crate::model::metrics::MetricsCounters;
 use crate::model::types;
 use crate::model::types::ConnectData;
 use crate::model::types::DisconnectData;
 use crate::model::types::DiscordAudioSample;
 use crate::model::types::DiscordRtcTimestamp;
+use crate::model::types::OpusPacket;
 use crate::model::types::VoiceChannelEvent;
+use crate::model::types::WhisperAudioSample;
 
 pub(crate) struct PacketHandler {
+    /// whether the first packet of audio was in the format we expect,
+    /// once we've seen it
+    audio_format_ok: OnceLock<bool>,
+    /// the voice channel we're connected to, if any
+    current_channel: RwLock<Option<ConnectData>>,
+    /// how each user's audio stands against the noise gate
+    gated_users: RwLock<HashMap<types::UserId, GateLevel>>,
+    metrics: Arc<MetricsCounters>,
+    noise_gate: Option<NoiseGate>,
+    opus_sink: Option<OpusSink>,
+    /// while set, nobody's audio is passed on to be transcribed
+    paused: AtomicBool,
+    /// everyone's latest transcribed audio, if we're keeping it
+    recent_audio: Option<Mutex<RecentAudio>>,
+    /// see `DiscrivenerConfig::rms_smoothing`
+    rms_smoothing: Option<std::time::Duration>,
     ssrc_to_user_id: RwLock<std::collections::HashMap<types::Ssrc, types::UserId>>,
+    transcribed_users: RwLock<TranscribedUsers>,
     tx_api_events: UnboundedSender<VoiceChannelEvent>,
-    tx_audio_data: UnboundedSender<DiscordAudioData>,
+    tx_audio_data: UnboundedSender<UserAudioData>,
+    /// everyone's audio goes here to be recorded, if we're recording
+    tx_recording: Option<SyncSender<RecordedPacket>>,
     tx_voice_activity: UnboundedSender<UserAudioEvent>,
 }
 
 impl PacketHandler {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn register(
+        check_audio_format: bool,
         driver: Arc<tokio::sync::Mutex<songbird::Driver>>,
+        metrics: Arc<MetricsCounters>,
+        noise_gate: Option<NoiseGate>,
+        opus_sink: Option<OpusSink>,
+        recent_audio: Option<RecentAudio>,
+        rms_smoothing: Option<std::time::Duration>,
+        transcribed_users: TranscribedUsers,
         tx_api_events: UnboundedSender<VoiceChannelEvent>,
-        tx_audio_data: UnboundedSender<DiscordAudioData>,
+        tx_audio_data: UnboundedSender<UserAudioData>,
+        tx_recording: Option<SyncSender<RecordedPacket>>,
         tx_voice_activity: UnboundedSender<UserAudioEvent>,
-    ) {
-        let handler = Self {
+    ) -> Arc<Self> {
+        let audio_format_ok = OnceLock::new();
+        if !check_audio_format {
+            audio_format_ok.set(true).unwrap();
+        }
+        let handler = Arc::new(Self {
+            audio_format_ok,
+            current_channel: RwLock::new(None),
+            gated_users: RwLock::new(HashMap::new()),
+            metrics,
+            noise_gate,
+            opus_sink,
+            paused: AtomicBool::new(false),
+            recent_audio: recent_audio.map(Mutex::new),
+            rms_smoothing,
             ssrc_to_user_id: RwLock::new(std::collections::HashMap::new()),
+            transcribed_users: RwLock::new(transcribed_users),
             tx_api_events,
             tx_audio_data,
+            tx_recording,
             tx_voice_activity,
-        };
-        register_events(handler, driver).await;
+        });
+        register_events(handler.clone(), driver).await;
+        handler
     }
 
-    fn on_user_join(&self, ssrc: types::Ssrc, user_id: types::UserId) {
-        {
+    pub(crate) fn on_user_join(&self, ssrc: types::Ssrc, user_id: types::UserId) {
+        let previous_user_id = {
             // map the SSRC to the user ID
-            self.ssrc_to_user_id.write().unwrap().insert(ssrc, user_id);
+            let mut ssrc_to_user_id = self.ssrc_to_user_id.write().unwrap();
+            // a user only has one stream at a time, so any other SSRC
+            // they had is finished
+            ssrc_to_user_id.retain(|other_ssrc, other_user_id| {
+                *other_user_id != user_id || *other_ssrc == ssrc
+            });
+            ssrc_to_user_id.insert(ssrc, user_id)
+        };
+        if let Some(previous_user_id) = previous_user_id {
+            if previous_user_id != user_id {
+                // Discord has reused this SSRC for someone else, so the
+                // previous user won't be getting any more audio from it.
+                // Have their audio transcribed now, rather than waiting
+                // for a silence which will never be reported.
+                self.tx_voice_activity
+                    .send(UserAudioEvent {
+                        user_id: previous_user_id,
+                        event_type: UserAudioEventType::Silent,
+                    })
+                    .unwrap();
+            }
         }
         self.tx_api_events
             .send(VoiceChannelEvent::UserJoin(user_id))
             .unwrap();
     }
 
-    fn on_start_talking(&self, ssrc: types::Ssrc) {
+    pub(crate) fn on_start_talking(&self, ssrc: types::Ssrc) {
         let user_id = self.user_id_from_ssrc(ssrc);
         if let Some(user_id) = user_id {
+            // start afresh, so that if they're still under the gate
+            // we'll say so again
+            self.gated_users.write().unwrap().remove(&user_id);
             self.tx_voice_activity
                 .send(UserAudioEvent {
                     user_id,
@@ -67,26 +149,186 @@ impl PacketHandler {
         }
     }
 
-    fn on_audio(
+    pub(crate) fn on_audio(
         &self,
         discord_audio: &[DiscordAudioSample],
         rtc_timestamp: DiscordRtcTimestamp,
         ssrc: types::Ssrc,
     ) {
-        if let Some(user_id) = self.user_id_from_ssrc(ssrc) {
-            self.tx_audio_data
-                .send(DiscordAudioData {
+        let samples_per_second = DISCORD_SAMPLES_PER_SECOND * DISCORD_AUDIO_CHANNELS;
+        let audio_duration = std::time::Duration::from_micros(
+            (discord_audio.len() * 1_000_000 / samples_per_second) as u64,
+        );
+        if !self.is_expected_format(discord_audio) {
+            self.metrics.record_dropped_audio(audio_duration);
+            return;
+        }
+        let Some(user_id) = self.user_id_from_ssrc(ssrc) else {
+            // we can't tell whose it is
+            self.metrics.record_dropped_audio(audio_duration);
+            return;
+        };
+        self.update_gate(user_id, discord_audio, audio_duration);
+        if self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        if !self.transcribed_users.read().unwrap().is_enabled(user_id) {
+            return;
+        }
+        // recorded however quiet it is
+        self.record(user_id, discord_audio);
+        self.metrics.record_audio_received(audio_duration);
+        if let Some(recent_audio) = &self.recent_audio {
+            recent_audio.lock().unwrap().push(user_id, discord_audio);
+        }
+        self.tx_audio_data
+            .send(UserAudioData {
+                user_id,
+                audio: AudioSamples::Discord(discord_audio.to_vec()),
+                rtc_timestamp,
+                ssrc,
+            })
+            .unwrap();
+    }
+
+    /// Passes on audio which the host decoded itself, and which is
+    /// already in whisper's format, to be transcribed.  It isn't
+    /// recorded, or kept as recent audio, as both of those are in
+    /// Discord's format, and it doesn't open or close the gate.
+    pub(crate) fn on_whisper_audio(
+        &self,
+        user_id: types::UserId,
+        whisper_audio: &[WhisperAudioSample],
+        rtc_timestamp: DiscordRtcTimestamp,
+    ) {
+        if self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        if !self.transcribed_users.read().unwrap().is_enabled(user_id) {
+            return;
+        }
+        let audio_duration = std::time::Duration::from_micros(
+            (whisper_audio.len() * 1_000_000 / WHISPER_SAMPLES_PER_SECOND) as u64,
+        );
+        self.metrics.record_audio_received(audio_duration);
+        self.tx_audio_data
+            .send(UserAudioData {
+                user_id,
+                audio: AudioSamples::Whisper(whisper_audio.to_vec()),
+                rtc_timestamp,
+                ssrc: SUBMITTED_AUDIO_SSRC,
+            })
+            .unwrap();
+    }
+
+    /// Checks the first packet of audio against the format the rest of
+    /// the pipeline assumes, which is 48khz stereo in 20ms packets, and
+    /// after that just remembers the answer.  If songbird's decoding
+    /// were ever set up differently, all our resampling would quietly
+    /// produce garbage, so it's better to stop and say so.
+    fn is_expected_format(&self, discord_audio: &[DiscordAudioSample]) -> bool {
+        if let Some(ok) = self.audio_format_ok.get() {
+            return *ok;
+        }
+        if discord_audio.is_empty() {
+            // nothing to go on yet
+            return true;
+        }
+        let ok = discord_audio.len() == DISCORD_PACKET_SAMPLES;
+        if self.audio_format_ok.set(ok).is_ok() && !ok {
+            eprintln!(
+                "Unexpected audio format: {} samples per packet, expected {}",
+                discord_audio.len(),
+                DISCORD_PACKET_SAMPLES
+            );
+            self.tx_api_events
+                .send(VoiceChannelEvent::UnexpectedAudioFormat {
+                    expected_samples: DISCORD_PACKET_SAMPLES,
+                    received_samples: discord_audio.len(),
+                })
+                .unwrap();
+        }
+        // another packet might have got there first
+        *self.audio_format_ok.get().unwrap()
+    }
+
+    /// Passes the audio on to be recorded, if we're recording.  If the
+    /// recording has fallen too far behind, the audio is dropped
+    /// rather than held on to.
+    fn record(&self, user_id: types::UserId, discord_audio: &[DiscordAudioSample]) {
+        let Some(tx_recording) = &self.tx_recording else {
+            return;
+        };
+        let packet = RecordedPacket {
+            user_id,
+            discord_audio: discord_audio.to_vec(),
+        };
+        if let Err(TrySendError::Full(_)) = tx_recording.try_send(packet) {
+            eprintln!(
+                "recording has fallen behind, dropping audio from {}",
+                user_id
+            );
+        }
+    }
+
+    /// Lets voice activity know when the user's audio has stayed below
+    /// the gate for long enough, or comes back above it, so that noise
+    /// from an open mic counts as silence.
+    fn update_gate(
+        &self,
+        user_id: types::UserId,
+        discord_audio: &[DiscordAudioSample],
+        audio_duration: std::time::Duration,
+    ) {
+        let Some(noise_gate) = self.noise_gate else {
+            return;
+        };
+        let (gated, changed) = {
+            let mut gated_users = self.gated_users.write().unwrap();
+            let gate = gated_users.entry(user_id).or_default();
+            let was_gated = gate.quiet >= noise_gate.hold;
+            let level = packet_level(discord_audio);
+            gate.level = match self.rms_smoothing {
+                Some(time_constant) => {
+                    let step = smoothing_step(&audio_duration, &time_constant);
+                    gate.level + step * (level - gate.level)
+                }
+                None => level,
+            };
+            if gate.level < noise_gate.threshold {
+                gate.quiet += audio_duration;
+            } else {
+                gate.quiet = std::time::Duration::ZERO;
+            }
+            let gated = gate.quiet >= noise_gate.hold;
+            (gated, gated != was_gated)
+        };
+        if changed {
+            self.tx_voice_activity
+                .send(UserAudioEvent {
                     user_id,
-                    discord_audio: discord_audio.to_vec(),
-                    rtc_timestamp,
+                    event_type: UserAudioEventType::Gated(gated),
                 })
                 .unwrap();
         }
     }
 
+    /// Hands the packet's Opus frame to the sink, if we have one.
+    fn on_opus(&self, opus_frame: &[u8], rtc_timestamp: u32, sequence: u16, ssrc: types::Ssrc) {
+        if let Some(OpusSink(opus_sink)) = &self.opus_sink {
+            opus_sink(OpusPacket {
+                opus_frame: opus_frame.to_vec(),
+                rtc_timestamp,
+                sequence,
+                ssrc,
+                user_id: self.user_id_from_ssrc(ssrc),
+            });
+        }
+    }
+
     /// Fired when a user stops talking.  Here, "stops talking" means
     /// the songbird driver has noticed 5 continuous packets (100ms) of silence.
-    fn on_stop_talking(&self, ssrc: types::Ssrc) {
+    pub(crate) fn on_stop_talking(&self, ssrc: types::Ssrc) {
         if let Some(user_id) = self.user_id_from_ssrc(ssrc) {
             self.tx_voice_activity
                 .send(UserAudioEvent {
@@ -111,11 +353,198 @@ impl PacketHandler {
             .unwrap();
     }
 
+    /// Starts or stops sending the user's audio on to be transcribed.
+    /// When stopping, anything already buffered for them is discarded.
+    pub(crate) fn set_transcription_enabled(&self, user_id: types::UserId, enabled: bool) {
+        let changed = self
+            .transcribed_users
+            .write()
+            .unwrap()
+            .set_enabled(user_id, enabled);
+        if changed {
+            let event_type = if enabled {
+                UserAudioEventType::TranscriptionEnabled
+            } else {
+                if let Some(recent_audio) = &self.recent_audio {
+                    recent_audio.lock().unwrap().forget(user_id);
+                }
+                UserAudioEventType::TranscriptionDisabled
+            };
+            self.tx_voice_activity
+                .send(UserAudioEvent {
+                    user_id,
+                    event_type,
+                })
+                .unwrap();
+        }
+        self.tx_api_events
+            .send(VoiceChannelEvent::TranscriptionEnabledChanged { enabled, user_id })
+            .unwrap();
+    }
+
+    /// Stops or restarts passing everyone's audio on to be transcribed.
+    /// Throwing away what's already buffered is up to the audio buffer
+    /// manager, which knows about everyone with audio, not just those
+    /// still in the channel.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        self.tx_api_events
+            .send(VoiceChannelEvent::TranscriptionPausedChanged { paused })
+            .unwrap();
+    }
+
+    /// Up to `duration` of the user's most recent audio, if we're
+    /// keeping it.
+    pub(crate) fn recent_audio(
+        &self,
+        user_id: types::UserId,
+        duration: std::time::Duration,
+    ) -> Vec<DiscordAudioSample> {
+        match &self.recent_audio {
+            Some(recent_audio) => recent_audio.lock().unwrap().get(user_id, duration),
+            None => Vec::new(),
+        }
+    }
+
+    /// Finalizes whatever the user has said so far, as though they had
+    /// stopped talking.
+    pub(crate) fn mark_utterance_boundary(&self, user_id: types::UserId) {
+        self.tx_voice_activity
+            .send(UserAudioEvent {
+                user_id,
+                event_type: UserAudioEventType::UtteranceBoundary,
+            })
+            .unwrap();
+    }
+
+    /// Finalizes whatever everyone has said so far, as the voice
+    /// connection has dropped and been resumed.  Streams can carry on
+    /// across a reconnect, but their timestamps can jump, so what
+    /// comes after is transcribed separately rather than lined up with
+    /// what came before.
+    pub(crate) fn on_reconnect(&self, connect_data: ConnectData) {
+        // the channel can have changed, if this was a move
+        *self.current_channel.write().unwrap() = Some(connect_data.clone());
+        let user_ids = self
+            .ssrc_to_user_id
+            .read()
+            .unwrap()
+            .values()
+            .copied()
+            .collect::<HashSet<types::UserId>>();
+        for user_id in user_ids {
+            self.tx_voice_activity
+                .send(UserAudioEvent {
+                    user_id,
+                    event_type: UserAudioEventType::UtteranceBoundary,
+                })
+                .ok();
+        }
+        if self
+            .tx_api_events
+            .send(VoiceChannelEvent::Reconnect(connect_data))
+            .is_err()
+        {
+            eprintln!("Reconnect event not sent (expected when exiting)");
+        }
+    }
+
+    pub(crate) fn on_connect(&self, connect_data: ConnectData) {
+        *self.current_channel.write().unwrap() = Some(connect_data.clone());
+        self.tx_api_events
+            .send(VoiceChannelEvent::Connect(connect_data))
+            .unwrap();
+    }
+
+    pub(crate) fn on_disconnect(&self, disconnect_data: DisconnectData) {
+        // whether it was asked for or not, we're no longer in the channel
+        self.forget_channel();
+        let result = self
+            .tx_api_events
+            .send(VoiceChannelEvent::Disconnect(disconnect_data));
+        if result.is_err() {
+            eprintln!("Disconnect event not sent (expected when exiting)");
+        }
+    }
+
+    /// The voice channel we're connected to, or None if we aren't.
+    pub(crate) fn current_channel(&self) -> Option<ConnectData> {
+        self.current_channel.read().unwrap().clone()
+    }
+
+    pub(crate) fn forget_channel(&self) {
+        *self.current_channel.write().unwrap() = None;
+    }
+
     fn user_id_from_ssrc(&self, ssrc: types::Ssrc) -> Option<types::UserId> {
         self.ssrc_to_user_id.read().unwrap().get(&ssrc).copied()
     }
 }
 
+/// A user's audio level, as the noise gate sees it.
+#[derive(Debug, Default)]
+struct GateLevel {
+    /// the level of their last packet, or smoothed over their last
+    /// few if `rms_smoothing` is set
+    level: f32,
+    /// how long the level has been below the gate
+    quiet: std::time::Duration,
+}
+
+/// The packet's RMS level, on the same scale as whisper's samples.
+fn packet_level(discord_audio: &[DiscordAudioSample]) -> f32 {
+    if discord_audio.is_empty() {
+        return 0.0;
+    }
+    let samples = discord_audio
+        .iter()
+        .map(|sample| *sample as WhisperAudioSample / i16::MAX as WhisperAudioSample)
+        .collect::<Vec<WhisperAudioSample>>();
+    rms_over_slice(&samples)
+}
+
+/// Decides whose audio gets transcribed.
+pub(crate) struct TranscribedUsers {
+    ignore_users: HashSet<types::UserId>,
+    only_users: Option<HashSet<types::UserId>>,
+    /// changes made while running, which take precedence over the
+    /// initial sets
+    overrides: HashMap<types::UserId, bool>,
+}
+
+impl TranscribedUsers {
+    pub(crate) fn new(
+        ignore_users: HashSet<types::UserId>,
+        only_users: Option<HashSet<types::UserId>>,
+    ) -> Self {
+        Self {
+            ignore_users,
+            only_users,
+            overrides: HashMap::new(),
+        }
+    }
+
+    fn is_enabled(&self, user_id: types::UserId) -> bool {
+        if let Some(enabled) = self.overrides.get(&user_id) {
+            return *enabled;
+        }
+        if self.ignore_users.contains(&user_id) {
+            return false;
+        }
+        match &self.only_users {
+            Some(only_users) => only_users.contains(&user_id),
+            None => true,
+        }
+    }
+
+    /// Returns true if this changed whether the user is transcribed.
+    fn set_enabled(&mut self, user_id: types::UserId, enabled: bool) -> bool {
+        let was_enabled = self.is_enabled(user_id);
+        self.overrides.insert(user_id, enabled);
+        was_enabled != enabled
+    }
+}
+
 struct MyEventHandler<T>
 where
     T: Fn(&songbird::EventContext, &Arc<PacketHandler>) + Send + Sync,
@@ -136,10 +565,9 @@ where
 }
 
 pub(crate) async fn register_events(
-    raw_handler: PacketHandler,
+    handler: Arc<PacketHandler>,
     driver: Arc<tokio::sync::Mutex<songbird::Driver>>,
 ) {
-    let handler = Arc::new(raw_handler);
     // event handlers for the songbird driver
     driver.lock().await.add_global_event(
         songbird::CoreEvent::SpeakingStateUpdate.into(),
@@ -193,14 +621,26 @@ pub(crate) async fn register_events(
             packet_handler: handler.clone(),
             handler: move |ctx, my_handler| {
                 if let EventContext::VoicePacket(VoiceData {
-                    audio: Some(discord_audio),
+                    audio,
                     packet,
-                    ..
+                    payload_end_pad,
+                    payload_offset,
                 }) = ctx
                 {
                     // An event which fires for every received audio packet,
-                    // containing the decoded data.
-                    my_handler.on_audio(discord_audio, packet.timestamp.0, packet.ssrc);
+                    // containing the decoded data if we asked for it.
+                    let payload_end = packet.payload.len().saturating_sub(*payload_end_pad);
+                    if let Some(opus_frame) = packet.payload.get(*payload_offset..payload_end) {
+                        my_handler.on_opus(
+                            opus_frame,
+                            packet.timestamp.0 .0,
+                            packet.sequence.0 .0,
+                            packet.ssrc,
+                        );
+                    }
+                    if let Some(discord_audio) = audio {
+                        my_handler.on_audio(discord_audio, packet.timestamp.0, packet.ssrc);
+                    }
                 }
             },
         },
@@ -226,10 +666,7 @@ pub(crate) async fn register_events(
             packet_handler: handler.clone(),
             handler: move |ctx, my_handler| {
                 if let EventContext::DriverConnect(connect_data) = ctx {
-                    my_handler
-                        .tx_api_events
-                        .send(VoiceChannelEvent::Connect(ConnectData::from(connect_data)))
-                        .unwrap();
+                    my_handler.on_connect(ConnectData::from(connect_data));
                 }
             },
         },
@@ -240,12 +677,7 @@ pub(crate) async fn register_events(
             packet_handler: handler.clone(),
             handler: move |ctx, my_handler| {
                 if let EventContext::DriverDisconnect(disconnect_data) = ctx {
-                    let result = my_handler.tx_api_events.send(VoiceChannelEvent::Disconnect(
-                        DisconnectData::from(disconnect_data),
-                    ));
-                    if result.is_err() {
-                        eprintln!("Disconnect event not sent (expected when exiting)");
-                    }
+                    my_handler.on_disconnect(DisconnectData::from(disconnect_data));
                 }
             },
         },
@@ -256,16 +688,439 @@ pub(crate) async fn register_events(
             packet_handler: handler,
             handler: move |ctx, my_handler| {
                 if let EventContext::DriverReconnect(connect_data) = ctx {
-                    match my_handler.tx_api_events.send(VoiceChannelEvent::Reconnect(
-                        ConnectData::from(connect_data),
-                    )) {
-                        Ok(_) => {} // everything is fine
-                        Err(_) => {
-                            eprintln!("Reconnect event not sent (expected when exiting)");
-                        }
-                    };
+                    my_handler.on_reconnect(ConnectData::from(connect_data));
                 }
             },
         },
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use crate::model::types::DisconnectKind;
+
+    use super::*;
+
+    fn make_handler() -> (
+        PacketHandler,
+        UnboundedReceiver<VoiceChannelEvent>,
+        UnboundedReceiver<UserAudioData>,
+        UnboundedReceiver<UserAudioEvent>,
+    ) {
+        let (tx_api_events, rx_api_events) = unbounded_channel();
+        let (tx_audio_data, rx_audio_data) = unbounded_channel();
+        let (tx_voice_activity, rx_voice_activity) = unbounded_channel();
+        let handler = PacketHandler {
+            audio_format_ok: OnceLock::new(),
+            current_channel: RwLock::new(None),
+            gated_users: RwLock::new(HashMap::new()),
+            metrics: Arc::new(MetricsCounters::new()),
+            noise_gate: Some(NoiseGate::default()),
+            opus_sink: None,
+            paused: AtomicBool::new(false),
+            recent_audio: Some(Mutex::new(RecentAudio::new(Duration::from_secs(1)))),
+            rms_smoothing: None,
+            ssrc_to_user_id: RwLock::new(std::collections::HashMap::new()),
+            transcribed_users: RwLock::new(TranscribedUsers::new(HashSet::new(), None)),
+            tx_api_events,
+            tx_audio_data,
+            tx_recording: None,
+            tx_voice_activity,
+        };
+        (handler, rx_api_events, rx_audio_data, rx_voice_activity)
+    }
+
+    #[test]
+    fn test_ssrc_reused_by_another_user() {
+        let (handler, _rx_api_events, mut rx_audio_data, mut rx_voice_activity) = make_handler();
+        let audio = [1000; 1920];
+
+        handler.on_user_join(100, 1);
+        handler.on_audio(&audio, Wrapping(0), 100);
+        handler.on_user_join(100, 2);
+        handler.on_audio(&audio, Wrapping(960), 100);
+
+        let first = rx_audio_data.try_recv().unwrap();
+        assert_eq!(first.user_id, 1);
+        assert_eq!(first.rtc_timestamp, Wrapping(0));
+        let second = rx_audio_data.try_recv().unwrap();
+        assert_eq!(second.user_id, 2);
+        assert_eq!(second.rtc_timestamp, Wrapping(960));
+        assert!(rx_audio_data.try_recv().is_err());
+
+        // the first user's audio should be wrapped up
+        let event = rx_voice_activity.try_recv().unwrap();
+        assert_eq!(event.user_id, 1);
+        assert_eq!(event.event_type, UserAudioEventType::Silent);
+        assert!(rx_voice_activity.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_user_gets_new_ssrc() {
+        let (handler, _rx_api_events, mut rx_audio_data, mut rx_voice_activity) = make_handler();
+        let audio = [1000; 1920];
+
+        handler.on_user_join(100, 1);
+        handler.on_user_join(200, 1);
+        // the old stream is no longer theirs
+        handler.on_audio(&audio, Wrapping(0), 100);
+        handler.on_audio(&audio, Wrapping(0), 200);
+
+        let received = rx_audio_data.try_recv().unwrap();
+        assert_eq!(received.user_id, 1);
+        assert_eq!(received.ssrc, 200);
+        assert!(rx_audio_data.try_recv().is_err());
+        assert!(rx_voice_activity.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_transcription_disabled() {
+        let (handler, mut rx_api_events, mut rx_audio_data, mut rx_voice_activity) = make_handler();
+        let audio = [1000; 1920];
+        handler.on_user_join(100, 1);
+        rx_api_events.try_recv().unwrap();
+
+        handler.set_transcription_enabled(1, false);
+        handler.on_audio(&audio, Wrapping(0), 100);
+        assert!(rx_audio_data.try_recv().is_err());
+        let event = rx_voice_activity.try_recv().unwrap();
+        assert_eq!(event.user_id, 1);
+        assert_eq!(event.event_type, UserAudioEventType::TranscriptionDisabled);
+        assert!(matches!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::TranscriptionEnabledChanged {
+                enabled: false,
+                user_id: 1
+            }
+        ));
+
+        handler.set_transcription_enabled(1, true);
+        handler.on_audio(&audio, Wrapping(960), 100);
+        assert_eq!(rx_audio_data.try_recv().unwrap().user_id, 1);
+        assert_eq!(
+            rx_voice_activity.try_recv().unwrap().event_type,
+            UserAudioEventType::TranscriptionEnabled
+        );
+        assert!(matches!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::TranscriptionEnabledChanged {
+                enabled: true,
+                user_id: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn test_paused() {
+        let (handler, mut rx_api_events, mut rx_audio_data, mut rx_voice_activity) = make_handler();
+        let audio = [1000; 1920];
+        handler.on_user_join(100, 1);
+        handler.on_user_join(200, 2);
+        rx_api_events.try_recv().unwrap();
+        rx_api_events.try_recv().unwrap();
+
+        handler.set_paused(true);
+        handler.on_audio(&audio, Wrapping(0), 100);
+        assert!(rx_audio_data.try_recv().is_err());
+        // throwing away what's buffered is left to the manager
+        assert!(rx_voice_activity.try_recv().is_err());
+        assert_eq!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::TranscriptionPausedChanged { paused: true }
+        );
+
+        // users who were disabled in the meantime stay disabled
+        handler.set_transcription_enabled(2, false);
+        rx_voice_activity.try_recv().unwrap();
+        rx_api_events.try_recv().unwrap();
+        handler.set_paused(false);
+        handler.on_audio(&audio, Wrapping(960), 100);
+        handler.on_audio(&audio, Wrapping(960), 200);
+        assert_eq!(rx_audio_data.try_recv().unwrap().user_id, 1);
+        assert!(rx_audio_data.try_recv().is_err());
+        assert_eq!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::TranscriptionPausedChanged { paused: false }
+        );
+    }
+
+    #[test]
+    fn test_gate() {
+        let (handler, _rx_api_events, mut rx_audio_data, mut rx_voice_activity) = make_handler();
+        let noise = [1; 1920];
+        let speech = [1000; 1920];
+        handler.on_user_join(100, 1);
+        handler.on_start_talking(100);
+        assert_eq!(
+            rx_voice_activity.try_recv().unwrap().event_type,
+            UserAudioEventType::Speaking
+        );
+
+        // a gap between words doesn't close the gate
+        for i in 0..10 {
+            handler.on_audio(&noise, Wrapping(i * 960), 100);
+        }
+        handler.on_audio(&speech, Wrapping(9600), 100);
+        assert!(rx_voice_activity.try_recv().is_err());
+
+        // an open mic does once it's been quiet for 300ms, only saying
+        // so once
+        for i in 11..30 {
+            handler.on_audio(&noise, Wrapping(i * 960), 100);
+            if i == 25 {
+                assert_eq!(
+                    rx_voice_activity.try_recv().unwrap().event_type,
+                    UserAudioEventType::Gated(true)
+                );
+            }
+            assert!(rx_voice_activity.try_recv().is_err());
+        }
+        // the audio is still passed on
+        assert_eq!(rx_audio_data.try_recv().unwrap().user_id, 1);
+
+        handler.on_audio(&speech, Wrapping(28800), 100);
+        handler.on_audio(&speech, Wrapping(29760), 100);
+        assert_eq!(
+            rx_voice_activity.try_recv().unwrap().event_type,
+            UserAudioEventType::Gated(false)
+        );
+        assert!(rx_voice_activity.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_gate_smoothed() {
+        let (mut handler, _rx_api_events, _rx_audio_data, mut rx_voice_activity) = make_handler();
+        handler.rms_smoothing = Some(Duration::from_millis(200));
+        let noise = [1; 1920];
+        let speech = [1000; 1920];
+        handler.on_user_join(100, 1);
+        for i in 0..20 {
+            handler.on_audio(&noise, Wrapping(i * 960), 100);
+        }
+        assert_eq!(
+            rx_voice_activity.try_recv().unwrap().event_type,
+            UserAudioEventType::Gated(true)
+        );
+
+        // a single click doesn't open the gate, as it would unsmoothed
+        handler.on_audio(&speech, Wrapping(20 * 960), 100);
+        assert!(rx_voice_activity.try_recv().is_err());
+
+        // but talking does, once the level has caught up
+        for i in 21..25 {
+            handler.on_audio(&speech, Wrapping(i * 960), 100);
+        }
+        assert_eq!(
+            rx_voice_activity.try_recv().unwrap().event_type,
+            UserAudioEventType::Gated(false)
+        );
+        assert!(rx_voice_activity.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_gate_disabled() {
+        let (mut handler, _rx_api_events, _rx_audio_data, mut rx_voice_activity) = make_handler();
+        handler.noise_gate = None;
+        handler.on_user_join(100, 1);
+        for i in 0..30 {
+            handler.on_audio(&[1; 1920], Wrapping(i * 960), 100);
+        }
+        assert!(rx_voice_activity.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_recent_audio() {
+        let (handler, _rx_api_events, _rx_audio_data, _rx_voice_activity) = make_handler();
+        handler.on_user_join(100, 1);
+        handler.on_audio(&[1000; 1920], Wrapping(0), 100);
+        handler.on_audio(&[2000; 1920], Wrapping(960), 100);
+        assert_eq!(
+            handler.recent_audio(1, Duration::from_millis(20)),
+            vec![2000; 1920]
+        );
+        assert_eq!(handler.recent_audio(1, Duration::from_secs(5)).len(), 3840);
+
+        // nothing is kept for users who aren't being transcribed
+        handler.set_transcription_enabled(1, false);
+        assert!(handler.recent_audio(1, Duration::from_secs(5)).is_empty());
+        handler.on_audio(&[1000; 1920], Wrapping(1920), 100);
+        assert!(handler.recent_audio(1, Duration::from_secs(5)).is_empty());
+    }
+
+    #[test]
+    fn test_whisper_audio() {
+        let (handler, _rx_api_events, mut rx_audio_data, _rx_voice_activity) = make_handler();
+        // no need to have joined, as the host tells us whose audio it is
+        handler.on_whisper_audio(1, &[0.25; 320], Wrapping(960));
+        let audio = rx_audio_data.try_recv().unwrap();
+        assert_eq!(audio.user_id, 1);
+        assert_eq!(audio.rtc_timestamp, Wrapping(960));
+        assert_eq!(audio.ssrc, SUBMITTED_AUDIO_SSRC);
+        assert!(matches!(audio.audio, AudioSamples::Whisper(samples) if samples == [0.25; 320]));
+
+        handler.set_transcription_enabled(1, false);
+        handler.on_whisper_audio(1, &[0.25; 320], Wrapping(1920));
+        handler.set_paused(true);
+        handler.on_whisper_audio(2, &[0.25; 320], Wrapping(960));
+        assert!(rx_audio_data.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_recording() {
+        let (handler, _rx_api_events, mut rx_audio_data, _rx_voice_activity) = make_handler();
+        let (tx_recording, rx_recording) = std::sync::mpsc::sync_channel(2);
+        let handler = PacketHandler {
+            tx_recording: Some(tx_recording),
+            ..handler
+        };
+        handler.on_user_join(100, 1);
+        handler.on_user_join(200, 2);
+
+        // recorded even though it's too quiet to get past the gate
+        handler.on_audio(&[1; 1920], Wrapping(0), 200);
+        handler.on_audio(&[1000; 1920], Wrapping(0), 100);
+        // the recording is behind, so this is dropped
+        handler.on_audio(&[2000; 1920], Wrapping(960), 100);
+        let packet = rx_recording.try_recv().unwrap();
+        assert_eq!(packet.user_id, 2);
+        assert_eq!(packet.discord_audio, vec![1; 1920]);
+        let packet = rx_recording.try_recv().unwrap();
+        assert_eq!(packet.user_id, 1);
+        assert_eq!(packet.discord_audio, vec![1000; 1920]);
+        assert!(rx_recording.try_recv().is_err());
+        while rx_audio_data.try_recv().is_ok() {}
+
+        // nothing is recorded for users who aren't being transcribed,
+        // or while we're paused
+        handler.set_transcription_enabled(2, false);
+        handler.on_audio(&[1000; 1920], Wrapping(960), 200);
+        handler.set_paused(true);
+        handler.on_audio(&[1000; 1920], Wrapping(1920), 100);
+        assert!(rx_recording.try_recv().is_err());
+        assert!(rx_audio_data.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_unexpected_audio_format() {
+        let (handler, mut rx_api_events, mut rx_audio_data, _rx_voice_activity) = make_handler();
+        handler.on_user_join(100, 1);
+        rx_api_events.try_recv().unwrap();
+
+        // 20ms of mono
+        handler.on_audio(&[1000; 960], Wrapping(0), 100);
+        assert_eq!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::UnexpectedAudioFormat {
+                expected_samples: 1920,
+                received_samples: 960,
+            }
+        );
+        assert!(rx_audio_data.try_recv().is_err());
+
+        // only the first packet is checked, and nothing is let through
+        handler.on_audio(&[1000; 1920], Wrapping(960), 100);
+        assert!(rx_api_events.try_recv().is_err());
+        assert!(rx_audio_data.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_mark_utterance_boundary() {
+        let (handler, _rx_api_events, _rx_audio_data, mut rx_voice_activity) = make_handler();
+        handler.mark_utterance_boundary(1);
+        let event = rx_voice_activity.try_recv().unwrap();
+        assert_eq!(event.user_id, 1);
+        assert_eq!(event.event_type, UserAudioEventType::UtteranceBoundary);
+    }
+
+    #[test]
+    fn test_reconnect_finalizes_everyone() {
+        let (handler, mut rx_api_events, _rx_audio_data, mut rx_voice_activity) = make_handler();
+        handler.on_user_join(100, 1);
+        handler.on_user_join(200, 2);
+        while rx_api_events.try_recv().is_ok() {}
+
+        let connect_data = ConnectData {
+            channel_id: Some(3),
+            guild_id: 4,
+            session_id: "session".to_string(),
+            server: "server".to_string(),
+        };
+        handler.on_reconnect(connect_data.clone());
+        let mut finalized = Vec::new();
+        while let Ok(event) = rx_voice_activity.try_recv() {
+            assert_eq!(event.event_type, UserAudioEventType::UtteranceBoundary);
+            finalized.push(event.user_id);
+        }
+        finalized.sort();
+        assert_eq!(finalized, vec![1, 2]);
+        assert_eq!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::Reconnect(connect_data)
+        );
+    }
+
+    #[test]
+    fn test_current_channel() {
+        let (handler, mut rx_api_events, _rx_audio_data, _rx_voice_activity) = make_handler();
+        assert_eq!(handler.current_channel(), None);
+
+        let connect_data = ConnectData {
+            channel_id: Some(3),
+            guild_id: 4,
+            session_id: "session".to_string(),
+            server: "server".to_string(),
+        };
+        handler.on_connect(connect_data.clone());
+        assert_eq!(handler.current_channel(), Some(connect_data.clone()));
+        assert_eq!(
+            rx_api_events.try_recv().unwrap(),
+            VoiceChannelEvent::Connect(connect_data.clone())
+        );
+
+        // moved to another channel
+        let moved = ConnectData {
+            channel_id: Some(5),
+            ..connect_data
+        };
+        handler.on_reconnect(moved.clone());
+        assert_eq!(handler.current_channel(), Some(moved));
+
+        handler.on_disconnect(DisconnectData {
+            kind: DisconnectKind::Runtime,
+            reason: None,
+            channel_id: Some(5),
+            guild_id: 4,
+            session_id: "session".to_string(),
+        });
+        assert_eq!(handler.current_channel(), None);
+    }
+
+    #[test]
+    fn test_transcribed_users() {
+        let mut everyone = TranscribedUsers::new(HashSet::new(), None);
+        assert!(everyone.is_enabled(1));
+        assert!(!everyone.set_enabled(1, true));
+        assert!(everyone.set_enabled(1, false));
+        assert!(!everyone.is_enabled(1));
+
+        let mut ignoring = TranscribedUsers::new(HashSet::from([1]), None);
+        assert!(!ignoring.is_enabled(1));
+        assert!(ignoring.is_enabled(2));
+        // runtime changes win over the initial set
+        assert!(ignoring.set_enabled(1, true));
+        assert!(ignoring.is_enabled(1));
+
+        let mut allowing = TranscribedUsers::new(HashSet::from([2]), Some(HashSet::from([1, 2])));
+        assert!(allowing.is_enabled(1));
+        // ignoring beats allowing
+        assert!(!allowing.is_enabled(2));
+        assert!(!allowing.is_enabled(3));
+        assert!(allowing.set_enabled(3, true));
+        assert!(allowing.is_enabled(3));
+    }
+}
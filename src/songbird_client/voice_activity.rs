@@ -1,5 +1,8 @@
 use crate::audio::events::UserAudioEvent;
 use crate::audio::events::UserAudioEventType;
+use crate::model::clock::Clock;
+use crate::model::clock::SystemClock;
+use crate::model::config::FinalizationMode;
 use crate::model::constants::FOREVER;
 use crate::model::types::UserId;
 use crate::model::types::VoiceChannelEvent;
@@ -32,6 +35,10 @@ impl SpeakingUsers {
         }
     }
 
+    pub fn contains(&self, user_id: &UserId) -> bool {
+        self.speaking_users.contains(user_id)
+    }
+
     pub fn remove(&mut self, user_id: &UserId) {
         self.speaking_users.remove(user_id);
         if self.speaking_users.is_empty() {
@@ -64,7 +71,8 @@ impl Ord for UserTime {
     }
 }
 
-struct UserIdleDetector {
+struct UserIdleDetector<C: Clock = SystemClock> {
+    clock: C,
     idle_times: BinaryHeap<UserTime>,
     tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
     user_silence_timeout: Duration,
@@ -74,8 +82,19 @@ impl UserIdleDetector {
     fn new(
         tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
         user_silence_timeout: Duration,
+    ) -> Self {
+        Self::with_clock(SystemClock, tx_silent_user_events, user_silence_timeout)
+    }
+}
+
+impl<C: Clock> UserIdleDetector<C> {
+    fn with_clock(
+        clock: C,
+        tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
+        user_silence_timeout: Duration,
     ) -> Self {
         Self {
+            clock,
             idle_times: BinaryHeap::new(),
             tx_silent_user_events,
             user_silence_timeout,
@@ -90,7 +109,7 @@ impl UserIdleDetector {
         self.purge_user(user_id);
         self.idle_times.push(UserTime {
             user_id: *user_id,
-            idle_timeout: time::Instant::now() + self.user_silence_timeout,
+            idle_timeout: self.clock.instant() + self.user_silence_timeout,
         });
     }
 
@@ -100,7 +119,7 @@ impl UserIdleDetector {
             idle_timeout,
         }) = self.idle_times.pop()
         {
-            assert!(idle_timeout <= Instant::now());
+            assert!(idle_timeout <= self.clock.instant());
             match self.tx_silent_user_events.send(UserAudioEvent {
                 user_id,
                 event_type: UserAudioEventType::Idle,
@@ -118,7 +137,7 @@ impl UserIdleDetector {
         self.idle_times
             .peek()
             .map(|user_time| user_time.idle_timeout)
-            .unwrap_or(Instant::now() + FOREVER)
+            .unwrap_or(self.clock.instant() + FOREVER)
     }
 
     fn purge_user(&mut self, user_id: &UserId) {
@@ -127,24 +146,171 @@ impl UserIdleDetector {
     }
 }
 
+/// Waits for everyone in the channel to have been silent for the
+/// timeout, then has everyone who spoke since the last time that
+/// happened finalize their audio together.
+struct ChannelIdleDetector<C: Clock = SystemClock> {
+    clock: C,
+    idle_timeout: Option<time::Instant>,
+    next_flush_id: u64,
+    speaking_users: collections::HashSet<UserId>,
+    /// everyone who has spoken since the channel last went idle
+    spoken_users: collections::BTreeSet<UserId>,
+    tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
+    user_silence_timeout: Duration,
+}
+
+impl ChannelIdleDetector {
+    fn new(
+        tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
+        user_silence_timeout: Duration,
+    ) -> Self {
+        Self::with_clock(SystemClock, tx_silent_user_events, user_silence_timeout)
+    }
+}
+
+impl<C: Clock> ChannelIdleDetector<C> {
+    fn with_clock(
+        clock: C,
+        tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
+        user_silence_timeout: Duration,
+    ) -> Self {
+        Self {
+            clock,
+            idle_timeout: None,
+            next_flush_id: 1,
+            speaking_users: collections::HashSet::new(),
+            spoken_users: collections::BTreeSet::new(),
+            tx_silent_user_events,
+            user_silence_timeout,
+        }
+    }
+
+    pub fn on_speaking(&mut self, user_id: &UserId) {
+        self.speaking_users.insert(*user_id);
+        self.spoken_users.insert(*user_id);
+        self.idle_timeout = None;
+    }
+
+    pub fn on_silent(&mut self, user_id: &UserId) {
+        self.speaking_users.remove(user_id);
+        if self.speaking_users.is_empty() && !self.spoken_users.is_empty() {
+            self.idle_timeout = Some(self.clock.instant() + self.user_silence_timeout);
+        }
+    }
+
+    pub fn on_idle_timeout(&mut self) {
+        if self.idle_timeout.take().is_none() {
+            return;
+        }
+        let flush_id = self.next_flush_id;
+        self.next_flush_id += 1;
+        let users = std::mem::take(&mut self.spoken_users);
+        let participants = users.len();
+        for user_id in users {
+            match self.tx_silent_user_events.send(UserAudioEvent {
+                user_id,
+                event_type: UserAudioEventType::ChannelIdle {
+                    flush_id,
+                    participants,
+                    session: false,
+                },
+            }) {
+                Ok(_) => {} // everything's fine
+                Err(err) => {
+                    eprintln!(
+                        "Failed to send channel idle event to audio thread, {:?}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn next_timeout(&self) -> Instant {
+        self.idle_timeout.unwrap_or(self.clock.instant() + FOREVER)
+    }
+}
+
+/// Decides when to have users' audio finalized, according to the
+/// finalization mode.
+enum IdleDetector {
+    PerUser(UserIdleDetector),
+    WholeChannel(ChannelIdleDetector),
+}
+
+impl IdleDetector {
+    fn new(
+        finalization_mode: FinalizationMode,
+        tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
+        user_silence_timeout: Duration,
+    ) -> Self {
+        match finalization_mode {
+            FinalizationMode::PerUser => IdleDetector::PerUser(UserIdleDetector::new(
+                tx_silent_user_events,
+                user_silence_timeout,
+            )),
+            FinalizationMode::WholeChannel => IdleDetector::WholeChannel(ChannelIdleDetector::new(
+                tx_silent_user_events,
+                user_silence_timeout,
+            )),
+        }
+    }
+
+    fn on_speaking(&mut self, user_id: &UserId) {
+        match self {
+            IdleDetector::PerUser(detector) => detector.on_speaking(user_id),
+            IdleDetector::WholeChannel(detector) => detector.on_speaking(user_id),
+        }
+    }
+
+    fn on_silent(&mut self, user_id: &UserId) {
+        match self {
+            IdleDetector::PerUser(detector) => detector.on_silent(user_id),
+            IdleDetector::WholeChannel(detector) => detector.on_silent(user_id),
+        }
+    }
+
+    fn on_idle_timeout(&mut self) {
+        match self {
+            IdleDetector::PerUser(detector) => detector.on_idle_timeout(),
+            IdleDetector::WholeChannel(detector) => detector.on_idle_timeout(),
+        }
+    }
+
+    fn next_timeout(&self) -> Instant {
+        match self {
+            IdleDetector::PerUser(detector) => detector.next_timeout(),
+            IdleDetector::WholeChannel(detector) => detector.next_timeout(),
+        }
+    }
+}
+
 pub(crate) struct VoiceActivity {
+    /// users who Discord says are speaking, but whose audio is below
+    /// the gate, so we're treating them as silent
+    gated_users: collections::HashSet<UserId>,
+    idle_detector: IdleDetector,
     rx_voice_activity: sync::mpsc::UnboundedReceiver<UserAudioEvent>,
     shutdown_token: CancellationToken,
     speaking_users: SpeakingUsers,
     tx_silent_user_events: sync::mpsc::UnboundedSender<UserAudioEvent>,
-    user_idle_detector: UserIdleDetector,
 }
 
 /// Input:
 ///  - user_id_starts_talking event
 ///  - user_id_stops_talking event
+///  - user's audio crossing the gate, which counts as them stopping
+///    or starting talking
 /// Output:
 ///  - emits events when:
 ///    - all users stop talking
 ///    - anyone then starts talking
-///  - after a user has been silent for N seconds
+///  - after a user has been silent for N seconds, or in
+///    whole-channel mode, after everyone has been silent for N seconds
 impl VoiceActivity {
     pub(crate) fn monitor(
+        finalization_mode: FinalizationMode,
         rx_voice_activity: sync::mpsc::UnboundedReceiver<UserAudioEvent>,
         shutdown_token: CancellationToken,
         tx_api_events: sync::mpsc::UnboundedSender<VoiceChannelEvent>,
@@ -153,14 +319,16 @@ impl VoiceActivity {
     ) -> task::JoinHandle<()> {
         let tx_silent_user_events_clone = tx_silent_user_events.clone();
         let mut voice_activity = Self {
+            gated_users: collections::HashSet::new(),
+            idle_detector: IdleDetector::new(
+                finalization_mode,
+                tx_silent_user_events_clone,
+                user_silence_timeout,
+            ),
             rx_voice_activity,
             shutdown_token,
             speaking_users: SpeakingUsers::new(tx_api_events),
             tx_silent_user_events,
-            user_idle_detector: UserIdleDetector::new(
-                tx_silent_user_events_clone,
-                user_silence_timeout,
-            ),
         };
         task::spawn(async move {
             voice_activity.loop_forever().await;
@@ -174,7 +342,7 @@ impl VoiceActivity {
         loop {
             idle_timeout
                 .as_mut()
-                .reset(self.user_idle_detector.next_timeout());
+                .reset(self.idle_detector.next_timeout());
 
             tokio::select! {
                 _ = self.shutdown_token.cancelled() => {
@@ -182,18 +350,21 @@ impl VoiceActivity {
                     return;
                 }
                 _ = idle_timeout.as_mut() => {
-                    self.user_idle_detector.on_idle_timeout()
+                    self.idle_detector.on_idle_timeout()
                 }
                 Some(event) = self.rx_voice_activity.recv() => {
+                    let Some(event) = self.apply_gate(event) else {
+                        continue;
+                    };
                     let UserAudioEvent { user_id, event_type } = &event;
                     match event_type {
                         UserAudioEventType::Speaking => {
                             self.speaking_users.add(user_id);
-                            self.user_idle_detector.on_speaking(user_id);
+                            self.idle_detector.on_speaking(user_id);
                         }
                         UserAudioEventType::Silent => {
                             self.speaking_users.remove(user_id);
-                            self.user_idle_detector.on_silent(user_id);
+                            self.idle_detector.on_silent(user_id);
                         }
                         _ => {}
                     };
@@ -210,6 +381,50 @@ impl VoiceActivity {
             }
         }
     }
+
+    /// Turns the user's audio crossing the gate into them stopping or
+    /// starting talking, so that an open mic which only picks up noise
+    /// still lets their audio be finalized.  Returns None if the event
+    /// makes no difference.
+    fn apply_gate(&mut self, event: UserAudioEvent) -> Option<UserAudioEvent> {
+        let UserAudioEvent {
+            user_id,
+            event_type,
+        } = event;
+        let event_type = match event_type {
+            UserAudioEventType::Gated(true) => {
+                if !self.speaking_users.contains(&user_id) {
+                    // they've already stopped
+                    return None;
+                }
+                self.gated_users.insert(user_id);
+                UserAudioEventType::Silent
+            }
+            UserAudioEventType::Gated(false) => {
+                if !self.gated_users.remove(&user_id) {
+                    return None;
+                }
+                UserAudioEventType::Speaking
+            }
+            UserAudioEventType::Silent => {
+                if self.gated_users.remove(&user_id) {
+                    // we already treated them as silent when they
+                    // went under the gate
+                    return None;
+                }
+                UserAudioEventType::Silent
+            }
+            UserAudioEventType::Speaking => {
+                self.gated_users.remove(&user_id);
+                UserAudioEventType::Speaking
+            }
+            event_type => event_type,
+        };
+        Some(UserAudioEvent {
+            user_id,
+            event_type,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -217,6 +432,89 @@ mod tests {
     use tokio::sync::mpsc::error::TryRecvError;
 
     use super::*;
+    use crate::model::clock::MockClock;
+
+    #[test]
+    fn test_idle_timeout_with_mock_clock() {
+        let clock = MockClock::new();
+        let (tx_silent_user, mut rx_silent_user) = sync::mpsc::unbounded_channel();
+        let mut detector =
+            UserIdleDetector::with_clock(clock.clone(), tx_silent_user, Duration::from_secs(1));
+
+        detector.on_silent(&1);
+        assert_eq!(
+            detector.next_timeout(),
+            clock.instant() + Duration::from_secs(1)
+        );
+
+        // no sleeping required
+        clock.advance(Duration::from_secs(1));
+        detector.on_idle_timeout();
+        assert!(rx_silent_user
+            .try_recv()
+            .is_ok_and(|x| (x.user_id == 1) && matches!(x.event_type, UserAudioEventType::Idle)));
+    }
+
+    #[test]
+    fn test_channel_idle_with_mock_clock() {
+        let clock = MockClock::new();
+        let (tx_silent_user, mut rx_silent_user) = sync::mpsc::unbounded_channel();
+        let mut detector =
+            ChannelIdleDetector::with_clock(clock.clone(), tx_silent_user, Duration::from_secs(1));
+        assert_eq!(detector.next_timeout(), clock.instant() + FOREVER);
+
+        detector.on_speaking(&2);
+        detector.on_speaking(&1);
+        detector.on_silent(&2);
+        // user 1 is still talking
+        assert_eq!(detector.next_timeout(), clock.instant() + FOREVER);
+        detector.on_silent(&1);
+        assert_eq!(
+            detector.next_timeout(),
+            clock.instant() + Duration::from_secs(1)
+        );
+
+        // someone speaking again holds off the flush
+        clock.advance(Duration::from_millis(500));
+        detector.on_speaking(&2);
+        assert_eq!(detector.next_timeout(), clock.instant() + FOREVER);
+        detector.on_silent(&2);
+
+        clock.advance(Duration::from_secs(1));
+        detector.on_idle_timeout();
+        for user_id in [1, 2] {
+            assert_eq!(
+                rx_silent_user.try_recv().unwrap(),
+                UserAudioEvent {
+                    user_id,
+                    event_type: UserAudioEventType::ChannelIdle {
+                        flush_id: 1,
+                        participants: 2,
+                        session: false,
+                    },
+                }
+            );
+        }
+        assert!(rx_silent_user.try_recv().is_err());
+
+        // nobody has spoken since, so there's nothing more to flush
+        assert_eq!(detector.next_timeout(), clock.instant() + FOREVER);
+        detector.on_silent(&1);
+        assert_eq!(detector.next_timeout(), clock.instant() + FOREVER);
+
+        detector.on_speaking(&3);
+        detector.on_silent(&3);
+        clock.advance(Duration::from_secs(1));
+        detector.on_idle_timeout();
+        assert_eq!(
+            rx_silent_user.try_recv().unwrap().event_type,
+            UserAudioEventType::ChannelIdle {
+                flush_id: 2,
+                participants: 1,
+                session: false,
+            }
+        );
+    }
 
     #[tokio::test]
     async fn test_voice_activity() {
@@ -225,6 +523,7 @@ mod tests {
         let (tx_silent_channel, mut rx_silent_channel) = sync::mpsc::unbounded_channel();
         let (tx_silent_user, mut rx_silent_user) = sync::mpsc::unbounded_channel();
         let voice_activity = VoiceActivity::monitor(
+            FinalizationMode::PerUser,
             rx,
             shutdown_token.clone(),
             tx_silent_channel,
@@ -334,6 +633,7 @@ mod tests {
         let (tx_silent_channel, mut rx_silent_channel) = sync::mpsc::unbounded_channel();
         let (tx_silent_user, mut rx_silent_user) = sync::mpsc::unbounded_channel();
         let voice_activity = VoiceActivity::monitor(
+            FinalizationMode::PerUser,
             rx,
             shutdown_token.clone(),
             tx_silent_channel,
@@ -465,6 +765,90 @@ mod tests {
         voice_activity.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_gated_open_mic_goes_idle() {
+        let shutdown_token = CancellationToken::new();
+        let (tx, rx) = sync::mpsc::unbounded_channel();
+        let (tx_silent_channel, mut rx_silent_channel) = sync::mpsc::unbounded_channel();
+        let (tx_silent_user, mut rx_silent_user) = sync::mpsc::unbounded_channel();
+        let voice_activity = VoiceActivity::monitor(
+            FinalizationMode::PerUser,
+            rx,
+            shutdown_token.clone(),
+            tx_silent_channel,
+            tx_silent_user,
+            Duration::from_millis(10),
+        );
+
+        // Discord says user 1 is speaking, and never says otherwise,
+        // but all we get from them is noise
+        tx.send(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::Speaking,
+        })
+        .unwrap();
+        for _ in 0..5 {
+            tx.send(UserAudioEvent {
+                user_id: 1,
+                event_type: UserAudioEventType::Gated(true),
+            })
+            .unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        for event_type in [
+            UserAudioEventType::Speaking,
+            UserAudioEventType::Silent,
+            UserAudioEventType::Idle,
+        ] {
+            assert_eq!(
+                rx_silent_user.try_recv().unwrap(),
+                UserAudioEvent {
+                    user_id: 1,
+                    event_type
+                }
+            );
+        }
+        assert!(rx_silent_user.try_recv().is_err());
+        assert_eq!(
+            rx_silent_channel.recv().await.unwrap(),
+            VoiceChannelEvent::ChannelSilent(false)
+        );
+        assert_eq!(
+            rx_silent_channel.recv().await.unwrap(),
+            VoiceChannelEvent::ChannelSilent(true)
+        );
+
+        // speech resumes, then Discord finally notices they stopped
+        for gated in [false, true] {
+            tx.send(UserAudioEvent {
+                user_id: 1,
+                event_type: UserAudioEventType::Gated(gated),
+            })
+            .unwrap();
+        }
+        tx.send(UserAudioEvent {
+            user_id: 1,
+            event_type: UserAudioEventType::Silent,
+        })
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        for event_type in [
+            UserAudioEventType::Speaking,
+            UserAudioEventType::Silent,
+            UserAudioEventType::Idle,
+        ] {
+            assert_eq!(rx_silent_user.try_recv().unwrap().event_type, event_type);
+        }
+        // the late Silent was already accounted for
+        assert!(rx_silent_user.try_recv().is_err());
+
+        shutdown_token.cancel();
+        voice_activity.await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_shutdown_on_token() {
         let shutdown_token = CancellationToken::new();
@@ -472,6 +856,7 @@ mod tests {
         let (tx_silent_channel, mut rx_silent_channel) = sync::mpsc::unbounded_channel();
         let (tx_silent_user, mut rx_silent_user) = sync::mpsc::unbounded_channel();
         let voice_activity = VoiceActivity::monitor(
+            FinalizationMode::PerUser,
             rx,
             shutdown_token.clone(),
             tx_silent_channel,
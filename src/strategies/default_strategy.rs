@@ -5,6 +5,9 @@ use crate::{audio::events::UserAudioEventType, model::types::Transcription};
 
 use super::strategy_trait::{TranscriptStrategy, WorkerActions, WorkerContext};
 
+/// Transcribes each utterance once, as soon as the user goes idle.
+/// There are no interim transcriptions for a final one to differ from,
+/// so `DiscrivenerConfig::commit_delay` doesn't apply, and is ignored.
 pub(crate) struct DefaultTranscriptStrategy {}
 
 impl DefaultTranscriptStrategy {
@@ -27,6 +30,15 @@ impl TranscriptStrategy for DefaultTranscriptStrategy {
                 // make a new transcription right now
                 Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))])
             }
+            // the worker takes care of these itself
+            UserAudioEventType::TranscriptionDisabled => None,
+            UserAudioEventType::TranscriptionEnabled => None,
+            UserAudioEventType::UtteranceBoundary => None,
+            UserAudioEventType::ChannelIdle { .. } => None,
+            UserAudioEventType::PipelineReset => None,
+            UserAudioEventType::EvictAudio { .. } => None,
+            UserAudioEventType::Gated(_) => None,
+            UserAudioEventType::AutoPeriodChanged(_) => None,
         }
     }
 
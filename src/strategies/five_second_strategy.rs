@@ -1,51 +1,105 @@
-use std::time::Duration;
+use std::{
+    cmp::{max, min},
+    time::Duration,
+};
 
 use crate::{
     audio::events::UserAudioEventType,
     model::{
-        constants::{AUDIO_TO_RECORD, USER_SILENCE_TIMEOUT},
+        config::TentativeTranscriptPolicy,
+        constants::{AUDIO_TO_RECORD, FIRST_TRANSCRIPT_PERIOD, USER_SILENCE_TIMEOUT},
         types::Transcription,
     },
 };
 
 use super::strategy_trait::{TranscriptStrategy, WorkerActions, WorkerContext};
 
-const FIRST_TRANSCRIPT_PERIOD: Duration = Duration::from_secs(5);
-const SUBSEQUENT_TRANSCRIPT_PERIOD: Duration = Duration::from_secs(1);
+/// how often we ask for interim transcriptions after the first, unless
+/// told otherwise with `Discrivener::set_auto_period`
+pub(crate) const SUBSEQUENT_TRANSCRIPT_PERIOD: Duration = Duration::from_secs(1);
 
 pub(crate) struct FiveSecondStrategy {
+    /// how long after the user goes silent we wait before asking for
+    /// the final transcription
+    commit_delay: Duration,
+    /// how much audio we wait for before the first interim transcription
+    first_transcript_period: Duration,
+    policy: TentativeTranscriptPolicy,
+    /// see `DiscrivenerConfig::segment_join_threshold`
+    segment_join_threshold: Duration,
+    /// how often we ask for interim transcriptions after the first
+    subsequent_transcript_period: Duration,
     tentative_transcript_opt: Option<Transcription>,
     tentative_transcripts_used: usize,
     tentative_transcripts_total: usize,
 }
 
 impl FiveSecondStrategy {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        commit_delay: Duration,
+        first_transcript_period: Duration,
+        policy: TentativeTranscriptPolicy,
+        segment_join_threshold: Duration,
+        subsequent_transcript_period: Duration,
+    ) -> Self {
         FiveSecondStrategy {
+            commit_delay,
+            first_transcript_period,
+            policy,
+            segment_join_threshold,
+            subsequent_transcript_period,
             tentative_transcript_opt: None,
             tentative_transcripts_used: 0,
             tentative_transcripts_total: 0,
         }
     }
 
+    /// True once there's enough audio for an interim transcription.
+    fn is_ready_for_transcription(&self, audio_duration: &Duration) -> bool {
+        audio_duration >= &self.first_transcript_period
+    }
+
     /// Returns the interval between now and when we want to take
     /// the next transcription.  This uses the following logic:
-    ///  - if the current audio duration is less than 5 seconds,
-    ///    then we want to take a transcription at the 5 second mark.
+    ///  - if the current audio duration is less than the first
+    ///    transcript period (5 seconds by default), then we want to
+    ///    take a transcription at the end of that period.
     ///  - if it's longer, than we want to take the next transcription
-    ///    at intervals of 1 second after the last transcription.
+    ///    at intervals of the subsequent transcript period (1 second
+    ///    by default) after the last transcription.
     fn get_next_transcript_time(&self, audio_duration: &Duration) -> Duration {
-        if audio_duration < &FIRST_TRANSCRIPT_PERIOD {
-            FIRST_TRANSCRIPT_PERIOD - *audio_duration
+        if !self.is_ready_for_transcription(audio_duration) {
+            self.first_transcript_period - *audio_duration
         } else {
             // apparently mod isn't implemented for Duration, so we have to
-            // do this the hard way.
-            let additional_audio = *audio_duration - FIRST_TRANSCRIPT_PERIOD;
-            let remainder_ms =
-                (additional_audio.as_millis() % SUBSEQUENT_TRANSCRIPT_PERIOD.as_millis()) as u64;
-            SUBSEQUENT_TRANSCRIPT_PERIOD - Duration::from_millis(remainder_ms)
+            // do this the hard way.  The period may have changed since
+            // the last transcription, so this is worked out afresh from
+            // the audio each time, rather than from when that was.
+            let period = self.subsequent_transcript_period;
+            let additional_audio = *audio_duration - self.first_transcript_period;
+            let remainder_ms = (additional_audio.as_millis() % period.as_millis()) as u64;
+            period - Duration::from_millis(remainder_ms)
         }
     }
+
+    /// True if the tentative transcript covers the given length of
+    /// audio, within our tolerance.
+    fn covers(&self, tentative_transcript: &Transcription, audio_duration: &Duration) -> bool {
+        let difference = max(tentative_transcript.audio_duration, *audio_duration)
+            - min(tentative_transcript.audio_duration, *audio_duration);
+        difference <= self.policy.duration_tolerance
+    }
+
+    /// True if whisper was sure enough of the tentative transcript's
+    /// tokens for it to be worth keeping.
+    fn is_confident(&self, tentative_transcript: &Transcription) -> bool {
+        let Some(min_confidence) = self.policy.min_confidence else {
+            return true;
+        };
+        tentative_transcript
+            .average_confidence()
+            .is_some_and(|average| average > min_confidence)
+    }
 }
 
 impl TranscriptStrategy for FiveSecondStrategy {
@@ -59,15 +113,16 @@ impl TranscriptStrategy for FiveSecondStrategy {
                 self.get_next_transcript_time(audio_duration),
             ))]),
             UserAudioEventType::Silent => {
-                // request a transcription, now!
-                Some(vec![WorkerActions::NewTranscript(Some(Duration::ZERO))])
+                // request a transcription, now!  Or once the commit
+                // delay is up, if they don't start talking again first.
+                Some(vec![WorkerActions::NewTranscript(Some(self.commit_delay))])
             }
             UserAudioEventType::Idle => {
                 // if we had a tentative transcript, and we haven't gotten
                 // more audio since then, then we can return the tentative
                 // transcript as-is.
                 if let Some(tentative_transcript) = self.tentative_transcript_opt.take() {
-                    if audio_duration == &tentative_transcript.audio_duration {
+                    if self.covers(&tentative_transcript, audio_duration) {
                         self.tentative_transcripts_used += 1;
                         return Some(vec![WorkerActions::Publish(tentative_transcript)]);
                     }
@@ -76,6 +131,26 @@ impl TranscriptStrategy for FiveSecondStrategy {
                 // on past performance
                 None
             }
+            // voice activity turns these into Speaking and Silent
+            UserAudioEventType::Gated(_) => None,
+            // nothing is buffered for them while they're disabled
+            UserAudioEventType::TranscriptionEnabled => None,
+            UserAudioEventType::AutoPeriodChanged(period) => {
+                // whatever we've already scheduled stands, and the
+                // new period applies from the next one
+                self.subsequent_transcript_period = *period;
+                None
+            }
+            UserAudioEventType::TranscriptionDisabled
+            | UserAudioEventType::UtteranceBoundary
+            | UserAudioEventType::ChannelIdle { .. }
+            | UserAudioEventType::PipelineReset
+            | UserAudioEventType::EvictAudio { .. } => {
+                // the worker either throws the audio away or finalizes
+                // it itself, so our transcript would no longer match it
+                self.tentative_transcript_opt = None;
+                None
+            }
         }
     }
 
@@ -98,7 +173,7 @@ impl TranscriptStrategy for FiveSecondStrategy {
         if context.silent_after || running_out_of_space {
             return Some(vec![
                 WorkerActions::Publish(transcript.clone()),
-                WorkerActions::NewTranscript(Some(FIRST_TRANSCRIPT_PERIOD)),
+                WorkerActions::NewTranscript(Some(self.first_transcript_period)),
             ]);
         }
 
@@ -106,11 +181,18 @@ impl TranscriptStrategy for FiveSecondStrategy {
             transcript.start_timestamp + transcript.audio_duration - USER_SILENCE_TIMEOUT;
 
         let (finalized_transcript, tentative_transcript) =
-            Transcription::split_at_end_time(transcript, end_time);
+            Transcription::split_at_end_time(transcript, end_time, self.segment_join_threshold);
 
-        self.tentative_transcript_opt = if context.audio_duration
-            == tentative_transcript.audio_duration
+        // compare against the buffer as it was when the transcription
+        // was requested, less what we're finalizing now.  Audio which
+        // has arrived since doesn't make the tentative transcript any
+        // less right about the audio it covers.
+        let remaining_duration = context
+            .requested_duration
+            .saturating_sub(finalized_transcript.audio_duration);
+        self.tentative_transcript_opt = if self.covers(&tentative_transcript, &remaining_duration)
             && !tentative_transcript.is_empty()
+            && self.is_confident(&tentative_transcript)
         {
             self.tentative_transcripts_total += 1;
             if 0 == self.tentative_transcripts_total % 10 {
@@ -135,3 +217,256 @@ impl TranscriptStrategy for FiveSecondStrategy {
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::model::types::{TextSegment, TokenWithProbability};
+
+    use super::*;
+
+    const AUDIO_DURATION: Duration = Duration::from_secs(3);
+
+    /// A transcription which is tentative all the way through.
+    fn unfinished_transcript(p: u32) -> Transcription {
+        Transcription {
+            start_timestamp: SystemTime::now(),
+            user_id: 1,
+            segments: vec![TextSegment {
+                start_offset_ms: 0,
+                end_offset_ms: AUDIO_DURATION.as_millis() as u32,
+                start_sample: 0,
+                end_sample: 0,
+                low_confidence_p: 0,
+                cleaned_text: None,
+                phonemes: None,
+                raw_token_ids: None,
+                whisper_timing: None,
+                tokens_with_probability: vec![TokenWithProbability {
+                    p,
+                    token_id: 0,
+                    token_text: " hello".to_string(),
+                }],
+            }],
+            audio_duration: AUDIO_DURATION,
+            processing_time: Duration::ZERO,
+            utterance_id: 0,
+            language: None,
+            audio_channel: None,
+        }
+    }
+
+    fn keeps_tentative(
+        policy: TentativeTranscriptPolicy,
+        transcript: &Transcription,
+        buffer_duration: Duration,
+    ) -> bool {
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::ZERO,
+            FIRST_TRANSCRIPT_PERIOD,
+            policy,
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        strategy.handle_transcription(
+            transcript,
+            WorkerContext {
+                audio_duration: buffer_duration,
+                requested_duration: buffer_duration,
+                silent_after: false,
+            },
+        );
+        strategy.tentative_transcript_opt.is_some()
+    }
+
+    #[test]
+    fn test_tentative_duration_tolerance() {
+        let policy = TentativeTranscriptPolicy::default();
+        let transcript = unfinished_transcript(90);
+        let tolerance = policy.duration_tolerance;
+        assert!(keeps_tentative(policy, &transcript, AUDIO_DURATION));
+        assert!(keeps_tentative(
+            policy,
+            &transcript,
+            AUDIO_DURATION + tolerance
+        ));
+        assert!(keeps_tentative(
+            policy,
+            &transcript,
+            AUDIO_DURATION - tolerance
+        ));
+        let just_over = tolerance + Duration::from_millis(1);
+        assert!(!keeps_tentative(
+            policy,
+            &transcript,
+            AUDIO_DURATION + just_over
+        ));
+        assert!(!keeps_tentative(
+            policy,
+            &transcript,
+            AUDIO_DURATION - just_over
+        ));
+
+        let exact = TentativeTranscriptPolicy {
+            duration_tolerance: Duration::ZERO,
+            ..policy
+        };
+        assert!(keeps_tentative(exact, &transcript, AUDIO_DURATION));
+        assert!(!keeps_tentative(
+            exact,
+            &transcript,
+            AUDIO_DURATION + Duration::from_millis(1)
+        ));
+    }
+
+    #[test]
+    fn test_tentative_min_confidence() {
+        let policy = TentativeTranscriptPolicy {
+            min_confidence: Some(60),
+            ..Default::default()
+        };
+        assert!(keeps_tentative(
+            policy,
+            &unfinished_transcript(61),
+            AUDIO_DURATION
+        ));
+        assert!(!keeps_tentative(
+            policy,
+            &unfinished_transcript(60),
+            AUDIO_DURATION
+        ));
+    }
+
+    #[test]
+    fn test_tentative_published_when_idle() {
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::ZERO,
+            FIRST_TRANSCRIPT_PERIOD,
+            TentativeTranscriptPolicy::default(),
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        strategy.handle_transcription(
+            &unfinished_transcript(90),
+            WorkerContext {
+                audio_duration: AUDIO_DURATION,
+                requested_duration: AUDIO_DURATION,
+                silent_after: false,
+            },
+        );
+        // the buffer has been rounded slightly differently since
+        let actions = strategy
+            .handle_event(
+                &UserAudioEventType::Idle,
+                &(AUDIO_DURATION + Duration::from_millis(1)),
+            )
+            .unwrap();
+        assert!(matches!(actions.as_slice(), [WorkerActions::Publish(_)]));
+    }
+
+    fn next_transcript_time(
+        strategy: &mut FiveSecondStrategy,
+        audio_duration: Duration,
+    ) -> Duration {
+        match strategy
+            .handle_event(&UserAudioEventType::Speaking, &audio_duration)
+            .unwrap()
+            .as_slice()
+        {
+            [WorkerActions::NewTranscript(Some(delay))] => *delay,
+            _ => panic!("expected a transcript to be scheduled"),
+        }
+    }
+
+    #[test]
+    fn test_early_requests_held_back() {
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::ZERO,
+            Duration::from_secs(2),
+            TentativeTranscriptPolicy::default(),
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        assert_eq!(
+            next_transcript_time(&mut strategy, Duration::from_millis(500)),
+            Duration::from_millis(1500)
+        );
+        // after that, every second as usual
+        assert_eq!(
+            next_transcript_time(&mut strategy, Duration::from_millis(2300)),
+            Duration::from_millis(700)
+        );
+    }
+
+    #[test]
+    fn test_auto_period_changed() {
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::ZERO,
+            Duration::from_secs(2),
+            TentativeTranscriptPolicy::default(),
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        assert_eq!(
+            next_transcript_time(&mut strategy, Duration::from_millis(4300)),
+            Duration::from_millis(700)
+        );
+        let actions = strategy.handle_event(
+            &UserAudioEventType::AutoPeriodChanged(Duration::from_secs(3)),
+            &Duration::from_millis(4500),
+        );
+        assert!(actions.is_none());
+        // lined up with the new period, counting from the first request
+        assert_eq!(
+            next_transcript_time(&mut strategy, Duration::from_millis(4500)),
+            Duration::from_millis(500)
+        );
+        // which still isn't held back any further than before
+        assert_eq!(
+            next_transcript_time(&mut strategy, Duration::from_millis(1000)),
+            Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn test_final_request_not_held_back() {
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::ZERO,
+            Duration::from_secs(2),
+            TentativeTranscriptPolicy::default(),
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        let actions = strategy
+            .handle_event(&UserAudioEventType::Silent, &Duration::from_millis(500))
+            .unwrap();
+        assert!(matches!(
+            actions.as_slice(),
+            [WorkerActions::NewTranscript(Some(Duration::ZERO))]
+        ));
+    }
+
+    #[test]
+    fn test_commit_delay() {
+        let mut strategy = FiveSecondStrategy::new(
+            Duration::from_millis(500),
+            Duration::from_secs(2),
+            TentativeTranscriptPolicy::default(),
+            Duration::ZERO,
+            SUBSEQUENT_TRANSCRIPT_PERIOD,
+        );
+        let actions = strategy
+            .handle_event(&UserAudioEventType::Silent, &Duration::from_secs(3))
+            .unwrap();
+        assert!(matches!(
+            actions.as_slice(),
+            [WorkerActions::NewTranscript(Some(delay))] if *delay == Duration::from_millis(500)
+        ));
+        // interim transcriptions aren't held back any further
+        assert_eq!(
+            next_transcript_time(&mut strategy, Duration::from_millis(500)),
+            Duration::from_millis(1500)
+        );
+    }
+}
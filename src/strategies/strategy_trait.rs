@@ -18,6 +18,9 @@ pub(crate) enum WorkerActions {
 
 pub(crate) struct WorkerContext {
     pub audio_duration: Duration,
+    /// how much of the buffer the transcription was requested for.
+    /// More audio may have arrived since.
+    pub requested_duration: Duration,
     pub silent_after: bool,
 }
 